@@ -0,0 +1,488 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Import and export of logins as CSV.
+//!
+//! Exports use the same layout as Firefox Desktop's "Export Logins...".
+//! Imports detect the layout from the header row, and understand the CSV
+//! exports of Firefox Desktop, Chromium-based browsers, Bitwarden and
+//! 1Password. Each row is fixed up and checked for dupes before it's added,
+//! and the returned [CsvImportReport] says what happened to every row.
+//!
+//! The parser and writer follow RFC 4180: fields may be quoted, quotes inside
+//! quoted fields are doubled, and quoted fields may contain commas and line
+//! breaks.
+
+use crate::db::LoginDb;
+use crate::error::*;
+use crate::login::Login;
+use serde_derive::*;
+use sync_guid::Guid;
+
+/// The CSV layouts we know how to import.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub enum CsvFormat {
+    /// `url,username,password,httpRealm,formActionOrigin,guid,timeCreated,
+    /// timeLastUsed,timePasswordChanged`. This is also what we export.
+    Firefox,
+    /// `name,url,username,password` and sometimes `note`.
+    Chromium,
+    /// `folder,favorite,type,name,notes,fields,reprompt,login_uri,
+    /// login_username,login_password,login_totp`. Only rows with a `type` of
+    /// `login` are imported.
+    Bitwarden,
+    /// `Title,Url,Username,Password,...`, or `website` instead of `Url` in
+    /// older versions.
+    OnePassword,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(tag = "status", rename_all = "camelCase")]
+pub enum CsvImportOutcome {
+    /// The login was added with this id.
+    Added { id: String },
+    /// A login with the same origin, username and form or realm already
+    /// exists, so the row was skipped.
+    Duplicate,
+    /// The row couldn't be imported. `reason` is an error label, like the ones
+    /// in `MigrationMetrics`, and never includes the row's contents.
+    Invalid { reason: String },
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct CsvImportEntry {
+    /// The line of the file where the row starts, counting the header as
+    /// line 1.
+    pub line: usize,
+    pub outcome: CsvImportOutcome,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct CsvImportReport {
+    pub format: CsvFormat,
+    pub num_added: u64,
+    pub num_duplicates: u64,
+    pub num_invalid: u64,
+    pub entries: Vec<CsvImportEntry>,
+}
+
+const EXPORT_HEADER: &[&str] = &[
+    "url",
+    "username",
+    "password",
+    "httpRealm",
+    "formActionOrigin",
+    "guid",
+    "timeCreated",
+    "timeLastUsed",
+    "timePasswordChanged",
+];
+
+/// Exports all logins in the Firefox Desktop layout.
+pub fn export_csv(db: &LoginDb) -> Result<String> {
+    let mut out = String::new();
+    write_record(&mut out, EXPORT_HEADER.iter().copied());
+    for login in db.get_all()? {
+        let time_created = login.time_created.to_string();
+        let time_last_used = login.time_last_used.to_string();
+        let time_password_changed = login.time_password_changed.to_string();
+        write_record(
+            &mut out,
+            [
+                login.hostname.as_str(),
+                login.username.as_str(),
+                login.password.as_str(),
+                login.http_realm.as_deref().unwrap_or_default(),
+                login.form_submit_url.as_deref().unwrap_or_default(),
+                login.id.as_str(),
+                time_created.as_str(),
+                time_last_used.as_str(),
+                time_password_changed.as_str(),
+            ]
+            .iter()
+            .copied(),
+        );
+    }
+    Ok(out)
+}
+
+/// Imports logins from a CSV file in any of the [CsvFormat] layouts. Rows that
+/// can't be imported, or that duplicate an existing login, are skipped and
+/// reported, but don't fail the import.
+pub fn import_csv(db: &LoginDb, csv: &str) -> Result<CsvImportReport> {
+    let mut records = parse_csv(csv)?.into_iter();
+    let (_, header) = match records.next() {
+        Some(header) => header,
+        None => throw!(ErrorKind::InvalidCsv("Missing header row".into())),
+    };
+    let columns = Columns::from_header(&header)?;
+
+    let mut report = CsvImportReport {
+        format: columns.format,
+        num_added: 0,
+        num_duplicates: 0,
+        num_invalid: 0,
+        entries: Vec::new(),
+    };
+    for (line, record) in records {
+        let outcome = match columns.login_from_record(&record) {
+            Ok(login) => import_login(db, login)?,
+            Err(reason) => CsvImportOutcome::Invalid {
+                reason: reason.into(),
+            },
+        };
+        match outcome {
+            CsvImportOutcome::Added { .. } => report.num_added += 1,
+            CsvImportOutcome::Duplicate => report.num_duplicates += 1,
+            CsvImportOutcome::Invalid { .. } => report.num_invalid += 1,
+        }
+        report.entries.push(CsvImportEntry { line, outcome });
+    }
+    log::info!(
+        "Imported {} logins from CSV ({} duplicates, {} invalid)",
+        report.num_added,
+        report.num_duplicates,
+        report.num_invalid
+    );
+    Ok(report)
+}
+
+// Only errors that affect the whole import are returned as `Err`. Problems
+// with the login itself are reported as `Invalid`.
+fn import_login(db: &LoginDb, login: Login) -> Result<CsvImportOutcome> {
+    let mut login = match login.fixup() {
+        Ok(login) => login,
+        Err(e) => {
+            return Ok(CsvImportOutcome::Invalid {
+                reason: e.label().into(),
+            })
+        }
+    };
+    // Keep the GUID from a Firefox export, unless it's taken.
+    if !login.guid().is_valid_for_sync_server() || db.exists(&login.id)? {
+        login.id = Guid::random().into_string();
+    }
+    if db.dupe_exists(&login)? {
+        return Ok(CsvImportOutcome::Duplicate);
+    }
+    match db.add(login) {
+        Ok(login) => Ok(CsvImportOutcome::Added { id: login.id }),
+        Err(e) => match e.kind() {
+            ErrorKind::InvalidLogin(_) | ErrorKind::DuplicateGuid(_) => {
+                Ok(CsvImportOutcome::Invalid {
+                    reason: e.label().into(),
+                })
+            }
+            _ => Err(e),
+        },
+    }
+}
+
+/// Where each field is in the records of a file.
+struct Columns {
+    format: CsvFormat,
+    len: usize,
+    url: usize,
+    username: Option<usize>,
+    password: usize,
+    http_realm: Option<usize>,
+    form_action_origin: Option<usize>,
+    guid: Option<usize>,
+    time_created: Option<usize>,
+    time_last_used: Option<usize>,
+    time_password_changed: Option<usize>,
+    // Bitwarden exports other item types (like cards and notes) too.
+    item_type: Option<usize>,
+}
+
+impl Columns {
+    fn from_header(header: &[String]) -> Result<Self> {
+        let names: Vec<String> = header.iter().map(|h| h.trim().to_lowercase()).collect();
+        let find = |candidates: &[&str]| {
+            names
+                .iter()
+                .position(|name| candidates.contains(&name.as_str()))
+        };
+        let has = |name: &str| names.iter().any(|n| n == name);
+        let format = if has("login_uri") {
+            CsvFormat::Bitwarden
+        } else if has("formactionorigin") || has("httprealm") {
+            CsvFormat::Firefox
+        } else if has("title") {
+            CsvFormat::OnePassword
+        } else if has("name") && has("url") {
+            CsvFormat::Chromium
+        } else {
+            throw!(ErrorKind::InvalidCsv("Unrecognized header row".into()))
+        };
+        let (url, password) = match (
+            find(&["url", "login_uri", "website", "login url"]),
+            find(&["password", "login_password"]),
+        ) {
+            (Some(url), Some(password)) => (url, password),
+            _ => throw!(ErrorKind::InvalidCsv(
+                "Header row is missing the URL or password column".into()
+            )),
+        };
+        Ok(Columns {
+            format,
+            len: names.len(),
+            url,
+            username: find(&["username", "login_username"]),
+            password,
+            http_realm: find(&["httprealm"]),
+            form_action_origin: find(&["formactionorigin"]),
+            guid: find(&["guid"]),
+            time_created: find(&["timecreated"]),
+            time_last_used: find(&["timelastused"]),
+            time_password_changed: find(&["timepasswordchanged"]),
+            item_type: match format {
+                CsvFormat::Bitwarden => find(&["type"]),
+                _ => None,
+            },
+        })
+    }
+
+    /// Builds a login from a record, or returns the reason it's invalid.
+    fn login_from_record(&self, record: &[String]) -> std::result::Result<Login, &'static str> {
+        if record.len() != self.len {
+            return Err("WrongColumnCount");
+        }
+        let field = |idx: Option<usize>| idx.map(|idx| record[idx].as_str()).unwrap_or_default();
+        let non_empty = |idx: Option<usize>| {
+            idx.map(|idx| record[idx].clone())
+                .filter(|value| !value.is_empty())
+        };
+        let timestamp = |idx: Option<usize>| field(idx).trim().parse::<i64>().unwrap_or_default();
+        if let Some(item_type) = self.item_type {
+            if record[item_type] != "login" {
+                return Err("NotALogin");
+            }
+        }
+        let http_realm = non_empty(self.http_realm);
+        // Logins from other password managers are for web forms, and
+        // should be filled into any form on the site.
+        let form_submit_url = match http_realm {
+            Some(_) => None,
+            None => Some(field(self.form_action_origin).to_string()),
+        };
+        Ok(Login {
+            id: field(self.guid).to_string(),
+            hostname: field(Some(self.url)).trim().to_string(),
+            username: field(self.username).to_string(),
+            password: field(Some(self.password)).to_string(),
+            http_realm,
+            form_submit_url,
+            time_created: timestamp(self.time_created).max(0),
+            time_last_used: timestamp(self.time_last_used).max(0),
+            time_password_changed: timestamp(self.time_password_changed).max(0),
+            ..Login::default()
+        })
+    }
+}
+
+/// Parses CSV into records, along with the line where each record starts.
+/// Blank lines are skipped.
+fn parse_csv(input: &str) -> Result<Vec<(usize, Vec<String>)>> {
+    let input = input.strip_prefix('\u{feff}').unwrap_or(input);
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = String::new();
+    let mut chars = input.chars().peekable();
+    let mut line = 1;
+    let mut record_line = 1;
+    // Whether the current field is quoted, and whether we're still inside
+    // the quotes.
+    let mut quoted = false;
+    let mut in_quotes = false;
+
+    while let Some(c) = chars.next() {
+        if in_quotes {
+            match c {
+                '"' if chars.peek() == Some(&'"') => {
+                    chars.next();
+                    field.push('"');
+                }
+                '"' => in_quotes = false,
+                '\n' => {
+                    line += 1;
+                    field.push(c);
+                }
+                _ => field.push(c),
+            }
+            continue;
+        }
+        match c {
+            '"' if field.is_empty() && !quoted => {
+                quoted = true;
+                in_quotes = true;
+            }
+            ',' => {
+                record.push(std::mem::take(&mut field));
+                quoted = false;
+            }
+            '\r' if chars.peek() == Some(&'\n') => {}
+            '\r' | '\n' => {
+                if !record.is_empty() || !field.is_empty() || quoted {
+                    record.push(std::mem::take(&mut field));
+                    records.push((record_line, std::mem::take(&mut record)));
+                }
+                quoted = false;
+                line += 1;
+                record_line = line;
+            }
+            _ => field.push(c),
+        }
+    }
+    if in_quotes {
+        throw!(ErrorKind::InvalidCsv(format!(
+            "Unterminated quoted field starting on line {}",
+            record_line
+        )));
+    }
+    if !record.is_empty() || !field.is_empty() || quoted {
+        record.push(field);
+        records.push((record_line, record));
+    }
+    Ok(records)
+}
+
+// Like Desktop, we quote every field, so that values with leading zeros or
+// that look like formulas aren't mangled by spreadsheets.
+fn write_record<'a>(out: &mut String, fields: impl Iterator<Item = &'a str>) {
+    for (i, field) in fields.enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        out.push('"');
+        out.push_str(&field.replace('"', "\"\""));
+        out.push('"');
+    }
+    out.push_str("\r\n");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn import(db: &LoginDb, csv: &str) -> CsvImportReport {
+        import_csv(db, csv).unwrap()
+    }
+
+    #[test]
+    fn test_parse_csv() {
+        let records =
+            parse_csv("\u{feff}a,\"b,c\",\"d \"\"e\"\"\"\r\n\r\n\"multi\nline\",,\"\"\nlast")
+                .unwrap();
+        let expected: Vec<(usize, Vec<String>)> = vec![
+            (1, vec!["a".into(), "b,c".into(), "d \"e\"".into()]),
+            (3, vec!["multi\nline".into(), "".into(), "".into()]),
+            (5, vec!["last".into()]),
+        ];
+        assert_eq!(records, expected);
+        assert!(parse_csv("a,\"b\nc").is_err());
+    }
+
+    #[test]
+    fn test_import_formats() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+
+        let report = import(
+            &db,
+            "name,url,username,password\n\
+             example,https://www.example.com/login?next=1,alice,hunter2\n\
+             http,http://example.org,,secret\n",
+        );
+        assert_eq!(report.format, CsvFormat::Chromium);
+        assert_eq!(report.num_added, 2);
+
+        let report = import(
+            &db,
+            "folder,favorite,type,name,notes,fields,reprompt,login_uri,login_username,login_password,login_totp\n\
+             ,,login,Example,,,0,https://www.example.com,bob,pa55word,\n\
+             ,,note,Secret note,text,,0,,,,\n",
+        );
+        assert_eq!(report.format, CsvFormat::Bitwarden);
+        assert_eq!(report.num_added, 1);
+        assert_eq!(
+            report.entries[1],
+            CsvImportEntry {
+                line: 3,
+                outcome: CsvImportOutcome::Invalid {
+                    reason: "NotALogin".into()
+                },
+            }
+        );
+
+        let report = import(
+            &db,
+            "Title,Url,Username,Password,OTPAuth,Favorite,Archived,Tags,Notes\n\
+             Example,https://www.example.com,alice,different,,false,false,,\n\
+             Empty,https://www.example.net,carol,,,false,false,,\n\
+             Short,https://www.example.net\n",
+        );
+        assert_eq!(report.format, CsvFormat::OnePassword);
+        assert_eq!(report.num_added, 0);
+        assert_eq!(report.num_duplicates, 1);
+        assert_eq!(report.num_invalid, 2);
+        assert_eq!(
+            report.entries[1].outcome,
+            CsvImportOutcome::Invalid {
+                reason: "InvalidLogin::EmptyPassword".into()
+            }
+        );
+        assert_eq!(
+            report.entries[2].outcome,
+            CsvImportOutcome::Invalid {
+                reason: "WrongColumnCount".into()
+            }
+        );
+
+        let logins = db.get_all().unwrap();
+        assert_eq!(logins.len(), 3);
+        let alice = logins.iter().find(|l| l.username == "alice").unwrap();
+        assert_eq!(alice.hostname, "https://www.example.com");
+        assert_eq!(alice.password, "hunter2");
+        assert_eq!(alice.form_submit_url.as_deref(), Some(""));
+
+        assert!(import_csv(&db, "foo,bar\n1,2\n").is_err());
+    }
+
+    #[test]
+    fn test_export_round_trip() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let login = db
+            .add(Login {
+                hostname: "https://www.example.com".into(),
+                http_realm: Some("Basic \"realm\", with comma".into()),
+                username: "user".into(),
+                password: "pass\nword".into(),
+                ..Login::default()
+            })
+            .unwrap();
+        let csv = export_csv(&db).unwrap();
+        assert!(csv.starts_with("\"url\",\"username\",\"password\",\"httpRealm\""));
+
+        // Importing into the same database finds the dupe.
+        let report = import(&db, &csv);
+        assert_eq!(report.format, CsvFormat::Firefox);
+        assert_eq!(report.num_duplicates, 1);
+
+        let other = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let report = import(&other, &csv);
+        assert_eq!(
+            report.entries,
+            vec![CsvImportEntry {
+                line: 2,
+                outcome: CsvImportOutcome::Added {
+                    id: login.id.clone()
+                },
+            }]
+        );
+        assert_eq!(other.get_by_id(&login.id).unwrap().unwrap(), login);
+    }
+}
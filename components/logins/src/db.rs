@@ -2,8 +2,11 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
+use crate::encryption::{self, EncryptorDecryptor};
 use crate::error::*;
+use crate::health::HealthState;
 use crate::login::{Login, SyncStatus};
+use crate::password_history;
 use crate::schema;
 use crate::util;
 use lazy_static::lazy_static;
@@ -15,8 +18,9 @@ use rusqlite::{
 use serde_derive::*;
 use sql_support::{self, ConnExt};
 use sql_support::{SqlInterruptHandle, SqlInterruptScope};
+use std::cell::RefCell;
 use std::ops::Deref;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::{atomic::AtomicUsize, Arc};
 use std::time::{Duration, Instant, SystemTime};
 use sync_guid::Guid;
@@ -45,6 +49,7 @@ pub struct MigrationMetrics {
 pub struct LoginDb {
     pub db: Connection,
     interrupt_counter: Arc<AtomicUsize>,
+    pub(crate) health: RefCell<HealthState>,
 }
 
 impl LoginDb {
@@ -52,6 +57,16 @@ impl LoginDb {
         db: Connection,
         encryption_key: Option<&str>,
         salt: Option<&str>,
+    ) -> Result<Self> {
+        Self::with_connection_and_field_encryption(db, encryption_key, salt, None, false)
+    }
+
+    fn with_connection_and_field_encryption(
+        db: Connection,
+        encryption_key: Option<&str>,
+        salt: Option<&str>,
+        encdec: Option<Arc<EncryptorDecryptor>>,
+        encrypt_origin: bool,
     ) -> Result<Self> {
         #[cfg(test)]
         {
@@ -78,16 +93,116 @@ impl LoginDb {
         // do this on Android, or allow caller to configure it.
         db.set_pragma("temp_store", 2)?;
 
+        encryption::register_sql_functions(&db, encdec.clone(), encrypt_origin)?;
+
         let mut logins = Self {
             db,
             interrupt_counter: Arc::new(AtomicUsize::new(0)),
+            health: RefCell::new(HealthState::default()),
         };
         let tx = logins.db.transaction()?;
         schema::init(&tx)?;
         tx.commit()?;
+        match encdec {
+            Some(encdec) => logins.check_field_encryption_canary(&encdec, encrypt_origin)?,
+            None => logins.check_not_field_encrypted()?,
+        }
         Ok(logins)
     }
 
+    /// Opens a database where only the secret fields of each login are
+    /// encrypted, with a key from `create_key`, instead of the whole database
+    /// being encrypted with SQLCipher. The secret fields are the username and
+    /// password, and the origin too if `encrypt_origin` is true. Fails with an
+    /// `InvalidKey` error if the database was created with a different key or
+    /// a different `encrypt_origin`, or already has logins that aren't field
+    /// encrypted. Opening a field encrypted database without its key fails in
+    /// the same way.
+    pub fn open_with_field_encryption(
+        path: impl AsRef<Path>,
+        key: &str,
+        encrypt_origin: bool,
+    ) -> Result<Self> {
+        let encdec = Arc::new(EncryptorDecryptor::new(key)?);
+        Self::with_connection_and_field_encryption(
+            Connection::open(path)?,
+            None,
+            None,
+            Some(encdec),
+            encrypt_origin,
+        )
+    }
+
+    // The first time a field encrypted database is opened, we store a known
+    // value encrypted with the key, so that opening it with the wrong key
+    // fails right away, like it does with SQLCipher, instead of on the first
+    // read. The canary also records that the database uses field encryption,
+    // and whether it encrypts origins, so we refuse to open an existing
+    // database in another mode; otherwise we'd mix plaintext and encrypted
+    // logins in the same tables.
+    fn check_field_encryption_canary(
+        &self,
+        encdec: &EncryptorDecryptor,
+        encrypt_origin: bool,
+    ) -> Result<()> {
+        match self.get_meta::<String>(schema::FIELD_ENCRYPTION_CANARY_META_KEY)? {
+            Some(canary) => {
+                if encdec.decrypt(&canary)? != FIELD_ENCRYPTION_CANARY {
+                    throw!(ErrorKind::CryptoError(
+                        jwcrypto::JwCryptoError::IllegalState("Unexpected canary value")
+                    ));
+                }
+                let encrypts_origin = self
+                    .get_meta::<bool>(schema::FIELD_ENCRYPTION_ORIGIN_META_KEY)?
+                    .unwrap_or(false);
+                if encrypts_origin != encrypt_origin {
+                    throw!(ErrorKind::CryptoError(
+                        jwcrypto::JwCryptoError::IllegalState(
+                            "The database was created with a different origin encryption mode"
+                        )
+                    ));
+                }
+            }
+            None => {
+                if self.has_logins()? {
+                    throw!(ErrorKind::CryptoError(
+                        jwcrypto::JwCryptoError::IllegalState(
+                            "Can't open a database without field encryption with a field key"
+                        )
+                    ));
+                }
+                self.put_meta(
+                    schema::FIELD_ENCRYPTION_CANARY_META_KEY,
+                    &encdec.encrypt(FIELD_ENCRYPTION_CANARY)?,
+                )?;
+                self.put_meta(schema::FIELD_ENCRYPTION_ORIGIN_META_KEY, &encrypt_origin)?
+            }
+        }
+        Ok(())
+    }
+
+    fn check_not_field_encrypted(&self) -> Result<()> {
+        if self
+            .get_meta::<String>(schema::FIELD_ENCRYPTION_CANARY_META_KEY)?
+            .is_some()
+        {
+            throw!(ErrorKind::CryptoError(
+                jwcrypto::JwCryptoError::IllegalState(
+                    "Can't open a field encrypted database without its key"
+                )
+            ));
+        }
+        Ok(())
+    }
+
+    fn has_logins(&self) -> Result<bool> {
+        Ok(self.query_row(
+            "SELECT EXISTS(SELECT 1 FROM loginsL) OR EXISTS(SELECT 1 FROM loginsM)",
+            NO_PARAMS,
+            |row| row.get(0),
+        )?)
+    }
+
     pub fn open(path: impl AsRef<Path>, encryption_key: Option<&str>) -> Result<Self> {
         Self::with_connection(Connection::open(path)?, encryption_key, None)
     }
@@ -105,6 +220,7 @@ impl LoginDb {
         Self::with_connection(Connection::open_in_memory()?, encryption_key, None)
     }
 
+    /// Only applies to SQLCipher databases.
     pub fn disable_mem_security(&self) -> Result<()> {
         self.conn().set_pragma("cipher_memory_security", false)?;
         Ok(())
@@ -115,6 +231,9 @@ impl LoginDb {
     /// Once the database is readable and writeable, PRAGMA rekey
     /// can be used to re-encrypt every page in the database with a new key.
     /// https://www.zetetic.net/sqlcipher/sqlcipher-api/#Changing_Key
+    ///
+    /// Only applies to SQLCipher databases, and can't be used to change the
+    /// key of a database opened with `open_with_field_encryption`.
     pub fn rekey_database(&self, new_encryption_key: &str) -> Result<()> {
         self.conn().set_pragma("rekey", new_encryption_key)?;
         Ok(())
@@ -195,6 +314,150 @@ pub fn open_and_migrate_to_plaintext_header(
     Ok(())
 }
 
+const FIELD_ENCRYPTION_CANARY: &str = "logins field encryption canary";
+
+/// Migrates an existing SQLCipher database to one where only the secret fields
+/// are encrypted, with `new_key` (from `create_key`). The origin is encrypted,
+/// too, if `encrypt_origin` is true (see `LoginDb::open_with_field_encryption`).
+/// The new database is
+/// written next to the old one, and replaces it once all the logins have been
+/// copied; if the migration fails, the old database is left as it was.
+///
+/// Logins that can't be copied are dropped, and counted as failures in the
+/// returned metrics. The `insert_phase` metrics cover the local logins, and
+/// the `fixup_phase` metrics the synced (mirror) logins.
+pub fn migrate_to_field_encryption(
+    path: impl AsRef<Path>,
+    sqlcipher_key: &str,
+    new_key: &str,
+    encrypt_origin: bool,
+) -> Result<MigrationMetrics> {
+    let path = path.as_ref();
+    // Fail if the database doesn't exist, instead of creating an empty one.
+    std::fs::metadata(path)?;
+    let mut new_path = path.as_os_str().to_owned();
+    new_path.push(".migrating");
+    let new_path = PathBuf::from(new_path);
+    // Remove any leftovers from a previous attempt.
+    if new_path.exists() {
+        std::fs::remove_file(&new_path)?;
+    }
+    match copy_to_field_encrypted_db(path, &new_path, sqlcipher_key, new_key, encrypt_origin) {
+        Ok(metrics) => {
+            std::fs::rename(&new_path, path)?;
+            log::info!(
+                "Finished migrating to field encryption with the following metrics: {:#?}",
+                metrics
+            );
+            Ok(metrics)
+        }
+        Err(e) => {
+            let _ = std::fs::remove_file(&new_path);
+            Err(e)
+        }
+    }
+}
+
+fn copy_to_field_encrypted_db(
+    path: &Path,
+    new_path: &Path,
+    sqlcipher_key: &str,
+    new_key: &str,
+    encrypt_origin: bool,
+) -> Result<MigrationMetrics> {
+    let start = Instant::now();
+    let encdec = Arc::new(EncryptorDecryptor::new(new_key)?);
+    // Create the schema and canary in the new database, then copy everything
+    // over from the old one. We copy from the old connection, so it needs the
+    // new key for `encrypt_field()` and `encrypt_origin()`; `KEY ''` attaches
+    // the new database without SQLCipher.
+    LoginDb::open_with_field_encryption(new_path, new_key, encrypt_origin)?;
+    let old = LoginDb::open(path, Some(sqlcipher_key))?;
+    encryption::register_sql_functions(&old.db, Some(encdec), encrypt_origin)?;
+    old.execute_named(
+        "ATTACH DATABASE :path AS new KEY ''",
+        named_params! { ":path": new_path.to_string_lossy() },
+    )?;
+
+    let tx = old.unchecked_transaction()?;
+    let local_phase = copy_logins_table(&old, "loginsL", LOCAL_ONLY_COLS)?;
+    let mirror_phase = copy_logins_table(&old, "loginsM", MIRROR_ONLY_COLS)?;
+    old.execute_all(&[
+        "INSERT OR IGNORE INTO new.loginsSyncMeta (key, value)
+         SELECT key, value FROM main.loginsSyncMeta",
+        "INSERT INTO new.loginsPasswordHistory (guid, password, time_replaced)
+         SELECT guid, encrypt_field(password), time_replaced
+         FROM main.loginsPasswordHistory",
+    ])?;
+    tx.commit()?;
+    old.execute("DETACH DATABASE new", NO_PARAMS)?;
+
+    let mut errors = Vec::new();
+    errors.extend(local_phase.errors.clone());
+    errors.extend(mirror_phase.errors.clone());
+    Ok(MigrationMetrics {
+        num_processed: local_phase.num_processed + mirror_phase.num_processed,
+        num_succeeded: local_phase.num_succeeded + mirror_phase.num_succeeded,
+        num_failed: local_phase.num_failed + mirror_phase.num_failed,
+        total_duration: start.elapsed().as_millis() as u64,
+        errors,
+        insert_phase: local_phase,
+        fixup_phase: mirror_phase,
+    })
+}
+
+const LOCAL_ONLY_COLS: &str = "local_modified, is_deleted, sync_status";
+const MIRROR_ONLY_COLS: &str = "server_modified, is_overridden";
+
+// Copies the rows one at a time, so that one bad row doesn't fail the whole
+// migration.
+fn copy_logins_table(db: &LoginDb, table: &str, table_cols: &str) -> Result<MigrationPhaseMetrics> {
+    let start = Instant::now();
+    let ids = db.query_rows_and_then_named(
+        &format!("SELECT id FROM main.{table}", table = table),
+        &[],
+        |row| -> Result<i64> { Ok(row.get(0)?) },
+    )?;
+    let sql = format!(
+        "INSERT INTO new.{table} ({common_cols}, {table_cols})
+         SELECT
+            guid,
+            encrypt_field(username),
+            encrypt_field(password),
+            encrypt_origin(hostname),
+            httpRealm,
+            formSubmitURL,
+            usernameField,
+            passwordField,
+            timeCreated,
+            timeLastUsed,
+            timePasswordChanged,
+            timesUsed,
+            {table_cols}
+         FROM main.{table}
+         WHERE id = :id",
+        table = table,
+        common_cols = schema::COMMON_COLS,
+        table_cols = table_cols,
+    );
+    let mut metrics = MigrationPhaseMetrics {
+        num_processed: ids.len() as u64,
+        ..MigrationPhaseMetrics::default()
+    };
+    for id in ids {
+        match db.execute_named_cached(&sql, named_params! { ":id": id }) {
+            Ok(_) => metrics.num_succeeded += 1,
+            Err(e) => {
+                log::warn!("Could not migrate {} row {} ({}).", table, id, e);
+                metrics.errors.push(Error::from(e).label().into());
+                metrics.num_failed += 1;
+            }
+        }
+    }
+    metrics.total_duration = start.elapsed().as_millis() as u64;
+    Ok(metrics)
+}
+
 impl ConnExt for LoginDb {
     #[inline]
     fn conn(&self) -> &Connection {
@@ -238,6 +501,12 @@ impl LoginDb {
         Ok(())
     }
 
+    /// Throws away the cached health report. Called whenever logins are
+    /// added, changed or removed.
+    pub(crate) fn invalidate_health_report(&self) {
+        self.health.borrow_mut().invalidate();
+    }
+
     // It would be nice if this were a batch-ish api (e.g. takes a slice of records and finds dupes
     // for each one if they exist)... I can't think of how to write that query, though.
     // NOTE: currently used only by sync - maybe it should move to the sync engine?
@@ -256,10 +525,10 @@ impl LoginDb {
         let mut query = format!(
             "SELECT {common}
              FROM loginsL
-             WHERE hostname IS :hostname
+             WHERE decrypt_origin(hostname) IS :hostname
                AND httpRealm IS :http_realm
-               AND username IS :username",
-            common = schema::COMMON_COLS,
+               AND decrypt_field(username) IS :username",
+            common = schema::DECRYPTED_COMMON_COLS,
         );
         if form_submit_host_port.is_some() {
             // Stolen from iOS
@@ -404,14 +673,14 @@ impl LoginDb {
                 is_deleted,
                 sync_status
             ) VALUES (
-                :hostname,
+                encrypt_origin(:hostname),
                 :http_realm,
                 :form_submit_url,
                 :username_field,
                 :password_field,
                 :times_used,
-                :username,
-                :password,
+                encrypt_field(:username),
+                encrypt_field(:password),
                 :guid,
                 :time_created,
                 :time_last_used,
@@ -449,6 +718,7 @@ impl LoginDb {
             throw!(ErrorKind::DuplicateGuid(login.guid().into_string()));
         }
         tx.commit()?;
+        self.invalidate_health_report();
         Ok(login)
     }
 
@@ -462,6 +732,10 @@ impl LoginDb {
             return Err(ErrorKind::NonEmptyTable.into());
         }
         let tx = self.unchecked_transaction()?;
+        // There aren't any logins, so any history left over belongs to
+        // logins that are gone, and mustn't be attached to imported logins
+        // with the same GUIDs.
+        password_history::clear(self, None)?;
         let now_ms = util::system_time_ms_i64(SystemTime::now());
         let import_start = Instant::now();
         let sql = format!(
@@ -482,14 +756,14 @@ impl LoginDb {
                 is_deleted,
                 sync_status
             ) VALUES (
-                :hostname,
+                encrypt_origin(:hostname),
                 :http_realm,
                 :form_submit_url,
                 :username_field,
                 :password_field,
                 :times_used,
-                :username,
-                :password,
+                encrypt_field(:username),
+                encrypt_field(:password),
                 :guid,
                 :time_created,
                 :time_last_used,
@@ -566,6 +840,7 @@ impl LoginDb {
             };
         }
         tx.commit()?;
+        self.invalidate_health_report();
 
         let num_post_fixup = import_start_total_logins - num_failed_fixup;
         let num_failed = num_failed_fixup + num_failed_insert;
@@ -617,6 +892,7 @@ impl LoginDb {
         self.mark_mirror_overridden(login.guid_str())?;
 
         let now_ms = util::system_time_ms_i64(SystemTime::now());
+        password_history::record_password_change(self, login.guid_str(), &login.password, now_ms)?;
 
         let sql = format!(
             "UPDATE loginsL
@@ -624,7 +900,7 @@ impl LoginDb {
                  timeLastUsed        = :now_millis,
                  -- Only update timePasswordChanged if, well, the password changed.
                  timePasswordChanged = (CASE
-                     WHEN password = :password
+                     WHEN decrypt_field(password) = :password
                      THEN timePasswordChanged
                      ELSE :now_millis
                  END),
@@ -633,9 +909,9 @@ impl LoginDb {
                  usernameField       = :username_field,
                  passwordField       = :password_field,
                  timesUsed           = timesUsed + 1,
-                 username            = :username,
-                 password            = :password,
-                 hostname            = :hostname,
+                 username            = encrypt_field(:username),
+                 password            = encrypt_field(:password),
+                 hostname            = encrypt_origin(:hostname),
                  -- leave New records as they are, otherwise update them to `changed`
                  sync_status         = max(sync_status, {changed})
              WHERE guid = :guid",
@@ -657,6 +933,7 @@ impl LoginDb {
             },
         )?;
         tx.commit()?;
+        self.invalidate_health_report();
         Ok(())
     }
 
@@ -686,8 +963,8 @@ impl LoginDb {
                 SELECT 1 FROM loginsL
                 WHERE is_deleted = 0
                     AND guid <> :guid
-                    AND hostname = :hostname
-                    AND NULLIF(username, '') = :username
+                    AND decrypt_origin(hostname) = :hostname
+                    AND NULLIF(decrypt_field(username), '') = :username
                     AND (
                         formSubmitURL = :form_submit
                         OR
@@ -699,8 +976,8 @@ impl LoginDb {
                 SELECT 1 FROM loginsM
                 WHERE is_overridden = 0
                     AND guid <> :guid
-                    AND hostname = :hostname
-                    AND NULLIF(username, '') = :username
+                    AND decrypt_origin(hostname) = :hostname
+                    AND NULLIF(decrypt_field(username), '') = :username
                     AND (
                         formSubmitURL = :form_submit
                         OR
@@ -724,7 +1001,7 @@ impl LoginDb {
             static ref DUPES_IGNORING_USERNAME_SQL: String = format!(
                 "SELECT {common_cols} FROM loginsL
                 WHERE is_deleted = 0
-                    AND hostname = :hostname
+                    AND decrypt_origin(hostname) = :hostname
                     AND (
                         formSubmitURL = :form_submit
                         OR
@@ -735,14 +1012,14 @@ impl LoginDb {
 
                 SELECT {common_cols} FROM loginsM
                 WHERE is_overridden = 0
-                    AND hostname = :hostname
+                    AND decrypt_origin(hostname) = :hostname
                     AND (
                         formSubmitURL = :form_submit
                         OR
                         httpRealm = :http_realm
                     )
                 ",
-                common_cols = schema::COMMON_COLS
+                common_cols = schema::DECRYPTED_COMMON_COLS
             );
         }
         let mut stmt = self.db.prepare_cached(&DUPES_IGNORING_USERNAME_SQL)?;
@@ -809,7 +1086,9 @@ impl LoginDb {
             WHERE guid = :guid",
             changed = SyncStatus::Changed as u8),
             named_params! { ":now_ms": now_ms, ":guid": id })?;
+        password_history::clear(self, Some(id))?;
         tx.commit()?;
+        self.invalidate_health_report();
         Ok(exists)
     }
 
@@ -882,7 +1161,9 @@ impl LoginDb {
                 changed = SyncStatus::Changed as u8),
             named_params! { ":now_ms": now_ms })?;
         scope.err_if_interrupted()?;
+        password_history::clear(self, None)?;
         tx.commit()?;
+        self.invalidate_health_report();
         Ok(())
     }
 
@@ -892,9 +1173,17 @@ impl LoginDb {
         self.execute_all(&[
             "DELETE FROM loginsL",
             "DELETE FROM loginsM",
-            "DELETE FROM loginsSyncMeta",
+            // The field encryption mode describes the database, not the
+            // logins in it, so it's kept.
+            &format!(
+                "DELETE FROM loginsSyncMeta WHERE key NOT IN ('{}', '{}')",
+                schema::FIELD_ENCRYPTION_CANARY_META_KEY,
+                schema::FIELD_ENCRYPTION_ORIGIN_META_KEY
+            ),
+            "DELETE FROM loginsPasswordHistory",
         ])?;
         tx.commit()?;
+        self.invalidate_health_report();
         Ok(())
     }
 }
@@ -904,7 +1193,7 @@ lazy_static! {
         "SELECT {common_cols} FROM loginsL WHERE is_deleted = 0
          UNION ALL
          SELECT {common_cols} FROM loginsM WHERE is_overridden = 0",
-        common_cols = schema::COMMON_COLS,
+        common_cols = schema::DECRYPTED_COMMON_COLS,
     );
     static ref GET_BY_GUID_SQL: String = format!(
         "SELECT {common_cols}
@@ -921,7 +1210,7 @@ lazy_static! {
          ORDER BY hostname ASC
 
          LIMIT 1",
-        common_cols = schema::COMMON_COLS,
+        common_cols = schema::DECRYPTED_COMMON_COLS,
     );
     pub static ref CLONE_ENTIRE_MIRROR_SQL: String = format!(
         "INSERT OR IGNORE INTO loginsL ({common_cols}, local_modified, is_deleted, sync_status)
@@ -1568,4 +1857,217 @@ mod tests {
         assert!(ensure_valid_salt("deadbeef").is_err());
         assert!(ensure_valid_salt("deadbeefdeadbeefdeadbeefdeadbeef").is_ok());
     }
+
+    #[test]
+    fn test_field_encryption() {
+        let dir = tempdir::TempDir::new("field_encryption").unwrap();
+        let dbpath = dir.path().join("logins.sqlite");
+        let key = crate::encryption::create_key().unwrap();
+        let db = LoginDb::open_with_field_encryption(&dbpath, &key, false).unwrap();
+        let login = db
+            .add(Login {
+                hostname: "https://www.example.com".into(),
+                form_submit_url: Some("https://www.example.com".into()),
+                username: "test-user".into(),
+                password: "test-password".into(),
+                ..Login::default()
+            })
+            .unwrap();
+
+        // The secret fields are encrypted on disk, but the origin isn't.
+        let (username, password, hostname): (String, String, String) = db
+            .query_row(
+                "SELECT username, password, hostname FROM loginsL",
+                NO_PARAMS,
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .unwrap();
+        assert_ne!(username, "test-user");
+        assert_ne!(password, "test-password");
+        assert_eq!(hostname, "https://www.example.com");
+
+        let fetched = db.get_by_id(&login.id).unwrap().unwrap();
+        assert_eq!(fetched.username, "test-user");
+        assert_eq!(fetched.password, "test-password");
+
+        // Dupe checks compare the decrypted values.
+        assert!(db
+            .dupe_exists(&Login {
+                id: Guid::random().to_string(),
+                ..login.clone()
+            })
+            .unwrap());
+        db.update(Login {
+            password: "new-password".into(),
+            ..login.clone()
+        })
+        .unwrap();
+        let updated = db.get_by_id(&login.id).unwrap().unwrap();
+        assert_eq!(updated.password, "new-password");
+        drop(db);
+
+        // Opening with the wrong key fails right away.
+        let wrong_key = crate::encryption::create_key().unwrap();
+        let err = LoginDb::open_with_field_encryption(&dbpath, &wrong_key, false).unwrap_err();
+        assert!(matches!(
+            LoginsStorageError::from(err),
+            LoginsStorageError::InvalidKey(_)
+        ));
+    }
+
+    #[test]
+    fn test_field_encryption_with_origin() {
+        let dir = tempdir::TempDir::new("field_encryption_with_origin").unwrap();
+        let dbpath = dir.path().join("logins.sqlite");
+        let key = crate::encryption::create_key().unwrap();
+        let db = LoginDb::open_with_field_encryption(&dbpath, &key, true).unwrap();
+        let login = db
+            .add(Login {
+                hostname: "https://www.example.com".into(),
+                form_submit_url: Some("https://www.example.com".into()),
+                username: "test-user".into(),
+                password: "test-password".into(),
+                ..Login::default()
+            })
+            .unwrap();
+
+        let hostname: String = db
+            .query_row("SELECT hostname FROM loginsL", NO_PARAMS, |row| row.get(0))
+            .unwrap();
+        assert_ne!(hostname, "https://www.example.com");
+
+        // Everything that looks logins up by origin still works.
+        let fetched = db.get_by_id(&login.id).unwrap().unwrap();
+        assert_eq!(fetched.hostname, "https://www.example.com");
+        assert_eq!(db.get_by_base_domain("example.com").unwrap().len(), 1);
+        assert!(db
+            .dupe_exists(&Login {
+                id: Guid::random().to_string(),
+                ..login.clone()
+            })
+            .unwrap());
+        let matches = crate::matching::find_logins_for_form(
+            &db,
+            &crate::matching::FormLoginQuery {
+                origin: "https://www.example.com".into(),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(matches.len(), 1);
+        db.update(Login {
+            hostname: "https://example.com".into(),
+            ..login.clone()
+        })
+        .unwrap();
+        assert_eq!(
+            db.get_by_id(&login.id).unwrap().unwrap().hostname,
+            "https://example.com"
+        );
+        drop(db);
+
+        // The database can't be reopened in the other mode.
+        let err = LoginDb::open_with_field_encryption(&dbpath, &key, false).unwrap_err();
+        assert!(matches!(
+            LoginsStorageError::from(err),
+            LoginsStorageError::InvalidKey(_)
+        ));
+        assert!(LoginDb::open_with_field_encryption(&dbpath, &key, true).is_ok());
+    }
+
+    #[test]
+    fn test_field_encrypted_db_needs_key() {
+        let dir = tempdir::TempDir::new("field_encrypted_db_needs_key").unwrap();
+        let dbpath = dir.path().join("logins.sqlite");
+        let key = crate::encryption::create_key().unwrap();
+        let db = LoginDb::open_with_field_encryption(&dbpath, &key, false).unwrap();
+        db.add(Login {
+            hostname: "https://www.example.com".into(),
+            form_submit_url: Some("https://www.example.com".into()),
+            username: "test-user".into(),
+            password: "test-password".into(),
+            ..Login::default()
+        })
+        .unwrap();
+        drop(db);
+
+        // Opening without the key would return the ciphertext as usernames
+        // and passwords, and write new logins in plaintext.
+        let err = LoginDb::open(&dbpath, None).unwrap_err();
+        assert!(matches!(
+            LoginsStorageError::from(err),
+            LoginsStorageError::InvalidKey(_)
+        ));
+        assert!(LoginDb::open_with_field_encryption(&dbpath, &key, false).is_ok());
+    }
+
+    #[test]
+    fn test_plaintext_db_rejects_field_key() {
+        let dir = tempdir::TempDir::new("plaintext_db_rejects_field_key").unwrap();
+        let dbpath = dir.path().join("logins.sqlite");
+        let db = LoginDb::open(&dbpath, None).unwrap();
+        db.add(Login {
+            hostname: "https://www.example.com".into(),
+            form_submit_url: Some("https://www.example.com".into()),
+            username: "test-user".into(),
+            password: "test-password".into(),
+            ..Login::default()
+        })
+        .unwrap();
+        drop(db);
+
+        // The existing logins aren't encrypted, so we can't start using a
+        // field key for this database.
+        let key = crate::encryption::create_key().unwrap();
+        let err = LoginDb::open_with_field_encryption(&dbpath, &key, false).unwrap_err();
+        assert!(matches!(
+            LoginsStorageError::from(err),
+            LoginsStorageError::InvalidKey(_)
+        ));
+
+        // And we shouldn't have written a canary, so it still opens as before.
+        let db = LoginDb::open(&dbpath, None).unwrap();
+        assert_eq!(db.get_all().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_migrate_to_field_encryption() {
+        let dir = tempdir::TempDir::new("migrate_to_field_encryption").unwrap();
+        let dbpath = dir.path().join("logins.sqlite");
+        let db = LoginDb::open(&dbpath, Some("testing")).unwrap();
+        let login = db
+            .add(Login {
+                hostname: "https://www.example.com".into(),
+                http_realm: Some("https://www.example.com".into()),
+                username: "test-user".into(),
+                password: "test-password".into(),
+                ..Login::default()
+            })
+            .unwrap();
+        db.put_meta(schema::LAST_SYNC_META_KEY, &1234i64).unwrap();
+        drop(db);
+
+        let key = crate::encryption::create_key().unwrap();
+        let metrics = migrate_to_field_encryption(&dbpath, "testing", &key, true).unwrap();
+        assert_eq!(metrics.num_processed, 1);
+        assert_eq!(metrics.num_succeeded, 1);
+        assert_eq!(metrics.num_failed, 0);
+
+        // The SQLCipher key no longer works, but the new key does.
+        assert!(LoginDb::open(&dbpath, Some("testing")).is_err());
+        let db = LoginDb::open_with_field_encryption(&dbpath, &key, true).unwrap();
+        let migrated = db.get_by_id(&login.id).unwrap().unwrap();
+        assert_eq!(migrated.hostname, "https://www.example.com");
+        assert_eq!(migrated.username, "test-user");
+        assert_eq!(migrated.password, "test-password");
+        let hostname: String = db
+            .query_row("SELECT hostname FROM loginsL", NO_PARAMS, |row| row.get(0))
+            .unwrap();
+        assert_ne!(hostname, "https://www.example.com");
+        assert_eq!(
+            db.get_meta::<i64>(schema::LAST_SYNC_META_KEY).unwrap(),
+            Some(1234)
+        );
+        assert!(!dir.path().join("logins.sqlite.migrating").exists());
+    }
 }
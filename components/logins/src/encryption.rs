@@ -0,0 +1,249 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+// This is the *local* encryption support - it has nothing to do with the
+// encryption used by sync.
+//
+// Historically, the entire logins database was encrypted with SQLCipher.
+// Databases opened with `LoginDb::open_with_field_encryption` instead use
+// regular sqlite, and only the secret fields (`username` and `password`, and
+// optionally the origin) are stored encrypted, as JWEs using a key managed by
+// the app. Everything else (the form/realm, timestamps and sync metadata) is
+// stored in the clear, so it can be queried without the key. An encrypted
+// origin can't be indexed, so finding logins for a site has to decrypt every
+// origin; apps that don't need it hidden can leave it in the clear.
+//
+// The storage API still accepts and returns cleartext logins. Rather than
+// threading the key through every query, we register SQL functions on the
+// connection, `encrypt_field()` and `decrypt_field()` for the secret fields,
+// and `encrypt_origin()` and `decrypt_origin()` for the origin, and the
+// queries wrap the columns with them. On SQLCipher databases (where there's no
+// field key), and for origins if they aren't encrypted, these functions return
+// their argument unchanged, so the same queries work for every kind of
+// database.
+
+use crate::error::*;
+use jwcrypto::JwCryptoError;
+use rusqlite::{
+    functions::{Context, FunctionFlags},
+    Connection,
+};
+use std::sync::Arc;
+
+// Rather than passing keys around everywhere we abstract the encryption
+// and decryption behind this struct.
+pub struct EncryptorDecryptor {
+    jwk: jwcrypto::Jwk,
+}
+
+impl EncryptorDecryptor {
+    pub fn new(key: &str) -> Result<Self> {
+        Ok(EncryptorDecryptor {
+            jwk: serde_json::from_str(key)?,
+        })
+    }
+
+    // For tests.
+    #[cfg(test)]
+    pub fn new_test_key() -> Self {
+        let jwk = jwcrypto::Jwk::new_direct_key(Some("test-key".to_string())).unwrap();
+        Self { jwk }
+    }
+
+    pub fn encrypt(&self, cleartext: &str) -> Result<String> {
+        Ok(self.encrypt_jwe(cleartext)?)
+    }
+
+    pub fn decrypt(&self, ciphertext: &str) -> Result<String> {
+        Ok(self.decrypt_jwe(ciphertext)?)
+    }
+
+    // The SQL functions report errors to sqlite as strings, so they don't
+    // need our `Error` wrapper.
+    fn encrypt_jwe(&self, cleartext: &str) -> std::result::Result<String, JwCryptoError> {
+        jwcrypto::encrypt_to_jwe(
+            cleartext.as_bytes(),
+            jwcrypto::EncryptionParameters::Direct {
+                enc: jwcrypto::EncryptionAlgorithm::A256GCM,
+                jwk: &self.jwk,
+            },
+        )
+    }
+
+    fn decrypt_jwe(&self, ciphertext: &str) -> std::result::Result<String, JwCryptoError> {
+        jwcrypto::decrypt_jwe(
+            ciphertext,
+            jwcrypto::DecryptionParameters::Direct {
+                jwk: self.jwk.clone(),
+            },
+        )
+    }
+}
+
+// sqlite only passes the message of an error reported by a SQL function back
+// to us, so we use this prefix to recognize field decryption errors (typically,
+// the wrong key) when converting to a `LoginsStorageError`.
+pub(crate) const FIELD_CRYPTO_ERROR_PREFIX: &str = "Field encryption error";
+
+/// Creates a new key suitable for `LoginStore::new_with_field_encryption`.
+pub fn create_key() -> Result<String> {
+    let key = jwcrypto::Jwk::new_direct_key(None)?;
+    Ok(serde_json::to_string(&key)?)
+}
+
+/// Registers `encrypt_field()`, `decrypt_field()`, `encrypt_origin()` and
+/// `decrypt_origin()` on the connection. If `encdec` is `None`, all of them
+/// return their argument unchanged; if `encrypt_origin` is false, the origin
+/// functions do.
+pub(crate) fn register_sql_functions(
+    conn: &Connection,
+    encdec: Option<Arc<EncryptorDecryptor>>,
+    encrypt_origin: bool,
+) -> rusqlite::Result<()> {
+    let origin_encdec = if encrypt_origin { encdec.clone() } else { None };
+    let origin_encryptor = origin_encdec.clone();
+    conn.create_scalar_function(
+        "encrypt_origin",
+        1,
+        FunctionFlags::SQLITE_UTF8,
+        move |ctx| {
+            map_field(
+                ctx,
+                origin_encryptor.as_deref(),
+                EncryptorDecryptor::encrypt_jwe,
+            )
+        },
+    )?;
+    conn.create_scalar_function(
+        "decrypt_origin",
+        1,
+        FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+        move |ctx| {
+            map_field(
+                ctx,
+                origin_encdec.as_deref(),
+                EncryptorDecryptor::decrypt_jwe,
+            )
+        },
+    )?;
+    let encryptor = encdec.clone();
+    // Encryption uses a random IV, so this one isn't deterministic.
+    conn.create_scalar_function("encrypt_field", 1, FunctionFlags::SQLITE_UTF8, move |ctx| {
+        map_field(ctx, encryptor.as_deref(), EncryptorDecryptor::encrypt_jwe)
+    })?;
+    conn.create_scalar_function(
+        "decrypt_field",
+        1,
+        FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+        move |ctx| map_field(ctx, encdec.as_deref(), EncryptorDecryptor::decrypt_jwe),
+    )?;
+    Ok(())
+}
+
+// NULL and empty values are stored as-is, so that the existing
+// `NULLIF(username, '')` checks keep working, and so that tombstones (which
+// blank out both fields) don't need the key.
+fn map_field(
+    ctx: &Context<'_>,
+    encdec: Option<&EncryptorDecryptor>,
+    f: fn(&EncryptorDecryptor, &str) -> std::result::Result<String, JwCryptoError>,
+) -> rusqlite::Result<Option<String>> {
+    let value = ctx.get::<Option<String>>(0)?;
+    match (value, encdec) {
+        (Some(v), Some(encdec)) if !v.is_empty() => f(encdec, &v).map(Some).map_err(|e| {
+            rusqlite::Error::UserFunctionError(
+                format!("{}: {}", FIELD_CRYPTO_ERROR_PREFIX, e).into(),
+            )
+        }),
+        (value, _) => Ok(value),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_encrypt() {
+        let ed = EncryptorDecryptor::new(&create_key().unwrap()).unwrap();
+        let cleartext = "secret";
+        let ciphertext = ed.encrypt(cleartext).unwrap();
+        assert_eq!(ed.decrypt(&ciphertext).unwrap(), cleartext);
+        let ed2 = EncryptorDecryptor::new(&create_key().unwrap()).unwrap();
+        assert!(matches!(
+            ed2.decrypt(&ciphertext).unwrap_err().kind(),
+            ErrorKind::CryptoError(_)
+        ));
+    }
+
+    #[test]
+    fn test_sql_functions() {
+        let conn = Connection::open_in_memory().unwrap();
+        register_sql_functions(
+            &conn,
+            Some(Arc::new(EncryptorDecryptor::new_test_key())),
+            false,
+        )
+        .unwrap();
+        let ciphertext: String = conn
+            .query_row(
+                "SELECT encrypt_field('secret')",
+                rusqlite::NO_PARAMS,
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_ne!(ciphertext, "secret");
+        let cleartext: String = conn
+            .query_row("SELECT decrypt_field(?)", &[&ciphertext], |row| row.get(0))
+            .unwrap();
+        assert_eq!(cleartext, "secret");
+        let (empty, null): (String, Option<String>) = conn
+            .query_row(
+                "SELECT encrypt_field(''), encrypt_field(NULL)",
+                rusqlite::NO_PARAMS,
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!(empty, "");
+        assert_eq!(null, None);
+        // Origins are only encrypted if asked for.
+        let origin: String = conn
+            .query_row(
+                "SELECT encrypt_origin('https://www.example.com')",
+                rusqlite::NO_PARAMS,
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(origin, "https://www.example.com");
+        register_sql_functions(
+            &conn,
+            Some(Arc::new(EncryptorDecryptor::new_test_key())),
+            true,
+        )
+        .unwrap();
+        let origin: String = conn
+            .query_row(
+                "SELECT decrypt_origin(encrypt_origin('https://www.example.com')),
+                        encrypt_origin('https://www.example.com')",
+                rusqlite::NO_PARAMS,
+                |row| {
+                    assert_ne!(row.get::<_, String>(1)?, "https://www.example.com");
+                    row.get(0)
+                },
+            )
+            .unwrap();
+        assert_eq!(origin, "https://www.example.com");
+
+        // Without a key, the functions don't change anything.
+        register_sql_functions(&conn, None, true).unwrap();
+        let passthrough: String = conn
+            .query_row(
+                "SELECT encrypt_field('secret')",
+                rusqlite::NO_PARAMS,
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(passthrough, "secret");
+    }
+}
@@ -95,6 +95,7 @@ impl LoginsSyncEngine {
         let tx = db.unchecked_transaction()?;
         plan.execute(&tx, scope)?;
         tx.commit()?;
+        db.invalidate_health_report();
         Ok(())
     }
 
@@ -167,7 +168,7 @@ impl LoginsSyncEngine {
                          ON loginsL.guid = to_fetch.fetch_guid",
                     // give each VALUES item 2 entries, an index and the parameter.
                     vals = values_with_idx,
-                    common_cols = schema::COMMON_COLS,
+                    common_cols = schema::DECRYPTED_COMMON_COLS,
                 );
 
                 let db = &self.store.db.lock().unwrap();
@@ -208,7 +209,10 @@ impl LoginsSyncEngine {
         let mut outgoing = OutgoingChangeset::new("passwords", st);
         let db = self.store.db.lock().unwrap();
         let mut stmt = db.prepare_cached(&format!(
-            "SELECT * FROM loginsL WHERE sync_status IS NOT {synced}",
+            "SELECT {common_cols}, is_deleted
+             FROM loginsL
+             WHERE sync_status IS NOT {synced}",
+            common_cols = schema::DECRYPTED_COMMON_COLS,
             synced = SyncStatus::Synced as u8
         ))?;
         let rows = stmt.query_and_then(NO_PARAMS, |row| {
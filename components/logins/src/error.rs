@@ -45,6 +45,15 @@ pub enum ErrorKind {
 
     #[error("{0}")]
     Interrupted(#[from] interrupt_support::Interrupted),
+
+    #[error("Crypto Error: {0}")]
+    CryptoError(#[from] jwcrypto::JwCryptoError),
+
+    #[error("IO error: {0}")]
+    IOError(#[from] std::io::Error),
+
+    #[error("Invalid CSV: {0}")]
+    InvalidCsv(String),
 }
 
 error_support::define_error! {
@@ -55,6 +64,8 @@ error_support::define_error! {
         (SqlError, rusqlite::Error),
         (InvalidLogin, InvalidLogin),
         (Interrupted, interrupt_support::Interrupted),
+        (CryptoError, jwcrypto::JwCryptoError),
+        (IOError, std::io::Error),
     }
 }
 
@@ -90,6 +101,9 @@ impl Error {
             ErrorKind::UrlParseError(_) => "UrlParseError",
             ErrorKind::SqlError(_) => "SqlError",
             ErrorKind::Interrupted(_) => "Interrupted",
+            ErrorKind::CryptoError(_) => "CryptoError",
+            ErrorKind::IOError(_) => "IOError",
+            ErrorKind::InvalidCsv(_) => "InvalidCsv",
             ErrorKind::InvalidLogin(desc) => match desc {
                 InvalidLogin::EmptyOrigin => "InvalidLogin::EmptyOrigin",
                 InvalidLogin::EmptyPassword => "InvalidLogin::EmptyPassword",
@@ -137,6 +151,9 @@ pub enum LoginsStorageError {
     /// This error is emitted in two cases:
     /// 1. An incorrect key is used to to open the login database
     /// 2. The file at the path specified is not a sqlite database.
+    /// 3. An incorrect key is used to decrypt the fields of a database
+    ///    opened with `new_with_field_encryption`, or the database is opened
+    ///    with a different `encrypt_origin` mode than it was created with.
     /// NOTE: Dropping sqlcipher means we will drop (1), so should rename it
     #[error("InvalidKey error: {0}")]
     InvalidKey(String),
@@ -231,6 +248,20 @@ impl From<Error> for LoginsStorageError {
                 LoginsStorageError::InvalidKey(label)
             }
 
+            // Field decryption happens in a SQL function, so a wrong key shows
+            // up as a SQL error with a message we can recognize.
+            ErrorKind::SqlError(rusqlite::Error::SqliteFailure(_, Some(msg)))
+                if msg.starts_with(crate::encryption::FIELD_CRYPTO_ERROR_PREFIX) =>
+            {
+                log::error!("Field decryption error: {}", msg);
+                LoginsStorageError::InvalidKey(label)
+            }
+
+            ErrorKind::CryptoError(err) => {
+                log::error!("Crypto error: {}", err);
+                LoginsStorageError::InvalidKey(label)
+            }
+
             ErrorKind::SqlError(rusqlite::Error::SqliteFailure(err, _))
                 if err.code == rusqlite::ErrorCode::OperationInterrupted =>
             {
@@ -0,0 +1,330 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Password health reports.
+//!
+//! A report lists the logins with problems an app might want to warn about:
+//!
+//! - The password is reused for logins on other origins.
+//! - The password is weak, according to [estimate_strength].
+//! - The login's site was breached after its password was last changed. We
+//!   don't fetch breach data ourselves; the app supplies the list with
+//!   [set_breaches], usually from a dataset it ships or downloads.
+//!
+//! Computing a report decrypts every password, so the last report is cached
+//! on the `LoginDb`, and thrown away whenever logins are added, changed or
+//! removed, including by sync, or when the breach list changes.
+
+use crate::db::LoginDb;
+use crate::error::*;
+use crate::login::Login;
+use serde_derive::*;
+use std::collections::{HashMap, HashSet};
+use url::{Host, Url};
+
+/// A breached site, as supplied by the app.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Breach {
+    pub name: String,
+    /// The breached site's domain. Logins for this domain and its subdomains
+    /// are affected.
+    pub domain: String,
+    /// When the breach happened, in milliseconds since the unix epoch.
+    pub breach_date: i64,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+pub enum HealthFinding {
+    /// The same password is used for logins on `num_origins` origins,
+    /// including this one.
+    #[serde(rename_all = "camelCase")]
+    Reused { num_origins: u32 },
+    /// The password's estimated strength is below the report's minimum.
+    Weak { strength: u8 },
+    /// The login's site was breached after its password was last changed.
+    #[serde(rename_all = "camelCase")]
+    Breached { name: String, breach_date: i64 },
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct LoginHealth {
+    pub id: String,
+    pub findings: Vec<HealthFinding>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct HealthReport {
+    /// Only logins with at least one finding are included.
+    pub logins: Vec<LoginHealth>,
+}
+
+/// The breach list and last report for a `LoginDb`.
+#[derive(Default)]
+pub(crate) struct HealthState {
+    breaches: Vec<Breach>,
+    // The last report, and the minimum strength it was computed with.
+    cached: Option<(u8, HealthReport)>,
+}
+
+impl HealthState {
+    pub(crate) fn invalidate(&mut self) {
+        self.cached = None;
+    }
+}
+
+/// The default minimum strength used by apps that don't have their own.
+pub const DEFAULT_MIN_STRENGTH: u8 = 2;
+
+/// Replaces the breach list used for health reports.
+pub fn set_breaches(db: &LoginDb, breaches: Vec<Breach>) {
+    let mut health = db.health.borrow_mut();
+    health.breaches = breaches;
+    health.invalidate();
+}
+
+/// Returns the health report for all logins, computing it if the cached report
+/// is stale. Passwords with a strength below `min_strength` are reported as
+/// weak.
+pub fn get_health_report(db: &LoginDb, min_strength: u8) -> Result<HealthReport> {
+    if let Some((cached_min_strength, report)) = &db.health.borrow().cached {
+        if *cached_min_strength == min_strength {
+            return Ok(report.clone());
+        }
+    }
+    let logins = db.get_all()?;
+    let report = {
+        let health = db.health.borrow();
+        compute_report(&logins, &health.breaches, min_strength)
+    };
+    db.health.borrow_mut().cached = Some((min_strength, report.clone()));
+    Ok(report)
+}
+
+fn compute_report(logins: &[Login], breaches: &[Breach], min_strength: u8) -> HealthReport {
+    let mut origins_by_password: HashMap<&str, HashSet<&str>> = HashMap::new();
+    for login in logins {
+        origins_by_password
+            .entry(login.password.as_str())
+            .or_default()
+            .insert(login.hostname.as_str());
+    }
+
+    let mut report = HealthReport { logins: Vec::new() };
+    for login in logins {
+        let mut findings = Vec::new();
+        let num_origins = origins_by_password[login.password.as_str()].len();
+        if num_origins > 1 {
+            findings.push(HealthFinding::Reused {
+                num_origins: num_origins as u32,
+            });
+        }
+        let strength = estimate_strength(&login.password);
+        if strength < min_strength {
+            findings.push(HealthFinding::Weak { strength });
+        }
+        if let Some(host) = login_domain(login) {
+            findings.extend(
+                breaches
+                    .iter()
+                    .filter(|breach| {
+                        breach.breach_date > login.time_password_changed
+                            && domain_matches(&host, &breach.domain)
+                    })
+                    .map(|breach| HealthFinding::Breached {
+                        name: breach.name.clone(),
+                        breach_date: breach.breach_date,
+                    }),
+            );
+        }
+        if !findings.is_empty() {
+            report.logins.push(LoginHealth {
+                id: login.id.clone(),
+                findings,
+            });
+        }
+    }
+    report
+}
+
+fn login_domain(login: &Login) -> Option<String> {
+    match Url::parse(&login.hostname).ok()?.host()? {
+        Host::Domain(domain) => Some(domain.to_ascii_lowercase()),
+        // Breach lists don't have IP addresses.
+        _ => None,
+    }
+}
+
+fn domain_matches(host: &str, domain: &str) -> bool {
+    let domain = domain.trim_start_matches("www.").to_ascii_lowercase();
+    host == domain
+        || (host.len() > domain.len()
+            && host.ends_with(&domain)
+            && host.as_bytes()[host.len() - domain.len() - 1] == b'.')
+}
+
+// A handful of the most common passwords, which are weak whatever their
+// length and mix of characters.
+const COMMON_PASSWORDS: &[&str] = &[
+    "123456",
+    "123456789",
+    "12345678",
+    "password",
+    "qwerty",
+    "qwerty123",
+    "1q2w3e4r",
+    "111111",
+    "1234567890",
+    "abc123",
+    "password1",
+    "iloveyou",
+    "000000",
+    "letmein",
+    "welcome",
+    "monkey",
+    "dragon",
+    "sunshine",
+    "princess",
+    "football",
+];
+
+/// Estimates a password's strength, from 0 (very weak) to 4 (strong). This is
+/// a rough estimate of the password's entropy, from its length and the kinds
+/// of characters it uses, with common passwords and repeated characters
+/// counting for less. It's meant for flagging obviously weak passwords, not
+/// as a replacement for a proper strength meter.
+pub fn estimate_strength(password: &str) -> u8 {
+    if COMMON_PASSWORDS.contains(&password.to_lowercase().as_str()) {
+        return 0;
+    }
+    let (mut lower, mut upper, mut digit, mut symbol, mut other) =
+        (false, false, false, false, false);
+    // Runs of the same character only count once.
+    let mut effective_len = 0u32;
+    let mut prev = None;
+    for c in password.chars() {
+        match c {
+            'a'..='z' => lower = true,
+            'A'..='Z' => upper = true,
+            '0'..='9' => digit = true,
+            c if c.is_ascii() => symbol = true,
+            _ => other = true,
+        }
+        if prev != Some(c) {
+            effective_len += 1;
+        }
+        prev = Some(c);
+    }
+    let pool_size: u32 = [
+        (lower, 26),
+        (upper, 26),
+        (digit, 10),
+        (symbol, 33),
+        (other, 100),
+    ]
+    .iter()
+    .filter(|(used, _)| *used)
+    .map(|(_, size)| size)
+    .sum();
+    if pool_size == 0 {
+        return 0;
+    }
+    let bits = f64::from(effective_len) * f64::from(pool_size).log2();
+    match bits {
+        b if b < 28.0 => 0,
+        b if b < 40.0 => 1,
+        b if b < 60.0 => 2,
+        b if b < 80.0 => 3,
+        _ => 4,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_estimate_strength() {
+        assert_eq!(estimate_strength(""), 0);
+        assert_eq!(estimate_strength("Password"), 0);
+        assert_eq!(estimate_strength("aaaaaaaaaaaaaaaa"), 0);
+        assert_eq!(estimate_strength("hunter2"), 1);
+        assert_eq!(estimate_strength("correcthorse"), 2);
+        assert_eq!(estimate_strength("c0rrect-Horse"), 3);
+        assert_eq!(estimate_strength("c0rrect-Horse-battery"), 4);
+    }
+
+    #[test]
+    fn test_health_report() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let add = |hostname: &str, password: &str, time_password_changed: i64| {
+            db.add(Login {
+                hostname: hostname.into(),
+                form_submit_url: Some("".into()),
+                username: "user".into(),
+                password: password.into(),
+                time_password_changed,
+                ..Login::default()
+            })
+            .unwrap()
+        };
+        let reused_a = add("https://a.example.com", "c0rrect-Horse-battery", 1000);
+        let reused_b = add("https://b.example.org", "c0rrect-Horse-battery", 1000);
+        let weak = add("https://weak.example.net", "hunter2", 1000);
+        let breached = add(
+            "https://accounts.breached.example",
+            "Tr0ub4dor&3-staple",
+            1000,
+        );
+        let changed = add("https://breached.example", "An0ther-Str0ng-one!", 3000);
+
+        set_breaches(
+            &db,
+            vec![Breach {
+                name: "Breached".into(),
+                domain: "www.breached.example".into(),
+                breach_date: 2000,
+            }],
+        );
+        let report = get_health_report(&db, DEFAULT_MIN_STRENGTH).unwrap();
+        let findings = |id: &str| {
+            report
+                .logins
+                .iter()
+                .find(|health| health.id == id)
+                .map(|health| health.findings.clone())
+        };
+        assert_eq!(report.logins.len(), 4);
+        let reused = Some(vec![HealthFinding::Reused { num_origins: 2 }]);
+        assert_eq!(findings(&reused_a.id), reused);
+        assert_eq!(findings(&reused_b.id), reused);
+        assert_eq!(
+            findings(&weak.id),
+            Some(vec![HealthFinding::Weak { strength: 1 }])
+        );
+        assert_eq!(
+            findings(&breached.id),
+            Some(vec![HealthFinding::Breached {
+                name: "Breached".into(),
+                breach_date: 2000,
+            }])
+        );
+        // Changed after the breach.
+        assert_eq!(findings(&changed.id), None);
+
+        // The cached report is thrown away when a password changes.
+        assert!(db.health.borrow().cached.is_some());
+        db.update(Login {
+            password: "a-N3w-Str0ng-password".into(),
+            ..reused_b
+        })
+        .unwrap();
+        assert!(db.health.borrow().cached.is_none());
+        let report = get_health_report(&db, DEFAULT_MIN_STRENGTH).unwrap();
+        assert!(!report.logins.iter().any(|health| health.id == reused_a.id));
+    }
+}
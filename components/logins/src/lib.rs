@@ -9,8 +9,13 @@
 mod error;
 mod login;
 
+mod csv;
 mod db;
+mod encryption;
 mod engine;
+mod health;
+mod matching;
+mod password_history;
 mod schema;
 mod store;
 mod update_plan;
@@ -18,11 +23,18 @@ mod util;
 
 uniffi_macros::include_scaffolding!("logins");
 
+pub use crate::csv::{CsvFormat, CsvImportEntry, CsvImportOutcome, CsvImportReport};
 pub use crate::db::{
     open_and_get_salt, open_and_migrate_to_plaintext_header, LoginDb, MigrationMetrics,
     MigrationPhaseMetrics,
 };
+pub use crate::encryption::create_key;
 pub use crate::engine::LoginsSyncEngine;
 pub use crate::error::*;
+pub use crate::health::{
+    estimate_strength, Breach, HealthFinding, HealthReport, LoginHealth, DEFAULT_MIN_STRENGTH,
+};
 pub use crate::login::*;
+pub use crate::matching::{FormLoginQuery, LoginMatch, OriginMatch, TargetMatch};
+pub use crate::password_history::PasswordHistoryEntry;
 pub use crate::store::*;
@@ -0,0 +1,433 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Finding the logins that can be filled into a form or HTTP auth prompt.
+//!
+//! Unlike `get_by_base_domain`, which returns every login for a site,
+//! [find_logins_for_form] only returns logins whose target matches the form's
+//! action origin or the prompt's realm, ranked by how closely their origin
+//! matches the page:
+//!
+//! 1. [OriginMatch::Exact]: the same scheme, host and port.
+//! 2. [OriginMatch::SchemeUpgrade]: an `http` login on the `https` version of
+//!    the page.
+//! 3. [OriginMatch::Subdomain]: one host is a subdomain of the other.
+//! 4. [OriginMatch::BaseDomain]: both hosts are in the same base domain. We
+//!    don't have a public suffix list, so this is only checked when the caller
+//!    passes the base domain, like they do for `get_by_base_domain`.
+//!
+//! `https` logins are never returned for `http` pages. Within each group,
+//! logins whose `usernameField` is one of the form's username field hints come
+//! first, then logins for the exact form action or realm before wildcard
+//! ones, then the most recently used.
+
+use crate::db::LoginDb;
+use crate::error::*;
+use crate::login::Login;
+use crate::schema;
+use std::cmp::Reverse;
+use url::{Host, Url};
+
+/// Describes the form or HTTP auth prompt to find logins for.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FormLoginQuery {
+    /// The origin of the page.
+    pub origin: String,
+    /// The origin the form submits to, if known. Ignored if `http_realm` is
+    /// set.
+    pub form_action_origin: Option<String>,
+    /// The realm of an HTTP auth prompt. If set, only HTTP auth logins are
+    /// returned; otherwise, only form logins are.
+    pub http_realm: Option<String>,
+    /// The names or ids of the fields the app thinks are username fields.
+    pub username_field_hints: Vec<String>,
+    /// The base domain (eTLD+1) of the page, if known, to also return logins
+    /// for other subdomains of it.
+    pub base_domain: Option<String>,
+}
+
+/// How a login's origin matches the page's. Better matches sort first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum OriginMatch {
+    Exact,
+    SchemeUpgrade,
+    Subdomain,
+    BaseDomain,
+}
+
+/// How a login's `formSubmitURL` or `httpRealm` matches the query.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum TargetMatch {
+    /// The login's form action origin is the form's.
+    FormAction,
+    /// The login can be used with any form action, or the query didn't
+    /// specify one.
+    AnyFormAction,
+    /// The login's realm is the prompt's.
+    HttpRealm,
+    /// The login can be used with any realm.
+    AnyHttpRealm,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoginMatch {
+    pub login: Login,
+    pub origin_match: OriginMatch,
+    pub target_match: TargetMatch,
+    /// Whether the login's `usernameField` is one of the query's hints.
+    pub username_field_matched: bool,
+}
+
+/// Returns the logins that can be filled into the form or prompt described by
+/// `query`, best matches first.
+pub fn find_logins_for_form(db: &LoginDb, query: &FormLoginQuery) -> Result<Vec<LoginMatch>> {
+    let page = match Url::parse(&query.origin) {
+        Ok(url) if url.has_host() => url,
+        _ => {
+            // don't log the input string as it's PII.
+            log::warn!("find_logins_for_form was passed an invalid origin");
+            return Ok(vec![]);
+        }
+    };
+    let base_domain = query
+        .base_domain
+        .as_deref()
+        .and_then(|domain| match Host::parse(domain) {
+            Ok(Host::Domain(domain)) => Some(domain),
+            _ => None,
+        });
+    let mut matches: Vec<LoginMatch> = get_candidates(db, &page, base_domain.as_deref())?
+        .into_iter()
+        .filter_map(|login| {
+            let login_url = Url::parse(&login.hostname).ok()?;
+            let origin_match = match_origin(&page, &login_url, base_domain.as_deref())?;
+            let target_match = match_target(&login, query)?;
+            let username_field_matched = !login.username_field.is_empty()
+                && query
+                    .username_field_hints
+                    .iter()
+                    .any(|hint| hint.eq_ignore_ascii_case(&login.username_field));
+            Some(LoginMatch {
+                login,
+                origin_match,
+                target_match,
+                username_field_matched,
+            })
+        })
+        .collect();
+    matches.sort_by_key(|m| {
+        (
+            m.origin_match,
+            !m.username_field_matched,
+            m.target_match,
+            Reverse(m.login.time_last_used),
+        )
+    });
+    Ok(matches)
+}
+
+/// Returns the logins whose origins might match the page, so that we only
+/// decrypt and rank those. This is a superset of the logins that
+/// `match_origin` accepts: logins for the page's host or its subdomains,
+/// for any of its parent domains, or in the base domain.
+fn get_candidates(db: &LoginDb, page: &Url, base_domain: Option<&str>) -> Result<Vec<Login>> {
+    let page_host = match page.host_str() {
+        Some(host) => host,
+        None => return Ok(vec![]),
+    };
+    // `Url` has already lowercased and punycoded the hosts, so a host can't
+    // contain `%`. `_` matches any character, which only adds candidates.
+    let mut patterns = Vec::new();
+    let mut add_host = |host: &str, with_subdomains: bool| {
+        patterns.push(format!("%://{}", host));
+        patterns.push(format!("%://{}:%", host));
+        if with_subdomains {
+            patterns.push(format!("%.{}", host));
+            patterns.push(format!("%.{}:%", host));
+        }
+    };
+    add_host(page_host, true);
+    if let Some(Host::Domain(domain)) = page.host() {
+        let mut parent = domain;
+        while let Some(dot) = parent.find('.') {
+            parent = &parent[dot + 1..];
+            add_host(parent, false);
+        }
+    }
+    if let Some(base) = base_domain {
+        add_host(base, true);
+    }
+    let hostname_matches = (1..=patterns.len())
+        .map(|i| format!("decrypt_origin(hostname) LIKE ?{}", i))
+        .collect::<Vec<_>>()
+        .join(" OR ");
+    let mut stmt = db.prepare(&format!(
+        "SELECT {common_cols} FROM loginsL
+         WHERE is_deleted = 0 AND ({hostname_matches})
+         UNION ALL
+         SELECT {common_cols} FROM loginsM
+         WHERE is_overridden = 0 AND ({hostname_matches})",
+        common_cols = schema::DECRYPTED_COMMON_COLS,
+        hostname_matches = hostname_matches,
+    ))?;
+    let rows = stmt.query_and_then(&patterns, Login::from_row)?;
+    rows.collect()
+}
+
+fn match_origin(page: &Url, login: &Url, base_domain: Option<&str>) -> Option<OriginMatch> {
+    let upgrade = login.scheme() == "http" && page.scheme() == "https";
+    if login.scheme() != page.scheme() && !upgrade {
+        return None;
+    }
+    match (page.host()?, login.host()?) {
+        (page_host, login_host) if page_host == login_host => {
+            if !upgrade && page.port_or_known_default() == login.port_or_known_default() {
+                Some(OriginMatch::Exact)
+            } else if upgrade && page.port().is_none() && login.port().is_none() {
+                Some(OriginMatch::SchemeUpgrade)
+            } else {
+                None
+            }
+        }
+        // IP addresses must match exactly.
+        (Host::Domain(page_host), Host::Domain(login_host)) => {
+            if is_subdomain(page_host, login_host) || is_subdomain(login_host, page_host) {
+                Some(OriginMatch::Subdomain)
+            } else {
+                let base = base_domain?;
+                let in_base = |host: &str| host == base || is_subdomain(host, base);
+                if in_base(page_host) && in_base(login_host) {
+                    Some(OriginMatch::BaseDomain)
+                } else {
+                    None
+                }
+            }
+        }
+        _ => None,
+    }
+}
+
+// Whether `host` is a subdomain of `parent`, which `Url` has already
+// lowercased and punycoded.
+fn is_subdomain(host: &str, parent: &str) -> bool {
+    host.len() > parent.len()
+        && host.ends_with(parent)
+        && host.as_bytes()[host.len() - parent.len() - 1] == b'.'
+}
+
+fn match_target(login: &Login, query: &FormLoginQuery) -> Option<TargetMatch> {
+    if let Some(realm) = &query.http_realm {
+        return match login.http_realm.as_deref()? {
+            "" => Some(TargetMatch::AnyHttpRealm),
+            login_realm if login_realm == realm => Some(TargetMatch::HttpRealm),
+            _ => None,
+        };
+    }
+    let login_action = login.form_submit_url.as_deref()?;
+    // "" and "." are wildcards, as documented on `Login`.
+    if login_action.is_empty() || login_action == "." {
+        return Some(TargetMatch::AnyFormAction);
+    }
+    let action = match &query.form_action_origin {
+        Some(action) => action,
+        None => return Some(TargetMatch::AnyFormAction),
+    };
+    if login_action == "javascript:" || action == "javascript:" {
+        return if login_action == action {
+            Some(TargetMatch::FormAction)
+        } else {
+            None
+        };
+    }
+    let (action, login_action) = (Url::parse(action).ok()?, Url::parse(login_action).ok()?);
+    match match_origin(&action, &login_action, None)? {
+        OriginMatch::Exact | OriginMatch::SchemeUpgrade => Some(TargetMatch::FormAction),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn add(
+        db: &LoginDb,
+        hostname: &str,
+        form_submit_url: Option<&str>,
+        realm: Option<&str>,
+    ) -> String {
+        db.add(Login {
+            hostname: hostname.into(),
+            form_submit_url: form_submit_url.map(Into::into),
+            http_realm: realm.map(Into::into),
+            username: hostname.into(),
+            password: "password".into(),
+            ..Login::default()
+        })
+        .unwrap()
+        .id
+    }
+
+    fn find(db: &LoginDb, query: FormLoginQuery) -> Vec<(String, OriginMatch, TargetMatch)> {
+        find_logins_for_form(db, &query)
+            .unwrap()
+            .into_iter()
+            .map(|m| (m.login.id, m.origin_match, m.target_match))
+            .collect()
+    }
+
+    #[test]
+    fn test_find_logins_for_form() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let exact = add(
+            &db,
+            "https://www.example.com",
+            Some("https://www.example.com"),
+            None,
+        );
+        let upgrade = add(&db, "http://www.example.com", Some(""), None);
+        let parent = add(
+            &db,
+            "https://example.com",
+            Some("https://example.com"),
+            None,
+        );
+        let sibling = add(&db, "https://accounts.example.com", Some(""), None);
+        let realm = add(&db, "https://www.example.com", None, Some("Example"));
+        // Not offered on an https page...
+        add(
+            &db,
+            "https://www.example.com",
+            Some("https://other.example"),
+            None,
+        );
+        // ...or on the http page.
+        add(&db, "https://www.example.org", Some(""), None);
+
+        let query = FormLoginQuery {
+            origin: "https://www.example.com".into(),
+            form_action_origin: Some("https://www.example.com".into()),
+            base_domain: Some("example.com".into()),
+            ..FormLoginQuery::default()
+        };
+        assert_eq!(
+            find(&db, query.clone()),
+            vec![
+                (exact.clone(), OriginMatch::Exact, TargetMatch::FormAction),
+                (
+                    upgrade,
+                    OriginMatch::SchemeUpgrade,
+                    TargetMatch::AnyFormAction
+                ),
+                (
+                    sibling.clone(),
+                    OriginMatch::BaseDomain,
+                    TargetMatch::AnyFormAction
+                ),
+            ]
+        );
+
+        // Without the base domain, only subdomains match. The parent's action
+        // is for its own origin, so it only matches without an action.
+        let results = find(
+            &db,
+            FormLoginQuery {
+                form_action_origin: None,
+                base_domain: None,
+                ..query.clone()
+            },
+        );
+        assert!(results.contains(&(parent, OriginMatch::Subdomain, TargetMatch::AnyFormAction)));
+        assert!(!results.iter().any(|(id, _, _)| id == &sibling));
+
+        assert_eq!(
+            find(
+                &db,
+                FormLoginQuery {
+                    http_realm: Some("Example".into()),
+                    ..query.clone()
+                }
+            ),
+            vec![(realm, OriginMatch::Exact, TargetMatch::HttpRealm)]
+        );
+
+        // https logins aren't offered on http pages.
+        assert!(find(
+            &db,
+            FormLoginQuery {
+                origin: "http://www.example.org".into(),
+                ..FormLoginQuery::default()
+            }
+        )
+        .is_empty());
+
+        // Username field hints rank logins within the same origin match.
+        let hinted = db
+            .add(Login {
+                hostname: "https://www.example.com".into(),
+                form_submit_url: Some("".into()),
+                username_field: "email".into(),
+                username: "hinted".into(),
+                password: "password".into(),
+                ..Login::default()
+            })
+            .unwrap();
+        let results = find_logins_for_form(
+            &db,
+            &FormLoginQuery {
+                username_field_hints: vec!["Email".into()],
+                ..query
+            },
+        )
+        .unwrap();
+        assert_eq!(results[0].login.id, hinted.id);
+        assert!(results[0].username_field_matched);
+        assert_eq!(results[1].login.id, exact);
+    }
+
+    #[test]
+    fn test_get_candidates() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        for hostname in &[
+            "https://www.example.com",
+            "http://www.example.com:8080",
+            "https://login.www.example.com",
+            "https://example.com",
+            "https://accounts.example.com",
+            "https://notexample.com",
+            "https://www.example.org",
+        ] {
+            add(&db, hostname, Some(""), None);
+        }
+        let page = Url::parse("https://www.example.com").unwrap();
+        let hostnames = |base_domain: Option<&str>| {
+            let mut hostnames = get_candidates(&db, &page, base_domain)
+                .unwrap()
+                .into_iter()
+                .map(|login| login.hostname)
+                .collect::<Vec<_>>();
+            hostnames.sort();
+            hostnames
+        };
+        assert_eq!(
+            hostnames(None),
+            vec![
+                "http://www.example.com:8080",
+                "https://example.com",
+                "https://login.www.example.com",
+                "https://www.example.com",
+            ]
+        );
+        assert_eq!(
+            hostnames(Some("example.com")),
+            vec![
+                "http://www.example.com:8080",
+                "https://accounts.example.com",
+                "https://example.com",
+                "https://login.www.example.com",
+                "https://www.example.com",
+            ]
+        );
+    }
+}
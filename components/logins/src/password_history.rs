@@ -0,0 +1,273 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Previous passwords for each login, so that users can get back a password
+//! they accidentally saved over.
+//!
+//! Password history is opt-in: nothing is kept until [set_limit] is called
+//! with a limit greater than zero. From then on, each time `LoginDb::update`
+//! changes a login's password, the old password is added to the login's
+//! history, and the oldest entries beyond the limit are removed. Only local
+//! changes are recorded; passwords changed by sync aren't.
+//!
+//! Entries are stored the same way as the current password, so they're
+//! encrypted with the field key on databases that use field encryption. They
+//! are removed when their login is deleted, and when the store is wiped.
+
+use crate::db::LoginDb;
+use crate::error::*;
+use crate::schema;
+use rusqlite::{named_params, Row, NO_PARAMS};
+use sql_support::ConnExt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PasswordHistoryEntry {
+    pub id: i64,
+    pub password: String,
+    /// When the password was replaced, in milliseconds since the unix epoch.
+    pub time_replaced: i64,
+}
+
+impl PasswordHistoryEntry {
+    fn from_row(row: &Row<'_>) -> Result<Self> {
+        Ok(PasswordHistoryEntry {
+            id: row.get("id")?,
+            password: row.get("password")?,
+            time_replaced: row.get("time_replaced")?,
+        })
+    }
+}
+
+/// Returns the maximum number of passwords kept for each login. Zero means
+/// password history is disabled, which is the default.
+pub fn get_limit(db: &LoginDb) -> Result<u32> {
+    Ok(db
+        .get_meta::<u32>(schema::PASSWORD_HISTORY_LIMIT_META_KEY)?
+        .unwrap_or_default())
+}
+
+/// Sets the maximum number of passwords kept for each login, removing the
+/// oldest entries of logins that have more. Setting the limit to zero
+/// disables password history, and removes all entries.
+pub fn set_limit(db: &LoginDb, limit: u32) -> Result<()> {
+    let tx = db.unchecked_transaction()?;
+    db.put_meta(schema::PASSWORD_HISTORY_LIMIT_META_KEY, &limit)?;
+    db.execute_named(
+        "DELETE FROM loginsPasswordHistory
+         WHERE id IN (
+             SELECT id FROM (
+                 SELECT id, row_number() OVER (
+                     PARTITION BY guid ORDER BY time_replaced DESC, id DESC
+                 ) AS n
+                 FROM loginsPasswordHistory
+             )
+             WHERE n > :limit
+         )",
+        named_params! { ":limit": limit },
+    )?;
+    tx.commit()?;
+    Ok(())
+}
+
+/// Returns a login's previous passwords, most recently replaced first.
+pub fn get_history(db: &LoginDb, guid: &str) -> Result<Vec<PasswordHistoryEntry>> {
+    db.query_rows_and_then_named_cached(
+        "SELECT id, decrypt_field(password) AS password, time_replaced
+         FROM loginsPasswordHistory
+         WHERE guid = :guid
+         ORDER BY time_replaced DESC, id DESC",
+        named_params! { ":guid": guid },
+        PasswordHistoryEntry::from_row,
+    )
+}
+
+/// Makes a previous password the login's current password. This is a normal
+/// `update`, so the password it replaces is added to the history in turn.
+pub fn restore(db: &LoginDb, guid: &str, entry_id: i64) -> Result<()> {
+    let password = db.try_query_row(
+        "SELECT decrypt_field(password) FROM loginsPasswordHistory
+         WHERE guid = :guid AND id = :id",
+        named_params! { ":guid": guid, ":id": entry_id },
+        |row| -> Result<String> { Ok(row.get(0)?) },
+        false,
+    )?;
+    let (password, login) = match (password, db.get_by_id(guid)?) {
+        (Some(password), Some(login)) => (password, login),
+        _ => throw!(ErrorKind::NoSuchRecord(format!("{}/{}", guid, entry_id))),
+    };
+    db.update(crate::login::Login { password, ..login })
+}
+
+/// Called by `LoginDb::update` before it changes a login, to add the
+/// current password to the history if it's about to change.
+pub(crate) fn record_password_change(
+    db: &LoginDb,
+    guid: &str,
+    new_password: &str,
+    now_ms: i64,
+) -> Result<()> {
+    let limit = get_limit(db)?;
+    if limit == 0 {
+        return Ok(());
+    }
+    // `update` has already made sure there's a local record, and the password
+    // is copied without decrypting it.
+    let inserted = db.execute_named_cached(
+        "INSERT INTO loginsPasswordHistory (guid, password, time_replaced)
+         SELECT guid, password, :now_ms
+         FROM loginsL
+         WHERE guid = :guid
+           AND password <> ''
+           AND decrypt_field(password) <> :password",
+        named_params! {
+            ":guid": guid,
+            ":password": new_password,
+            ":now_ms": now_ms,
+        },
+    )?;
+    if inserted > 0 {
+        db.execute_named_cached(
+            "DELETE FROM loginsPasswordHistory
+             WHERE guid = :guid
+               AND id NOT IN (
+                   SELECT id FROM loginsPasswordHistory
+                   WHERE guid = :guid
+                   ORDER BY time_replaced DESC, id DESC
+                   LIMIT :limit
+               )",
+            named_params! { ":guid": guid, ":limit": limit },
+        )?;
+    }
+    Ok(())
+}
+
+/// Removes the history for one login, or for all logins if `guid` is `None`.
+pub(crate) fn clear(db: &LoginDb, guid: Option<&str>) -> Result<()> {
+    match guid {
+        Some(guid) => db.execute_named_cached(
+            "DELETE FROM loginsPasswordHistory WHERE guid = :guid",
+            named_params! { ":guid": guid },
+        )?,
+        None => db.execute_cached("DELETE FROM loginsPasswordHistory", NO_PARAMS)?,
+    };
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::login::Login;
+    use crate::update_plan::UpdatePlan;
+    use rusqlite::NO_PARAMS;
+
+    fn update_password(db: &LoginDb, login: &Login, password: &str) {
+        db.update(Login {
+            password: password.into(),
+            ..login.clone()
+        })
+        .unwrap();
+    }
+
+    fn add_with_history(db: &LoginDb, hostname: &str) -> Login {
+        let login = db
+            .add(Login {
+                hostname: hostname.into(),
+                form_submit_url: Some(hostname.into()),
+                username: "user".into(),
+                password: "first".into(),
+                ..Login::default()
+            })
+            .unwrap();
+        update_password(db, &login, "second");
+        assert_eq!(history(db, &login.id), vec!["first"]);
+        login
+    }
+
+    fn history(db: &LoginDb, guid: &str) -> Vec<String> {
+        get_history(db, guid)
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.password)
+            .collect()
+    }
+
+    #[test]
+    fn test_password_history() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let login = db
+            .add(Login {
+                hostname: "https://www.example.com".into(),
+                form_submit_url: Some("https://www.example.com".into()),
+                username: "user".into(),
+                password: "first".into(),
+                ..Login::default()
+            })
+            .unwrap();
+
+        // Nothing is kept until history is enabled.
+        update_password(&db, &login, "second");
+        assert!(history(&db, &login.id).is_empty());
+
+        set_limit(&db, 2).unwrap();
+        update_password(&db, &login, "third");
+        // Updates that don't change the password don't add entries.
+        update_password(&db, &login, "third");
+        update_password(&db, &login, "fourth");
+        update_password(&db, &login, "fifth");
+        assert_eq!(history(&db, &login.id), vec!["fourth", "third"]);
+
+        // Restoring a password saves the current one.
+        let third = get_history(&db, &login.id).unwrap()[1].id;
+        restore(&db, &login.id, third).unwrap();
+        assert_eq!(db.get_by_id(&login.id).unwrap().unwrap().password, "third");
+        assert_eq!(history(&db, &login.id), vec!["fifth", "fourth"]);
+        assert!(restore(&db, &login.id, third).is_err());
+
+        set_limit(&db, 1).unwrap();
+        assert_eq!(history(&db, &login.id), vec!["fifth"]);
+
+        db.delete(&login.id).unwrap();
+        assert!(history(&db, &login.id).is_empty());
+    }
+
+    #[test]
+    fn test_deletes_clear_history() {
+        let dir = tempdir::TempDir::new("deletes_clear_history").unwrap();
+        let key = crate::encryption::create_key().unwrap();
+        let db = LoginDb::open_with_field_encryption(dir.path().join("logins.sqlite"), &key, false)
+            .unwrap();
+        set_limit(&db, 5).unwrap();
+
+        let login = add_with_history(&db, "https://www.example.com");
+        // Old passwords are encrypted on disk, too.
+        let stored: String = db
+            .query_row(
+                "SELECT password FROM loginsPasswordHistory",
+                NO_PARAMS,
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_ne!(stored, "first");
+        db.delete(&login.id).unwrap();
+        assert!(history(&db, &login.id).is_empty());
+
+        // Deletes from the server.
+        let login = add_with_history(&db, "https://www.example.org");
+        let mut plan = UpdatePlan::default();
+        plan.plan_delete(login.guid());
+        plan.execute(&db.db, &db.begin_interrupt_scope()).unwrap();
+        assert!(history(&db, &login.id).is_empty());
+
+        let login = add_with_history(&db, "https://www.example.net");
+        db.wipe(&db.begin_interrupt_scope()).unwrap();
+        assert!(history(&db, &login.id).is_empty());
+
+        // Importing replaces any history that outlived its login.
+        db.wipe_local().unwrap();
+        let login = add_with_history(&db, "https://www.example.com");
+        db.execute("DELETE FROM loginsL", NO_PARAMS).unwrap();
+        db.import_multiple(&[login.clone()]).unwrap();
+        assert!(history(&db, &login.id).is_empty());
+    }
+}
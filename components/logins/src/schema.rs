@@ -2,15 +2,17 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
-//! Logins Schema v4
+//! Logins Schema v5
 //! ================
 //!
 //! The schema we use is a evolution of the firefox-ios logins database format.
-//! There are three tables:
+//! There are four tables:
 //!
 //! - `loginsL`: The local table.
 //! - `loginsM`: The mirror table.
 //! - `loginsSyncMeta`: The table used to to store various sync metadata.
+//! - `loginsPasswordHistory`: Previous passwords, if password history is
+//!   enabled.
 //!
 //! ## `loginsL`
 //!
@@ -87,15 +89,33 @@
 //!    [GLOBAL_STATE_META_KEY]. This is a `sync15::GlobalState` stored as
 //!    JSON.
 //!
+//! ## `loginsPasswordHistory`
+//!
+//! This stores the passwords that `LoginDb::update` replaced, for logins in
+//! either table, so that they can be restored. It was added in version 5, and
+//! is only written to once password history has been enabled, by setting a
+//! limit under [PASSWORD_HISTORY_LIMIT_META_KEY].
+//!
+//! ### `loginsPasswordHistory` Columns
+//!
+//! - `guid`: The GUID of the login the password belonged to.
+//!
+//! - `password`: The replaced password, stored the same way as the `password`
+//!   column of `loginsL`.
+//!
+//! - `time_replaced`: A millisecond local timestamp of when the password was
+//!   replaced.
+//!
 
 use crate::error::*;
 use lazy_static::lazy_static;
 use rusqlite::Connection;
 use sql_support::ConnExt;
 
-/// Note that firefox-ios is currently on version 3. Version 4 is this version,
-/// which adds a metadata table and changes timestamps to be in milliseconds
-pub const VERSION: i64 = 4;
+/// Note that firefox-ios is currently on version 3. Version 4 adds a metadata
+/// table and changes timestamps to be in milliseconds, and version 5 adds the
+/// password history table.
+pub const VERSION: i64 = 5;
 
 /// Every column shared by both tables except for `id`
 ///
@@ -129,6 +149,29 @@ pub const COMMON_COLS: &str = "
     timesUsed
 ";
 
+/// The same columns as [COMMON_COLS], but with the secret fields decrypted.
+/// Use this instead of [COMMON_COLS] when reading logins, and [COMMON_COLS]
+/// when copying rows between tables.
+///
+/// On databases opened with `LoginDb::open_with_field_encryption`, `username`
+/// and `password` are stored encrypted, and so is `hostname` if the database
+/// encrypts origins (see the `encryption` module). On SQLCipher databases,
+/// `decrypt_field()` and `decrypt_origin()` don't change anything.
+pub const DECRYPTED_COMMON_COLS: &str = "
+    guid,
+    decrypt_field(username) AS username,
+    decrypt_field(password) AS password,
+    decrypt_origin(hostname) AS hostname,
+    httpRealm,
+    formSubmitURL,
+    usernameField,
+    passwordField,
+    timeCreated,
+    timeLastUsed,
+    timePasswordChanged,
+    timesUsed
+";
+
 const COMMON_SQL: &str = "
     id                  INTEGER PRIMARY KEY AUTOINCREMENT,
     hostname            TEXT NOT NULL,
@@ -179,6 +222,20 @@ const CREATE_META_TABLE_SQL: &str = "
     )
 ";
 
+const CREATE_PASSWORD_HISTORY_TABLE_SQL: &str = "
+    CREATE TABLE IF NOT EXISTS loginsPasswordHistory (
+        id            INTEGER PRIMARY KEY AUTOINCREMENT,
+        guid          TEXT NOT NULL,
+        password      TEXT NOT NULL,
+        time_replaced INTEGER NOT NULL
+    )
+";
+
+const CREATE_PASSWORD_HISTORY_GUID_INDEX_SQL: &str = "
+    CREATE INDEX IF NOT EXISTS idx_loginsPasswordHistory_guid
+    ON loginsPasswordHistory (guid, time_replaced)
+";
+
 const CREATE_OVERRIDE_HOSTNAME_INDEX_SQL: &str = "
     CREATE INDEX IF NOT EXISTS idx_loginsM_is_overridden_hostname
     ON loginsM (is_overridden, hostname)
@@ -209,6 +266,9 @@ pub(crate) static LAST_SYNC_META_KEY: &str = "last_sync_time";
 pub(crate) static GLOBAL_STATE_META_KEY: &str = "global_state_v2";
 pub(crate) static GLOBAL_SYNCID_META_KEY: &str = "global_sync_id";
 pub(crate) static COLLECTION_SYNCID_META_KEY: &str = "passwords_sync_id";
+pub(crate) static FIELD_ENCRYPTION_CANARY_META_KEY: &str = "field_encryption_canary";
+pub(crate) static FIELD_ENCRYPTION_ORIGIN_META_KEY: &str = "field_encryption_encrypts_origin";
+pub(crate) static PASSWORD_HISTORY_LIMIT_META_KEY: &str = "password_history_limit";
 
 pub(crate) fn init(db: &Connection) -> Result<()> {
     let user_version = db.query_one::<i64>("PRAGMA user_version")?;
@@ -277,9 +337,15 @@ fn upgrade(db: &Connection, from: i64) -> Result<()> {
             CREATE_META_TABLE_SQL,
             UPDATE_LOCAL_TIMESTAMPS_TO_MILLIS_SQL,
             UPDATE_MIRROR_TIMESTAMPS_TO_MILLIS_SQL,
-            &*SET_VERSION_SQL,
         ])?;
     }
+    if from < 5 {
+        db.execute_all(&[
+            CREATE_PASSWORD_HISTORY_TABLE_SQL,
+            CREATE_PASSWORD_HISTORY_GUID_INDEX_SQL,
+        ])?;
+    }
+    db.execute_all(&[&*SET_VERSION_SQL])?;
     Ok(())
 }
 
@@ -291,6 +357,8 @@ pub(crate) fn create(db: &Connection) -> Result<()> {
         CREATE_OVERRIDE_HOSTNAME_INDEX_SQL,
         CREATE_DELETED_HOSTNAME_INDEX_SQL,
         CREATE_META_TABLE_SQL,
+        CREATE_PASSWORD_HISTORY_TABLE_SQL,
+        CREATE_PASSWORD_HISTORY_GUID_INDEX_SQL,
         &*SET_VERSION_SQL,
     ])?;
     Ok(())
@@ -302,6 +370,7 @@ pub(crate) fn drop(db: &Connection) -> Result<()> {
         "DROP TABLE IF EXISTS loginsM",
         "DROP TABLE IF EXISTS loginsL",
         "DROP TABLE IF EXISTS loginsSyncMeta",
+        "DROP TABLE IF EXISTS loginsPasswordHistory",
         "PRAGMA user_version = 0",
     ])?;
     Ok(())
@@ -1,9 +1,12 @@
 /* This Source Code Form is subject to the terms of the Mozilla Public
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
-use crate::db::LoginDb;
+use crate::db::{migrate_to_field_encryption, LoginDb};
 use crate::error::*;
+use crate::health::Breach;
 use crate::login::Login;
+use crate::matching::{self, FormLoginQuery, LoginMatch};
+use crate::password_history::{self, PasswordHistoryEntry};
 use crate::LoginsSyncEngine;
 use std::path::Path;
 use std::sync::{Arc, Mutex, Weak};
@@ -31,6 +34,19 @@ pub fn get_registered_sync_engine(name: &str) -> Option<Box<dyn SyncEngine>> {
     }
 }
 
+/// Migrates a SQLCipher database to one that can be opened with
+/// `LoginStore::new_with_field_encryption`, and returns the migration metrics
+/// as JSON.
+pub fn migrate_sqlcipher_db_to_field_encryption(
+    path: impl AsRef<Path>,
+    sqlcipher_key: &str,
+    new_key: &str,
+    encrypt_origin: bool,
+) -> Result<String> {
+    let metrics = migrate_to_field_encryption(path, sqlcipher_key, new_key, encrypt_origin)?;
+    Ok(serde_json::to_string(&metrics)?)
+}
+
 pub struct LoginStore {
     pub db: Mutex<LoginDb>,
 }
@@ -46,6 +62,24 @@ impl LoginStore {
         Ok(Self { db })
     }
 
+    /// Opens a store where only the secret fields of each login are encrypted,
+    /// with a key from `create_key`, instead of the whole database being
+    /// encrypted with SQLCipher. The origin is encrypted, too, if
+    /// `encrypt_origin` is true; a database must always be opened with the
+    /// same `encrypt_origin`.
+    pub fn new_with_field_encryption(
+        path: impl AsRef<Path>,
+        key: &str,
+        encrypt_origin: bool,
+    ) -> Result<Self> {
+        let db = Mutex::new(LoginDb::open_with_field_encryption(
+            path,
+            key,
+            encrypt_origin,
+        )?);
+        Ok(Self { db })
+    }
+
     pub fn new_in_memory(encryption_key: Option<&str>) -> Result<Self> {
         let db = Mutex::new(LoginDb::open_in_memory(encryption_key)?);
         Ok(Self { db })
@@ -63,6 +97,12 @@ impl LoginStore {
         self.db.lock().unwrap().get_by_base_domain(base_domain)
     }
 
+    /// Returns the logins that can be filled into a form or HTTP auth prompt,
+    /// best matches first.
+    pub fn find_logins_for_form(&self, query: FormLoginQuery) -> Result<Vec<LoginMatch>> {
+        matching::find_logins_for_form(&self.db.lock().unwrap(), &query)
+    }
+
     pub fn potential_dupes_ignoring_username(&self, login: Login) -> Result<Vec<Login>> {
         self.db
             .lock()
@@ -122,6 +162,52 @@ impl LoginStore {
         Ok(serde_json::to_string(&metrics)?)
     }
 
+    /// Imports logins from CSV exported by Firefox Desktop, a Chromium-based
+    /// browser, Bitwarden or 1Password, and returns a `CsvImportReport` as
+    /// JSON.
+    pub fn import_csv(&self, csv: String) -> Result<String> {
+        let report = crate::csv::import_csv(&self.db.lock().unwrap(), &csv)?;
+        Ok(serde_json::to_string(&report)?)
+    }
+
+    /// Exports all logins as CSV, in the same layout as Firefox Desktop.
+    pub fn export_csv(&self) -> Result<String> {
+        crate::csv::export_csv(&self.db.lock().unwrap())
+    }
+
+    /// Replaces the list of breached sites used by `get_health_report`. The
+    /// list is JSON, an array of `Breach`es.
+    pub fn set_breaches(&self, breaches: String) -> Result<()> {
+        let breaches: Vec<Breach> = serde_json::from_str(&breaches)?;
+        crate::health::set_breaches(&self.db.lock().unwrap(), breaches);
+        Ok(())
+    }
+
+    /// Returns a `HealthReport` as JSON, listing the logins with reused, weak
+    /// or breached passwords. Passwords with an estimated strength (from 0 to
+    /// 4) below `min_strength` are reported as weak.
+    pub fn get_health_report(&self, min_strength: u8) -> Result<String> {
+        let report = crate::health::get_health_report(&self.db.lock().unwrap(), min_strength)?;
+        Ok(serde_json::to_string(&report)?)
+    }
+
+    /// Sets how many previous passwords are kept for each login. Zero, the
+    /// default, disables password history and removes any kept passwords.
+    pub fn set_password_history_limit(&self, limit: u32) -> Result<()> {
+        password_history::set_limit(&self.db.lock().unwrap(), limit)
+    }
+
+    /// Returns a login's previous passwords, most recently replaced first.
+    pub fn get_password_history(&self, id: &str) -> Result<Vec<PasswordHistoryEntry>> {
+        password_history::get_history(&self.db.lock().unwrap(), id)
+    }
+
+    /// Makes a previous password from `get_password_history` the login's
+    /// current password.
+    pub fn restore_password(&self, id: &str, entry_id: i64) -> Result<()> {
+        password_history::restore(&self.db.lock().unwrap(), id, entry_id)
+    }
+
     pub fn disable_mem_security(&self) -> Result<()> {
         self.db.lock().unwrap().disable_mem_security()
     }
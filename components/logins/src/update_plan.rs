@@ -82,6 +82,14 @@ impl UpdatePlan {
                 ),
                 chunk,
             )?;
+            // Like a local delete, a remote one removes the password history.
+            conn.execute(
+                &format!(
+                    "DELETE FROM loginsPasswordHistory WHERE guid IN ({vars})",
+                    vars = sql_support::repeat_sql_vars(chunk.len())
+                ),
+                chunk,
+            )?;
             scope.err_if_interrupted()?;
             Ok(())
         })?;
@@ -107,9 +115,9 @@ impl UpdatePlan {
                 formSubmitURL   = :form_submit_url,
                 usernameField   = :username_field,
                 passwordField   = :password_field,
-                password        = :password,
-                hostname        = :hostname,
-                username        = :username,
+                password        = encrypt_field(:password),
+                hostname        = encrypt_origin(:hostname),
+                username        = encrypt_field(:username),
                 -- Avoid zeroes if the remote has been overwritten by an older client.
                 timesUsed           = coalesce(nullif(:times_used,            0), timesUsed),
                 timeLastUsed        = coalesce(nullif(:time_last_used,        0), timeLastUsed),
@@ -168,9 +176,9 @@ impl UpdatePlan {
                 :form_submit_url,
                 :username_field,
                 :password_field,
-                :password,
-                :hostname,
-                :username,
+                encrypt_field(:password),
+                encrypt_origin(:hostname),
+                encrypt_field(:username),
 
                 :times_used,
                 :time_last_used,
@@ -215,9 +223,9 @@ impl UpdatePlan {
                  timeLastUsed        = :time_last_used,
                  timePasswordChanged = :time_password_changed,
                  timesUsed           = :times_used,
-                 password            = :password,
-                 hostname            = :hostname,
-                 username            = :username,
+                 password            = encrypt_field(:password),
+                 hostname            = encrypt_origin(:hostname),
+                 username            = encrypt_field(:username),
                  sync_status         = {changed}
              WHERE guid = :guid",
             changed = SyncStatus::Changed as u8
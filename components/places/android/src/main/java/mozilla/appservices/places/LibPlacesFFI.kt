@@ -72,6 +72,7 @@ internal interface LibPlacesFFI : Library {
         handle: PlacesConnectionHandle,
         search: String,
         limit: Int,
+        search_mode: Int,
         out_err: RustError.ByReference
     ): RustBuffer.ByValue
 
@@ -147,8 +148,25 @@ internal interface LibPlacesFFI : Library {
         out_err: RustError.ByReference
     )
 
+    /** Returns a JSON string, which you need to free with places_destroy_string */
+    fun places_check_and_fix_database(
+        handle: PlacesConnectionHandle,
+        out_err: RustError.ByReference
+    ): Pointer?
+
+    fun places_enable_search_index(
+        handle: PlacesConnectionHandle,
+        out_err: RustError.ByReference
+    )
+
+    fun places_disable_search_index(
+        handle: PlacesConnectionHandle,
+        out_err: RustError.ByReference
+    )
+
     fun places_prune_destructively(
         handle: PlacesConnectionHandle,
+        sync_deletions: Byte,
         out_err: RustError.ByReference
     )
 
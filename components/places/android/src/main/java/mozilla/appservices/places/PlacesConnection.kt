@@ -296,9 +296,9 @@ open class PlacesReaderConnection internal constructor(connHandle: Long) :
     ReadableHistoryConnection,
     ReadableHistoryMetadataConnection,
     ReadableBookmarksConnection {
-    override fun queryAutocomplete(query: String, limit: Int): List<SearchResult> {
+    override fun queryAutocomplete(query: String, limit: Int, mode: SearchMode): List<SearchResult> {
         val resultBuffer = rustCall { error ->
-            LibPlacesFFI.INSTANCE.places_query_autocomplete(this.handle.get(), query, limit, error)
+            LibPlacesFFI.INSTANCE.places_query_autocomplete(this.handle.get(), query, limit, mode.value, error)
         }
         try {
             val results = MsgTypes.SearchResultList.parseFrom(resultBuffer.asCodedInputStream()!!)
@@ -637,9 +637,29 @@ class PlacesWriterConnection internal constructor(connHandle: Long, api: PlacesA
         }
     }
 
-    override fun pruneDestructively() {
+    override fun checkAndFixDatabase(): JSONObject {
+        val json = rustCallForString { error ->
+            LibPlacesFFI.INSTANCE.places_check_and_fix_database(this.handle.get(), error)
+        }
+        return JSONObject(json)
+    }
+
+    override fun enableSearchIndex() {
+        rustCall { error ->
+            LibPlacesFFI.INSTANCE.places_enable_search_index(this.handle.get(), error)
+        }
+    }
+
+    override fun disableSearchIndex() {
         rustCall { error ->
-            LibPlacesFFI.INSTANCE.places_prune_destructively(this.handle.get(), error)
+            LibPlacesFFI.INSTANCE.places_disable_search_index(this.handle.get(), error)
+        }
+    }
+
+    override fun pruneDestructively(syncDeletions: Boolean) {
+        val syncDeletionsArg: Byte = if (syncDeletions) { 1 } else { 0 }
+        rustCall { error ->
+            LibPlacesFFI.INSTANCE.places_prune_destructively(this.handle.get(), syncDeletionsArg, error)
         }
     }
 
@@ -1006,9 +1026,12 @@ interface ReadableHistoryConnection : InterruptibleConnection {
      *
      * @param query a string to match results against.
      * @param limit a maximum number of results to retrieve.
+     * @param mode how to find matches. [SearchMode.FULL_TEXT] falls back to
+     * [SearchMode.SCAN] unless the search index has been enabled with
+     * [WritableHistoryConnection.enableSearchIndex].
      * @return a list of [SearchResult] matching the [query], in arbitrary order.
      */
-    fun queryAutocomplete(query: String, limit: Int): List<SearchResult>
+    fun queryAutocomplete(query: String, limit: Int, mode: SearchMode = SearchMode.SCAN): List<SearchResult>
 
     /**
      * See if a url that's sufficiently close to `search` exists in
@@ -1142,15 +1165,44 @@ interface WritableHistoryConnection : ReadableHistoryConnection {
     fun runMaintenance()
 
     /**
-     * Aggressively prune history visits. These deletions are not intended
-     * to be synced, however due to the way history sync works, this can
-     * still cause data loss.
+     * Checks the database for problems, and fixes the ones that can be
+     * fixed, like bookmarks whose pages are missing, or folders with gaps in
+     * their children's positions. Bookmark fixes are uploaded on the next
+     * sync.
      *
-     * As a result, this should only be called if a low disk space
-     * notification is received from the OS, and things like the network
-     * cache have already been cleared.
+     * This reads the whole database, so unlike [runMaintenance], it
+     * shouldn't be run often.
+     *
+     * @return a JSON object describing what was found. If `databaseOk` is
+     * false, the database file is damaged, and nothing else was checked.
+     */
+    fun checkAndFixDatabase(): JSONObject
+
+    /**
+     * Creates the full-text search index used by [SearchMode.FULL_TEXT], and
+     * indexes all existing pages. This can take a while for large databases,
+     * but only needs to happen once. Does nothing if the index already exists.
      */
-    fun pruneDestructively()
+    fun enableSearchIndex()
+
+    /**
+     * Removes the full-text search index. [SearchMode.FULL_TEXT] searches
+     * scan the database after this.
+     */
+    fun disableSearchIndex()
+
+    /**
+     * Aggressively prune history visits, until the database is half its
+     * previous size. Bookmarked and tagged pages are never removed.
+     *
+     * This should only be called if a low disk space notification is
+     * received from the OS, and things like the network cache have already
+     * been cleared.
+     *
+     * @param syncDeletions if true, the pruned visits are also deleted from
+     * the server and other devices. Otherwise, they're only removed locally.
+     */
+    fun pruneDestructively(syncDeletions: Boolean = false)
 
     /**
      * Delete everything locally.
@@ -1323,7 +1375,8 @@ enum class SearchResultReason {
     URL,
     PREVIOUS_USE,
     BOOKMARK,
-    TAG;
+    TAG,
+    KEYWORD_SEARCH;
 
     companion object {
         fun fromMessage(reason: MsgTypes.SearchResultReason): SearchResultReason {
@@ -1334,6 +1387,7 @@ enum class SearchResultReason {
                 MsgTypes.SearchResultReason.PREVIOUS_USE -> PREVIOUS_USE
                 MsgTypes.SearchResultReason.BOOKMARK -> BOOKMARK
                 MsgTypes.SearchResultReason.TAG -> TAG
+                MsgTypes.SearchResultReason.KEYWORD_SEARCH -> KEYWORD_SEARCH
             }
         }
     }
@@ -1400,6 +1454,21 @@ data class TopFrecentSiteInfo(
     }
 }
 
+/**
+ * How [ReadableHistoryConnection.queryAutocomplete] finds matches.
+ */
+enum class SearchMode(val value: Int) {
+    /** Match the query against every visited page, ranking matches by frecency. */
+    SCAN(1),
+
+    /**
+     * Use the full-text search index to find pages with words that start with
+     * each word in the query. This is much faster than scanning large
+     * databases, but won't find matches in the middle of a word.
+     */
+    FULL_TEXT(2)
+}
+
 /**
  * Frecency threshold options for fetching top frecent sites. Requests a page that was visited
  * with a frecency score greater or equal to the [value].
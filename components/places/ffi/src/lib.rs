@@ -22,7 +22,7 @@ use sql_support::SqlInterruptHandle;
 use std::os::raw::c_char;
 use sync_guid::Guid as SyncGuid;
 
-use places::api::matcher::{self, match_url, search_frecent, SearchParams};
+use places::api::matcher::{self, match_url, search_frecent, SearchMode, SearchParams};
 
 // indirection to help `?` figure out the target error type
 fn parse_url(url: &str) -> places::Result<url::Url> {
@@ -186,15 +186,21 @@ pub extern "C" fn places_query_autocomplete(
     handle: u64,
     search: FfiStr<'_>,
     limit: u32,
+    search_mode: u8,
     error: &mut ExternError,
 ) -> ByteBuffer {
     log::debug!("places_query_autocomplete");
     CONNECTIONS.call_with_result(error, handle, |conn| -> places::Result<_> {
+        let mode = match SearchMode::from_primitive(search_mode) {
+            Some(mode) => mode,
+            None => return Err(ErrorKind::InvalidSearchMode.into()),
+        };
         let results = search_frecent(
             conn,
             SearchParams {
                 search_string: search.into_string(),
                 limit,
+                mode,
             },
         )?
         .into_iter()
@@ -353,11 +359,45 @@ pub extern "C" fn places_run_maintenance(handle: u64, error: &mut ExternError) {
     CONNECTIONS.call_with_result(error, handle, |conn| storage::run_maintenance(conn))
 }
 
+/// Returns the integrity report as a JSON string, which must be freed using
+/// `places_destroy_string`.
+#[no_mangle]
+pub extern "C" fn places_check_and_fix_database(
+    handle: u64,
+    error: &mut ExternError,
+) -> *mut c_char {
+    log::debug!("places_check_and_fix_database");
+    CONNECTIONS.call_with_result(error, handle, |conn| -> places::Result<_> {
+        let report = storage::integrity::check_and_fix_database(conn)?;
+        Ok(serde_json::to_string(&report)?)
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn places_enable_search_index(handle: u64, error: &mut ExternError) {
+    log::debug!("places_enable_search_index");
+    CONNECTIONS.call_with_result(error, handle, |conn| {
+        storage::search_index::enable_search_index(conn)
+    })
+}
+
 #[no_mangle]
-pub extern "C" fn places_prune_destructively(handle: u64, error: &mut ExternError) {
+pub extern "C" fn places_disable_search_index(handle: u64, error: &mut ExternError) {
+    log::debug!("places_disable_search_index");
+    CONNECTIONS.call_with_result(error, handle, |conn| {
+        storage::search_index::disable_search_index(conn)
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn places_prune_destructively(
+    handle: u64,
+    sync_deletions: u8, // JNA has issues with bools...
+    error: &mut ExternError,
+) {
     log::debug!("places_prune_destructively");
     CONNECTIONS.call_with_result(error, handle, |conn| {
-        storage::history::prune_destructively(conn)
+        storage::history::prune_destructively(conn, sync_deletions != 0)
     })
 }
 
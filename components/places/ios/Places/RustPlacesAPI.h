@@ -63,6 +63,7 @@ void places_note_observation(PlacesConnectionHandle handle,
 char *_Nullable places_query_autocomplete(PlacesConnectionHandle handle,
                                           const char *_Nonnull search,
                                           int32_t limit,
+                                          uint8_t search_mode,
                                           PlacesRustError *_Nonnull out_err);
 
 char *_Nullable places_match_url(PlacesConnectionHandle handle,
@@ -113,7 +114,17 @@ void places_wipe_local(PlacesConnectionHandle handle,
 void places_run_maintenance(PlacesConnectionHandle handle,
                             PlacesRustError *_Nonnull out_err);
 
+char *_Nullable places_check_and_fix_database(PlacesConnectionHandle handle,
+                                              PlacesRustError *_Nonnull out_err);
+
+void places_enable_search_index(PlacesConnectionHandle handle,
+                                PlacesRustError *_Nonnull out_err);
+
+void places_disable_search_index(PlacesConnectionHandle handle,
+                                 PlacesRustError *_Nonnull out_err);
+
 void places_prune_destructively(PlacesConnectionHandle handle,
+                                uint8_t sync_deletions,
                                 PlacesRustError *_Nonnull out_err);
 
 void places_delete_everything(PlacesConnectionHandle handle,
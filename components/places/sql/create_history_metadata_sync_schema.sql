@@ -0,0 +1,17 @@
+-- This Source Code Form is subject to the terms of the Mozilla Public
+-- License, v. 2.0. If a copy of the MPL was not distributed with this
+-- file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+-- Indexes and tables for syncing history metadata. These depend on the Sync
+-- columns in `moz_places_metadata`, which version 16 added to existing
+-- databases, so they can't live in `create_shared_schema.sql`.
+
+CREATE UNIQUE INDEX IF NOT EXISTS moz_places_metadata_guid_uniqueindex
+ON moz_places_metadata(guid);
+
+-- Metadata entries the user deleted, which we need to delete on the server.
+-- Entries removed because they expired, or because their page was removed
+-- from history, don't need tombstones.
+CREATE TABLE IF NOT EXISTS moz_places_metadata_tombstones (
+    guid TEXT PRIMARY KEY
+) WITHOUT ROWID;
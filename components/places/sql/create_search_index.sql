@@ -0,0 +1,139 @@
+-- This Source Code Form is subject to the terms of the Mozilla Public
+-- License, v. 2.0. If a copy of the MPL was not distributed with this
+-- file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+-- This file defines the optional full-text search index used by
+-- `SearchMode::FullText`, which is created by `enable_search_index`.
+--
+-- Unlike our other triggers, these aren't temp triggers: the index is created
+-- on demand, after other connections might already be open, and every
+-- connection that writes needs to keep it in sync.
+
+-- There's one row per page, with the page id as its rowid. The title column
+-- also holds the titles of any bookmarks for the page, since the matcher
+-- prefers bookmark titles to page titles. `remove_diacritics 2` needs SQLite
+-- 3.27.
+CREATE VIRTUAL TABLE IF NOT EXISTS moz_places_fts USING fts5(
+    url,
+    title,
+    tags,
+    keyword,
+    tokenize = 'unicode61 remove_diacritics 2'
+);
+
+-- What we index for each page.
+CREATE VIEW IF NOT EXISTS moz_places_fts_content(id, url, title, tags, keyword) AS
+SELECT h.id,
+       h.url,
+       IFNULL(h.title, '') || IFNULL(' ' || (SELECT group_concat(b.title, ' ')
+                                             FROM moz_bookmarks b
+                                             WHERE b.fk = h.id AND b.title NOT NULL), ''),
+       (SELECT group_concat(t.tag, ',')
+        FROM moz_tags t
+        JOIN moz_tags_relation r ON r.tag_id = t.id
+        WHERE r.place_id = h.id),
+       (SELECT k.keyword FROM moz_keywords k WHERE k.place_id = h.id)
+FROM moz_places h;
+
+CREATE TRIGGER IF NOT EXISTS moz_places_fts_afterinsert_trigger
+AFTER INSERT ON moz_places
+BEGIN
+    INSERT INTO moz_places_fts(rowid, url, title, tags, keyword)
+    SELECT id, url, title, tags, keyword FROM moz_places_fts_content WHERE id = NEW.id;
+END;
+
+-- We don't index frecency, which changes much more often than anything else.
+CREATE TRIGGER IF NOT EXISTS moz_places_fts_afterupdate_trigger
+AFTER UPDATE OF url, title ON moz_places
+BEGIN
+    DELETE FROM moz_places_fts WHERE rowid = NEW.id;
+    INSERT INTO moz_places_fts(rowid, url, title, tags, keyword)
+    SELECT id, url, title, tags, keyword FROM moz_places_fts_content WHERE id = NEW.id;
+END;
+
+CREATE TRIGGER IF NOT EXISTS moz_places_fts_afterdelete_trigger
+AFTER DELETE ON moz_places
+BEGIN
+    DELETE FROM moz_places_fts WHERE rowid = OLD.id;
+END;
+
+-- Bookmark, tag and keyword changes reindex the affected pages. A deleted
+-- page won't be in the content view, so reindexing it just removes it.
+CREATE TRIGGER IF NOT EXISTS moz_bookmarks_fts_afterinsert_trigger
+AFTER INSERT ON moz_bookmarks
+WHEN NEW.fk NOT NULL
+BEGIN
+    DELETE FROM moz_places_fts WHERE rowid = NEW.fk;
+    INSERT INTO moz_places_fts(rowid, url, title, tags, keyword)
+    SELECT id, url, title, tags, keyword FROM moz_places_fts_content WHERE id = NEW.fk;
+END;
+
+CREATE TRIGGER IF NOT EXISTS moz_bookmarks_fts_afterupdate_trigger
+AFTER UPDATE OF title, fk ON moz_bookmarks
+BEGIN
+    DELETE FROM moz_places_fts WHERE rowid IN (OLD.fk, NEW.fk);
+    INSERT INTO moz_places_fts(rowid, url, title, tags, keyword)
+    SELECT id, url, title, tags, keyword FROM moz_places_fts_content
+    WHERE id IN (OLD.fk, NEW.fk);
+END;
+
+CREATE TRIGGER IF NOT EXISTS moz_bookmarks_fts_afterdelete_trigger
+AFTER DELETE ON moz_bookmarks
+WHEN OLD.fk NOT NULL
+BEGIN
+    DELETE FROM moz_places_fts WHERE rowid = OLD.fk;
+    INSERT INTO moz_places_fts(rowid, url, title, tags, keyword)
+    SELECT id, url, title, tags, keyword FROM moz_places_fts_content WHERE id = OLD.fk;
+END;
+
+CREATE TRIGGER IF NOT EXISTS moz_tags_relation_fts_afterinsert_trigger
+AFTER INSERT ON moz_tags_relation
+BEGIN
+    DELETE FROM moz_places_fts WHERE rowid = NEW.place_id;
+    INSERT INTO moz_places_fts(rowid, url, title, tags, keyword)
+    SELECT id, url, title, tags, keyword FROM moz_places_fts_content WHERE id = NEW.place_id;
+END;
+
+CREATE TRIGGER IF NOT EXISTS moz_tags_relation_fts_afterdelete_trigger
+AFTER DELETE ON moz_tags_relation
+BEGIN
+    DELETE FROM moz_places_fts WHERE rowid = OLD.place_id;
+    INSERT INTO moz_places_fts(rowid, url, title, tags, keyword)
+    SELECT id, url, title, tags, keyword FROM moz_places_fts_content WHERE id = OLD.place_id;
+END;
+
+CREATE TRIGGER IF NOT EXISTS moz_tags_fts_afterupdate_trigger
+AFTER UPDATE OF tag ON moz_tags
+BEGIN
+    DELETE FROM moz_places_fts WHERE rowid IN (
+        SELECT place_id FROM moz_tags_relation WHERE tag_id = NEW.id
+    );
+    INSERT INTO moz_places_fts(rowid, url, title, tags, keyword)
+    SELECT id, url, title, tags, keyword FROM moz_places_fts_content
+    WHERE id IN (SELECT place_id FROM moz_tags_relation WHERE tag_id = NEW.id);
+END;
+
+CREATE TRIGGER IF NOT EXISTS moz_keywords_fts_afterinsert_trigger
+AFTER INSERT ON moz_keywords
+BEGIN
+    DELETE FROM moz_places_fts WHERE rowid = NEW.place_id;
+    INSERT INTO moz_places_fts(rowid, url, title, tags, keyword)
+    SELECT id, url, title, tags, keyword FROM moz_places_fts_content WHERE id = NEW.place_id;
+END;
+
+CREATE TRIGGER IF NOT EXISTS moz_keywords_fts_afterupdate_trigger
+AFTER UPDATE ON moz_keywords
+BEGIN
+    DELETE FROM moz_places_fts WHERE rowid IN (OLD.place_id, NEW.place_id);
+    INSERT INTO moz_places_fts(rowid, url, title, tags, keyword)
+    SELECT id, url, title, tags, keyword FROM moz_places_fts_content
+    WHERE id IN (OLD.place_id, NEW.place_id);
+END;
+
+CREATE TRIGGER IF NOT EXISTS moz_keywords_fts_afterdelete_trigger
+AFTER DELETE ON moz_keywords
+BEGIN
+    DELETE FROM moz_places_fts WHERE rowid = OLD.place_id;
+    INSERT INTO moz_places_fts(rowid, url, title, tags, keyword)
+    SELECT id, url, title, tags, keyword FROM moz_places_fts_content WHERE id = OLD.place_id;
+END;
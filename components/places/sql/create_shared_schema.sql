@@ -2,10 +2,6 @@
 -- License, v. 2.0. If a copy of the MPL was not distributed with this
 -- file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
--- XXX - TODO - moz_annos
--- XXX - TODO - moz_anno_attributes
--- XXX - TODO - moz_items_annos
-
 CREATE TABLE IF NOT EXISTS moz_places (
     id INTEGER PRIMARY KEY,
     url LONGVARCHAR NOT NULL,
@@ -229,21 +225,22 @@ CREATE TABLE IF NOT EXISTS moz_bookmarks_synced_tag_relation(
 -- these with custom search engines eventually (bug 648398); however, we
 -- must still round-trip keywords imported via Sync or migrated from Fennec.
 -- Since none of the `moz_bookmarks_synced_*` tables are durable, we store
--- keywords for URLs in a separate table. Unlike Desktop, we don't support
--- custom POST data, since we don't sync it (bug 1345417), and Fennec
--- doesn't write it.
+-- keywords for URLs in a separate table. Like Desktop, a keyword can have
+-- custom POST data, but we don't sync it (bug 1345417), and Fennec doesn't
+-- write it, so it only survives on this device.
 CREATE TABLE IF NOT EXISTS moz_keywords(
     place_id INTEGER PRIMARY KEY REFERENCES moz_places(id)
                      ON DELETE RESTRICT,
-    keyword TEXT NOT NULL UNIQUE
+    keyword TEXT NOT NULL UNIQUE,
+    post_data TEXT
 );
 
 ----------------------------------------------------------------------
 --------------------History Metadata----------------------------------
 ----------------------------------------------------------------------
 
--- These tables store metadata information related to moz_places.
--- None of this data is synced for now.
+-- These tables store metadata information related to moz_places. Metadata is
+-- synced by its own engine, in `history_metadata_sync`, to its own collection.
 CREATE TABLE IF NOT EXISTS moz_places_metadata (
     id INTEGER PRIMARY KEY,
     created_at INTEGER NOT NULL DEFAULT 0,
@@ -258,6 +255,14 @@ CREATE TABLE IF NOT EXISTS moz_places_metadata (
     typing_time INTEGER NOT NULL DEFAULT 0,
     key_presses INTEGER NOT NULL DEFAULT 0,
 
+    guid TEXT,
+    sync_status TINYINT NOT NULL DEFAULT 1, -- 1 is SyncStatus::New
+    sync_change_counter INTEGER NOT NULL DEFAULT 1,
+    -- The `total_view_time` we last uploaded to, or downloaded from, the
+    -- server. Anything above this was added locally since, and gets added to
+    -- the server's view time when merging.
+    synced_view_time INTEGER NOT NULL DEFAULT 0,
+
     FOREIGN KEY(place_id) REFERENCES moz_places(id) ON DELETE CASCADE,
     FOREIGN KEY(search_query_id) REFERENCES moz_places_metadata_search_queries(id) ON DELETE CASCADE,
     FOREIGN KEY(referrer_place_id) REFERENCES moz_places(id) ON DELETE CASCADE
@@ -269,3 +274,109 @@ CREATE TABLE IF NOT EXISTS moz_places_metadata_search_queries (
     id INTEGER PRIMARY KEY,
     term TEXT NOT NULL UNIQUE
 );
+
+-- The GUID index and `moz_places_metadata_tombstones` are created in
+-- `sql/create_history_metadata_sync_schema.sql`, because older databases
+-- run this file before the Sync columns are added to `moz_places_metadata`.
+
+----------------------------------------------------------------------
+--------------------Annotations---------------------------------------
+----------------------------------------------------------------------
+
+-- Like Desktop, annotations are arbitrary name-value pairs attached to a page
+-- (`moz_annos`) or a bookmark item (`moz_items_annos`). They aren't synced.
+CREATE TABLE IF NOT EXISTS moz_anno_attributes (
+    id INTEGER PRIMARY KEY,
+    name VARCHAR(32) UNIQUE NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS moz_annos (
+    id INTEGER PRIMARY KEY,
+    place_id INTEGER NOT NULL REFERENCES moz_places(id) ON DELETE CASCADE,
+    anno_attribute_id INTEGER NOT NULL REFERENCES moz_anno_attributes(id),
+    -- Unlike Desktop, `content` has no declared type, so SQLite stores
+    -- integers and doubles as-is instead of converting them to text.
+    content,
+    flags INTEGER NOT NULL DEFAULT 0,
+    expiration INTEGER NOT NULL DEFAULT 4, -- 4 is AnnotationExpiration::Never
+    type INTEGER NOT NULL DEFAULT 3, -- 3 is a string.
+    dateAdded INTEGER NOT NULL DEFAULT 0,
+    lastModified INTEGER NOT NULL DEFAULT 0,
+    UNIQUE(place_id, anno_attribute_id)
+);
+
+CREATE TABLE IF NOT EXISTS moz_items_annos (
+    id INTEGER PRIMARY KEY,
+    item_id INTEGER NOT NULL REFERENCES moz_bookmarks(id) ON DELETE CASCADE,
+    anno_attribute_id INTEGER NOT NULL REFERENCES moz_anno_attributes(id),
+    content,
+    flags INTEGER NOT NULL DEFAULT 0,
+    expiration INTEGER NOT NULL DEFAULT 4,
+    type INTEGER NOT NULL DEFAULT 3,
+    dateAdded INTEGER NOT NULL DEFAULT 0,
+    lastModified INTEGER NOT NULL DEFAULT 0,
+    UNIQUE(item_id, anno_attribute_id)
+);
+
+CREATE INDEX IF NOT EXISTS moz_annos_attributeindex ON moz_annos(anno_attribute_id);
+CREATE INDEX IF NOT EXISTS moz_items_annos_attributeindex ON moz_items_annos(anno_attribute_id);
+
+----------------------------------------------------------------------
+--------------------Favicons------------------------------------------
+----------------------------------------------------------------------
+
+-- Like Desktop, we store icons separately from `moz_places`: many pages share
+-- the same icon, and we want to keep icons for pages that aren't in history.
+-- Each row is one size of an icon; an icon URL can have several sizes.
+CREATE TABLE IF NOT EXISTS moz_icons (
+    id INTEGER PRIMARY KEY,
+    icon_url TEXT NOT NULL,
+    icon_url_hash INTEGER NOT NULL DEFAULT 0,
+    width INTEGER NOT NULL DEFAULT 0,
+    -- Set for "root" icons, like `/favicon.ico`, which apply to every page on
+    -- their origin. This is the icon's origin, like `https://example.com`.
+    root_origin TEXT,
+    expire_at INTEGER NOT NULL DEFAULT 0, -- In milliseconds.
+    data_id INTEGER NOT NULL REFERENCES moz_icons_data(id) ON DELETE CASCADE,
+    UNIQUE(icon_url_hash, icon_url, width)
+);
+
+CREATE INDEX IF NOT EXISTS moz_icons_dataindex ON moz_icons(data_id);
+CREATE INDEX IF NOT EXISTS moz_icons_rootoriginindex ON moz_icons(root_origin)
+                                                     WHERE root_origin NOT NULL;
+
+-- The icon payloads. Identical payloads are stored once, and shared between
+-- all the `moz_icons` rows that use them.
+CREATE TABLE IF NOT EXISTS moz_icons_data (
+    id INTEGER PRIMARY KEY,
+    data_hash INTEGER NOT NULL,
+    mime_type TEXT NOT NULL,
+    data BLOB NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS moz_icons_data_hashindex ON moz_icons_data(data_hash);
+
+CREATE TABLE IF NOT EXISTS moz_pages_w_icons (
+    id INTEGER PRIMARY KEY,
+    page_url TEXT NOT NULL,
+    page_url_hash INTEGER NOT NULL DEFAULT 0
+);
+
+CREATE INDEX IF NOT EXISTS moz_pages_w_icons_urlhashindex ON moz_pages_w_icons(page_url_hash);
+
+CREATE TABLE IF NOT EXISTS moz_icons_to_pages (
+    page_id INTEGER NOT NULL REFERENCES moz_pages_w_icons(id) ON DELETE CASCADE,
+    icon_id INTEGER NOT NULL REFERENCES moz_icons(id) ON DELETE CASCADE,
+    PRIMARY KEY(page_id, icon_id)
+) WITHOUT ROWID;
+
+CREATE INDEX IF NOT EXISTS moz_icons_to_pages_iconindex ON moz_icons_to_pages(icon_id);
+
+-- Sites the user removed from their top sites, which `get_top_sites` won't
+-- suggest again. `site` is the host and port, without a leading "www.".
+-- Pinned sites are stored as bookmarks, so that they sync; the blocklist is
+-- local to this device.
+CREATE TABLE IF NOT EXISTS moz_topsites_blocklist (
+    site TEXT PRIMARY KEY,
+    blocked_at INTEGER NOT NULL
+) WITHOUT ROWID;
@@ -44,3 +44,36 @@ CREATE TEMP TABLE moz_updateoriginsupdate_temp (
     frecency_delta INTEGER NOT NULL,
     PRIMARY KEY (prefix, host)
 ) WITHOUT ROWID;
+
+-- Changes waiting to be passed to observers registered with
+-- `PlacesApi::register_observer`. The observer triggers in
+-- `create_shared_triggers.sql` add rows in the same transaction as the change,
+-- so rolling it back drops its events, too. `PlacesTransaction::commit` passes
+-- the rows to observers and clears the table once the transaction commits.
+CREATE TEMP TABLE moz_places_events_temp (
+    id INTEGER PRIMARY KEY,
+    kind INTEGER NOT NULL, -- An `EventKind` from `api/observer.rs`.
+    guid TEXT NOT NULL,
+    url TEXT,
+    title TEXT,
+    parent_guid TEXT,
+    old_parent_guid TEXT,
+    position INTEGER,
+    old_position INTEGER,
+    item_type INTEGER,
+    visit_date INTEGER,
+    visit_type INTEGER,
+    is_local INTEGER,
+    frecency INTEGER
+);
+
+-- Undo and redo entries for bookmark changes made through the public
+-- `storage::bookmarks` functions. Each entry is a JSON array of the
+-- `JournalOp`s from `storage/bookmarks/journal.rs` that reverse a change.
+-- Entries are written in the same transaction as the change, so they're
+-- rolled back with it. The journal only lasts as long as the connection.
+CREATE TEMP TABLE moz_bookmarks_journal_temp (
+    id INTEGER PRIMARY KEY,
+    isRedo INTEGER NOT NULL, -- 0 for the undo stack, 1 for the redo stack.
+    ops TEXT NOT NULL
+);
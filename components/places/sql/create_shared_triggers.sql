@@ -351,3 +351,92 @@ BEGIN
         SELECT id FROM moz_places_metadata pm WHERE pm.search_query_id = OLD.search_query_id
     );
 END;
+
+-- These triggers record changes for observers. The numbers in the `kind`
+-- column are `EventKind`s from `api/observer.rs`. `places_observed()` is false
+-- when the API has no observers, so we don't record anything then.
+CREATE TEMP TRIGGER moz_historyvisits_afterinsert_observer_trigger
+AFTER INSERT ON moz_historyvisits FOR EACH ROW WHEN places_observed()
+BEGIN
+    INSERT INTO moz_places_events_temp(kind, guid, url, visit_date, visit_type, is_local)
+    SELECT 1, guid, url, NEW.visit_date, NEW.visit_type, NEW.is_local
+    FROM moz_places WHERE id = NEW.place_id;
+END;
+
+CREATE TEMP TRIGGER moz_places_afterdelete_observer_trigger
+AFTER DELETE ON moz_places FOR EACH ROW WHEN places_observed()
+BEGIN
+    INSERT INTO moz_places_events_temp(kind, guid, url)
+    VALUES(2, OLD.guid, OLD.url);
+END;
+
+CREATE TEMP TRIGGER moz_places_afterupdate_title_observer_trigger
+AFTER UPDATE OF title ON moz_places FOR EACH ROW
+WHEN OLD.title IS NOT NEW.title AND places_observed()
+BEGIN
+    INSERT INTO moz_places_events_temp(kind, guid, url, title)
+    VALUES(3, NEW.guid, NEW.url, NEW.title);
+END;
+
+CREATE TEMP TRIGGER moz_places_afterupdate_frecency_observer_trigger
+AFTER UPDATE OF frecency ON moz_places FOR EACH ROW
+WHEN OLD.frecency <> NEW.frecency AND places_observed()
+BEGIN
+    INSERT INTO moz_places_events_temp(kind, guid, url, frecency)
+    VALUES(4, NEW.guid, NEW.url, NEW.frecency);
+END;
+
+CREATE TEMP TRIGGER moz_bookmarks_afterinsert_observer_trigger
+AFTER INSERT ON moz_bookmarks FOR EACH ROW WHEN places_observed()
+BEGIN
+    INSERT INTO moz_places_events_temp(kind, guid, url, title, parent_guid,
+                                       position, item_type)
+    VALUES(5, NEW.guid,
+           (SELECT url FROM moz_places WHERE id = NEW.fk),
+           NEW.title,
+           (SELECT guid FROM moz_bookmarks WHERE id = NEW.parent),
+           NEW.position,
+           NEW.type);
+END;
+
+-- Inserting, moving or removing an item also shifts the positions of its
+-- siblings. We only report the item itself, which is the one whose parent or
+-- `lastModified` changed.
+CREATE TEMP TRIGGER moz_bookmarks_afterupdate_moved_observer_trigger
+AFTER UPDATE OF parent, position ON moz_bookmarks FOR EACH ROW
+WHEN (OLD.parent <> NEW.parent OR
+      (OLD.position <> NEW.position AND OLD.lastModified <> NEW.lastModified))
+     AND places_observed()
+BEGIN
+    INSERT INTO moz_places_events_temp(kind, guid, parent_guid, old_parent_guid,
+                                       position, old_position)
+    VALUES(6, NEW.guid,
+           (SELECT guid FROM moz_bookmarks WHERE id = NEW.parent),
+           (SELECT guid FROM moz_bookmarks WHERE id = OLD.parent),
+           NEW.position,
+           OLD.position);
+END;
+
+CREATE TEMP TRIGGER moz_bookmarks_afterupdate_observer_trigger
+AFTER UPDATE OF title, fk ON moz_bookmarks FOR EACH ROW
+WHEN (OLD.title IS NOT NEW.title OR OLD.fk IS NOT NEW.fk) AND places_observed()
+BEGIN
+    INSERT INTO moz_places_events_temp(kind, guid, url, title, item_type)
+    VALUES(7, NEW.guid,
+           (SELECT url FROM moz_places WHERE id = NEW.fk),
+           NEW.title,
+           NEW.type);
+END;
+
+CREATE TEMP TRIGGER moz_bookmarks_afterdelete_observer_trigger
+AFTER DELETE ON moz_bookmarks FOR EACH ROW WHEN places_observed()
+BEGIN
+    INSERT INTO moz_places_events_temp(kind, guid, url, title, parent_guid,
+                                       position, item_type)
+    VALUES(8, OLD.guid,
+           (SELECT url FROM moz_places WHERE id = OLD.fk),
+           OLD.title,
+           (SELECT guid FROM moz_bookmarks WHERE id = OLD.parent),
+           OLD.position,
+           OLD.type);
+END;
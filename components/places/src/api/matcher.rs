@@ -6,6 +6,7 @@ use crate::db::PlacesDb;
 use crate::error::Result;
 pub use crate::match_impl::{MatchBehavior, SearchBehavior};
 use crate::msg_types::{SearchResultMessage, SearchResultReason};
+use crate::storage::{keywords, search_index};
 use rusqlite::{types::ToSql, Row};
 use serde_derive::*;
 use sql_support::{maybe_log_plan, ConnExt};
@@ -41,6 +42,41 @@ where
 pub struct SearchParams {
     pub search_string: String,
     pub limit: u32,
+    pub mode: SearchMode,
+}
+
+/// How `search_frecent` finds history and bookmark suggestions.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[repr(u8)]
+pub enum SearchMode {
+    /// Match the search string against every visited page, ranking matches by
+    /// frecency.
+    Scan = 1,
+
+    /// Use the full-text search index to find pages with words that start
+    /// with each word in the search string, ranking matches by relevance and
+    /// frecency. This is much faster than scanning for large databases, but
+    /// won't find matches in the middle of a word. Falls back to scanning if
+    /// the index hasn't been enabled with
+    /// `storage::search_index::enable_search_index`.
+    FullText = 2,
+}
+
+impl SearchMode {
+    pub fn from_primitive(p: u8) -> Option<Self> {
+        match p {
+            1 => Some(SearchMode::Scan),
+            2 => Some(SearchMode::FullText),
+            _ => None,
+        }
+    }
+}
+
+impl Default for SearchMode {
+    #[inline]
+    fn default() -> Self {
+        SearchMode::Scan
+    }
 }
 
 /// Synchronously queries all providers for autocomplete matches, then filters
@@ -59,23 +95,34 @@ pub fn search_frecent(conn: &PlacesDb, params: SearchParams) -> Result<Vec<Searc
     // and a search if all else fails. We only try origins and URLs for
     // heuristic matches, since that's all we support.
 
+    // Try to match a keyword search, like `w rust` for a Wikipedia search.
+    let keyword_search = KeywordSearch::new(&params.search_string);
+    // Try to match on the origin, or the full URL.
+    let origin_or_url = OriginOrUrl::new(&params.search_string);
+    // query adaptive matches and suggestions, matching Anywhere.
+    let adaptive = Adaptive::with_behavior(
+        &params.search_string,
+        MatchBehavior::Anywhere,
+        SearchBehavior::default(),
+    );
+    let suggestions = Suggestions::with_behavior(
+        &params.search_string,
+        MatchBehavior::Anywhere,
+        SearchBehavior::default(),
+    );
+    let full_text = FullText::with_behavior(
+        &params.search_string,
+        MatchBehavior::Anywhere,
+        SearchBehavior::default(),
+    );
+    let use_index = params.mode == SearchMode::FullText
+        && full_text.fts_query.is_some()
+        && search_index::is_search_index_enabled(conn)?;
+    let last: &dyn Matcher = if use_index { &full_text } else { &suggestions };
+
     let mut matches = match_with_limit(
         conn,
-        &[
-            // Try to match on the origin, or the full URL.
-            &OriginOrUrl::new(&params.search_string),
-            // query adaptive matches and suggestions, matching Anywhere.
-            &Adaptive::with_behavior(
-                &params.search_string,
-                MatchBehavior::Anywhere,
-                SearchBehavior::default(),
-            ),
-            &Suggestions::with_behavior(
-                &params.search_string,
-                MatchBehavior::Anywhere,
-                SearchBehavior::default(),
-            ),
-        ],
+        &[&keyword_search, &origin_or_url, &adaptive, last],
         params.limit,
     )?;
 
@@ -184,6 +231,9 @@ fn looks_like_origin(string: &str) -> bool {
 #[derive(Debug, Clone, Serialize, Eq, PartialEq)]
 pub enum MatchReason {
     Keyword,
+    /// The first word of the query is a search keyword, and the rest are the
+    /// search terms, substituted into the keyword's URL.
+    KeywordSearch,
     Origin,
     Url,
     PreviousUse,
@@ -279,6 +329,14 @@ impl SearchResult {
         })
     }
 
+    pub fn from_full_text_row(row: &rusqlite::Row<'_>) -> Result<Self> {
+        let mut result = Self::from_suggestion_row(row)?;
+        if !row.get::<_, bool>("bookmarked")? {
+            result.reasons.retain(|r| *r != MatchReason::Bookmark);
+        }
+        Ok(result)
+    }
+
     pub fn from_origin_row(row: &rusqlite::Row<'_>) -> Result<Self> {
         let search_string = row.get::<_, String>("searchString")?;
         let url = row.get::<_, String>("url")?;
@@ -358,6 +416,7 @@ impl From<MatchReason> for SearchResultReason {
     fn from(mr: MatchReason) -> Self {
         match mr {
             MatchReason::Keyword => SearchResultReason::Keyword,
+            MatchReason::KeywordSearch => SearchResultReason::KeywordSearch,
             MatchReason::Origin => SearchResultReason::Origin,
             MatchReason::Url => SearchResultReason::Url,
             MatchReason::PreviousUse => SearchResultReason::PreviousUse,
@@ -371,6 +430,39 @@ trait Matcher {
     fn search(&self, conn: &PlacesDb, max_results: u32) -> Result<Vec<SearchResult>>;
 }
 
+/// Matches keyword searches. Keywords without search terms are left to
+/// `Suggestions`, which matches them as bookmarks. Keywords with POST data
+/// aren't matched, since a `SearchResult` can only hold a URL; apps that
+/// support POST data should use `keywords::resolve_keyword_search` instead.
+struct KeywordSearch<'query> {
+    query: &'query str,
+}
+
+impl<'query> KeywordSearch<'query> {
+    pub fn new(query: &'query str) -> KeywordSearch<'query> {
+        KeywordSearch { query }
+    }
+}
+
+impl<'query> Matcher for KeywordSearch<'query> {
+    fn search(&self, conn: &PlacesDb, _: u32) -> Result<Vec<SearchResult>> {
+        if !self.query.trim().contains(char::is_whitespace) {
+            return Ok(Vec::new());
+        }
+        Ok(match keywords::resolve_keyword_search(conn, self.query)? {
+            Some(found) if found.post_data.is_none() => vec![SearchResult {
+                search_string: self.query.into(),
+                url: found.url,
+                title: found.title,
+                icon_url: None,
+                frecency: found.frecency,
+                reasons: vec![MatchReason::KeywordSearch],
+            }],
+            _ => Vec::new(),
+        })
+    }
+}
+
 struct OriginOrUrl<'query> {
     query: &'query str,
 }
@@ -609,6 +701,112 @@ impl<'query> Matcher for Suggestions<'query> {
     }
 }
 
+struct FullText<'query> {
+    query: &'query str,
+    fts_query: Option<String>,
+    match_behavior: MatchBehavior,
+    search_behavior: SearchBehavior,
+}
+
+impl<'query> FullText<'query> {
+    pub fn with_behavior(
+        query: &'query str,
+        match_behavior: MatchBehavior,
+        search_behavior: SearchBehavior,
+    ) -> FullText<'query> {
+        FullText {
+            query,
+            fts_query: search_index::fts_query_for_search_string(query),
+            match_behavior,
+            search_behavior,
+        }
+    }
+}
+
+// The index only narrows down the candidates, and ranks them by relevance;
+// `AUTOCOMPLETE_MATCH` still decides which ones match, so that the match and
+// search behaviors mean the same thing as they do when scanning. We look at
+// more candidates than we need, since some of them won't match.
+const MAX_FULL_TEXT_CANDIDATES: u32 = 500;
+
+impl<'query> Matcher for FullText<'query> {
+    fn search(&self, conn: &PlacesDb, max_results: u32) -> Result<Vec<SearchResult>> {
+        let fts_query = match &self.fts_query {
+            Some(fts_query) => fts_query,
+            None => return Ok(vec![]),
+        };
+        let mut matches = query_flat_rows_and_then_named(
+            conn,
+            "
+            SELECT c.*,
+                   AUTOCOMPLETE_MATCH(:searchString, c.url,
+                                      IFNULL(c.btitle, c.title), c.tags,
+                                      c.visit_count, c.typed,
+                                      c.bookmarked, NULL,
+                                      :matchBehavior, :searchBehavior) AS matched
+            FROM (
+              SELECT h.url AS url, h.title AS title,
+                     EXISTS(SELECT 1 FROM moz_bookmarks
+                            WHERE fk = h.id) AS bookmarked,
+                     (SELECT title FROM moz_bookmarks
+                      WHERE fk = h.id AND
+                            title NOT NULL
+                      ORDER BY lastModified DESC
+                      LIMIT 1) AS btitle,
+                     f.tags AS tags,
+                     f.keyword AS keyword,
+                     h.visit_count_local + h.visit_count_remote AS visit_count,
+                     h.typed AS typed,
+                     h.id AS id,
+                     NULL AS open_count,
+                     h.frecency AS frecency,
+                     :searchString AS searchString,
+                     bm25(moz_places_fts, 1.0, 2.0, 2.0, 4.0) AS rank
+              FROM moz_places_fts f
+              JOIN moz_places h ON h.id = f.rowid
+              WHERE moz_places_fts MATCH :ftsQuery
+                AND h.frecency > 0
+                AND (+h.visit_count_local > 0 OR +h.visit_count_remote > 0)
+              ORDER BY rank
+              LIMIT :maxCandidates
+            ) AS c",
+            &[
+                (":searchString", &self.query),
+                (":ftsQuery", fts_query),
+                (":matchBehavior", &self.match_behavior),
+                (":searchBehavior", &self.search_behavior),
+                (":maxCandidates", &MAX_FULL_TEXT_CANDIDATES),
+            ],
+            |row| -> Result<Option<(f64, SearchResult)>> {
+                let keyword = row.get::<_, Option<String>>("keyword")?;
+                let keyword_matched = keyword.map_or(false, |k| k.starts_with(self.query));
+                if !keyword_matched && !row.get::<_, bool>("matched")? {
+                    return Ok(None);
+                }
+                let mut result = SearchResult::from_full_text_row(row)?;
+                if keyword_matched {
+                    result.reasons.insert(0, MatchReason::Keyword);
+                }
+                // BM25 scores are negative, and lower is more relevant. We
+                // flip the sign, and boost frecent pages logarithmically, so
+                // that a very frecent page doesn't drown out better matches.
+                let rank = row.get::<_, f64>("rank")?;
+                let score = -rank * (1.0 + (result.frecency.max(0) as f64 + 1.0).ln());
+                Ok(Some((score, result)))
+            },
+        )?
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>();
+        matches.sort_by(|(a, _), (b, _)| b.partial_cmp(a).unwrap_or(std::cmp::Ordering::Equal));
+        Ok(matches
+            .into_iter()
+            .take(max_results as usize)
+            .map(|(_, result)| result)
+            .collect())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -680,6 +878,7 @@ mod tests {
             SearchParams {
                 search_string: "example.com".into(),
                 limit: 10,
+                mode: SearchMode::Scan,
             },
         )
         .expect("Should search by origin");
@@ -695,6 +894,7 @@ mod tests {
             SearchParams {
                 search_string: "http://example.com".into(),
                 limit: 10,
+                mode: SearchMode::Scan,
             },
         )
         .expect("Should search by URL without path");
@@ -709,6 +909,7 @@ mod tests {
             SearchParams {
                 search_string: "http://example.com/1".into(),
                 limit: 10,
+                mode: SearchMode::Scan,
             },
         )
         .expect("Should search by URL with path");
@@ -725,6 +926,7 @@ mod tests {
             SearchParams {
                 search_string: "ample".into(),
                 limit: 10,
+                mode: SearchMode::Scan,
             },
         )
         .expect("Should search by adaptive input history");
@@ -739,6 +941,7 @@ mod tests {
             SearchParams {
                 search_string: "example".into(),
                 limit: 1,
+                mode: SearchMode::Scan,
             },
         )
         .expect("Should search until reaching limit");
@@ -771,6 +974,7 @@ mod tests {
             SearchParams {
                 search_string: "http://exämple.com".into(),
                 limit: 10,
+                mode: SearchMode::Scan,
             },
         )
         .expect("Should search by URL without path");
@@ -786,6 +990,7 @@ mod tests {
             SearchParams {
                 search_string: "http://exämple.com/1".into(),
                 limit: 10,
+                mode: SearchMode::Scan,
             },
         )
         .expect("Should search by URL with path");
@@ -810,6 +1015,7 @@ mod tests {
             SearchParams {
                 search_string: ball_of_yarn_about_blank.into(),
                 limit: 10,
+                mode: SearchMode::Scan,
             },
         )
         .unwrap();
@@ -838,7 +1044,151 @@ mod tests {
             SearchParams {
                 search_string: "not-a-url".into(),
                 limit: 10,
+                mode: SearchMode::Scan,
             },
         );
     }
+
+    #[test]
+    fn search_full_text() -> Result<()> {
+        use crate::storage::search_index::enable_search_index;
+        use crate::storage::tags::tag_url;
+        use rusqlite::NO_PARAMS;
+
+        let conn = new_mem_connection();
+        let visits = [
+            (
+                "https://developer.mozilla.org/docs/Web",
+                "Web technology docs",
+            ),
+            ("https://www.rust-lang.org/learn", "Learn Rust"),
+            ("https://example.com/recipes", "Recipes"),
+        ];
+        for (url, title) in visits.iter() {
+            apply_observation(
+                &conn,
+                VisitObservation::new(Url::parse(url)?)
+                    .with_title(title.to_string())
+                    .with_visit_type(VisitTransition::Typed)
+                    .with_at(Timestamp::now()),
+            )?;
+        }
+        tag_url(
+            &conn,
+            &Url::parse("https://example.com/recipes")?,
+            "cooking",
+        )?;
+        conn.execute(
+            "INSERT INTO moz_keywords(place_id, keyword)
+             SELECT id, 'rl' FROM moz_places WHERE url = 'https://www.rust-lang.org/learn'",
+            NO_PARAMS,
+        )?;
+
+        let search = |search_string: &str, mode| {
+            search_frecent(
+                &conn,
+                SearchParams {
+                    search_string: search_string.into(),
+                    limit: 10,
+                    mode,
+                },
+            )
+        };
+
+        // Without the index, we fall back to scanning.
+        assert_eq!(
+            search("technology", SearchMode::FullText)?,
+            search("technology", SearchMode::Scan)?
+        );
+
+        enable_search_index(&conn)?;
+
+        let results = search("web tech", SearchMode::FullText)?;
+        assert_eq!(results.len(), 1);
+        assert_eq!(
+            results[0].url.as_str(),
+            "https://developer.mozilla.org/docs/Web"
+        );
+        assert_eq!(results[0].title, "Web technology docs");
+
+        let results = search("cook", SearchMode::FullText)?;
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].url.as_str(), "https://example.com/recipes");
+        assert_eq!(
+            results[0].reasons,
+            vec![MatchReason::Tags("cooking".into())]
+        );
+
+        let results = search("rl", SearchMode::FullText)?;
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].url.as_str(), "https://www.rust-lang.org/learn");
+        assert_eq!(results[0].reasons, vec![MatchReason::Keyword]);
+
+        // The index only matches the starts of words, unlike scanning.
+        assert!(search("nology", SearchMode::FullText)?.is_empty());
+        assert_eq!(search("nology", SearchMode::Scan)?.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn search_keywords() -> Result<()> {
+        use crate::storage::bookmarks::{
+            insert_bookmark, BookmarkPosition, BookmarkRootGuid, InsertableBookmark,
+        };
+        use crate::storage::keywords::set_keyword;
+
+        let conn = new_mem_connection();
+        let bookmarks = [
+            ("https://search.example/?q=%s", "Example search", "s", None),
+            ("https://post.example/", "POST search", "p", Some("q=%s")),
+        ];
+        for (url, title, keyword, post_data) in bookmarks.iter() {
+            let url = Url::parse(url)?;
+            insert_bookmark(
+                &conn,
+                &InsertableBookmark {
+                    parent_guid: BookmarkRootGuid::Unfiled.into(),
+                    position: BookmarkPosition::Append,
+                    date_added: None,
+                    last_modified: None,
+                    guid: None,
+                    url: url.clone(),
+                    title: Some(title.to_string()),
+                }
+                .into(),
+            )?;
+            set_keyword(&conn, &url, keyword, *post_data)?;
+        }
+
+        let search = |search_string: &str| {
+            search_frecent(
+                &conn,
+                SearchParams {
+                    search_string: search_string.into(),
+                    limit: 10,
+                    mode: SearchMode::Scan,
+                },
+            )
+        };
+
+        let results = search("s rust & cargo")?;
+        assert_eq!(results.len(), 1);
+        assert_eq!(
+            results[0].url.as_str(),
+            "https://search.example/?q=rust%20%26%20cargo"
+        );
+        assert_eq!(results[0].title, "Example search");
+        assert_eq!(results[0].reasons, vec![MatchReason::KeywordSearch]);
+
+        // Keywords with POST data can't be expressed as a URL.
+        assert!(search("p rust")?
+            .iter()
+            .all(|r| !r.reasons.contains(&MatchReason::KeywordSearch)));
+
+        // Without search terms, the keyword isn't a search.
+        assert!(search("s")?
+            .iter()
+            .all(|r| !r.reasons.contains(&MatchReason::KeywordSearch)));
+        Ok(())
+    }
 }
@@ -4,7 +4,9 @@
 
 pub mod history;
 pub mod matcher;
+pub mod observer;
 pub mod places_api;
+pub mod reader_pool;
 use crate::db::PlacesDb;
 use crate::error::Result;
 use crate::observation::VisitObservation;
@@ -0,0 +1,535 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Change notifications for history and bookmarks.
+//!
+//! Observers are registered with `PlacesApi::register_observer`, and receive
+//! events for changes made on any of the API's write connections, including
+//! changes applied by the history and bookmark sync engines.
+//!
+//! Temp triggers (see `create_shared_triggers.sql`) record changes in the same
+//! transaction that makes them, and `PlacesTransaction::commit` passes them to
+//! observers once that transaction commits. Events are delivered on the thread
+//! that committed the transaction, in the order the changes were made.
+//! Changes made outside a `PlacesTransaction`, or in a chunk of a Sync
+//! transaction that was committed before the rest of it failed, are delivered
+//! with the next transaction the connection commits.
+
+use crate::db::PlacesDb;
+use crate::error::*;
+use crate::types::{BookmarkType, VisitTransition};
+use lazy_static::lazy_static;
+use rusqlite::Row;
+use sql_support::ConnExt;
+use std::collections::HashMap;
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, RwLock,
+};
+use sync_guid::Guid as SyncGuid;
+use types::Timestamp;
+use url::Url;
+
+lazy_static! {
+    // Like `GLOBAL_BOOKMARK_CHANGE_COUNTERS`, observers are shared by all
+    // connections for an API, so we index them by the "api id" of the API.
+    static ref OBSERVERS: RwLock<HashMap<usize, Vec<(ObserverId, Arc<dyn PlacesObserver>)>>> =
+        RwLock::new(HashMap::new());
+}
+
+static OBSERVER_ID_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// A change to history or bookmarks.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PlacesEvent {
+    VisitAdded {
+        page_guid: SyncGuid,
+        url: Url,
+        visit_date: Timestamp,
+        visit_type: VisitTransition,
+        is_local: bool,
+    },
+    /// A page was removed, along with all its visits.
+    PageDeleted { page_guid: SyncGuid, url: Url },
+    TitleChanged {
+        page_guid: SyncGuid,
+        url: Url,
+        title: Option<String>,
+    },
+    FrecencyChanged {
+        page_guid: SyncGuid,
+        url: Url,
+        frecency: i64,
+    },
+    BookmarkInserted {
+        guid: SyncGuid,
+        /// Only `None` for the bookmarks root.
+        parent_guid: Option<SyncGuid>,
+        position: u32,
+        bookmark_type: BookmarkType,
+        url: Option<Url>,
+        title: Option<String>,
+    },
+    /// An item moved to a different folder, or to a different position in
+    /// its folder. Siblings whose positions shifted because of an insert,
+    /// move or removal aren't reported.
+    BookmarkMoved {
+        guid: SyncGuid,
+        old_parent_guid: Option<SyncGuid>,
+        old_position: u32,
+        new_parent_guid: Option<SyncGuid>,
+        new_position: u32,
+    },
+    /// A bookmark's title or URL changed.
+    BookmarkUpdated {
+        guid: SyncGuid,
+        bookmark_type: BookmarkType,
+        url: Option<Url>,
+        title: Option<String>,
+    },
+    /// An item was removed. Removing a folder also reports its descendants;
+    /// `parent_guid` might be `None` for those if their parent was removed
+    /// first.
+    BookmarkRemoved {
+        guid: SyncGuid,
+        parent_guid: Option<SyncGuid>,
+        position: u32,
+        bookmark_type: BookmarkType,
+        url: Option<Url>,
+    },
+}
+
+// The values in the `kind` column of `moz_places_events_temp`. These must
+// match the triggers in `create_shared_triggers.sql`.
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum EventKind {
+    VisitAdded = 1,
+    PageDeleted = 2,
+    TitleChanged = 3,
+    FrecencyChanged = 4,
+    BookmarkInserted = 5,
+    BookmarkMoved = 6,
+    BookmarkUpdated = 7,
+    BookmarkRemoved = 8,
+}
+
+impl EventKind {
+    fn from_u8(v: u8) -> Option<Self> {
+        Some(match v {
+            1 => EventKind::VisitAdded,
+            2 => EventKind::PageDeleted,
+            3 => EventKind::TitleChanged,
+            4 => EventKind::FrecencyChanged,
+            5 => EventKind::BookmarkInserted,
+            6 => EventKind::BookmarkMoved,
+            7 => EventKind::BookmarkUpdated,
+            8 => EventKind::BookmarkRemoved,
+            _ => return None,
+        })
+    }
+}
+
+fn opt_url(row: &Row<'_>, col: &str) -> Result<Option<Url>> {
+    Ok(match row.get::<_, Option<String>>(col)? {
+        Some(url) => Some(Url::parse(&url)?),
+        None => None,
+    })
+}
+
+impl PlacesEvent {
+    fn from_row(row: &Row<'_>) -> Result<Option<Self>> {
+        let kind = match EventKind::from_u8(row.get("kind")?) {
+            Some(kind) => kind,
+            None => return Ok(None),
+        };
+        let guid: SyncGuid = row.get("guid")?;
+        Ok(Some(match kind {
+            EventKind::VisitAdded => {
+                let visit_type = match VisitTransition::from_primitive(row.get("visit_type")?) {
+                    Some(visit_type) => visit_type,
+                    None => return Ok(None),
+                };
+                PlacesEvent::VisitAdded {
+                    page_guid: guid,
+                    url: Url::parse(&row.get::<_, String>("url")?)?,
+                    visit_date: row.get("visit_date")?,
+                    visit_type,
+                    is_local: row.get("is_local")?,
+                }
+            }
+            EventKind::PageDeleted => PlacesEvent::PageDeleted {
+                page_guid: guid,
+                url: Url::parse(&row.get::<_, String>("url")?)?,
+            },
+            EventKind::TitleChanged => PlacesEvent::TitleChanged {
+                page_guid: guid,
+                url: Url::parse(&row.get::<_, String>("url")?)?,
+                title: row.get("title")?,
+            },
+            EventKind::FrecencyChanged => PlacesEvent::FrecencyChanged {
+                page_guid: guid,
+                url: Url::parse(&row.get::<_, String>("url")?)?,
+                frecency: row.get("frecency")?,
+            },
+            EventKind::BookmarkInserted => PlacesEvent::BookmarkInserted {
+                guid,
+                parent_guid: row.get("parent_guid")?,
+                position: row.get("position")?,
+                bookmark_type: row.get("item_type")?,
+                url: opt_url(row, "url")?,
+                title: row.get("title")?,
+            },
+            EventKind::BookmarkMoved => PlacesEvent::BookmarkMoved {
+                guid,
+                old_parent_guid: row.get("old_parent_guid")?,
+                old_position: row.get("old_position")?,
+                new_parent_guid: row.get("parent_guid")?,
+                new_position: row.get("position")?,
+            },
+            EventKind::BookmarkUpdated => PlacesEvent::BookmarkUpdated {
+                guid,
+                bookmark_type: row.get("item_type")?,
+                url: opt_url(row, "url")?,
+                title: row.get("title")?,
+            },
+            EventKind::BookmarkRemoved => PlacesEvent::BookmarkRemoved {
+                guid,
+                parent_guid: row.get("parent_guid")?,
+                position: row.get("position")?,
+                bookmark_type: row.get("item_type")?,
+                url: opt_url(row, "url")?,
+            },
+        }))
+    }
+}
+
+/// Receives change notifications. See the module docs for when, and on which
+/// thread, `on_events` is called.
+pub trait PlacesObserver: Send + Sync {
+    /// Called with all the events for a committed transaction. Observers
+    /// shouldn't write to the database from here, since the connection that
+    /// committed might be in use by the caller.
+    fn on_events(&self, events: &[PlacesEvent]);
+}
+
+/// Identifies a registered observer, so that it can be unregistered.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct ObserverId(usize);
+
+pub(crate) fn register(api_id: usize, observer: Arc<dyn PlacesObserver>) -> ObserverId {
+    let id = ObserverId(OBSERVER_ID_COUNTER.fetch_add(1, Ordering::SeqCst));
+    let mut map = OBSERVERS.write().expect("observers poisoned");
+    map.entry(api_id).or_default().push((id, observer));
+    id
+}
+
+pub(crate) fn unregister(api_id: usize, id: ObserverId) -> bool {
+    let mut map = OBSERVERS.write().expect("observers poisoned");
+    let observers = match map.get_mut(&api_id) {
+        Some(observers) => observers,
+        None => return false,
+    };
+    let len = observers.len();
+    observers.retain(|(existing, _)| *existing != id);
+    let removed = observers.len() != len;
+    if observers.is_empty() {
+        map.remove(&api_id);
+    }
+    removed
+}
+
+pub(crate) fn unregister_all(api_id: usize) {
+    let mut map = OBSERVERS.write().expect("observers poisoned");
+    map.remove(&api_id);
+}
+
+/// Returns whether an API has any observers. This backs the
+/// `places_observed()` SQL function used by the observer triggers.
+pub(crate) fn has_observers(api_id: usize) -> bool {
+    let map = OBSERVERS.read().expect("observers poisoned");
+    map.contains_key(&api_id)
+}
+
+/// Passes recorded events to the API's observers, and clears them. Called
+/// after a transaction commits on a write connection.
+pub(crate) fn notify_observers(db: &PlacesDb) -> Result<()> {
+    // An event we can't read is logged and skipped, instead of failing, so
+    // that it doesn't stay in the table and block every later notification.
+    let events = db.query_rows_and_then_named_cached(
+        "SELECT kind, guid, url, title, parent_guid, old_parent_guid, position,
+                old_position, item_type, visit_date, visit_type, is_local, frecency
+         FROM moz_places_events_temp
+         ORDER BY id",
+        &[],
+        |row| -> rusqlite::Result<_> {
+            Ok(PlacesEvent::from_row(row).unwrap_or_else(|e| {
+                log::warn!("Skipping invalid places event: {}", e);
+                None
+            }))
+        },
+    )?;
+    if events.is_empty() {
+        return Ok(());
+    }
+    db.execute_batch("DELETE FROM moz_places_events_temp")?;
+    let events = events.into_iter().flatten().collect::<Vec<_>>();
+    if events.is_empty() {
+        return Ok(());
+    }
+    // Clone the list so that observers can register or unregister observers
+    // from `on_events` without deadlocking.
+    let observers = {
+        let map = OBSERVERS.read().expect("observers poisoned");
+        match map.get(&db.api_id()) {
+            Some(observers) => observers
+                .iter()
+                .map(|(_, observer)| observer.clone())
+                .collect::<Vec<_>>(),
+            None => return Ok(()),
+        }
+    };
+    for observer in observers {
+        observer.on_events(&events);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::places_api::{test::new_mem_api, ConnectionType};
+    use crate::observation::VisitObservation;
+    use crate::storage::bookmarks::{
+        delete_bookmark, insert_bookmark, update_bookmark, BookmarkPosition, BookmarkRootGuid,
+        InsertableBookmark, InsertableItem, UpdatableBookmark, UpdatableItem, UpdateTreeLocation,
+    };
+    use crate::storage::history::{apply_observation, delete_visits_for};
+    use pretty_assertions::assert_eq;
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct RecordingObserver {
+        events: Mutex<Vec<PlacesEvent>>,
+    }
+
+    impl RecordingObserver {
+        fn take(&self) -> Vec<PlacesEvent> {
+            std::mem::take(&mut *self.events.lock().unwrap())
+        }
+    }
+
+    impl PlacesObserver for RecordingObserver {
+        fn on_events(&self, events: &[PlacesEvent]) {
+            self.events.lock().unwrap().extend_from_slice(events);
+        }
+    }
+
+    fn insertable(url: &Url, title: &str) -> InsertableItem {
+        InsertableItem::Bookmark(InsertableBookmark {
+            parent_guid: BookmarkRootGuid::Unfiled.into(),
+            position: BookmarkPosition::Append,
+            date_added: None,
+            last_modified: None,
+            guid: None,
+            url: url.clone(),
+            title: Some(title.into()),
+        })
+    }
+
+    #[test]
+    fn test_history_events() -> Result<()> {
+        let api = new_mem_api();
+        let observer = Arc::new(RecordingObserver::default());
+        api.register_observer(observer.clone());
+        let conn = api.open_connection(ConnectionType::ReadWrite)?;
+
+        let url = Url::parse("https://www.example.com/")?;
+        let now = Timestamp::now();
+        apply_observation(
+            &conn,
+            VisitObservation::new(url.clone())
+                .with_visit_type(VisitTransition::Link)
+                .with_at(now)
+                .with_title(Some("Example".to_string())),
+        )?;
+        let events = observer.take();
+        let page_guid = match &events[0] {
+            PlacesEvent::VisitAdded {
+                page_guid,
+                url: event_url,
+                visit_date,
+                visit_type,
+                is_local,
+            } => {
+                assert_eq!(event_url, &url);
+                assert_eq!(*visit_date, now);
+                assert_eq!(*visit_type, VisitTransition::Link);
+                assert!(*is_local);
+                page_guid.clone()
+            }
+            e => panic!("Unexpected event: {:?}", e),
+        };
+        assert!(events.contains(&PlacesEvent::TitleChanged {
+            page_guid: page_guid.clone(),
+            url: url.clone(),
+            title: Some("Example".into()),
+        }));
+        assert!(events
+            .iter()
+            .any(|e| matches!(e, PlacesEvent::FrecencyChanged { .. })));
+
+        delete_visits_for(&conn, &page_guid)?;
+        assert!(observer
+            .take()
+            .contains(&PlacesEvent::PageDeleted { page_guid, url }));
+        Ok(())
+    }
+
+    #[test]
+    fn test_skips_invalid_events() -> Result<()> {
+        let api = new_mem_api();
+        let observer = Arc::new(RecordingObserver::default());
+        api.register_observer(observer.clone());
+        let conn = api.open_connection(ConnectionType::ReadWrite)?;
+
+        conn.execute_batch(&format!(
+            "INSERT INTO moz_places_events_temp(kind, guid, url)
+             VALUES({}, 'page_guid___', 'not a url')",
+            EventKind::PageDeleted as u8
+        ))?;
+        let url = Url::parse("https://www.example.com/")?;
+        let guid = insert_bookmark(&conn, &insertable(&url, "title"))?;
+        let events = observer.take();
+        assert_eq!(events.len(), 1);
+        assert!(matches!(&events[0], PlacesEvent::BookmarkInserted { guid: g, .. } if g == &guid));
+        let pending: u32 = conn.query_one("SELECT COUNT(*) FROM moz_places_events_temp")?;
+        assert_eq!(pending, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_bookmark_events() -> Result<()> {
+        let api = new_mem_api();
+        let observer = Arc::new(RecordingObserver::default());
+        api.register_observer(observer.clone());
+        let conn = api.open_connection(ConnectionType::ReadWrite)?;
+
+        let url = Url::parse("https://www.example.com/")?;
+        let guid1 = insert_bookmark(&conn, &insertable(&url, "first"))?;
+        let guid2 = insert_bookmark(&conn, &insertable(&url, "second"))?;
+        let unfiled: SyncGuid = BookmarkRootGuid::Unfiled.into();
+        assert_eq!(
+            observer.take(),
+            vec![
+                PlacesEvent::BookmarkInserted {
+                    guid: guid1.clone(),
+                    parent_guid: Some(unfiled.clone()),
+                    position: 0,
+                    bookmark_type: BookmarkType::Bookmark,
+                    url: Some(url.clone()),
+                    title: Some("first".into()),
+                },
+                PlacesEvent::BookmarkInserted {
+                    guid: guid2.clone(),
+                    parent_guid: Some(unfiled.clone()),
+                    position: 1,
+                    bookmark_type: BookmarkType::Bookmark,
+                    url: Some(url.clone()),
+                    title: Some("second".into()),
+                },
+            ]
+        );
+
+        // Moving the second bookmark to the front shifts the first, but we
+        // only report the item that moved.
+        update_bookmark(
+            &conn,
+            &guid2,
+            &UpdatableItem::Bookmark(UpdatableBookmark {
+                location: UpdateTreeLocation::Position(BookmarkPosition::Specific(0)),
+                ..Default::default()
+            }),
+        )?;
+        assert_eq!(
+            observer.take(),
+            vec![PlacesEvent::BookmarkMoved {
+                guid: guid2.clone(),
+                old_parent_guid: Some(unfiled.clone()),
+                old_position: 1,
+                new_parent_guid: Some(unfiled.clone()),
+                new_position: 0,
+            }]
+        );
+
+        update_bookmark(
+            &conn,
+            &guid1,
+            &UpdatableItem::Bookmark(UpdatableBookmark {
+                title: Some("renamed".into()),
+                ..Default::default()
+            }),
+        )?;
+        assert_eq!(
+            observer.take(),
+            vec![PlacesEvent::BookmarkUpdated {
+                guid: guid1.clone(),
+                bookmark_type: BookmarkType::Bookmark,
+                url: Some(url.clone()),
+                title: Some("renamed".into()),
+            }]
+        );
+
+        delete_bookmark(&conn, &guid2)?;
+        assert_eq!(
+            observer.take(),
+            vec![PlacesEvent::BookmarkRemoved {
+                guid: guid2,
+                parent_guid: Some(unfiled),
+                position: 0,
+                bookmark_type: BookmarkType::Bookmark,
+                url: Some(url),
+            }]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_rollback_and_unregister() -> Result<()> {
+        let api = new_mem_api();
+        let observer = Arc::new(RecordingObserver::default());
+        let id = api.register_observer(observer.clone());
+        let conn = api.open_connection(ConnectionType::ReadWrite)?;
+        let url = Url::parse("https://www.example.com/")?;
+
+        let guid = insert_bookmark(&conn, &insertable(&url, "committed"))?;
+        assert_eq!(observer.take().len(), 1);
+
+        // Rolled back changes aren't reported, not even with the next commit.
+        let tx = conn.begin_transaction()?;
+        conn.execute_named_cached(
+            "UPDATE moz_bookmarks SET title = 'rolled back' WHERE guid = :guid",
+            &[(":guid", &guid)],
+        )?;
+        tx.rollback()?;
+        assert!(observer.take().is_empty());
+        insert_bookmark(&conn, &insertable(&url, "second"))?;
+        let events = observer.take();
+        assert_eq!(events.len(), 1);
+        assert!(matches!(
+            &events[0],
+            PlacesEvent::BookmarkInserted { title: Some(title), .. } if title == "second"
+        ));
+
+        assert!(api.unregister_observer(id));
+        assert!(!api.unregister_observer(id));
+        insert_bookmark(&conn, &insertable(&url, "unobserved"))?;
+        assert!(observer.take().is_empty());
+        assert_eq!(
+            conn.query_one::<i64>("SELECT COUNT(*) FROM moz_places_events_temp")?,
+            0
+        );
+        Ok(())
+    }
+}
@@ -2,12 +2,17 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
+use crate::api::observer::{self, ObserverId, PlacesObserver};
+use crate::api::reader_pool::{ReaderPool, DEFAULT_MAX_READERS};
 use crate::bookmark_sync::engine::BookmarksEngine;
 use crate::db::db::PlacesDb;
 use crate::error::*;
+use crate::frecency::FrecencySettings;
+use crate::history_metadata_sync::engine::HistoryMetadataEngine;
 use crate::history_sync::engine::HistoryEngine;
 use crate::storage::{
-    self, bookmarks::bookmark_sync, delete_meta, get_meta, history::history_sync, put_meta,
+    self, bookmarks::bookmark_sync, delete_meta, get_meta, history::history_sync,
+    history_metadata::history_metadata_sync, put_meta,
 };
 use crate::util::normalize_path;
 use lazy_static::lazy_static;
@@ -80,28 +85,53 @@ pub struct SyncState {
 pub struct PlacesApi {
     db_name: PathBuf,
     write_connection: Mutex<Option<PlacesDb>>,
+    reader_pool: ReaderPool,
     sync_state: Mutex<Option<SyncState>>,
     coop_tx_lock: Arc<Mutex<()>>,
     sync_conn_active: AtomicBool,
+    frecency_settings: Arc<FrecencySettings>,
     id: usize,
 }
 impl PlacesApi {
     /// Create a new, or fetch an already open, PlacesApi backed by a file on disk.
     pub fn new(db_name: impl AsRef<Path>) -> Result<Arc<Self>> {
+        Self::new_with_frecency_settings(db_name, FrecencySettings::default())
+    }
+
+    /// Like `new`, but calculates frecencies with the given settings instead
+    /// of the defaults. If the settings have a different version than the
+    /// ones the database was last opened with, all frecencies are marked as
+    /// stale, and `storage::run_maintenance` recalculates them. If the
+    /// database is already open, this returns the existing PlacesApi, which
+    /// keeps its settings.
+    pub fn new_with_frecency_settings(
+        db_name: impl AsRef<Path>,
+        frecency_settings: FrecencySettings,
+    ) -> Result<Arc<Self>> {
         let db_name = normalize_path(db_name)?;
-        Self::new_or_existing(db_name)
+        Self::new_or_existing(db_name, frecency_settings)
     }
 
     /// Create a new, or fetch an already open, memory-based PlacesApi. You must
     /// provide a name, but you are still able to have a single writer and many
     ///  reader connections to the same memory DB open.
     pub fn new_memory(db_name: &str) -> Result<Arc<Self>> {
+        Self::new_memory_with_frecency_settings(db_name, FrecencySettings::default())
+    }
+
+    /// Like `new_memory`, but with the given frecency settings, like
+    /// `new_with_frecency_settings`.
+    pub fn new_memory_with_frecency_settings(
+        db_name: &str,
+        frecency_settings: FrecencySettings,
+    ) -> Result<Arc<Self>> {
         let name = PathBuf::from(format!("file:{}?mode=memory&cache=shared", db_name));
-        Self::new_or_existing(name)
+        Self::new_or_existing(name, frecency_settings)
     }
     fn new_or_existing_into(
         target: &mut HashMap<PathBuf, Weak<PlacesApi>>,
         db_name: PathBuf,
+        frecency_settings: FrecencySettings,
     ) -> Result<Arc<Self>> {
         let id = ID_COUNTER.fetch_add(1, Ordering::SeqCst);
         match target.get(&db_name).and_then(Weak::upgrade) {
@@ -110,17 +140,27 @@ impl PlacesApi {
                 // We always create a new read-write connection for an initial open so
                 // we can create the schema and/or do version upgrades.
                 let coop_tx_lock = Arc::new(Mutex::new(()));
-                let connection = PlacesDb::open(
+                let frecency_settings = Arc::new(frecency_settings);
+                let mut connection = PlacesDb::open(
                     &db_name,
                     ConnectionType::ReadWrite,
                     id,
                     coop_tx_lock.clone(),
                 )?;
+                connection.set_frecency_settings(frecency_settings.clone());
+                storage::history::mark_frecencies_stale_if_settings_changed(&connection)?;
                 let new = PlacesApi {
                     db_name: db_name.clone(),
                     write_connection: Mutex::new(Some(connection)),
+                    reader_pool: ReaderPool::new(
+                        db_name.clone(),
+                        id,
+                        coop_tx_lock.clone(),
+                        DEFAULT_MAX_READERS,
+                    ),
                     sync_state: Mutex::new(None),
                     sync_conn_active: AtomicBool::new(false),
+                    frecency_settings,
                     id,
                     coop_tx_lock,
                 };
@@ -131,9 +171,9 @@ impl PlacesApi {
         }
     }
 
-    fn new_or_existing(db_name: PathBuf) -> Result<Arc<Self>> {
+    fn new_or_existing(db_name: PathBuf, frecency_settings: FrecencySettings) -> Result<Arc<Self>> {
         let mut guard = APIS.lock().unwrap();
-        Self::new_or_existing_into(&mut guard, db_name)
+        Self::new_or_existing_into(&mut guard, db_name, frecency_settings)
     }
 
     /// Open a connection to the database.
@@ -162,16 +202,29 @@ impl PlacesApi {
         }
     }
 
+    /// Returns the pool of read-only connections for this API. Unlike the
+    /// connections returned by `open_connection`, pooled connections are
+    /// reused, and can all be interrupted at once.
+    pub fn reader_pool(&self) -> &ReaderPool {
+        &self.reader_pool
+    }
+
+    /// Returns the settings used to calculate frecencies.
+    pub fn frecency_settings(&self) -> &FrecencySettings {
+        &self.frecency_settings
+    }
+
     pub fn open_sync_connection(&self) -> Result<SyncConn<'_>> {
         self.sync_conn_active
             .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
             .map_err(|_| ErrorKind::ConnectionAlreadyOpen)?;
-        let db = PlacesDb::open(
+        let mut db = PlacesDb::open(
             self.db_name.clone(),
             ConnectionType::Sync,
             self.id,
             self.coop_tx_lock.clone(),
         )?;
+        db.set_frecency_settings(self.frecency_settings.clone());
         Ok(SyncConn {
             db,
             flag: &self.sync_conn_active,
@@ -324,12 +377,13 @@ impl PlacesApi {
         let interruptee = conn.begin_interrupt_scope();
         let bm_engine = BookmarksEngine::new(&conn, &interruptee);
         let history_engine = HistoryEngine::new(&conn, &interruptee);
+        let history_metadata_engine = HistoryMetadataEngine::new(&conn, &interruptee);
         let mut mem_cached_state = sync_state.mem_cached_state.take();
         let mut disk_cached_state = sync_state.disk_cached_state.take();
 
         // NOTE: After here we must never return Err()!
         let result = sync15::sync_multiple(
-            &[&history_engine, &bm_engine],
+            &[&history_engine, &history_metadata_engine, &bm_engine],
             &mut disk_cached_state,
             &mut mem_cached_state,
             client_init,
@@ -401,6 +455,8 @@ impl PlacesApi {
         HistoryEngine::migrate_v1_global_state(&conn)?;
 
         history_sync::reset(&conn, &sync15::EngineSyncAssociation::Disconnected)?;
+        // History metadata syncs along with history.
+        history_metadata_sync::reset(&conn, &sync15::EngineSyncAssociation::Disconnected)?;
         Ok(())
     }
 
@@ -412,6 +468,24 @@ impl PlacesApi {
         let conn = self.open_sync_connection()?;
         Ok(conn.new_interrupt_handle())
     }
+
+    /// Registers an observer to be notified of changes to history and
+    /// bookmarks made on any of this API's write connections. See the
+    /// `observer` module for details.
+    pub fn register_observer(&self, observer: Arc<dyn PlacesObserver>) -> ObserverId {
+        observer::register(self.id, observer)
+    }
+
+    /// Unregisters an observer. Returns `false` if it wasn't registered.
+    pub fn unregister_observer(&self, id: ObserverId) -> bool {
+        observer::unregister(self.id, id)
+    }
+}
+
+impl Drop for PlacesApi {
+    fn drop(&mut self) {
+        observer::unregister_all(self.id);
+    }
 }
 
 /// Wrapper around PlacesDb that automatically sets a flag (`sync_conn_active`)
@@ -589,4 +663,56 @@ mod tests {
         assert_ne!(1, conn.db.query_one::<i64>("PRAGMA user_version")?);
         Ok(())
     }
+
+    #[test]
+    fn test_frecency_settings_version() -> Result<()> {
+        use crate::frecency::FrecencyAging;
+        use crate::observation::VisitObservation;
+        use crate::storage::{fetch_page_info, history::apply_observation, run_maintenance};
+        use crate::types::VisitTransition;
+        use std::time::{Duration, SystemTime};
+        use url::Url;
+
+        let db_name = "test-frecency-settings-version";
+        let url = Url::parse("https://www.example.com")?;
+        let api = PlacesApi::new_memory(db_name)?;
+        let conn = api.open_connection(ConnectionType::ReadWrite)?;
+        let visited = SystemTime::now() - Duration::from_secs(60 * 86_400);
+        apply_observation(
+            &conn,
+            VisitObservation::new(url.clone())
+                .with_visit_type(VisitTransition::Link)
+                .with_at(Some(visited.into())),
+        )?;
+        assert_eq!(fetch_page_info(&conn, &url)?.unwrap().page.frecency, 30);
+
+        // The reader keeps the memory database around after the API is gone,
+        // so we can reopen it with different settings.
+        let _reader = api.open_connection(ConnectionType::ReadOnly)?;
+        drop(conn);
+        drop(api);
+        let api = PlacesApi::new_memory_with_frecency_settings(
+            db_name,
+            FrecencySettings {
+                version: 2,
+                aging: FrecencyAging::Decay { half_life_days: 10 },
+                ..FrecencySettings::default()
+            },
+        )?;
+        assert_eq!(api.frecency_settings().version, 2);
+        let conn = api.open_connection(ConnectionType::ReadWrite)?;
+        assert_eq!(fetch_page_info(&conn, &url)?.unwrap().page.frecency, 30);
+        assert_eq!(
+            conn.query_one::<i64>("SELECT COUNT(*) FROM moz_places_stale_frecencies")?,
+            1
+        );
+
+        run_maintenance(&conn)?;
+        assert_eq!(fetch_page_info(&conn, &url)?.unwrap().page.frecency, 2);
+        assert_eq!(
+            conn.query_one::<i64>("SELECT COUNT(*) FROM moz_places_stale_frecencies")?,
+            0
+        );
+        Ok(())
+    }
 }
@@ -0,0 +1,307 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! A pool of read-only connections, so that autocomplete and history UI
+//! queries can run in parallel.
+//!
+//! Each `PlacesApi` has a pool, returned by `PlacesApi::reader_pool`. The
+//! pool opens connections as they're checked out, up to its maximum size,
+//! and keeps them open once they're returned. When all connections are
+//! checked out, `checkout` blocks until one is returned, and `try_checkout`
+//! returns `None`.
+//!
+//! Each checked out connection can be interrupted with its own interrupt
+//! handle. `interrupt_all` interrupts every connection in the pool at once,
+//! for example, before a large write or a sync, so that stale queries don't
+//! hold up the writer or waste time.
+
+use super::places_api::ConnectionType;
+use crate::db::PlacesDb;
+use crate::error::*;
+use sql_support::SqlInterruptHandle;
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::path::PathBuf;
+use std::sync::{Arc, Condvar, Mutex};
+
+/// The default maximum number of connections in a pool.
+pub const DEFAULT_MAX_READERS: usize = 4;
+
+pub struct ReaderPool {
+    db_name: PathBuf,
+    api_id: usize,
+    coop_tx_lock: Arc<Mutex<()>>,
+    state: Mutex<PoolState>,
+    returned: Condvar,
+}
+
+struct PoolState {
+    max_size: usize,
+    idle: Vec<(usize, PlacesDb)>,
+    // Interrupt handles for all open connections, checked out or idle,
+    // indexed by the connection's id in the pool. This also counts
+    // connections that are still being opened, with `None` handles, so that
+    // we don't open more than `max_size`.
+    interrupt_handles: HashMap<usize, Option<SqlInterruptHandle>>,
+    next_id: usize,
+}
+
+impl ReaderPool {
+    pub(crate) fn new(
+        db_name: PathBuf,
+        api_id: usize,
+        coop_tx_lock: Arc<Mutex<()>>,
+        max_size: usize,
+    ) -> Self {
+        ReaderPool {
+            db_name,
+            api_id,
+            coop_tx_lock,
+            state: Mutex::new(PoolState {
+                max_size: max_size.max(1),
+                idle: Vec::new(),
+                interrupt_handles: HashMap::new(),
+                next_id: 0,
+            }),
+            returned: Condvar::new(),
+        }
+    }
+
+    /// Returns the maximum number of connections in the pool.
+    pub fn max_size(&self) -> usize {
+        self.state.lock().unwrap().max_size
+    }
+
+    /// Sets the maximum number of connections in the pool. If the pool has
+    /// more connections than this, the extra connections are closed as
+    /// they're returned.
+    pub fn set_max_size(&self, max_size: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.max_size = max_size.max(1);
+        while state.interrupt_handles.len() > state.max_size {
+            match state.idle.pop() {
+                Some((id, _)) => {
+                    state.interrupt_handles.remove(&id);
+                }
+                None => break,
+            }
+        }
+        // More connections might be available now.
+        self.returned.notify_all();
+    }
+
+    /// Returns the number of open connections in the pool, including the
+    /// ones that are checked out.
+    pub fn open_count(&self) -> usize {
+        self.state.lock().unwrap().interrupt_handles.len()
+    }
+
+    /// Checks out a connection, waiting for one to be returned if they're all
+    /// checked out. The connection is returned to the pool when the
+    /// `PooledReader` is dropped. Use `PlacesDb::new_interrupt_handle` to
+    /// get an interrupt handle for just this connection.
+    pub fn checkout(&self) -> Result<PooledReader<'_>> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            match self.take_or_reserve(&mut state) {
+                Checkout::Idle(reader) => return Ok(reader),
+                Checkout::Reserved(id) => {
+                    drop(state);
+                    return self.open_reserved(id);
+                }
+                Checkout::Full => state = self.returned.wait(state).unwrap(),
+            }
+        }
+    }
+
+    /// Like `checkout`, but returns `None` instead of waiting if all
+    /// connections are checked out.
+    pub fn try_checkout(&self) -> Result<Option<PooledReader<'_>>> {
+        let mut state = self.state.lock().unwrap();
+        match self.take_or_reserve(&mut state) {
+            Checkout::Idle(reader) => Ok(Some(reader)),
+            Checkout::Reserved(id) => {
+                drop(state);
+                Ok(Some(self.open_reserved(id)?))
+            }
+            Checkout::Full => Ok(None),
+        }
+    }
+
+    /// Interrupts all queries running on the pool's connections. Queries
+    /// that start after this returns aren't affected.
+    pub fn interrupt_all(&self) {
+        let state = self.state.lock().unwrap();
+        for handle in state.interrupt_handles.values().flatten() {
+            handle.interrupt();
+        }
+    }
+
+    /// Takes an idle connection, or reserves an id for a new one if the pool
+    /// isn't full.
+    fn take_or_reserve(&self, state: &mut PoolState) -> Checkout<'_> {
+        if let Some((id, db)) = state.idle.pop() {
+            return Checkout::Idle(PooledReader {
+                pool: self,
+                id,
+                db: Some(db),
+            });
+        }
+        if state.interrupt_handles.len() < state.max_size {
+            let id = state.next_id;
+            state.next_id += 1;
+            state.interrupt_handles.insert(id, None);
+            return Checkout::Reserved(id);
+        }
+        Checkout::Full
+    }
+
+    /// Opens a new connection for a reserved id. We do this without holding
+    /// the lock, since opening a connection can be slow.
+    fn open_reserved(&self, id: usize) -> Result<PooledReader<'_>> {
+        let result = PlacesDb::open(
+            &self.db_name,
+            ConnectionType::ReadOnly,
+            self.api_id,
+            self.coop_tx_lock.clone(),
+        );
+        let mut state = self.state.lock().unwrap();
+        match result {
+            Ok(db) => {
+                state
+                    .interrupt_handles
+                    .insert(id, Some(db.new_interrupt_handle()));
+                Ok(PooledReader {
+                    pool: self,
+                    id,
+                    db: Some(db),
+                })
+            }
+            Err(e) => {
+                // Release the reservation, so that someone else can try.
+                state.interrupt_handles.remove(&id);
+                self.returned.notify_one();
+                Err(e)
+            }
+        }
+    }
+
+    fn checkin(&self, id: usize, db: PlacesDb) {
+        let mut state = self.state.lock().unwrap();
+        if state.interrupt_handles.len() > state.max_size {
+            // The pool shrank while this connection was checked out.
+            state.interrupt_handles.remove(&id);
+        } else {
+            state.idle.push((id, db));
+        }
+        self.returned.notify_one();
+    }
+}
+
+enum Checkout<'pool> {
+    /// An idle connection.
+    Idle(PooledReader<'pool>),
+    /// The id reserved for a new connection, which the caller must open.
+    Reserved(usize),
+    /// All connections are checked out.
+    Full,
+}
+
+/// A connection checked out from a `ReaderPool`. Derefs to `PlacesDb`, and
+/// returns the connection to the pool when dropped.
+pub struct PooledReader<'pool> {
+    pool: &'pool ReaderPool,
+    id: usize,
+    db: Option<PlacesDb>,
+}
+
+impl<'pool> Deref for PooledReader<'pool> {
+    type Target = PlacesDb;
+    fn deref(&self) -> &PlacesDb {
+        self.db.as_ref().expect("connection is only taken on drop")
+    }
+}
+
+impl<'pool> Drop for PooledReader<'pool> {
+    fn drop(&mut self) {
+        if let Some(db) = self.db.take() {
+            self.pool.checkin(self.id, db);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::places_api::test::new_mem_api;
+    use sql_support::ConnExt;
+    use std::thread;
+
+    #[test]
+    fn test_checkout_and_return() -> Result<()> {
+        let api = new_mem_api();
+        let pool = api.reader_pool();
+        pool.set_max_size(2);
+
+        let first = pool.checkout()?;
+        let second = pool.checkout()?;
+        assert_eq!(pool.open_count(), 2);
+        assert!(pool.try_checkout()?.is_none());
+        assert_eq!(first.query_one::<i64>("SELECT 1")?, 1);
+
+        drop(first);
+        let third = pool.try_checkout()?.expect("should reuse connection");
+        assert_eq!(pool.open_count(), 2);
+
+        // Shrinking the pool closes connections as they're returned.
+        pool.set_max_size(1);
+        drop(second);
+        assert_eq!(pool.open_count(), 1);
+        assert!(pool.try_checkout()?.is_none());
+        drop(third);
+        assert!(pool.try_checkout()?.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_checkout_waits() -> Result<()> {
+        let api = new_mem_api();
+        api.reader_pool().set_max_size(1);
+        let reader = api.reader_pool().checkout()?;
+
+        let waiter = {
+            let api = Arc::clone(&api);
+            thread::spawn(move || -> Result<i64> {
+                let reader = api.reader_pool().checkout()?;
+                Ok(reader.query_one::<i64>("SELECT 2")?)
+            })
+        };
+        drop(reader);
+        assert_eq!(waiter.join().unwrap()?, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_interrupt_all() -> Result<()> {
+        let api = new_mem_api();
+        let pool = api.reader_pool();
+        let first = pool.checkout()?;
+        let second = pool.checkout()?;
+        let first_scope = first.begin_interrupt_scope();
+        let second_scope = second.begin_interrupt_scope();
+
+        pool.interrupt_all();
+        assert!(first_scope.err_if_interrupted().is_err());
+        assert!(second_scope.err_if_interrupted().is_err());
+
+        // New scopes aren't interrupted.
+        assert!(!first.begin_interrupt_scope().err_if_interrupted().is_err());
+
+        // Each connection can also be interrupted on its own.
+        let scope = second.begin_interrupt_scope();
+        first.new_interrupt_handle().interrupt();
+        assert!(!scope.err_if_interrupted().is_err());
+        Ok(())
+    }
+}
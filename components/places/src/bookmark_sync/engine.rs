@@ -11,13 +11,14 @@ use super::{SyncedBookmarkKind, SyncedBookmarkValidity};
 use crate::api::places_api::ConnectionType;
 use crate::db::{GlobalChangeCounterTracker, PlacesDb};
 use crate::error::*;
-use crate::frecency::{calculate_frecency, DEFAULT_FRECENCY_SETTINGS};
 use crate::storage::{
     bookmarks::{
         bookmark_sync::{create_synced_bookmark_roots, reset},
         BookmarkRootGuid,
     },
-    delete_pending_temp_tables, get_meta, put_meta,
+    delete_pending_temp_tables, get_meta,
+    history::recalculate_stale_frecencies,
+    put_meta,
 };
 use crate::types::{BookmarkType, SyncStatus};
 use dogear::{
@@ -42,12 +43,6 @@ pub const LAST_SYNC_META_KEY: &str = "bookmarks_last_sync_time";
 pub const GLOBAL_SYNCID_META_KEY: &str = "bookmarks_global_sync_id";
 pub const COLLECTION_SYNCID_META_KEY: &str = "bookmarks_sync_id";
 
-/// The maximum number of URLs for which to recalculate frecencies at once.
-/// This is a trade-off between write efficiency and transaction time: higher
-/// maximums mean fewer write statements, but longer transactions, possibly
-/// blocking writes from other connections.
-const MAX_FRECENCIES_TO_RECALCULATE_PER_CHUNK: usize = 400;
-
 /// Adapts an interruptee to a Dogear abort signal.
 struct MergeInterruptee<'a, I>(&'a I);
 
@@ -456,17 +451,21 @@ impl<'a> BookmarksEngine<'a> {
         // Remove all keywords from old and new URLs, and remove new keywords
         // from all existing URLs. The `NOT NULL` conditions are important; they
         // ensure that SQLite uses our partial indexes on `itemsToApply`,
-        // instead of a table scan.
+        // instead of a table scan. We keep keywords that aren't changing, so
+        // that we don't lose their POST data, which isn't synced.
         log::debug!("Removing old keywords");
         self.interruptee.err_if_interrupted()?;
         self.db.execute_batch(
             "DELETE FROM moz_keywords
-             WHERE place_id IN (SELECT oldPlaceId FROM itemsToApply
-                                WHERE oldPlaceId NOT NULL) OR
-                   place_id IN (SELECT newPlaceId FROM itemsToApply
-                                WHERE newPlaceId NOT NULL) OR
-                   keyword IN (SELECT newKeyword FROM itemsToApply
-                               WHERE newKeyword NOT NULL)",
+             WHERE (place_id IN (SELECT oldPlaceId FROM itemsToApply
+                                 WHERE oldPlaceId NOT NULL) OR
+                    place_id IN (SELECT newPlaceId FROM itemsToApply
+                                 WHERE newPlaceId NOT NULL) OR
+                    keyword IN (SELECT newKeyword FROM itemsToApply
+                                WHERE newKeyword NOT NULL)) AND
+                   NOT EXISTS(SELECT 1 FROM itemsToApply
+                              WHERE newPlaceId = moz_keywords.place_id AND
+                                    newKeyword = moz_keywords.keyword)",
         )?;
 
         log::debug!("Removing old tags");
@@ -831,71 +830,7 @@ impl<'a> BookmarksEngine<'a> {
     }
 
     pub(crate) fn update_frecencies(&self) -> Result<()> {
-        let mut tx = self.db.begin_transaction()?;
-
-        let mut frecencies = Vec::with_capacity(MAX_FRECENCIES_TO_RECALCULATE_PER_CHUNK);
-        loop {
-            let sql = format!(
-                "SELECT place_id FROM moz_places_stale_frecencies
-                 ORDER BY stale_at DESC
-                 LIMIT {}",
-                MAX_FRECENCIES_TO_RECALCULATE_PER_CHUNK
-            );
-            let mut stmt = self.db.prepare_maybe_cached(&sql, true)?;
-            let mut results = stmt.query(NO_PARAMS)?;
-            while let Some(row) = results.next()? {
-                let place_id = row.get("place_id")?;
-                // Frecency recalculation runs several statements, so check to
-                // make sure we aren't interrupted before each calculation.
-                self.interruptee.err_if_interrupted()?;
-                let frecency =
-                    calculate_frecency(self.db, &DEFAULT_FRECENCY_SETTINGS, place_id, Some(false))?;
-                frecencies.push((place_id, frecency));
-            }
-            if frecencies.is_empty() {
-                break;
-            }
-
-            // Update all frecencies in one fell swoop...
-            self.db.execute_batch(&format!(
-                "WITH frecencies(id, frecency) AS (
-                   VALUES {}
-                 )
-                 UPDATE moz_places SET
-                   frecency = (SELECT frecency FROM frecencies f
-                               WHERE f.id = id)
-                 WHERE id IN (SELECT f.id FROM frecencies f)",
-                sql_support::repeat_display(frecencies.len(), ",", |index, f| {
-                    let (id, frecency) = frecencies[index];
-                    write!(f, "({}, {})", id, frecency)
-                })
-            ))?;
-            tx.maybe_commit()?;
-            self.interruptee.err_if_interrupted()?;
-
-            // ...And remove them from the stale table.
-            self.db.execute_batch(&format!(
-                "DELETE FROM moz_places_stale_frecencies
-                 WHERE place_id IN ({})",
-                sql_support::repeat_display(frecencies.len(), ",", |index, f| {
-                    let (id, _) = frecencies[index];
-                    write!(f, "{}", id)
-                })
-            ))?;
-            tx.maybe_commit()?;
-            self.interruptee.err_if_interrupted()?;
-
-            // If the query returned fewer URLs than the maximum, we're done.
-            // Otherwise, we might have more, so clear the ones we just
-            // recalculated and fetch the next chunk.
-            if frecencies.len() < MAX_FRECENCIES_TO_RECALCULATE_PER_CHUNK {
-                break;
-            }
-            frecencies.clear();
-        }
-
-        tx.commit()?;
-
+        recalculate_stale_frecencies(self.db, self.interruptee)?;
         Ok(())
     }
 }
@@ -1130,8 +1065,8 @@ impl<'a> Merger<'a> {
     /// Prepares synced bookmarks for merging.
     fn prepare(&self) -> Result<()> {
         // Sync and Fennec associate keywords with bookmarks, and don't sync
-        // POST data; Rust Places associates them with URLs, and also doesn't
-        // support POST data; Desktop associates keywords with (URL, POST data)
+        // POST data; Rust Places associates them with URLs, and keeps their
+        // POST data local; Desktop associates keywords with (URL, POST data)
         // pairs, and multiple bookmarks may have the same URL.
         //
         // When a keyword changes, clients should reupload all bookmarks with
@@ -3225,6 +3160,103 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_local_keyword_changes() -> Result<()> {
+        use crate::storage::keywords::{get_keyword, set_keyword};
+
+        let api = new_mem_api();
+        let writer = api.open_connection(ConnectionType::ReadWrite)?;
+        let syncer = api.open_sync_connection()?;
+        let url = Url::parse("http://example.com/a")?;
+
+        let bookmark_a = |title: &str, keyword: &str| {
+            json!({
+                "id": "bookmarkAAAA",
+                "type": "bookmark",
+                "parentid": "toolbar",
+                "parentName": "toolbar",
+                "dateAdded": 1_552_183_116_885u64,
+                "title": title,
+                "bmkUri": "http://example.com/a",
+                "keyword": keyword,
+            })
+        };
+        let records = vec![
+            json!({
+                "id": "toolbar",
+                "type": "folder",
+                "parentid": "places",
+                "parentName": "",
+                "dateAdded": 0,
+                "title": "toolbar",
+                "children": ["bookmarkAAAA"],
+            }),
+            bookmark_a("A", "a"),
+        ];
+
+        let interrupt_scope = syncer.begin_interrupt_scope();
+        let engine = BookmarksEngine::new(&syncer, &interrupt_scope);
+
+        let mut incoming = IncomingChangeset::new(engine.collection_name(), ServerTimestamp(0));
+        for record in records {
+            let payload = Payload::from_json(record).unwrap();
+            incoming.changes.push((payload, ServerTimestamp(0)));
+        }
+        let outgoing = engine
+            .apply_incoming(vec![incoming], &mut telemetry::Engine::new("bookmarks"))
+            .expect("Should apply incoming records");
+        let outgoing_ids = outgoing
+            .changes
+            .iter()
+            .map(|p| p.id.clone())
+            .collect::<Vec<_>>();
+        engine
+            .sync_finished(ServerTimestamp(0), outgoing_ids)
+            .expect("Should push synced changes back to the engine");
+
+        // POST data isn't synced, so adding it doesn't upload anything...
+        set_keyword(&writer, &url, "a", Some("q=%s"))?;
+        let outgoing = engine
+            .apply_incoming(
+                vec![IncomingChangeset::new(
+                    engine.collection_name(),
+                    ServerTimestamp(1000),
+                )],
+                &mut telemetry::Engine::new("bookmarks"),
+            )
+            .expect("Should fetch outgoing records after adding POST data");
+        assert!(outgoing.changes.is_empty());
+
+        // ...And applying a remote change with the same keyword keeps it.
+        let mut incoming = IncomingChangeset::new(engine.collection_name(), ServerTimestamp(2000));
+        incoming.changes.push((
+            Payload::from_json(bookmark_a("A (remote)", "a")).unwrap(),
+            ServerTimestamp(2000),
+        ));
+        engine
+            .apply_incoming(vec![incoming], &mut telemetry::Engine::new("bookmarks"))
+            .expect("Should apply remote change");
+        let keyword = get_keyword(&writer, "a")?.expect("Should keep keyword");
+        assert_eq!(keyword.post_data.as_deref(), Some("q=%s"));
+
+        // Changing the keyword locally reuploads the bookmark.
+        set_keyword(&writer, &url, "b", None)?;
+        let outgoing = engine
+            .apply_incoming(
+                vec![IncomingChangeset::new(
+                    engine.collection_name(),
+                    ServerTimestamp(3000),
+                )],
+                &mut telemetry::Engine::new("bookmarks"),
+            )
+            .expect("Should fetch outgoing records after changing keyword");
+        assert_eq!(outgoing.changes.len(), 1);
+        assert_eq!(outgoing.changes[0].id, "bookmarkAAAA");
+        assert_eq!(outgoing.changes[0].data["keyword"], "b");
+
+        Ok(())
+    }
+
     #[test]
     fn test_apply_complex_bookmark_keywords() -> Result<()> {
         use crate::storage::bookmarks::bookmarks_get_url_for_keyword;
@@ -5,6 +5,7 @@
 use super::schema;
 use crate::api::places_api::ConnectionType;
 use crate::error::*;
+use crate::frecency::FrecencySettings;
 use lazy_static::lazy_static;
 use rusqlite::{self, Connection, Transaction};
 use sql_support::{
@@ -99,6 +100,7 @@ pub struct PlacesDb {
     interrupt_counter: Arc<AtomicUsize>,
     api_id: usize,
     pub(super) coop_tx_lock: Arc<Mutex<()>>,
+    frecency_settings: Arc<FrecencySettings>,
 }
 
 impl PlacesDb {
@@ -115,6 +117,8 @@ impl PlacesDb {
             api_id,
             interrupt_counter: Arc::new(AtomicUsize::new(0)),
             coop_tx_lock,
+            // The API sets this for connections that write frecencies.
+            frecency_settings: Arc::new(FrecencySettings::default()),
         }
     }
 
@@ -178,6 +182,16 @@ impl PlacesDb {
     pub fn api_id(&self) -> usize {
         self.api_id
     }
+
+    /// Returns the settings used to calculate frecencies on this connection.
+    #[inline]
+    pub fn frecency_settings(&self) -> &FrecencySettings {
+        &self.frecency_settings
+    }
+
+    pub(crate) fn set_frecency_settings(&mut self, settings: Arc<FrecencySettings>) {
+        self.frecency_settings = settings;
+    }
 }
 
 impl Drop for PlacesDb {
@@ -289,6 +303,12 @@ fn define_functions(c: &Connection, api_id: usize) -> rusqlite::Result<()> {
         FunctionFlags::SQLITE_UTF8,
         move |ctx| -> rusqlite::Result<i64> { sql_fns::note_bookmarks_sync_change(ctx, api_id) },
     )?;
+    c.create_scalar_function(
+        "places_observed",
+        0,
+        FunctionFlags::SQLITE_UTF8,
+        move |ctx| -> rusqlite::Result<bool> { sql_fns::places_observed(ctx, api_id) },
+    )?;
     Ok(())
 }
 
@@ -465,6 +485,13 @@ pub(crate) mod sql_fns {
         // Because we only ever check for equality, we can use Relaxed ordering.
         Ok(counter.fetch_add(1, Ordering::Relaxed))
     }
+
+    /// Returns whether the observer triggers should record changes for this
+    /// API.
+    #[inline(never)]
+    pub fn places_observed(_ctx: &Context<'_>, api_id: usize) -> Result<bool> {
+        Ok(crate::api::observer::has_observers(api_id))
+    }
 }
 
 #[cfg(test)]
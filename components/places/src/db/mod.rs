@@ -5,7 +5,7 @@
 // We don't want 'db.rs' as a sub-module. We could move the contents here? Or something else?
 #[allow(clippy::module_inception)] // FIXME
 pub mod db;
-mod schema;
+pub(crate) mod schema;
 mod tx;
 pub use self::tx::PlacesTransaction;
 
@@ -11,12 +11,17 @@ use crate::types::SyncStatus;
 use rusqlite::Connection;
 use sql_support::ConnExt;
 
-pub const VERSION: u32 = 15;
+pub const VERSION: u32 = 20;
 
 // Shared schema and temp tables for the read-write and Sync connections.
 const CREATE_SHARED_SCHEMA_SQL: &str = include_str!("../../sql/create_shared_schema.sql");
 const CREATE_SHARED_TEMP_TABLES_SQL: &str = include_str!("../../sql/create_shared_temp_tables.sql");
 
+// History metadata Sync indexes and tables, which need the columns added in
+// version 16.
+const CREATE_HISTORY_METADATA_SYNC_SCHEMA_SQL: &str =
+    include_str!("../../sql/create_history_metadata_sync_schema.sql");
+
 // Sync-specific temp tables and triggers.
 const CREATE_SYNC_TEMP_TABLES_SQL: &str = include_str!("../../sql/create_sync_temp_tables.sql");
 const CREATE_SYNC_TRIGGERS_SQL: &str = include_str!("../../sql/create_sync_triggers.sql");
@@ -72,6 +77,7 @@ fn update_origin_frecency_stats(op: &str) -> String {
 pub fn init(conn: &Connection) -> rusqlite::Result<()> {
     log::debug!("Initializing schema");
     conn.execute_batch(CREATE_SHARED_SCHEMA_SQL)?;
+    conn.execute_batch(CREATE_HISTORY_METADATA_SYNC_SCHEMA_SQL)?;
     create_bookmark_roots(conn)?;
     Ok(())
 }
@@ -137,6 +143,29 @@ where
     Ok(())
 }
 
+/// Adds a column to an existing table, unless it's already there. Migrations
+/// that re-run `CREATE_SHARED_SCHEMA_SQL` create tables with their current
+/// columns, so a later `ALTER TABLE` for the same column would fail.
+fn add_column_if_missing(
+    db: &Connection,
+    table: &str,
+    column: &str,
+    declaration: &str,
+) -> rusqlite::Result<()> {
+    let exists: bool = db.query_row(
+        "SELECT EXISTS(SELECT 1 FROM pragma_table_info(?) WHERE name = ?)",
+        &[table, column],
+        |row| row.get(0),
+    )?;
+    if !exists {
+        db.execute_batch(&format!(
+            "ALTER TABLE {} ADD COLUMN {} {}",
+            table, column, declaration
+        ))?;
+    }
+    Ok(())
+}
+
 pub fn upgrade_from(db: &Connection, from: u32) -> rusqlite::Result<()> {
     log::debug!("Upgrading schema from {} to {}", from, VERSION);
 
@@ -259,6 +288,43 @@ pub fn upgrade_from(db: &Connection, from: u32) -> rusqlite::Result<()> {
         ],
         || Ok(()),
     )?;
+    migration(db, from, 15, &[CREATE_SHARED_SCHEMA_SQL], || Ok(()))?; // favicons.
+    migration(db, from, 16, &[], || {
+        // History metadata syncing. Databases that ran migration 14 or
+        // earlier already have these columns, because that migration
+        // recreated `moz_places_metadata` from the current shared schema.
+        add_column_if_missing(db, "moz_places_metadata", "guid", "TEXT")?;
+        add_column_if_missing(
+            db,
+            "moz_places_metadata",
+            "sync_status",
+            "TINYINT NOT NULL DEFAULT 1",
+        )?;
+        add_column_if_missing(
+            db,
+            "moz_places_metadata",
+            "sync_change_counter",
+            "INTEGER NOT NULL DEFAULT 1",
+        )?;
+        add_column_if_missing(
+            db,
+            "moz_places_metadata",
+            "synced_view_time",
+            "INTEGER NOT NULL DEFAULT 0",
+        )?;
+        db.execute_batch(
+            "UPDATE moz_places_metadata SET guid = generate_guid()
+                 WHERE guid IS NULL",
+        )?;
+        db.execute_batch(CREATE_HISTORY_METADATA_SYNC_SCHEMA_SQL)
+    })?;
+    migration(db, from, 17, &[CREATE_SHARED_SCHEMA_SQL], || Ok(()))?; // annotations.
+    migration(db, from, 18, &[CREATE_SHARED_SCHEMA_SQL], || Ok(()))?; // top sites blocklist.
+    migration(db, from, 19, &[], || {
+        // Databases that ran an earlier migration through the shared schema
+        // already have this column.
+        add_column_if_missing(db, "moz_keywords", "post_data", "TEXT")
+    })?;
 
     // Add more migrations here...
     Ok(())
@@ -805,7 +871,7 @@ mod tests {
             .expect("Should open second in-memory database with shared cache");
         assert_eq!(
             get_current_schema_version(&upgrade)?,
-            15,
+            20,
             "Should upgrade schema without errors"
         );
         // One with no mirror entry should still be New
@@ -831,4 +897,174 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_upgrade_schema_16_17() -> Result<()> {
+        let path = "file:test_upgrade_schema_16_17?mode=memory&cache=shared";
+
+        // Replace `moz_places_metadata` with the version 16 table, which
+        // doesn't have any Sync columns.
+        let db = PlacesDb::open(path, ConnectionType::ReadWrite, 0, Default::default())
+            .expect("Should open first in-memory database with shared cache");
+        db.execute_batch(
+            "DROP TABLE moz_places_metadata;
+             CREATE TABLE moz_places_metadata (
+                 id INTEGER PRIMARY KEY,
+                 created_at INTEGER NOT NULL DEFAULT 0,
+                 updated_at INTEGER NOT NULL DEFAULT 0,
+                 place_id INTEGER NOT NULL,
+                 total_view_time INTEGER NOT NULL DEFAULT 0,
+                 search_query_id INTEGER,
+                 referrer_place_id INTEGER,
+                 document_type INTEGER NOT NULL DEFAULT 0,
+                 typing_time INTEGER NOT NULL DEFAULT 0,
+                 key_presses INTEGER NOT NULL DEFAULT 0
+             );
+             DROP TABLE moz_places_metadata_tombstones;
+             INSERT INTO moz_places(guid, url, url_hash)
+             VALUES('place_guid__', 'https://example.com/', hash('https://example.com/'));
+             INSERT INTO moz_places_metadata(place_id, total_view_time)
+             SELECT id, 1000 FROM moz_places WHERE guid = 'place_guid__';
+             PRAGMA user_version = 16;",
+        )?;
+
+        let upgrade = PlacesDb::open(path, ConnectionType::ReadWrite, 0, Default::default())
+            .expect("Should open second in-memory database with shared cache");
+        assert_eq!(get_current_schema_version(&upgrade)?, VERSION);
+
+        // Existing entries get a GUID, and will be uploaded on the next sync.
+        let (guid, sync_status, sync_change_counter) = upgrade.query_row(
+            "SELECT guid, sync_status, sync_change_counter FROM moz_places_metadata",
+            NO_PARAMS,
+            |row| {
+                Ok((
+                    row.get::<_, SyncGuid>(0)?,
+                    row.get::<_, u32>(1)?,
+                    row.get::<_, u32>(2)?,
+                ))
+            },
+        )?;
+        assert!(guid.is_valid_for_places());
+        assert_eq!(sync_status, SyncStatus::New as u32);
+        assert_eq!(sync_change_counter, 1);
+        assert_eq!(
+            select_simple_int(
+                &upgrade,
+                "SELECT COUNT(*) FROM moz_places_metadata_tombstones"
+            ),
+            0
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_upgrade_schema_15_16() -> Result<()> {
+        let path = "file:test_upgrade_schema_15_16?mode=memory&cache=shared";
+
+        // Roll the schema back to version 15: drop everything added since,
+        // and replace `moz_places_metadata` and `moz_keywords` with their
+        // version 15 tables.
+        let db = PlacesDb::open(path, ConnectionType::ReadWrite, 0, Default::default())
+            .expect("Should open first in-memory database with shared cache");
+        db.execute_batch(
+            "DROP TABLE moz_icons_to_pages;
+             DROP TABLE moz_pages_w_icons;
+             DROP TABLE moz_icons;
+             DROP TABLE moz_icons_data;
+             DROP TABLE moz_items_annos;
+             DROP TABLE moz_annos;
+             DROP TABLE moz_anno_attributes;
+             DROP TABLE moz_topsites_blocklist;
+             DROP TABLE moz_places_metadata_tombstones;
+             DROP TABLE moz_places_metadata;
+             CREATE TABLE moz_places_metadata (
+                 id INTEGER PRIMARY KEY,
+                 created_at INTEGER NOT NULL DEFAULT 0,
+                 updated_at INTEGER NOT NULL DEFAULT 0,
+                 place_id INTEGER NOT NULL,
+                 total_view_time INTEGER NOT NULL DEFAULT 0,
+                 search_query_id INTEGER,
+                 referrer_place_id INTEGER,
+                 document_type INTEGER NOT NULL DEFAULT 0,
+                 typing_time INTEGER NOT NULL DEFAULT 0,
+                 key_presses INTEGER NOT NULL DEFAULT 0
+             );
+             DROP TABLE moz_keywords;
+             CREATE TABLE moz_keywords(
+                 place_id INTEGER PRIMARY KEY REFERENCES moz_places(id)
+                                  ON DELETE RESTRICT,
+                 keyword TEXT NOT NULL UNIQUE
+             );
+             INSERT INTO moz_places(guid, url, url_hash)
+             VALUES('place_guid__', 'https://example.com/', hash('https://example.com/'));
+             INSERT INTO moz_places_metadata(place_id, total_view_time)
+             SELECT id, 1000 FROM moz_places WHERE guid = 'place_guid__';
+             INSERT INTO moz_keywords(place_id, keyword)
+             SELECT id, 'ex' FROM moz_places WHERE guid = 'place_guid__';
+             PRAGMA user_version = 15;",
+        )?;
+
+        let upgrade = PlacesDb::open(path, ConnectionType::ReadWrite, 0, Default::default())
+            .expect("Should open second in-memory database with shared cache");
+        assert_eq!(get_current_schema_version(&upgrade)?, VERSION);
+
+        let guid = upgrade.query_row("SELECT guid FROM moz_places_metadata", NO_PARAMS, |row| {
+            row.get::<_, SyncGuid>(0)
+        })?;
+        assert!(guid.is_valid_for_places());
+        assert_eq!(
+            select_simple_int(
+                &upgrade,
+                "SELECT COUNT(*) FROM moz_places_metadata_tombstones"
+            ),
+            0
+        );
+        assert_eq!(
+            select_simple_int(
+                &upgrade,
+                "SELECT COUNT(*) FROM moz_keywords
+                 WHERE keyword = 'ex' AND post_data IS NULL"
+            ),
+            1
+        );
+        assert_eq!(
+            select_simple_int(&upgrade, "SELECT COUNT(*) FROM moz_annos"),
+            0
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_upgrade_schema_18_19() -> Result<()> {
+        let path = "file:test_upgrade_schema_18_19?mode=memory&cache=shared";
+
+        // `moz_keywords` already has `post_data` here, like it does in any
+        // database that created the table from the shared schema.
+        let db = PlacesDb::open(path, ConnectionType::ReadWrite, 0, Default::default())
+            .expect("Should open first in-memory database with shared cache");
+        db.execute_batch(
+            "INSERT INTO moz_places(guid, url, url_hash)
+             VALUES('place_guid__', 'https://example.com/?q=%s',
+                    hash('https://example.com/?q=%s'));
+             INSERT INTO moz_keywords(place_id, keyword, post_data)
+             SELECT id, 'ex', 'q=%s' FROM moz_places WHERE guid = 'place_guid__';
+             PRAGMA user_version = 18;",
+        )?;
+
+        let upgrade = PlacesDb::open(path, ConnectionType::ReadWrite, 0, Default::default())
+            .expect("Should open second in-memory database with shared cache");
+        assert_eq!(get_current_schema_version(&upgrade)?, VERSION);
+        assert_eq!(
+            select_simple_int(
+                &upgrade,
+                "SELECT COUNT(*) FROM moz_keywords
+                 WHERE keyword = 'ex' AND post_data = 'q=%s'"
+            ),
+            1
+        );
+
+        Ok(())
+    }
 }
@@ -4,7 +4,9 @@
 
 mod coop_transaction;
 
+use crate::api::observer;
 use crate::api::places_api::ConnectionType;
+use crate::db::PlacesDb;
 use crate::error::*;
 use coop_transaction::ChunkedCoopTransaction;
 use rusqlite::Connection;
@@ -20,7 +22,10 @@ macro_rules! debug_complaint {
 }
 /// High level transaction type which "does the right thing" for you.
 /// Construct one with `PlacesDb::begin_transaction()`.
-pub struct PlacesTransaction<'conn>(PlacesTransactionRepr<'conn>);
+pub struct PlacesTransaction<'conn> {
+    repr: PlacesTransactionRepr<'conn>,
+    db: &'conn PlacesDb,
+}
 
 /// Only separated from PlacesTransaction so that the internals of the former
 /// are private (so that it can't be `matched` on, for example)
@@ -37,7 +42,7 @@ impl<'conn> PlacesTransaction<'conn> {
     /// earliest opportunity.
     #[inline]
     pub fn should_commit(&self) -> bool {
-        match &self.0 {
+        match &self.repr {
             PlacesTransactionRepr::ChunkedWrite(tx) => tx.should_commit(),
             _ => true,
         }
@@ -50,7 +55,7 @@ impl<'conn> PlacesTransaction<'conn> {
     ///   warning and does nothing.
     #[inline]
     pub fn maybe_commit(&mut self) -> Result<()> {
-        if let PlacesTransactionRepr::ChunkedWrite(tx) = &mut self.0 {
+        if let PlacesTransactionRepr::ChunkedWrite(tx) = &mut self.repr {
             tx.maybe_commit()?;
         } else {
             debug_complaint!("maybe_commit called on a non-chunked transaction");
@@ -60,11 +65,27 @@ impl<'conn> PlacesTransaction<'conn> {
 
     /// Consumes and commits a PlacesTransaction transaction.
     pub fn commit(self) -> Result<()> {
-        match self.0 {
-            PlacesTransactionRepr::ChunkedWrite(t) => t.commit()?,
-            PlacesTransactionRepr::UnchunkedWrite(t) => t.commit()?,
-            PlacesTransactionRepr::ReadOnly(t) => t.commit()?,
+        let notify = match self.repr {
+            PlacesTransactionRepr::ChunkedWrite(t) => {
+                t.commit()?;
+                true
+            }
+            PlacesTransactionRepr::UnchunkedWrite(t) => {
+                t.commit()?;
+                true
+            }
+            PlacesTransactionRepr::ReadOnly(t) => {
+                t.commit()?;
+                false
+            }
         };
+        if notify {
+            // The transaction has already committed, so failing to notify
+            // observers shouldn't fail the caller.
+            if let Err(e) = observer::notify_observers(self.db) {
+                log::error!("Failed to notify observers: {}", e);
+            }
+        }
         Ok(())
     }
 
@@ -72,7 +93,7 @@ impl<'conn> PlacesTransaction<'conn> {
     /// maybe_commit has been called, this may only roll back as far as that
     /// call.
     pub fn rollback(self) -> Result<()> {
-        match self.0 {
+        match self.repr {
             PlacesTransactionRepr::ChunkedWrite(t) => t.rollback()?,
             PlacesTransactionRepr::UnchunkedWrite(t) => t.rollback()?,
             PlacesTransactionRepr::ReadOnly(t) => t.rollback()?,
@@ -88,7 +109,7 @@ impl super::PlacesDb {
     /// - for ReadWrite connections, begins a normal coop transaction
     /// - for ReadOnly connections, begins an unchecked transaction.
     pub fn begin_transaction(&self) -> Result<PlacesTransaction<'_>> {
-        Ok(PlacesTransaction(match self.conn_type() {
+        let repr = match self.conn_type() {
             ConnectionType::Sync => {
                 PlacesTransactionRepr::ChunkedWrite(self.chunked_coop_trransaction()?)
             }
@@ -99,7 +120,8 @@ impl super::PlacesDb {
                 // Use an unchecked transaction with no locking.
                 PlacesTransactionRepr::ReadOnly(self.unchecked_transaction()?)
             }
-        }))
+        };
+        Ok(PlacesTransaction { repr, db: self })
     }
 }
 
@@ -107,7 +129,7 @@ impl<'conn> std::ops::Deref for PlacesTransaction<'conn> {
     type Target = Connection;
 
     fn deref(&self) -> &Connection {
-        match &self.0 {
+        match &self.repr {
             PlacesTransactionRepr::ChunkedWrite(t) => t,
             PlacesTransactionRepr::UnchunkedWrite(t) => t,
             PlacesTransactionRepr::ReadOnly(t) => t,
@@ -37,6 +37,9 @@ pub enum ErrorKind {
     #[error("An invalid connection type was specified")]
     InvalidConnectionType,
 
+    #[error("An invalid search mode was specified")]
+    InvalidSearchMode,
+
     #[error("IO error: {0}")]
     IoError(#[from] std::io::Error),
 
@@ -75,6 +78,12 @@ pub enum ErrorKind {
     #[error("Can not import from database version {0}")]
     UnsupportedDatabaseVersion(i64),
 
+    #[error("Invalid bookmarks backup: {0}")]
+    InvalidBackup(String),
+
+    #[error("Invalid import file: {0}")]
+    InvalidImportFile(String),
+
     #[error("Error opening database: {0}")]
     OpenDatabaseError(#[from] sql_support::open_database::Error),
 }
@@ -125,9 +134,19 @@ pub enum InvalidPlaceInfo {
     #[error("URL too long")]
     UrlTooLong,
 
+    #[error("Icon data too large")]
+    IconTooLarge,
+
     // Like Urls, a tag is considered private info, so the value isn't in the error.
     #[error("The tag value is invalid")]
     InvalidTag,
+    // Keywords are private info too, for the same reason as tags.
+    #[error("The keyword value is invalid")]
+    InvalidKeyword,
+    #[error("The keyword is already used for a different URL")]
+    KeywordInUse,
+    #[error("The annotation name is invalid")]
+    InvalidAnnotationName,
     #[error("Cannot change the '{0}' property of a bookmark of type {1:?}")]
     IllegalChange(&'static str, BookmarkType),
 
@@ -14,8 +14,31 @@ enum RedirectBonus {
     Normal,
 }
 
+/// How visits are weighted by their age.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FrecencyAging {
+    /// Visits are weighted by the bucket their age falls into, using the
+    /// bucket cutoffs and weights in `FrecencySettings`. This matches
+    /// Desktop.
+    Buckets,
+    /// Visit weights decay exponentially with age, starting from the first
+    /// bucket's weight, and halving every `half_life_days`.
+    Decay { half_life_days: u32 },
+}
+
+/// The settings used to calculate frecencies. Each `PlacesApi` has its own
+/// settings, which it passes to its write and sync connections.
+///
+/// `version` identifies the settings. When a database is opened with a
+/// different version than the one its frecencies were calculated with, all
+/// frecencies are marked as stale, and recalculated by
+/// `storage::run_maintenance`. Changing any of the
+/// other settings without bumping the version won't recalculate existing
+/// frecencies.
 #[derive(Debug, Clone, PartialEq)]
 pub struct FrecencySettings {
+    pub version: u32,
+    pub aging: FrecencyAging,
     // TODO: These probably should not all be i32s...
     pub num_visits: i32,                     // from "places.frecency.numVisits"
     pub first_bucket_cutoff_days: i32,       // from "places.frecency.firstBucketCutoff"
@@ -43,6 +66,8 @@ pub struct FrecencySettings {
 }
 
 pub const DEFAULT_FRECENCY_SETTINGS: FrecencySettings = FrecencySettings {
+    version: 1,
+    aging: FrecencyAging::Buckets,
     // These are the default values of the preferences.
     num_visits: 10,
     first_bucket_cutoff_days: 4,
@@ -104,8 +129,12 @@ impl FrecencySettings {
         }
     }
 
-    fn get_frecency_aged_weight(&self, age_in_days: i32) -> i32 {
-        if age_in_days <= self.first_bucket_cutoff_days {
+    fn get_frecency_aged_weight(&self, age_in_days: i32) -> f32 {
+        if let FrecencyAging::Decay { half_life_days } = self.aging {
+            let half_lives = age_in_days.max(0) as f32 / half_life_days.max(1) as f32;
+            return self.first_bucket_weight as f32 * 0.5f32.powf(half_lives);
+        }
+        let weight = if age_in_days <= self.first_bucket_cutoff_days {
             self.first_bucket_weight
         } else if age_in_days <= self.second_bucket_cutoff_days {
             self.second_bucket_weight
@@ -115,7 +144,8 @@ impl FrecencySettings {
             self.fourth_bucket_weight
         } else {
             self.default_bucket_weight
-        }
+        };
+        weight as f32
     }
 }
 
@@ -248,7 +278,7 @@ impl<'db, 's> FrecencyComputation<'db, 's> {
                 );
             }
             if bonus != 0 {
-                let weight = self.settings.get_frecency_aged_weight(age_in_days) as f32;
+                let weight = self.settings.get_frecency_aged_weight(age_in_days);
                 points_for_sampled_visits += weight * (bonus as f32 / 100.0)
             }
             num_sampled_visits += 1;
@@ -74,6 +74,16 @@ pub fn hash_string(string: &str) -> u32 {
         .fold(0u32, |hash, &cur| add_u32_to_hash(hash, u32::from(cur)))
 }
 
+/// Like `hash_string`, but for arbitrary bytes. This isn't a cryptographic
+/// hash, so callers that need to know two payloads are identical must still
+/// compare them.
+#[inline]
+pub fn hash_bytes(bytes: &[u8]) -> u32 {
+    bytes
+        .iter()
+        .fold(0u32, |hash, &cur| add_u32_to_hash(hash, u32::from(cur)))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
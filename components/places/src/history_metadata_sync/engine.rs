@@ -0,0 +1,140 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use crate::api::places_api::ConnectionType;
+use crate::db::PlacesDb;
+use crate::error::*;
+use crate::storage::history_metadata::history_metadata_sync::{delete_everything, reset};
+use rusqlite::types::{FromSql, ToSql};
+use sql_support::SqlInterruptScope;
+use sync15::telemetry;
+use sync15::{
+    CollSyncIds, CollectionRequest, EngineSyncAssociation, IncomingChangeset, OutgoingChangeset,
+    ServerTimestamp, SyncEngine,
+};
+use sync_guid::Guid;
+
+use super::plan::{apply_plan, finish_plan};
+use super::{COLLECTION_NAME, MAX_INCOMING_RECORDS};
+
+pub const LAST_SYNC_META_KEY: &str = "history_metadata_last_sync_time";
+// Like the other engines in this crate, we use our own meta keys for the sync
+// IDs, because engines are reset individually.
+pub const GLOBAL_SYNCID_META_KEY: &str = "history_metadata_global_sync_id";
+pub const COLLECTION_SYNCID_META_KEY: &str = "history_metadata_sync_id";
+
+// Like the `HistoryEngine`, this is short-lived and constructed each sync by
+// something which owns the connection.
+pub struct HistoryMetadataEngine<'a> {
+    pub db: &'a PlacesDb,
+    interruptee: &'a SqlInterruptScope,
+}
+
+impl<'a> HistoryMetadataEngine<'a> {
+    pub fn new(db: &'a PlacesDb, interruptee: &'a SqlInterruptScope) -> Self {
+        assert_eq!(db.conn_type(), ConnectionType::Sync);
+        Self { db, interruptee }
+    }
+
+    fn put_meta(&self, key: &str, value: &dyn ToSql) -> Result<()> {
+        crate::storage::put_meta(self.db, key, value)
+    }
+
+    fn get_meta<T: FromSql>(&self, key: &str) -> Result<Option<T>> {
+        crate::storage::get_meta(self.db, key)
+    }
+
+    fn do_apply_incoming(
+        &self,
+        inbound: IncomingChangeset,
+        telem: &mut telemetry::Engine,
+    ) -> Result<OutgoingChangeset> {
+        let timestamp = inbound.timestamp;
+        let outgoing = {
+            let mut incoming_telemetry = telemetry::EngineIncoming::new();
+            let result = apply_plan(self.db, inbound, &mut incoming_telemetry, self.interruptee);
+            telem.incoming(incoming_telemetry);
+            result
+        }?;
+        self.put_meta(LAST_SYNC_META_KEY, &(timestamp.as_millis() as i64))?;
+        Ok(outgoing)
+    }
+
+    fn do_sync_finished(
+        &self,
+        new_timestamp: ServerTimestamp,
+        records_synced: Vec<Guid>,
+    ) -> Result<()> {
+        log::info!(
+            "history metadata sync completed after uploading {} records",
+            records_synced.len()
+        );
+        finish_plan(self.db)?;
+        self.put_meta(LAST_SYNC_META_KEY, &(new_timestamp.as_millis() as i64))?;
+        Ok(())
+    }
+}
+
+impl<'a> SyncEngine for HistoryMetadataEngine<'a> {
+    fn collection_name(&self) -> std::borrow::Cow<'static, str> {
+        COLLECTION_NAME.into()
+    }
+
+    fn apply_incoming(
+        &self,
+        inbound: Vec<IncomingChangeset>,
+        telem: &mut telemetry::Engine,
+    ) -> anyhow::Result<OutgoingChangeset> {
+        assert_eq!(inbound.len(), 1, "history metadata only requests one item");
+        let inbound = inbound.into_iter().next().unwrap();
+        Ok(self.do_apply_incoming(inbound, telem)?)
+    }
+
+    fn sync_finished(
+        &self,
+        new_timestamp: ServerTimestamp,
+        records_synced: Vec<Guid>,
+    ) -> anyhow::Result<()> {
+        self.do_sync_finished(new_timestamp, records_synced)?;
+        Ok(())
+    }
+
+    fn get_collection_requests(
+        &self,
+        server_timestamp: ServerTimestamp,
+    ) -> anyhow::Result<Vec<CollectionRequest>> {
+        let since = ServerTimestamp(
+            self.get_meta::<i64>(LAST_SYNC_META_KEY)?
+                .unwrap_or_default(),
+        );
+        Ok(if since == server_timestamp {
+            vec![]
+        } else {
+            vec![CollectionRequest::new(COLLECTION_NAME)
+                .full()
+                .newer_than(since)
+                .limit(MAX_INCOMING_RECORDS)]
+        })
+    }
+
+    fn get_sync_assoc(&self) -> anyhow::Result<EngineSyncAssociation> {
+        let global = self.get_meta(GLOBAL_SYNCID_META_KEY)?;
+        let coll = self.get_meta(COLLECTION_SYNCID_META_KEY)?;
+        Ok(if let (Some(global), Some(coll)) = (global, coll) {
+            EngineSyncAssociation::Connected(CollSyncIds { global, coll })
+        } else {
+            EngineSyncAssociation::Disconnected
+        })
+    }
+
+    fn reset(&self, assoc: &EngineSyncAssociation) -> anyhow::Result<()> {
+        reset(self.db, assoc)?;
+        Ok(())
+    }
+
+    fn wipe(&self) -> anyhow::Result<()> {
+        delete_everything(self.db)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,22 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Syncs history metadata (`moz_places_metadata`) to its own collection, so
+//! that view times and highlights work across devices.
+//!
+//! Each record is one metadata entry. When the same entry changed on
+//! several devices, we add up the view time each of them observed, and keep
+//! the most recent `updated_at`.
+
+pub mod engine;
+mod plan;
+pub mod record;
+
+pub const COLLECTION_NAME: &str = "historymetadata";
+
+const MAX_INCOMING_RECORDS: usize = 5000;
+const MAX_OUTGOING_RECORDS: usize = 5000;
+/// How long the server keeps records, in seconds. We also don't upload or
+/// apply entries that haven't been updated for longer than this.
+pub const HISTORY_METADATA_TTL: u32 = 5_184_000; // 60 days
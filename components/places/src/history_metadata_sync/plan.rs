@@ -0,0 +1,570 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use super::record::{HistoryMetadataRecord, HistoryMetadataSyncRecord};
+use super::{COLLECTION_NAME, HISTORY_METADATA_TTL, MAX_OUTGOING_RECORDS};
+use crate::api::history::can_add_url;
+use crate::db::PlacesDb;
+use crate::error::*;
+use crate::storage::{
+    delete_pending_temp_tables,
+    history_metadata::{
+        history_metadata_sync::{
+            apply_synced_deletion, apply_synced_insertion, apply_synced_merge, fetch_local,
+            fetch_outgoing, finish_outgoing, has_tombstone, LocalMetadata, MergedMetadata,
+            OutgoingInfo,
+        },
+        DocumentType,
+    },
+};
+use interrupt_support::Interruptee;
+use std::time::Duration;
+use sync15::telemetry;
+use sync15::{IncomingChangeset, OutgoingChangeset, Payload};
+use sync_guid::Guid as SyncGuid;
+use types::Timestamp;
+use url::Url;
+
+/// The action we'll take *locally* for each incoming record.
+#[derive(Debug)]
+enum IncomingPlan {
+    /// A record we want to ignore - because of its URL, because it's expired,
+    /// or because we deleted it locally.
+    Skip,
+    /// Something's wrong with this record.
+    Invalid(Error),
+    /// The record appears sane, but there was some error.
+    Failed(Error),
+    /// We should locally delete this.
+    Delete,
+    /// We don't have this entry, so we should add it.
+    Insert(HistoryMetadataRecord, DocumentType),
+    /// We have this entry, and should merge the incoming record into it.
+    Merge(MergedMetadata),
+    /// We have this entry, and it's the same as the incoming record. We still
+    /// need to note that it's synced.
+    Reconciled(MergedMetadata),
+}
+
+fn document_type_from_u8(v: u8) -> DocumentType {
+    match v {
+        1 => DocumentType::Media,
+        _ => DocumentType::Regular,
+    }
+}
+
+/// Merges an incoming record into an entry we have locally. View time is
+/// added up: the server's total already includes everything we uploaded
+/// before, so we only add what we've observed since.
+fn merge(local: &LocalMetadata, incoming: &HistoryMetadataRecord) -> MergedMetadata {
+    let unsynced_view_time = (local.total_view_time - local.synced_view_time).max(0);
+    // If the server has an older record than one we've already seen, don't
+    // let our view time go backwards.
+    let total_view_time =
+        (incoming.total_view_time + unsynced_view_time).max(local.total_view_time);
+
+    let incoming_created_at = Timestamp(incoming.created_at);
+    let incoming_updated_at = Timestamp(incoming.updated_at);
+    let incoming_document_type = document_type_from_u8(incoming.document_type);
+    let document_type = if local.updated_at > incoming_updated_at {
+        local.document_type
+    } else {
+        incoming_document_type
+    };
+    let merged = MergedMetadata {
+        id: local.id,
+        created_at: local.created_at.min(incoming_created_at),
+        updated_at: local.updated_at.max(incoming_updated_at),
+        total_view_time,
+        document_type,
+        synced_view_time: incoming.total_view_time,
+        needs_upload: false,
+    };
+    let needs_upload = merged.total_view_time != incoming.total_view_time
+        || merged.created_at != incoming_created_at
+        || merged.updated_at != incoming_updated_at
+        || merged.document_type != incoming_document_type;
+    MergedMetadata {
+        needs_upload,
+        ..merged
+    }
+}
+
+fn plan_incoming_record(
+    db: &PlacesDb,
+    mut record: HistoryMetadataRecord,
+    expired_before: Timestamp,
+) -> IncomingPlan {
+    if !record.id.is_valid_for_places() {
+        return IncomingPlan::Invalid(InvalidPlaceInfo::InvalidGuid.into());
+    }
+    let url = match Url::parse(&record.url) {
+        Ok(u) => u,
+        Err(e) => return IncomingPlan::Invalid(e.into()),
+    };
+    match can_add_url(&url) {
+        Ok(true) => {}
+        Ok(false) => return IncomingPlan::Skip,
+        Err(e) => return IncomingPlan::Failed(e),
+    }
+    if Timestamp(record.updated_at) < expired_before {
+        return IncomingPlan::Skip;
+    }
+
+    // A bad referrer or search term isn't worth dropping the view time for.
+    record.referrer_url = record
+        .referrer_url
+        .as_deref()
+        .and_then(|referrer_url| Url::parse(referrer_url).ok())
+        .filter(|referrer_url| *referrer_url != url)
+        .map(String::from);
+    record.search_term = record.search_term.filter(|term| !term.is_empty());
+    record.url = url.into();
+
+    // If we deleted the entry locally, our tombstone wins.
+    match has_tombstone(db, &record.id) {
+        Ok(true) => return IncomingPlan::Skip,
+        Ok(false) => {}
+        Err(e) => return IncomingPlan::Failed(e),
+    }
+
+    match fetch_local(db, &record.id) {
+        Ok(Some(local)) => {
+            let merged = merge(&local, &record);
+            if !merged.needs_upload
+                && merged.total_view_time == local.total_view_time
+                && merged.updated_at == local.updated_at
+                && merged.created_at == local.created_at
+                && merged.document_type == local.document_type
+            {
+                IncomingPlan::Reconciled(merged)
+            } else {
+                IncomingPlan::Merge(merged)
+            }
+        }
+        Ok(None) => {
+            let document_type = document_type_from_u8(record.document_type);
+            IncomingPlan::Insert(record, document_type)
+        }
+        Err(e) => IncomingPlan::Failed(e),
+    }
+}
+
+pub fn apply_plan(
+    db: &PlacesDb,
+    inbound: IncomingChangeset,
+    telem: &mut telemetry::EngineIncoming,
+    interruptee: &impl Interruptee,
+) -> Result<OutgoingChangeset> {
+    let expired_before = Timestamp::now()
+        .checked_sub(Duration::from_secs(HISTORY_METADATA_TTL.into()))
+        .unwrap_or_default();
+
+    let mut plans: Vec<(SyncGuid, IncomingPlan)> = Vec::with_capacity(inbound.changes.len());
+    for incoming in inbound.changes {
+        interruptee.err_if_interrupted()?;
+        let item = match HistoryMetadataSyncRecord::from_payload(incoming.0) {
+            Ok(item) => item,
+            Err(e) => {
+                log::warn!("Error deserializing incoming record: {}", e);
+                telem.failed(1);
+                continue;
+            }
+        };
+        let plan = match item.record {
+            Some(record) => plan_incoming_record(db, record, expired_before),
+            None => IncomingPlan::Delete,
+        };
+        plans.push((item.guid, plan));
+    }
+
+    let mut tx = db.begin_transaction()?;
+    for (guid, plan) in plans {
+        interruptee.err_if_interrupted()?;
+        match plan {
+            IncomingPlan::Skip => {
+                log::trace!("incoming: skipping item {:?}", guid);
+            }
+            IncomingPlan::Invalid(err) => {
+                log::warn!(
+                    "incoming: record {:?} skipped because it is invalid: {}",
+                    guid,
+                    err
+                );
+                telem.failed(1);
+            }
+            IncomingPlan::Failed(err) => {
+                log::error!("incoming: record {:?} failed to apply: {}", guid, err);
+                telem.failed(1);
+            }
+            IncomingPlan::Delete => {
+                log::trace!("incoming: deleting {:?}", guid);
+                apply_synced_deletion(db, &guid)?;
+                telem.applied(1);
+            }
+            IncomingPlan::Insert(record, document_type) => {
+                log::trace!("incoming: inserting {:?}", guid);
+                apply_synced_insertion(&tx, &record, document_type)?;
+                telem.applied(1);
+            }
+            IncomingPlan::Merge(merged) => {
+                log::trace!("incoming: merging {:?}: {:?}", guid, merged);
+                apply_synced_merge(db, &merged)?;
+                telem.applied(1);
+            }
+            IncomingPlan::Reconciled(merged) => {
+                log::trace!("incoming: reconciled {:?}", guid);
+                apply_synced_merge(db, &merged)?;
+                telem.reconciled(1);
+            }
+        }
+        if tx.should_commit() {
+            // Inserting entries can add pages, so update their origins and
+            // frecencies before committing.
+            delete_pending_temp_tables(db)?;
+        }
+        tx.maybe_commit()?;
+    }
+    delete_pending_temp_tables(db)?;
+    tx.commit()?;
+
+    let mut outgoing = OutgoingChangeset::new(COLLECTION_NAME, inbound.timestamp);
+    let tx = db.begin_transaction()?;
+    for info in fetch_outgoing(db, MAX_OUTGOING_RECORDS, expired_before)? {
+        let payload = match info {
+            OutgoingInfo::Record(record) => {
+                Payload::from_record(record)?.with_auto_field("ttl", Some(HISTORY_METADATA_TTL))
+            }
+            OutgoingInfo::Tombstone(guid) => {
+                Payload::new_tombstone_with_ttl(guid, HISTORY_METADATA_TTL)
+            }
+        };
+        log::trace!("outgoing {:?}", payload);
+        outgoing.changes.push(payload);
+    }
+    tx.commit()?;
+
+    log::info!("incoming: {}", serde_json::to_string(&telem).unwrap());
+    Ok(outgoing)
+}
+
+pub fn finish_plan(db: &PlacesDb) -> Result<()> {
+    let tx = db.begin_transaction()?;
+    finish_outgoing(db)?;
+    tx.commit()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::places_api::ConnectionType;
+    use crate::storage::history_metadata::{
+        apply_metadata_observation, delete_metadata, get_highlights, get_latest_for_url,
+        HistoryHighlightWeights, HistoryMetadataObservation,
+    };
+    use interrupt_support::NeverInterrupts;
+    use serde_json::json;
+    use sql_support::ConnExt;
+    use sync15::ServerTimestamp;
+
+    fn observe(db: &PlacesDb, url: &str, view_time: i32) {
+        apply_metadata_observation(
+            db,
+            HistoryMetadataObservation {
+                url: url.into(),
+                view_time: Some(view_time),
+                search_term: None,
+                document_type: None,
+                referrer_url: None,
+                title: None,
+            },
+        )
+        .expect("should apply observation");
+    }
+
+    fn sync(db: &PlacesDb, records: Vec<serde_json::Value>) -> Vec<Payload> {
+        let mut incoming = IncomingChangeset::new(COLLECTION_NAME, ServerTimestamp(0i64));
+        for record in records {
+            let payload = Payload::from_json(record).expect("should be a valid payload");
+            incoming.changes.push((payload, ServerTimestamp(0i64)));
+        }
+        let outgoing = apply_plan(
+            db,
+            incoming,
+            &mut telemetry::EngineIncoming::new(),
+            &NeverInterrupts,
+        )
+        .expect("should apply plan");
+        finish_plan(db).expect("should finish plan");
+        outgoing.changes
+    }
+
+    fn local_guid(db: &PlacesDb) -> SyncGuid {
+        db.query_row_and_then_named(
+            "SELECT guid FROM moz_places_metadata",
+            &[],
+            |row| row.get(0),
+            false,
+        )
+        .expect("should have an entry")
+    }
+
+    fn total_view_time(db: &PlacesDb, url: &str) -> i32 {
+        get_latest_for_url(db, &Url::parse(url).unwrap())
+            .expect("should fetch metadata")
+            .expect("should have metadata")
+            .total_view_time
+    }
+
+    #[test]
+    fn test_merge() {
+        let local = LocalMetadata {
+            id: 1,
+            created_at: Timestamp(1000),
+            updated_at: Timestamp(5000),
+            total_view_time: 300,
+            synced_view_time: 200,
+            document_type: DocumentType::Media,
+        };
+        let mut incoming = HistoryMetadataRecord {
+            id: SyncGuid::random(),
+            url: "https://example.com/".into(),
+            title: None,
+            referrer_url: None,
+            search_term: None,
+            document_type: 0,
+            total_view_time: 250,
+            created_at: 1000,
+            updated_at: 4000,
+        };
+
+        // We observed 100ms since we last synced, and another device observed
+        // 50ms, so we should have both.
+        let merged = merge(&local, &incoming);
+        assert_eq!(merged.total_view_time, 350);
+        assert_eq!(merged.synced_view_time, 250);
+        assert_eq!(merged.updated_at, Timestamp(5000));
+        assert_eq!(merged.document_type, DocumentType::Media);
+        assert!(merged.needs_upload);
+
+        // Nothing changed locally since we last synced, so we just take the
+        // incoming record.
+        let local = LocalMetadata {
+            total_view_time: 200,
+            updated_at: Timestamp(3000),
+            ..local
+        };
+        let merged = merge(&local, &incoming);
+        assert_eq!(merged.total_view_time, 250);
+        assert_eq!(merged.updated_at, Timestamp(4000));
+        assert_eq!(merged.document_type, DocumentType::Regular);
+        assert!(!merged.needs_upload);
+
+        // A stale record shouldn't make our view time go backwards.
+        incoming.total_view_time = 100;
+        let merged = merge(&local, &incoming);
+        assert_eq!(merged.total_view_time, 200);
+        assert!(merged.needs_upload);
+    }
+
+    #[test]
+    fn test_upload_and_merge() -> Result<()> {
+        let _ = env_logger::try_init();
+        let db = PlacesDb::open_in_memory(ConnectionType::Sync)?;
+        let url = "https://example.com/";
+        observe(&db, url, 1000);
+
+        let outgoing = sync(&db, vec![]);
+        assert_eq!(outgoing.len(), 1);
+        let guid = local_guid(&db);
+        assert_eq!(outgoing[0].id, guid);
+        assert_eq!(outgoing[0].data["url"], url);
+        assert_eq!(outgoing[0].data["totalViewTime"], 1000);
+        assert_eq!(outgoing[0].data["ttl"], HISTORY_METADATA_TTL);
+
+        // Nothing changed, so nothing to upload.
+        assert!(sync(&db, vec![]).is_empty());
+
+        // We observe another 500ms, while another device observes 2000ms.
+        observe(&db, url, 500);
+        assert_eq!(total_view_time(&db, url), 1500);
+        let now = Timestamp::now().as_millis();
+        let outgoing = sync(
+            &db,
+            vec![json!({
+                "id": guid,
+                "url": url,
+                "documentType": 0,
+                "totalViewTime": 3000,
+                "createdAt": now - 1000,
+                "updatedAt": now + 1000,
+            })],
+        );
+        assert_eq!(total_view_time(&db, url), 3500);
+        assert_eq!(outgoing.len(), 1);
+        assert_eq!(outgoing[0].data["totalViewTime"], 3500);
+        assert_eq!(outgoing[0].data["updatedAt"], now + 1000);
+
+        // Our own record coming back shouldn't change anything.
+        let outgoing = sync(
+            &db,
+            vec![json!({
+                "id": guid,
+                "url": url,
+                "totalViewTime": 3500,
+                "createdAt": now - 1000,
+                "updatedAt": now + 1000,
+            })],
+        );
+        assert!(outgoing.is_empty());
+        assert_eq!(total_view_time(&db, url), 3500);
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_new() -> Result<()> {
+        let _ = env_logger::try_init();
+        let db = PlacesDb::open_in_memory(ConnectionType::Sync)?;
+        let now = Timestamp::now().as_millis();
+        let outgoing = sync(
+            &db,
+            vec![
+                json!({
+                    "id": "aaaaaaaaaaaa",
+                    "url": "https://example.com",
+                    "title": "Example",
+                    "referrerUrl": "https://www.mozilla.org/",
+                    "searchTerm": "example",
+                    "documentType": 1,
+                    "totalViewTime": 3000,
+                    "createdAt": now,
+                    "updatedAt": now,
+                }),
+                // Expired.
+                json!({
+                    "id": "bbbbbbbbbbbb",
+                    "url": "https://example.org",
+                    "totalViewTime": 3000,
+                    "createdAt": 1000,
+                    "updatedAt": 1000,
+                }),
+                // Invalid URL.
+                json!({
+                    "id": "cccccccccccc",
+                    "url": "not a url",
+                    "totalViewTime": 3000,
+                    "createdAt": now,
+                    "updatedAt": now,
+                }),
+            ],
+        );
+        assert!(outgoing.is_empty());
+
+        let metadata = get_latest_for_url(&db, &Url::parse("https://example.com")?)?
+            .expect("should have metadata");
+        assert_eq!(metadata.title.as_deref(), Some("Example"));
+        assert_eq!(
+            metadata.referrer_url.as_deref(),
+            Some("https://www.mozilla.org/")
+        );
+        assert_eq!(metadata.search_term.as_deref(), Some("example"));
+        assert_eq!(metadata.document_type, DocumentType::Media);
+        assert_eq!(metadata.total_view_time, 3000);
+        assert!(get_latest_for_url(&db, &Url::parse("https://example.org")?)?.is_none());
+
+        // Synced metadata counts towards highlights.
+        let highlights = get_highlights(
+            &db,
+            HistoryHighlightWeights {
+                view_time: 1.0,
+                frequency: 1.0,
+            },
+            10,
+        )?;
+        assert_eq!(highlights.len(), 1);
+        assert_eq!(highlights[0].url, "https://example.com/");
+        Ok(())
+    }
+
+    #[test]
+    fn test_deletions() -> Result<()> {
+        let _ = env_logger::try_init();
+        let db = PlacesDb::open_in_memory(ConnectionType::Sync)?;
+
+        // Entries we haven't uploaded don't need tombstones.
+        observe(&db, "https://example.com/", 1000);
+        delete_metadata(&db, "https://example.com/", None, None)?;
+        assert!(sync(&db, vec![]).is_empty());
+
+        observe(&db, "https://example.com/", 1000);
+        sync(&db, vec![]);
+        let guid = local_guid(&db);
+        delete_metadata(&db, "https://example.com/", None, None)?;
+
+        // Our tombstone wins over an incoming change.
+        let now = Timestamp::now().as_millis();
+        let outgoing = sync(
+            &db,
+            vec![json!({
+                "id": guid,
+                "url": "https://example.com/",
+                "totalViewTime": 5000,
+                "createdAt": now,
+                "updatedAt": now,
+            })],
+        );
+        assert_eq!(outgoing.len(), 1);
+        assert_eq!(outgoing[0].id, guid);
+        assert!(outgoing[0].is_tombstone());
+        assert!(get_latest_for_url(&db, &Url::parse("https://example.com/")?)?.is_none());
+
+        // Incoming tombstones delete local entries.
+        observe(&db, "https://example.org/", 1000);
+        sync(&db, vec![]);
+        let guid = local_guid(&db);
+        let outgoing = sync(&db, vec![json!({ "id": guid, "deleted": true })]);
+        assert!(outgoing.is_empty());
+        assert!(get_latest_for_url(&db, &Url::parse("https://example.org/")?)?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_keeps_tombstones_written_during_sync() -> Result<()> {
+        let _ = env_logger::try_init();
+        let db = PlacesDb::open_in_memory(ConnectionType::Sync)?;
+
+        observe(&db, "https://example.com/", 1000);
+        sync(&db, vec![]);
+        let uploaded_guid = local_guid(&db);
+        delete_metadata(&db, "https://example.com/", None, None)?;
+
+        let outgoing = apply_plan(
+            &db,
+            IncomingChangeset::new(COLLECTION_NAME, ServerTimestamp(0i64)),
+            &mut telemetry::EngineIncoming::new(),
+            &NeverInterrupts,
+        )?;
+        assert_eq!(outgoing.changes.len(), 1);
+        assert_eq!(outgoing.changes[0].id, uploaded_guid);
+
+        // A tombstone written after we fetched outgoing records wasn't
+        // uploaded, so it should still be there for the next sync.
+        db.execute_named_cached(
+            "INSERT INTO moz_places_metadata_tombstones(guid) VALUES (:guid)",
+            &[(":guid", &"deletedguid1")],
+        )?;
+        finish_plan(&db)?;
+
+        assert!(!has_tombstone(&db, &uploaded_guid)?);
+        assert!(has_tombstone(&db, &SyncGuid::from("deletedguid1"))?);
+
+        let outgoing = sync(&db, vec![]);
+        assert_eq!(outgoing.len(), 1);
+        assert_eq!(outgoing[0].id, "deletedguid1");
+        assert!(outgoing[0].is_tombstone());
+        assert!(!has_tombstone(&db, &SyncGuid::from("deletedguid1"))?);
+        Ok(())
+    }
+}
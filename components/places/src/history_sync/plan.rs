@@ -295,7 +295,7 @@ pub fn finish_plan(db: &PlacesDb) -> Result<()> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::api::matcher::{search_frecent, SearchParams};
+    use crate::api::matcher::{search_frecent, SearchMode, SearchParams};
     use crate::api::places_api::ConnectionType;
     use crate::db::PlacesDb;
     use crate::history_sync::ServerVisitTimestamp;
@@ -788,6 +788,7 @@ mod tests {
             SearchParams {
                 search_string: "http://example.com".into(),
                 limit: 2,
+                mode: SearchMode::Scan,
             },
         )?;
         assert_eq!(found.len(), 1);
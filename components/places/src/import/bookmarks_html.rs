@@ -0,0 +1,990 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Import and export of bookmarks in the Netscape bookmark file format - the
+//! `bookmarks.html` file understood by Desktop Firefox and pretty much every
+//! other browser.
+//!
+//! The format isn't really HTML, and isn't well specified - it's whatever
+//! Netscape Navigator happened to write, and every browser since then has
+//! added its own attributes. We follow what Desktop's `BookmarkHTMLUtils`
+//! does:
+//!
+//! - Folders are `<DT><H3>` elements followed by a `<DL>` holding their
+//!   children. The toolbar and "Other Bookmarks" folders are marked with
+//!   `PERSONAL_TOOLBAR_FOLDER` and `UNFILED_BOOKMARKS_FOLDER` attributes;
+//!   everything else at the top level belongs to the menu.
+//! - Bookmarks are `<DT><A>` elements, with the tags in `TAGS` and the keyword
+//!   in `SHORTCUTURL`.
+//! - Separators are `<HR>` elements.
+//! - `ADD_DATE` and `LAST_MODIFIED` are in seconds since the epoch.
+//!
+//! Both directions work incrementally on `Read`/`Write` streams, so the file
+//! itself is never held in memory. The import parser is deliberately lenient,
+//! since files written by other browsers (and hand-edited ones) are often
+//! missing closing tags.
+
+use crate::db::PlacesDb;
+use crate::error::*;
+use crate::import::fennec::bookmarks::BookmarksMigrationResult;
+use crate::storage::bookmarks::{
+    bookmarks_get_keyword_for_url, fetch_tree, insert_tree_in_tx, BookmarkNode, BookmarkRootGuid,
+    BookmarkTreeNode, FetchDepth, FolderNode, SeparatorNode, USER_CONTENT_ROOTS,
+};
+use crate::storage::keywords::set_keyword_in_tx;
+use crate::storage::tags::{get_tags_for_url, tag_url_in_tx, validate_tag, ValidatedTag};
+use crate::storage::{delete_pending_temp_tables, URL_LENGTH_MAX};
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+use std::time::Instant;
+use types::Timestamp;
+use url::Url;
+
+const TOOLBAR_FOLDER_ATTR: &str = "PERSONAL_TOOLBAR_FOLDER";
+const UNFILED_FOLDER_ATTR: &str = "UNFILED_BOOKMARKS_FOLDER";
+// Not understood by Desktop, which will import the mobile root as a regular
+// folder, but it lets us round-trip our own exports.
+const MOBILE_FOLDER_ATTR: &str = "MOBILE_BOOKMARKS_FOLDER";
+
+/// The roots we export after the menu contents, along with the attribute that
+/// marks them and the title we use for them.
+const SPECIAL_ROOTS: [(BookmarkRootGuid, &str, &str); 3] = [
+    (
+        BookmarkRootGuid::Toolbar,
+        TOOLBAR_FOLDER_ATTR,
+        "Bookmarks Toolbar",
+    ),
+    (
+        BookmarkRootGuid::Unfiled,
+        UNFILED_FOLDER_ATTR,
+        "Other Bookmarks",
+    ),
+    (
+        BookmarkRootGuid::Mobile,
+        MOBILE_FOLDER_ATTR,
+        "Mobile Bookmarks",
+    ),
+];
+
+/// Import bookmarks from a Netscape bookmark file, appending them to the
+/// existing roots. Tags and keywords are applied to the imported URLs. The
+/// import happens in a single transaction, so either everything that could be
+/// parsed is imported or nothing is.
+///
+/// Items we can't import (bookmarks with invalid URLs, for example) are
+/// skipped and counted in `num_failed`.
+pub fn import_html_bookmarks(
+    db: &PlacesDb,
+    reader: impl BufRead,
+) -> Result<BookmarksMigrationResult> {
+    let import_start = Instant::now();
+    let scope = db.begin_interrupt_scope();
+
+    log::debug!("Parsing bookmarks file");
+    let mut parser = HtmlParser::new();
+    let mut tokenizer = Tokenizer::new(reader);
+    while let Some(token) = tokenizer.next_token()? {
+        parser.process(token);
+        scope.err_if_interrupted()?;
+    }
+    let parsed = parser.finish();
+
+    let tx = db.begin_transaction()?;
+    for root in &parsed.roots {
+        if !root.children.is_empty() {
+            log::debug!("Inserting bookmarks into {:?}", root.guid);
+            insert_tree_in_tx(db, root)?;
+            scope.err_if_interrupted()?;
+        }
+    }
+
+    log::debug!("Applying tags and keywords");
+    for annotations in &parsed.annotations {
+        for tag in &annotations.tags {
+            match validate_tag(tag) {
+                ValidatedTag::Invalid(_) => log::warn!("Ignoring invalid tag"),
+                ValidatedTag::Normalized(t) | ValidatedTag::Original(t) => {
+                    tag_url_in_tx(db, &annotations.url, t)?
+                }
+            }
+        }
+        if let Some(keyword) = &annotations.keyword {
+            match set_keyword_in_tx(db, &annotations.url, keyword, None) {
+                Ok(()) => {}
+                // Keywords that are invalid, or already used for a different
+                // URL, are skipped, like invalid tags.
+                Err(e) if matches!(e.kind(), ErrorKind::InvalidPlaceInfo(_)) => {
+                    log::warn!("Ignoring keyword: {}", e)
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        scope.err_if_interrupted()?;
+    }
+    delete_pending_temp_tables(db)?;
+    tx.commit()?;
+
+    let metrics = BookmarksMigrationResult {
+        num_total: parsed.num_total,
+        num_succeeded: parsed.num_total - parsed.num_failed,
+        num_failed: parsed.num_failed,
+        total_duration: import_start.elapsed().as_millis(),
+    };
+    log::info!("Successfully imported bookmarks: {:?}", metrics);
+    Ok(metrics)
+}
+
+/// Export all bookmarks as a Netscape bookmark file that Desktop (and other
+/// browsers) can import. Returns the number of items written, not counting
+/// the roots.
+pub fn export_html_bookmarks(db: &PlacesDb, writer: impl Write) -> Result<u32> {
+    let scope = db.begin_interrupt_scope();
+    let mut exporter = HtmlExporter {
+        db,
+        writer,
+        num_exported: 0,
+    };
+    exporter.write_header()?;
+
+    // Like Desktop, the menu contents live directly in the top-level list,
+    // and the other roots are folders inside it.
+    writeln!(exporter.writer, "<DL><p>")?;
+    if let Some((BookmarkTreeNode::Folder(menu), _, _)) =
+        fetch_tree(db, &BookmarkRootGuid::Menu.into(), &FetchDepth::Deepest)?
+    {
+        exporter.write_children(&menu, 1)?;
+    }
+    for &(root, attr, default_title) in &SPECIAL_ROOTS {
+        scope.err_if_interrupted()?;
+        if let Some((BookmarkTreeNode::Folder(folder), _, _)) =
+            fetch_tree(db, &root.as_guid(), &FetchDepth::Deepest)?
+        {
+            if folder.children.is_empty() {
+                continue;
+            }
+            exporter.write_folder(&folder, 1, Some((attr, default_title)))?;
+        }
+    }
+    writeln!(exporter.writer, "</DL>")?;
+    exporter.writer.flush()?;
+    Ok(exporter.num_exported)
+}
+
+struct HtmlExporter<'a, W> {
+    db: &'a PlacesDb,
+    writer: W,
+    num_exported: u32,
+}
+
+impl<'a, W: Write> HtmlExporter<'a, W> {
+    fn write_header(&mut self) -> Result<()> {
+        write!(
+            self.writer,
+            "<!DOCTYPE NETSCAPE-Bookmark-file-1>\n\
+             <!-- This is an automatically generated file.\n     \
+             It will be read and overwritten.\n     \
+             DO NOT EDIT! -->\n\
+             <META HTTP-EQUIV=\"Content-Type\" CONTENT=\"text/html; charset=UTF-8\">\n\
+             <TITLE>Bookmarks</TITLE>\n\
+             <H1>Bookmarks Menu</H1>\n\n"
+        )?;
+        Ok(())
+    }
+
+    fn write_children(&mut self, folder: &FolderNode, depth: usize) -> Result<()> {
+        for child in &folder.children {
+            match child {
+                BookmarkTreeNode::Bookmark(b) => self.write_bookmark(b, depth)?,
+                BookmarkTreeNode::Separator(_) => {
+                    writeln!(self.writer, "{:indent$}<HR>", "", indent = depth * 4)?;
+                    self.num_exported += 1;
+                }
+                BookmarkTreeNode::Folder(f) => self.write_folder(f, depth, None)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn write_folder(
+        &mut self,
+        folder: &FolderNode,
+        depth: usize,
+        special_root: Option<(&str, &str)>,
+    ) -> Result<()> {
+        let indent = depth * 4;
+        write!(self.writer, "{:indent$}<DT><H3", "", indent = indent)?;
+        // The dates of our roots don't mean anything to whoever imports them,
+        // since they'll be merged into their own roots.
+        let title = match special_root {
+            Some((attr, default_title)) => {
+                write!(self.writer, " {}=\"true\"", attr)?;
+                default_title
+            }
+            None => {
+                self.write_dates(folder.date_added, folder.last_modified)?;
+                self.num_exported += 1;
+                folder.title.as_deref().unwrap_or("")
+            }
+        };
+        writeln!(self.writer, ">{}</H3>", escape_html(title))?;
+        writeln!(self.writer, "{:indent$}<DL><p>", "", indent = indent)?;
+        self.write_children(folder, depth + 1)?;
+        writeln!(self.writer, "{:indent$}</DL><p>", "", indent = indent)?;
+        Ok(())
+    }
+
+    fn write_bookmark(&mut self, bookmark: &BookmarkNode, depth: usize) -> Result<()> {
+        write!(
+            self.writer,
+            "{:indent$}<DT><A HREF=\"{}\"",
+            "",
+            escape_html(bookmark.url.as_str()),
+            indent = depth * 4
+        )?;
+        self.write_dates(bookmark.date_added, bookmark.last_modified)?;
+        if let Some(keyword) = bookmarks_get_keyword_for_url(self.db, &bookmark.url)? {
+            write!(self.writer, " SHORTCUTURL=\"{}\"", escape_html(&keyword))?;
+        }
+        let mut tags = get_tags_for_url(self.db, &bookmark.url)?;
+        if !tags.is_empty() {
+            tags.sort();
+            write!(self.writer, " TAGS=\"{}\"", escape_html(&tags.join(",")))?;
+        }
+        writeln!(
+            self.writer,
+            ">{}</A>",
+            escape_html(bookmark.title.as_deref().unwrap_or(""))
+        )?;
+        self.num_exported += 1;
+        Ok(())
+    }
+
+    fn write_dates(
+        &mut self,
+        date_added: Option<Timestamp>,
+        last_modified: Option<Timestamp>,
+    ) -> Result<()> {
+        if let Some(date_added) = date_added {
+            write!(
+                self.writer,
+                " ADD_DATE=\"{}\"",
+                date_added.as_millis() / 1000
+            )?;
+        }
+        if let Some(last_modified) = last_modified {
+            write!(
+                self.writer,
+                " LAST_MODIFIED=\"{}\"",
+                last_modified.as_millis() / 1000
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn escape_html(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn unescape_html(s: &str) -> String {
+    if !s.contains('&') {
+        return s.to_string();
+    }
+    let mut unescaped = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(amp) = rest.find('&') {
+        unescaped.push_str(&rest[..amp]);
+        rest = &rest[amp..];
+        // Entities we understand are all short, so don't go looking for a
+        // `;` that's miles away.
+        let decoded = rest.find(';').filter(|&end| end <= 10).and_then(|end| {
+            let entity = &rest[1..end];
+            let c = match entity {
+                "amp" => Some('&'),
+                "lt" => Some('<'),
+                "gt" => Some('>'),
+                "quot" => Some('"'),
+                "apos" => Some('\''),
+                _ if entity.starts_with("#x") || entity.starts_with("#X") => {
+                    u32::from_str_radix(&entity[2..], 16)
+                        .ok()
+                        .and_then(std::char::from_u32)
+                }
+                _ if entity.starts_with('#') => entity[1..]
+                    .parse::<u32>()
+                    .ok()
+                    .and_then(std::char::from_u32),
+                _ => None,
+            };
+            c.map(|c| (c, end))
+        });
+        match decoded {
+            Some((c, end)) => {
+                unescaped.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                unescaped.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    unescaped.push_str(rest);
+    unescaped
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Start {
+        name: String,
+        attrs: HashMap<String, String>,
+    },
+    End(String),
+    Text(String),
+}
+
+/// A minimal, streaming tokenizer for the subset of HTML found in bookmark
+/// files. Tag and attribute names are upper-cased; comments, doctypes and
+/// processing instructions are skipped.
+struct Tokenizer<R> {
+    reader: R,
+    buf: Vec<u8>,
+    in_tag: bool,
+}
+
+impl<R: BufRead> Tokenizer<R> {
+    fn new(reader: R) -> Self {
+        Self {
+            reader,
+            buf: Vec::new(),
+            in_tag: false,
+        }
+    }
+
+    fn next_token(&mut self) -> Result<Option<Token>> {
+        loop {
+            if self.in_tag {
+                self.in_tag = false;
+                match self.read_tag()? {
+                    TagResult::Token(token) => return Ok(Some(token)),
+                    TagResult::Skipped => continue,
+                    TagResult::Eof => return Ok(None),
+                }
+            }
+            self.buf.clear();
+            if self.reader.read_until(b'<', &mut self.buf)? == 0 {
+                return Ok(None);
+            }
+            if self.buf.last() == Some(&b'<') {
+                self.buf.pop();
+                self.in_tag = true;
+            }
+            let text = String::from_utf8_lossy(&self.buf);
+            if !text.trim().is_empty() {
+                return Ok(Some(Token::Text(unescape_html(&text))));
+            }
+        }
+    }
+
+    fn read_tag(&mut self) -> Result<TagResult> {
+        self.buf.clear();
+        loop {
+            if self.reader.read_until(b'>', &mut self.buf)? == 0 || self.buf.last() != Some(&b'>') {
+                // A truncated tag at the end of the file.
+                return Ok(TagResult::Eof);
+            }
+            // Keep going if the `>` we found is inside a comment, or inside a
+            // quoted attribute value.
+            let complete = if self.buf.starts_with(b"!--") {
+                self.buf.ends_with(b"-->")
+            } else {
+                self.buf.iter().filter(|&&b| b == b'"').count() % 2 == 0
+            };
+            if complete {
+                break;
+            }
+        }
+        self.buf.pop();
+        let tag = String::from_utf8_lossy(&self.buf);
+        Ok(match parse_tag(&tag) {
+            Some(token) => TagResult::Token(token),
+            None => TagResult::Skipped,
+        })
+    }
+}
+
+enum TagResult {
+    Token(Token),
+    Skipped,
+    Eof,
+}
+
+fn parse_tag(tag: &str) -> Option<Token> {
+    let tag = tag.trim();
+    if tag.starts_with('!') || tag.starts_with('?') {
+        return None;
+    }
+    if let Some(name) = tag.strip_prefix('/') {
+        return Some(Token::End(name.trim().to_ascii_uppercase()));
+    }
+    let name_end = tag
+        .find(|c: char| c.is_whitespace() || c == '/')
+        .unwrap_or_else(|| tag.len());
+    Some(Token::Start {
+        name: tag[..name_end].to_ascii_uppercase(),
+        attrs: parse_attributes(&tag[name_end..]),
+    })
+}
+
+fn parse_attributes(s: &str) -> HashMap<String, String> {
+    let mut attrs = HashMap::new();
+    let mut rest = s.trim_start();
+    while !rest.is_empty() {
+        let name_end = rest
+            .find(|c: char| c == '=' || c.is_whitespace())
+            .unwrap_or_else(|| rest.len());
+        let name = rest[..name_end].to_ascii_uppercase();
+        rest = rest[name_end..].trim_start();
+        let value = match rest.strip_prefix('=') {
+            Some(after_eq) => {
+                let after_eq = after_eq.trim_start();
+                let (value, remainder) = match after_eq.chars().next() {
+                    Some(quote @ '"') | Some(quote @ '\'') => {
+                        let quoted = &after_eq[1..];
+                        match quoted.find(quote) {
+                            Some(end) => (&quoted[..end], &quoted[end + 1..]),
+                            None => (quoted, ""),
+                        }
+                    }
+                    _ => {
+                        let end = after_eq
+                            .find(char::is_whitespace)
+                            .unwrap_or_else(|| after_eq.len());
+                        (&after_eq[..end], &after_eq[end..])
+                    }
+                };
+                rest = remainder.trim_start();
+                unescape_html(value)
+            }
+            None => String::new(),
+        };
+        if !name.is_empty() && name != "/" {
+            attrs.insert(name, value);
+        }
+    }
+    attrs
+}
+
+fn parse_date(attrs: &HashMap<String, String>, name: &str) -> Option<Timestamp> {
+    attrs
+        .get(name)
+        .and_then(|v| v.trim().parse::<u64>().ok())
+        .filter(|&secs| secs > 0)
+        .map(|secs| Timestamp(secs.saturating_mul(1000)))
+}
+
+/// Tags and keywords aren't part of our bookmark tree, so we collect them
+/// separately and apply them once the tree has been inserted.
+struct UrlAnnotations {
+    url: Url,
+    tags: Vec<String>,
+    keyword: Option<String>,
+}
+
+struct ParsedBookmarks {
+    /// The menu, toolbar, unfiled and mobile roots, with their children, in
+    /// `USER_CONTENT_ROOTS` order.
+    roots: Vec<FolderNode>,
+    annotations: Vec<UrlAnnotations>,
+    num_total: u32,
+    num_failed: u32,
+}
+
+struct Frame {
+    folder: FolderNode,
+    /// Set if this folder's children should be moved into one of our roots.
+    root: Option<BookmarkRootGuid>,
+}
+
+enum PendingItem {
+    /// A `<H3>` we've seen, but whose `<DL>` we haven't.
+    Folder {
+        folder: FolderNode,
+        root: Option<BookmarkRootGuid>,
+    },
+    /// An `<A>` whose title we're collecting.
+    Bookmark {
+        href: String,
+        date_added: Option<Timestamp>,
+        last_modified: Option<Timestamp>,
+        tags: Vec<String>,
+        keyword: Option<String>,
+    },
+}
+
+struct HtmlParser {
+    stack: Vec<Frame>,
+    pending: Option<PendingItem>,
+    collecting_title: bool,
+    title: String,
+    roots: Vec<FolderNode>,
+    annotations: Vec<UrlAnnotations>,
+    num_total: u32,
+    num_failed: u32,
+}
+
+impl HtmlParser {
+    fn new() -> Self {
+        let roots = USER_CONTENT_ROOTS
+            .iter()
+            .map(|root| FolderNode {
+                guid: Some(root.as_guid()),
+                ..Default::default()
+            })
+            .collect();
+        Self {
+            stack: Vec::new(),
+            pending: None,
+            collecting_title: false,
+            title: String::new(),
+            roots,
+            annotations: Vec::new(),
+            num_total: 0,
+            num_failed: 0,
+        }
+    }
+
+    fn process(&mut self, token: Token) {
+        match token {
+            Token::Start { name, attrs } => match name.as_str() {
+                "H3" => {
+                    self.flush_pending_folder();
+                    // Only folders directly in the top-level list can be
+                    // roots.
+                    let root = if self.stack.len() <= 1 {
+                        if attrs.contains_key(TOOLBAR_FOLDER_ATTR) {
+                            Some(BookmarkRootGuid::Toolbar)
+                        } else if attrs.contains_key(UNFILED_FOLDER_ATTR) {
+                            Some(BookmarkRootGuid::Unfiled)
+                        } else if attrs.contains_key(MOBILE_FOLDER_ATTR) {
+                            Some(BookmarkRootGuid::Mobile)
+                        } else {
+                            None
+                        }
+                    } else {
+                        None
+                    };
+                    self.pending = Some(PendingItem::Folder {
+                        folder: FolderNode {
+                            date_added: parse_date(&attrs, "ADD_DATE"),
+                            last_modified: parse_date(&attrs, "LAST_MODIFIED"),
+                            ..Default::default()
+                        },
+                        root,
+                    });
+                    self.start_title();
+                }
+                "A" => {
+                    self.flush_pending_folder();
+                    let tags = attrs
+                        .get("TAGS")
+                        .map(|tags| {
+                            tags.split(',')
+                                .map(str::trim)
+                                .filter(|t| !t.is_empty())
+                                .map(ToString::to_string)
+                                .collect()
+                        })
+                        .unwrap_or_default();
+                    let keyword = attrs
+                        .get("SHORTCUTURL")
+                        .map(|k| k.trim().to_lowercase())
+                        .filter(|k| !k.is_empty());
+                    self.pending = Some(PendingItem::Bookmark {
+                        href: attrs.get("HREF").cloned().unwrap_or_default(),
+                        date_added: parse_date(&attrs, "ADD_DATE"),
+                        last_modified: parse_date(&attrs, "LAST_MODIFIED"),
+                        tags,
+                        keyword,
+                    });
+                    self.start_title();
+                }
+                "HR" => {
+                    self.flush_pending_folder();
+                    self.num_total += 1;
+                    self.current_folder()
+                        .children
+                        .push(SeparatorNode::default().into());
+                }
+                "DL" => {
+                    let frame = match self.pending.take() {
+                        Some(PendingItem::Folder { folder, root }) => Frame { folder, root },
+                        pending => {
+                            self.pending = pending;
+                            if self.stack.is_empty() {
+                                // The top-level list, which is the menu.
+                                Frame {
+                                    folder: FolderNode::default(),
+                                    root: Some(BookmarkRootGuid::Menu),
+                                }
+                            } else {
+                                // A list without a heading; treat it as an
+                                // untitled folder.
+                                Frame {
+                                    folder: FolderNode::default(),
+                                    root: None,
+                                }
+                            }
+                        }
+                    };
+                    self.stack.push(frame);
+                }
+                _ => {}
+            },
+            Token::End(name) => match name.as_str() {
+                "H3" => {
+                    if let Some(PendingItem::Folder { folder, .. }) = &mut self.pending {
+                        folder.title =
+                            Some(self.title.trim().to_string()).filter(|title| !title.is_empty());
+                    }
+                    self.collecting_title = false;
+                }
+                "A" => self.finish_bookmark(),
+                "DL" => {
+                    self.flush_pending_folder();
+                    self.pop_frame();
+                }
+                _ => {}
+            },
+            Token::Text(text) => {
+                if self.collecting_title {
+                    self.title.push_str(&text);
+                }
+            }
+        }
+    }
+
+    fn finish(mut self) -> ParsedBookmarks {
+        // Be forgiving about missing closing tags at the end of the file.
+        self.finish_bookmark();
+        self.flush_pending_folder();
+        while !self.stack.is_empty() {
+            self.pop_frame();
+        }
+        ParsedBookmarks {
+            roots: self.roots,
+            annotations: self.annotations,
+            num_total: self.num_total,
+            num_failed: self.num_failed,
+        }
+    }
+
+    fn start_title(&mut self) {
+        self.title.clear();
+        self.collecting_title = true;
+    }
+
+    fn current_folder(&mut self) -> &mut FolderNode {
+        if self.stack.is_empty() {
+            self.stack.push(Frame {
+                folder: FolderNode::default(),
+                root: Some(BookmarkRootGuid::Menu),
+            });
+        }
+        &mut self.stack.last_mut().unwrap().folder
+    }
+
+    /// Adds a folder without a `<DL>` as an empty folder.
+    fn flush_pending_folder(&mut self) {
+        if let Some(PendingItem::Folder { folder, root }) = self.pending.take() {
+            self.collecting_title = false;
+            if root.is_none() {
+                self.num_total += 1;
+                self.current_folder().children.push(folder.into());
+            }
+        }
+    }
+
+    fn pop_frame(&mut self) {
+        let frame = match self.stack.pop() {
+            Some(frame) => frame,
+            None => return,
+        };
+        match frame.root {
+            Some(root) => {
+                if let Some(index) = USER_CONTENT_ROOTS.iter().position(|r| *r == root) {
+                    self.roots[index].children.extend(frame.folder.children);
+                }
+            }
+            None => {
+                self.num_total += 1;
+                self.current_folder().children.push(frame.folder.into());
+            }
+        }
+    }
+
+    fn finish_bookmark(&mut self) {
+        let (href, date_added, last_modified, tags, keyword) = match self.pending.take() {
+            Some(PendingItem::Bookmark {
+                href,
+                date_added,
+                last_modified,
+                tags,
+                keyword,
+            }) => (href, date_added, last_modified, tags, keyword),
+            pending => {
+                self.pending = pending;
+                return;
+            }
+        };
+        self.collecting_title = false;
+        self.num_total += 1;
+        let url = match Url::parse(href.trim()) {
+            Ok(url) if url.as_str().len() <= URL_LENGTH_MAX => url,
+            Ok(_) => {
+                log::warn!("Ignoring bookmark with a URL that's too long");
+                self.num_failed += 1;
+                return;
+            }
+            Err(e) => {
+                log::warn!("Ignoring bookmark with invalid URL: {:?}", e);
+                self.num_failed += 1;
+                return;
+            }
+        };
+        if !tags.is_empty() || keyword.is_some() {
+            self.annotations.push(UrlAnnotations {
+                url: url.clone(),
+                tags,
+                keyword,
+            });
+        }
+        let title = Some(self.title.trim().to_string()).filter(|title| !title.is_empty());
+        self.current_folder().children.push(
+            BookmarkNode {
+                guid: None,
+                date_added,
+                last_modified,
+                title,
+                url,
+            }
+            .into(),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::places_api::test::new_mem_connection;
+    use crate::storage::bookmarks::bookmarks_get_url_for_keyword;
+    use std::io::Cursor;
+
+    const CHROMIUM_EXPORT: &str = r#"<!DOCTYPE NETSCAPE-Bookmark-file-1>
+<!-- This is an automatically generated file.
+     It will be read and overwritten.
+     DO NOT EDIT! -->
+<META HTTP-EQUIV="Content-Type" CONTENT="text/html; charset=UTF-8">
+<TITLE>Bookmarks</TITLE>
+<H1>Bookmarks</H1>
+<DL><p>
+    <DT><H3 ADD_DATE="1600000000" LAST_MODIFIED="1600000100" PERSONAL_TOOLBAR_FOLDER="true">Bookmarks bar</H3>
+    <DL><p>
+        <DT><A HREF="https://example.com/?a=1&amp;b=2" ADD_DATE="1600000050">Example &amp; co</A>
+        <DT><H3 ADD_DATE="1600000001">Nested</H3>
+        <DL><p>
+            <DT><A HREF="https://www.mozilla.org/" SHORTCUTURL="Moz" TAGS="web,browsers">Mozilla</A>
+            <HR>
+            <DT><A HREF="not a url">Broken</A>
+        </DL><p>
+    </DL><p>
+    <DT><A HREF="https://menu.example.com/">In the menu</A>
+</DL><p>
+"#;
+
+    fn get_folder(db: &PlacesDb, root: BookmarkRootGuid) -> FolderNode {
+        match fetch_tree(db, &root.as_guid(), &FetchDepth::Deepest).expect("should work") {
+            Some((BookmarkTreeNode::Folder(f), _, _)) => f,
+            _ => panic!("root should be a folder"),
+        }
+    }
+
+    #[test]
+    fn test_unescape() {
+        assert_eq!(unescape_html("a &amp; b"), "a & b");
+        assert_eq!(unescape_html("&lt;&#62;&#x26;&quot;"), "<>&\"");
+        assert_eq!(unescape_html("fish & chips"), "fish & chips");
+        assert_eq!(unescape_html("&bogus;"), "&bogus;");
+        assert_eq!(
+            escape_html("<a href=\"&\">"),
+            "&lt;a href=&quot;&amp;&quot;&gt;"
+        );
+    }
+
+    #[test]
+    fn test_tokenizer() -> Result<()> {
+        let html = "<!-- a > comment --><DT><a href=\"x>y\" ADD_DATE=1 private>Title</A>";
+        let mut tokenizer = Tokenizer::new(Cursor::new(html));
+        assert_eq!(
+            tokenizer.next_token()?,
+            Some(Token::Start {
+                name: "DT".into(),
+                attrs: HashMap::new()
+            })
+        );
+        match tokenizer.next_token()? {
+            Some(Token::Start { name, attrs }) => {
+                assert_eq!(name, "A");
+                assert_eq!(attrs["HREF"], "x>y");
+                assert_eq!(attrs["ADD_DATE"], "1");
+                assert_eq!(attrs["PRIVATE"], "");
+            }
+            t => panic!("unexpected token {:?}", t),
+        }
+        assert_eq!(tokenizer.next_token()?, Some(Token::Text("Title".into())));
+        assert_eq!(tokenizer.next_token()?, Some(Token::End("A".into())));
+        assert_eq!(tokenizer.next_token()?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_import() -> Result<()> {
+        let conn = new_mem_connection();
+        let result = import_html_bookmarks(&conn, Cursor::new(CHROMIUM_EXPORT))?;
+        assert_eq!(result.num_total, 6);
+        assert_eq!(result.num_succeeded, 5);
+        assert_eq!(result.num_failed, 1);
+
+        let toolbar = get_folder(&conn, BookmarkRootGuid::Toolbar);
+        assert_eq!(toolbar.children.len(), 2);
+        match &toolbar.children[0] {
+            BookmarkTreeNode::Bookmark(b) => {
+                assert_eq!(b.url.as_str(), "https://example.com/?a=1&b=2");
+                assert_eq!(b.title.as_deref(), Some("Example & co"));
+                assert_eq!(b.date_added, Some(Timestamp(1_600_000_050_000)));
+            }
+            n => panic!("expected a bookmark, got {:?}", n),
+        }
+        let nested = match &toolbar.children[1] {
+            BookmarkTreeNode::Folder(f) => f,
+            n => panic!("expected a folder, got {:?}", n),
+        };
+        assert_eq!(nested.title.as_deref(), Some("Nested"));
+        assert_eq!(nested.date_added, Some(Timestamp(1_600_000_001_000)));
+        assert_eq!(nested.children.len(), 2);
+        assert!(matches!(nested.children[1], BookmarkTreeNode::Separator(_)));
+
+        let menu = get_folder(&conn, BookmarkRootGuid::Menu);
+        assert_eq!(menu.children.len(), 1);
+
+        let mozilla = Url::parse("https://www.mozilla.org/")?;
+        let mut tags = get_tags_for_url(&conn, &mozilla)?;
+        tags.sort();
+        assert_eq!(tags, vec!["browsers".to_string(), "web".to_string()]);
+        assert_eq!(bookmarks_get_url_for_keyword(&conn, "moz")?, Some(mozilla));
+        Ok(())
+    }
+
+    fn foreign_count(db: &PlacesDb, url: &str) -> Result<i64> {
+        Ok(db.query_row_and_then_named(
+            "SELECT foreign_count FROM moz_places
+             WHERE url_hash = hash(:url) AND url = :url",
+            &[(":url", &url)],
+            |row| row.get(0),
+            false,
+        )?)
+    }
+
+    #[test]
+    fn test_import_keywords_foreign_count() -> Result<()> {
+        let conn = new_mem_connection();
+        import_html_bookmarks(
+            &conn,
+            Cursor::new(r#"<DT><A HREF="https://example.com/" SHORTCUTURL="ex">Ex</A>"#),
+        )?;
+        assert_eq!(foreign_count(&conn, "https://example.com/")?, 2);
+
+        // Replacing the keyword for a URL, and reusing the old keyword for
+        // another URL, should keep `foreign_count` in sync with the number of
+        // bookmarks and keywords for each URL.
+        import_html_bookmarks(
+            &conn,
+            Cursor::new(
+                r#"<DT><A HREF="https://example.com/" SHORTCUTURL="Other">Ex</A>
+                <DT><A HREF="https://example.org/" SHORTCUTURL="ex">Org</A>
+                <DT><A HREF="https://example.net/" SHORTCUTURL="other">Net</A>"#,
+            ),
+        )?;
+        assert_eq!(foreign_count(&conn, "https://example.com/")?, 3);
+        assert_eq!(foreign_count(&conn, "https://example.org/")?, 2);
+        // "other" is already used for example.com, so this one is skipped.
+        assert_eq!(foreign_count(&conn, "https://example.net/")?, 1);
+        assert_eq!(
+            bookmarks_get_url_for_keyword(&conn, "other")?,
+            Some(Url::parse("https://example.com/")?)
+        );
+        assert_eq!(
+            bookmarks_get_url_for_keyword(&conn, "ex")?,
+            Some(Url::parse("https://example.org/")?)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_round_trip() -> Result<()> {
+        let conn = new_mem_connection();
+        import_html_bookmarks(&conn, Cursor::new(CHROMIUM_EXPORT))?;
+
+        let mut exported = Vec::new();
+        let num_exported = export_html_bookmarks(&conn, &mut exported)?;
+        assert_eq!(num_exported, 5);
+        let html = String::from_utf8(exported).expect("should be utf-8");
+        assert!(html.contains("PERSONAL_TOOLBAR_FOLDER=\"true\""));
+        assert!(html.contains("SHORTCUTURL=\"moz\" TAGS=\"browsers,web\""));
+        assert!(!html.contains(UNFILED_FOLDER_ATTR));
+
+        // Importing our export into an empty database and exporting again
+        // should give us exactly the same file.
+        let conn2 = new_mem_connection();
+        let result = import_html_bookmarks(&conn2, Cursor::new(html.clone()))?;
+        assert_eq!(result.num_total, 5);
+        assert_eq!(result.num_failed, 0);
+        let mut reexported = Vec::new();
+        export_html_bookmarks(&conn2, &mut reexported)?;
+        assert_eq!(
+            String::from_utf8(reexported).expect("should be utf-8"),
+            html
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_import_malformed() -> Result<()> {
+        let conn = new_mem_connection();
+        // No `<DL>` at all, an empty folder without a list, and no closing
+        // tags.
+        let html = "<DT><H3>Empty</H3><DT><A HREF=\"https://example.com/\">Example";
+        let result = import_html_bookmarks(&conn, Cursor::new(html))?;
+        assert_eq!(result.num_total, 2);
+        assert_eq!(result.num_failed, 0);
+        let menu = get_folder(&conn, BookmarkRootGuid::Menu);
+        assert_eq!(menu.children.len(), 2);
+        assert!(matches!(menu.children[0], BookmarkTreeNode::Folder(_)));
+        match &menu.children[1] {
+            BookmarkTreeNode::Bookmark(b) => assert_eq!(b.title.as_deref(), Some("Example")),
+            n => panic!("expected a bookmark, got {:?}", n),
+        }
+        Ok(())
+    }
+}
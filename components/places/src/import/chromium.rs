@@ -0,0 +1,21 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Imports history and bookmarks from Chromium-family browsers (Chrome, Edge,
+//! Brave, Opera, Vivaldi...), which all use the same profile format:
+//!
+//! - `History` is an SQLite database with `urls` and `visits` tables.
+//! - `Bookmarks` is a JSON file with a tree under each of its roots.
+//!
+//! Chromium stores times as microseconds since 1601-01-01 UTC (the Windows
+//! `FILETIME` epoch). Chromium keeps `History` locked while it's running, so
+//! callers should import from a copy.
+
+pub mod bookmarks;
+pub mod history;
+pub use bookmarks::import as import_bookmarks;
+pub use history::import as import_history;
+
+/// Milliseconds between the Windows epoch and the Unix epoch.
+const WINDOWS_EPOCH_OFFSET_MS: i64 = 11_644_473_600_000;
@@ -0,0 +1,281 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use super::WINDOWS_EPOCH_OFFSET_MS;
+use crate::api::places_api::PlacesApi;
+use crate::error::*;
+use crate::import::common::{insert_bookmark_roots, sanitize_timestamp, validate_url};
+use crate::import::fennec::bookmarks::BookmarksMigrationResult;
+use crate::storage::bookmarks::{BookmarkNode, BookmarkRootGuid, BookmarkTreeNode, FolderNode};
+use serde_derive::*;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::time::Instant;
+use types::Timestamp;
+
+#[derive(Deserialize)]
+struct ChromiumBookmarks {
+    roots: ChromiumRoots,
+}
+
+/// Chromium's roots. `other` is "Other bookmarks", and `synced` is "Mobile
+/// bookmarks".
+#[derive(Deserialize)]
+struct ChromiumRoots {
+    bookmark_bar: Option<ChromiumNode>,
+    other: Option<ChromiumNode>,
+    synced: Option<ChromiumNode>,
+}
+
+#[derive(Deserialize)]
+struct ChromiumNode {
+    #[serde(rename = "type")]
+    node_type: String,
+    #[serde(default)]
+    name: String,
+    url: Option<String>,
+    // Times are strings holding microseconds since the Windows epoch.
+    date_added: Option<String>,
+    date_modified: Option<String>,
+    #[serde(default)]
+    children: Vec<ChromiumNode>,
+}
+
+/// Imports bookmarks from a Chromium `Bookmarks` file, appending them to our
+/// roots: the bookmarks bar goes to the toolbar, "Other bookmarks" to
+/// unfiled, and "Mobile bookmarks" to mobile. The import happens in a single
+/// transaction.
+///
+/// Items we can't import (bookmarks with invalid URLs, for example) are
+/// skipped and counted in `num_failed`.
+pub fn import(
+    places_api: &PlacesApi,
+    path: impl AsRef<std::path::Path>,
+) -> Result<BookmarksMigrationResult> {
+    let file = File::open(path)?;
+    do_import(places_api, BufReader::new(file))
+}
+
+fn do_import(places_api: &PlacesApi, reader: impl Read) -> Result<BookmarksMigrationResult> {
+    let import_start = Instant::now();
+    log::debug!("Parsing Chromium bookmarks");
+    let bookmarks: ChromiumBookmarks = serde_json::from_reader(reader)?;
+
+    let mut converter = Converter::default();
+    let roots = [
+        (BookmarkRootGuid::Toolbar, &bookmarks.roots.bookmark_bar),
+        (BookmarkRootGuid::Unfiled, &bookmarks.roots.other),
+        (BookmarkRootGuid::Mobile, &bookmarks.roots.synced),
+    ]
+    .iter()
+    .map(|(guid, root)| FolderNode {
+        guid: Some(guid.as_guid()),
+        children: root
+            .as_ref()
+            .map(|root| converter.convert_children(root))
+            .unwrap_or_default(),
+        ..Default::default()
+    })
+    .collect::<Vec<_>>();
+
+    let conn = places_api.open_sync_connection()?;
+    insert_bookmark_roots(&conn, &roots)?;
+
+    let metrics = BookmarksMigrationResult {
+        num_total: converter.num_total,
+        num_succeeded: converter.num_total - converter.num_failed,
+        num_failed: converter.num_failed,
+        total_duration: import_start.elapsed().as_millis(),
+    };
+    log::info!("Successfully imported bookmarks: {:?}", metrics);
+    Ok(metrics)
+}
+
+fn chromium_time(time: &Option<String>) -> Timestamp {
+    sanitize_timestamp(
+        time.as_ref()
+            .and_then(|t| t.parse::<i64>().ok())
+            .map(|t| t / 1000 - WINDOWS_EPOCH_OFFSET_MS),
+    )
+}
+
+#[derive(Default)]
+struct Converter {
+    num_total: u32,
+    num_failed: u32,
+}
+
+impl Converter {
+    fn convert_children(&mut self, folder: &ChromiumNode) -> Vec<BookmarkTreeNode> {
+        folder
+            .children
+            .iter()
+            .filter_map(|child| self.convert(child))
+            .collect()
+    }
+
+    fn convert(&mut self, node: &ChromiumNode) -> Option<BookmarkTreeNode> {
+        self.num_total += 1;
+        let date_added = chromium_time(&node.date_added);
+        let title = Some(node.name.clone()).filter(|name| !name.is_empty());
+        match node.node_type.as_str() {
+            "url" => match node.url.as_deref().and_then(validate_url) {
+                Some(url) => Some(
+                    BookmarkNode {
+                        guid: None,
+                        date_added: Some(date_added),
+                        last_modified: Some(date_added),
+                        title,
+                        url,
+                    }
+                    .into(),
+                ),
+                None => {
+                    self.num_failed += 1;
+                    None
+                }
+            },
+            "folder" => {
+                // Chromium updates `date_modified` when a folder's children
+                // change, and sets it to 0 if they never have.
+                let last_modified = match &node.date_modified {
+                    Some(modified) if modified != "0" => chromium_time(&node.date_modified),
+                    _ => date_added,
+                };
+                Some(
+                    FolderNode {
+                        date_added: Some(date_added),
+                        last_modified: Some(last_modified.max(date_added)),
+                        title,
+                        children: self.convert_children(node),
+                        ..Default::default()
+                    }
+                    .into(),
+                )
+            }
+            _ => {
+                self.num_failed += 1;
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::places_api::test::new_mem_api;
+    use crate::api::places_api::ConnectionType;
+    use crate::storage::bookmarks::{fetch_tree, FetchDepth};
+    use pretty_assertions::assert_eq;
+    use url::Url;
+
+    // 2021-09-01T00:00:00Z, as Chromium stores it.
+    const DATE_ADDED: &str = "13274928000000000";
+
+    #[test]
+    fn test_import() -> Result<()> {
+        let api = new_mem_api();
+        let json = format!(
+            r#"{{
+                "checksum": "ignored",
+                "roots": {{
+                    "bookmark_bar": {{
+                        "children": [{{
+                            "date_added": "{date_added}",
+                            "name": "Example",
+                            "type": "url",
+                            "url": "https://www.example.com/"
+                        }}, {{
+                            "children": [{{
+                                "date_added": "{date_added}",
+                                "name": "Invalid",
+                                "type": "url",
+                                "url": "not a url"
+                            }}, {{
+                                "date_added": "{date_added}",
+                                "name": "Mozilla",
+                                "type": "url",
+                                "url": "https://www.mozilla.org/"
+                            }}],
+                            "date_added": "{date_added}",
+                            "date_modified": "0",
+                            "name": "Folder",
+                            "type": "folder"
+                        }}],
+                        "date_added": "{date_added}",
+                        "name": "Bookmarks bar",
+                        "type": "folder"
+                    }},
+                    "other": {{
+                        "children": [],
+                        "name": "Other bookmarks",
+                        "type": "folder"
+                    }},
+                    "synced": {{
+                        "children": [{{
+                            "name": "",
+                            "type": "url",
+                            "url": "https://mobile.example.com/"
+                        }}],
+                        "name": "Mobile bookmarks",
+                        "type": "folder"
+                    }}
+                }},
+                "version": 1
+            }}"#,
+            date_added = DATE_ADDED
+        );
+        let metrics = do_import(&api, json.as_bytes())?;
+        assert_eq!(metrics.num_total, 5);
+        assert_eq!(metrics.num_succeeded, 4);
+        assert_eq!(metrics.num_failed, 1);
+
+        let conn = api.open_connection(ConnectionType::ReadOnly)?;
+        let (toolbar, _, _) = fetch_tree(
+            &conn,
+            &BookmarkRootGuid::Toolbar.into(),
+            &FetchDepth::Deepest,
+        )?
+        .expect("should have the toolbar");
+        let toolbar = match toolbar {
+            BookmarkTreeNode::Folder(f) => f,
+            _ => panic!("the toolbar should be a folder"),
+        };
+        assert_eq!(toolbar.children.len(), 2);
+        match &toolbar.children[0] {
+            BookmarkTreeNode::Bookmark(b) => {
+                assert_eq!(b.url, Url::parse("https://www.example.com/")?);
+                assert_eq!(b.title.as_deref(), Some("Example"));
+                assert_eq!(b.date_added, Some(Timestamp(1_630_454_400_000)));
+            }
+            n => panic!("Unexpected node: {:?}", n),
+        }
+        match &toolbar.children[1] {
+            BookmarkTreeNode::Folder(f) => {
+                assert_eq!(f.title.as_deref(), Some("Folder"));
+                assert_eq!(f.children.len(), 1);
+            }
+            n => panic!("Unexpected node: {:?}", n),
+        }
+
+        let (mobile, _, _) = fetch_tree(
+            &conn,
+            &BookmarkRootGuid::Mobile.into(),
+            &FetchDepth::Deepest,
+        )?
+        .expect("should have the mobile root");
+        match mobile {
+            BookmarkTreeNode::Folder(f) => {
+                assert_eq!(f.children.len(), 1);
+                assert_eq!(
+                    f.children[0].node_type(),
+                    crate::types::BookmarkType::Bookmark
+                );
+            }
+            n => panic!("Unexpected node: {:?}", n),
+        }
+        Ok(())
+    }
+}
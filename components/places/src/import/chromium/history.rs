@@ -0,0 +1,257 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use super::WINDOWS_EPOCH_OFFSET_MS;
+use crate::api::places_api::PlacesApi;
+use crate::bookmark_sync::engine::BookmarksEngine;
+use crate::error::*;
+use crate::import::common::attached_database;
+use crate::import::fennec::history::{select_count, HistoryMigrationResult};
+use crate::types::VisitTransition;
+use rusqlite::{Connection, NO_PARAMS};
+use sql_support::ConnExt;
+use std::time::Instant;
+use url::Url;
+
+// The parts of the `urls` and `visits` tables we use haven't changed since
+// well before this version, which shipped in 2011.
+const CHROMIUM_DB_VERSION: i64 = 20;
+
+// Chromium's page transitions. The low byte is the "core" type, and the rest
+// are qualifiers. See `ui/base/page_transition_types.h`.
+const CORE_MASK: i64 = 0xFF;
+const TYPED: i64 = 1;
+const AUTO_BOOKMARK: i64 = 2;
+const AUTO_SUBFRAME: i64 = 3;
+const MANUAL_SUBFRAME: i64 = 4;
+const GENERATED: i64 = 5;
+const RELOAD: i64 = 8;
+const KEYWORD: i64 = 9;
+const KEYWORD_GENERATED: i64 = 10;
+const SERVER_REDIRECT: i64 = 0x8000_0000;
+
+/// Maps a Chromium page transition to one of ours, or `None` for visits we
+/// don't store, which are automatic loads in subframes.
+fn visit_type_for_transition(transition: i64) -> Option<VisitTransition> {
+    if transition & SERVER_REDIRECT != 0 {
+        // Chromium doesn't record whether the redirect was permanent.
+        return Some(VisitTransition::RedirectTemporary);
+    }
+    Some(match transition & CORE_MASK {
+        AUTO_SUBFRAME => return None,
+        TYPED | GENERATED | KEYWORD | KEYWORD_GENERATED => VisitTransition::Typed,
+        AUTO_BOOKMARK => VisitTransition::Bookmark,
+        MANUAL_SUBFRAME => VisitTransition::FramedLink,
+        RELOAD => VisitTransition::Reload,
+        // Links, top-level navigations from extensions, form submissions...
+        _ => VisitTransition::Link,
+    })
+}
+
+/// Imports history from a Chromium `History` database. Visits are added to
+/// existing history, and visits we already have are skipped.
+pub fn import(
+    places_api: &PlacesApi,
+    path: impl AsRef<std::path::Path>,
+) -> Result<HistoryMigrationResult> {
+    let url = crate::util::ensure_url_path(path)?;
+    do_import(places_api, url)
+}
+
+fn do_import(places_api: &PlacesApi, chromium_db_file_url: Url) -> Result<HistoryMigrationResult> {
+    let conn = places_api.open_sync_connection()?;
+
+    let scope = conn.begin_interrupt_scope();
+
+    define_sql_functions(&conn)?;
+
+    let import_start = Instant::now();
+    log::trace!("Attaching database {}", chromium_db_file_url);
+    let auto_detach = attached_database(&conn, &chromium_db_file_url, "chromium")?;
+
+    let db_version = conn
+        .try_query_one::<i64>(
+            "SELECT CAST(value AS INTEGER) FROM chromium.meta WHERE key = 'version'",
+            &[],
+            false,
+        )
+        .ok()
+        .flatten()
+        .unwrap_or(0);
+    if db_version < CHROMIUM_DB_VERSION {
+        return Err(ErrorKind::UnsupportedDatabaseVersion(db_version).into());
+    }
+
+    let tx = conn.begin_transaction()?;
+
+    log::debug!("Counting Chromium history visits");
+    let num_total = select_count(&conn, &COUNT_CHROMIUM_HISTORY_VISITS);
+
+    log::debug!("Creating and populating staging table");
+    conn.execute_batch(&CREATE_STAGING_TABLE)?;
+    conn.execute_batch(&FILL_STAGING)?;
+    scope.err_if_interrupted()?;
+
+    log::debug!("Populating missing entries in moz_places");
+    conn.execute_batch(&FILL_MOZ_PLACES)?;
+    scope.err_if_interrupted()?;
+
+    log::debug!("Inserting the history visits");
+    let num_succeeded = conn.execute(&INSERT_HISTORY_VISITS, NO_PARAMS)? as u32;
+    scope.err_if_interrupted()?;
+
+    log::debug!("Committing...");
+    tx.commit()?;
+
+    // Note: update_frecencies manages its own transaction, which is fine,
+    // since nothing that bad will happen if it is aborted.
+    log::debug!("Updating frecencies");
+    let engine = BookmarksEngine::new(&conn, &scope);
+    engine.update_frecencies()?;
+
+    auto_detach.execute_now()?;
+
+    let metrics = HistoryMigrationResult {
+        num_total,
+        num_succeeded,
+        // Visits we already had are counted as failures.
+        num_failed: num_total.saturating_sub(num_succeeded),
+        total_duration: import_start.elapsed().as_millis(),
+    };
+    log::info!("Successfully imported history visits: {:?}", metrics);
+
+    Ok(metrics)
+}
+
+lazy_static::lazy_static! {
+    // As with Fennec, the staging table lets us normalize URLs. It's keyed by
+    // Chromium's `urls.id`, which is what `visits.url` refers to.
+    static ref CREATE_STAGING_TABLE: &'static str = "
+        CREATE TEMP TABLE temp.chromiumHistoryStaging(
+            id INTEGER PRIMARY KEY,
+            url TEXT NOT NULL,
+            url_hash INTEGER NOT NULL,
+            title TEXT
+        )"
+    ;
+
+    static ref FILL_STAGING: &'static str = "
+        INSERT OR IGNORE INTO temp.chromiumHistoryStaging(id, url, url_hash, title)
+            SELECT id, url, hash(url), title
+            FROM (SELECT u.id, validate_url(u.url) AS url, sanitize_utf8(u.title) AS title
+                  FROM chromium.urls u)
+            WHERE url IS NOT NULL"
+    ;
+
+    static ref FILL_MOZ_PLACES: &'static str =
+        "INSERT OR IGNORE INTO main.moz_places(guid, url, url_hash, title, frecency, sync_change_counter)
+            SELECT
+                IFNULL(
+                    (SELECT p.guid FROM main.moz_places p WHERE p.url_hash = t.url_hash AND p.url = t.url),
+                    generate_guid()
+                ),
+                t.url,
+                t.url_hash,
+                NULLIF(t.title, ''),
+                -1,
+                1
+            FROM temp.chromiumHistoryStaging t"
+    ;
+
+    // Chromium records where each visit came from in `from_visit`, but we
+    // don't rebuild redirect chains yet. Visits synced from other devices are
+    // in `visit_source`, which older databases don't have, so everything is
+    // imported as a local visit. Visits we already have for a page at the
+    // same time are skipped, so importing twice doesn't duplicate them.
+    static ref INSERT_HISTORY_VISITS: String = format!(
+        "INSERT INTO main.moz_historyvisits(from_visit, place_id, visit_date, visit_type, is_local)
+            SELECT NULL, n.place_id, n.visit_date, n.visit_type, 1
+            FROM (SELECT
+                      p.id AS place_id,
+                      sanitize_timestamp(v.visit_time / 1000 - {epoch_offset}) AS visit_date,
+                      chromium_visit_type(v.transition) AS visit_type
+                  FROM chromium.visits v
+                  JOIN temp.chromiumHistoryStaging t ON t.id = v.url
+                  JOIN main.moz_places p ON p.url_hash = t.url_hash AND p.url = t.url
+                  WHERE (v.transition & {core_mask}) != {auto_subframe}) n
+            WHERE NOT EXISTS(SELECT 1 FROM main.moz_historyvisits e
+                             WHERE e.place_id = n.place_id AND e.visit_date = n.visit_date)",
+        epoch_offset = WINDOWS_EPOCH_OFFSET_MS,
+        core_mask = CORE_MASK,
+        auto_subframe = AUTO_SUBFRAME,
+    );
+
+    // We don't import automatic subframe loads, so they aren't counted.
+    static ref COUNT_CHROMIUM_HISTORY_VISITS: String = format!(
+        "SELECT COUNT(*) FROM chromium.visits v
+         WHERE (v.transition & {core_mask}) != {auto_subframe}",
+        core_mask = CORE_MASK,
+        auto_subframe = AUTO_SUBFRAME,
+    );
+}
+
+fn define_sql_functions(c: &Connection) -> Result<()> {
+    use rusqlite::functions::FunctionFlags;
+    c.create_scalar_function(
+        "validate_url",
+        1,
+        FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+        crate::import::common::sql_fns::validate_url,
+    )?;
+    c.create_scalar_function(
+        "sanitize_timestamp",
+        1,
+        FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+        crate::import::common::sql_fns::sanitize_timestamp,
+    )?;
+    c.create_scalar_function(
+        "sanitize_utf8",
+        1,
+        FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+        crate::import::common::sql_fns::sanitize_utf8,
+    )?;
+    c.create_scalar_function(
+        "chromium_visit_type",
+        1,
+        FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+        |ctx| -> rusqlite::Result<Option<VisitTransition>> {
+            Ok(visit_type_for_transition(ctx.get::<i64>(0)?))
+        },
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_visit_type_for_transition() {
+        assert_eq!(visit_type_for_transition(0), Some(VisitTransition::Link));
+        // A typed URL from the address bar, which is the start and end of
+        // its redirect chain.
+        assert_eq!(
+            visit_type_for_transition(0x3200_0001),
+            Some(VisitTransition::Typed)
+        );
+        assert_eq!(
+            visit_type_for_transition(0x2000_0000 | SERVER_REDIRECT),
+            Some(VisitTransition::RedirectTemporary)
+        );
+        assert_eq!(
+            visit_type_for_transition(AUTO_BOOKMARK),
+            Some(VisitTransition::Bookmark)
+        );
+        assert_eq!(visit_type_for_transition(AUTO_SUBFRAME), None);
+        assert_eq!(
+            visit_type_for_transition(MANUAL_SUBFRAME),
+            Some(VisitTransition::FramedLink)
+        );
+        assert_eq!(
+            visit_type_for_transition(RELOAD),
+            Some(VisitTransition::Reload)
+        );
+        assert_eq!(visit_type_for_transition(7), Some(VisitTransition::Link));
+    }
+}
@@ -3,8 +3,13 @@
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
 use crate::api::places_api::SyncConn;
+use crate::db::PlacesDb;
 use crate::error::*;
+use crate::storage::annotations::DESCRIPTION_ANNO;
+use crate::storage::bookmarks::{insert_tree_in_tx, FolderNode};
+use crate::storage::{delete_pending_temp_tables, URL_LENGTH_MAX};
 use rusqlite::named_params;
+use std::convert::TryFrom;
 use types::Timestamp;
 use url::Url;
 
@@ -20,30 +25,43 @@ lazy_static::lazy_static! {
     pub static ref NOW: Timestamp = Timestamp::now();
 }
 
+/// Returns `ts` if it's a sane timestamp in milliseconds or microseconds, or
+/// `NOW` otherwise. This is what the `sanitize_timestamp` SQL function does,
+/// for importers that don't go through SQL.
+pub fn sanitize_timestamp(ts: Option<i64>) -> Timestamp {
+    let now = *NOW;
+    let is_sane = |ts: Timestamp| -> bool { Timestamp::EARLIEST <= ts && ts <= now };
+    if let Some(ts) = ts {
+        let ts = Timestamp(u64::try_from(ts).unwrap_or(0));
+        if is_sane(ts) {
+            return ts;
+        }
+        // Maybe the timestamp was actually in μs?
+        let ts = Timestamp(ts.as_millis() / 1000);
+        if is_sane(ts) {
+            return ts;
+        }
+    }
+    now
+}
+
+/// Returns `href` as a URL we'd store, or `None` if it's invalid or too long.
+/// This is what the `validate_url` SQL function does, for importers that don't
+/// go through SQL.
+pub fn validate_url(href: &str) -> Option<Url> {
+    if href.len() > URL_LENGTH_MAX {
+        return None;
+    }
+    Url::parse(href).ok()
+}
+
 pub mod sql_fns {
-    use crate::import::common::NOW;
-    use crate::storage::URL_LENGTH_MAX;
     use rusqlite::{functions::Context, types::ValueRef, Result};
-    use std::convert::TryFrom;
     use types::Timestamp;
-    use url::Url;
 
     #[inline(never)]
     pub fn sanitize_timestamp(ctx: &Context<'_>) -> Result<Timestamp> {
-        let now = *NOW;
-        let is_sane = |ts: Timestamp| -> bool { Timestamp::EARLIEST <= ts && ts <= now };
-        if let Ok(ts) = ctx.get::<i64>(0) {
-            let ts = Timestamp(u64::try_from(ts).unwrap_or(0));
-            if is_sane(ts) {
-                return Ok(ts);
-            }
-            // Maybe the timestamp was actually in μs?
-            let ts = Timestamp(ts.as_millis() / 1000);
-            if is_sane(ts) {
-                return Ok(ts);
-            }
-        }
-        Ok(now)
+        Ok(super::sanitize_timestamp(ctx.get::<i64>(0).ok()))
     }
 
     // Possibly better named as "normalize URL" - even in non-error cases, the
@@ -56,14 +74,7 @@ pub mod sql_fns {
         } else {
             return Ok(None);
         };
-        if href.len() > URL_LENGTH_MAX {
-            return Ok(None);
-        }
-        if let Ok(url) = Url::parse(&href) {
-            Ok(Some(url.into()))
-        } else {
-            Ok(None)
-        }
+        Ok(super::validate_url(&href).map(String::from))
     }
 
     // Sanitize a text column into valid utf-8. Leave NULLs alone, but all other
@@ -79,6 +90,52 @@ pub mod sql_fns {
     }
 }
 
+/// Imports bookmark descriptions from `staging_table` as item annotations.
+/// The table needs `guid`, `description`, `date_added` and `modified`
+/// columns, and this must run after merging, once the bookmarks exist.
+pub fn import_bookmark_descriptions(conn: &PlacesDb, staging_table: &str) -> Result<()> {
+    conn.execute_named(
+        "INSERT OR IGNORE INTO main.moz_anno_attributes(name) VALUES(:name)",
+        named_params! { ":name": DESCRIPTION_ANNO },
+    )?;
+    // `moz_items_annos` defaults to a string that never expires.
+    conn.execute_named(
+        &format!(
+            "INSERT OR REPLACE INTO main.moz_items_annos(item_id, anno_attribute_id, content,
+                                                         dateAdded, lastModified)
+             SELECT b.id,
+                    (SELECT id FROM main.moz_anno_attributes WHERE name = :name),
+                    stage.description,
+                    stage.date_added,
+                    stage.modified
+             FROM {staging_table} stage
+             JOIN main.moz_bookmarks b ON b.guid = stage.guid
+             WHERE stage.description IS NOT NULL AND stage.description != ''",
+            staging_table = staging_table
+        ),
+        named_params! { ":name": DESCRIPTION_ANNO },
+    )?;
+    Ok(())
+}
+
+/// Appends the children of each of `roots` to the root with the same guid,
+/// in a single transaction. This is how importers that build the tree in
+/// Rust, rather than staging it in the mirror, write it out.
+pub fn insert_bookmark_roots(db: &PlacesDb, roots: &[FolderNode]) -> Result<()> {
+    let scope = db.begin_interrupt_scope();
+    let tx = db.begin_transaction()?;
+    for root in roots {
+        if !root.children.is_empty() {
+            log::debug!("Inserting bookmarks into {:?}", root.guid);
+            insert_tree_in_tx(db, root)?;
+            scope.err_if_interrupted()?;
+        }
+    }
+    delete_pending_temp_tables(db)?;
+    tx.commit()?;
+    Ok(())
+}
+
 pub fn attached_database<'a>(
     conn: &'a SyncConn<'a>,
     path: &Url,
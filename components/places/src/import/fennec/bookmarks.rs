@@ -9,7 +9,7 @@ use crate::bookmark_sync::{
 };
 use crate::db::db::PlacesDb;
 use crate::error::*;
-use crate::import::common::{attached_database, ExecuteOnDrop};
+use crate::import::common::{attached_database, import_bookmark_descriptions, ExecuteOnDrop};
 use crate::storage::bookmarks::{bookmark_sync::create_synced_bookmark_roots, PublicNode};
 use crate::types::{BookmarkType, SyncStatus};
 use rusqlite::NO_PARAMS;
@@ -127,6 +127,9 @@ fn do_import(places_api: &PlacesApi, fennec_db_file_url: Url) -> Result<Bookmark
     log::debug!("Fixing up bookmarks");
     conn.execute_batch(&FIXUP_MOZ_BOOKMARKS)?;
     scope.err_if_interrupted()?;
+    log::debug!("Importing descriptions");
+    import_bookmark_descriptions(&conn, "temp.fennecBookmarksStaging")?;
+    scope.err_if_interrupted()?;
     log::debug!("Cleaning up mirror...");
     clear_mirror_on_drop.execute_now()?;
     log::debug!("Committing...");
@@ -300,6 +303,7 @@ lazy_static::lazy_static! {
             bmkUri,
             keyword,
             tags,
+            description,
             date_added,
             modified,
             isLocal
@@ -315,8 +319,11 @@ lazy_static::lazy_static! {
                     THEN validate_url(b.url)
                 ELSE NULL
             END as uri,
-            sanitize_utf8(b.keyword),
+            -- Keywords are case-insensitive, and can't have leading or
+            -- trailing whitespace.
+            NULLIF(lower(trim(sanitize_utf8(b.keyword))), ''),
             sanitize_utf8(b.tags),
+            sanitize_utf8(b.description),
             -- See above for notes about 'date_added' and 'modified'
             CASE
                 WHEN b.tags IS NOT NULL OR
@@ -375,6 +382,7 @@ lazy_static::lazy_static! {
                 CHECK(type != {fennec_bookmark_type} OR validate_url(bmkUri) == bmkUri),
             keyword TEXT,
             tags TEXT,
+            description TEXT,
             date_added INTEGER NOT NULL,
             modified INTEGER NOT NULL,
             isLocal TINYINT NOT NULL
@@ -33,6 +33,16 @@ pub fn import(
     do_import(places_api, url)
 }
 
+fn fennec_table_exists(conn: &PlacesDb, name: &str) -> Result<bool> {
+    Ok(conn
+        .try_query_one::<i64>(
+            "SELECT 1 FROM fennec.sqlite_master WHERE type = 'table' AND name = :name",
+            &[(":name", &name)],
+            false,
+        )?
+        .is_some())
+}
+
 pub fn select_count(conn: &PlacesDb, stmt: &str) -> u32 {
     let count: Result<Option<u32>> =
         conn.try_query_row(stmt, &[], |row| Ok(row.get::<_, u32>(0)?), false);
@@ -77,6 +87,13 @@ fn do_import(places_api: &PlacesApi, android_db_file_url: Url) -> Result<History
     conn.execute_batch(&INSERT_HISTORY_VISITS)?;
     scope.err_if_interrupted()?;
 
+    // Older Fennec databases don't have URL annotations.
+    if fennec_table_exists(&conn, "urlannotations")? {
+        log::debug!("Importing URL annotations");
+        conn.execute_batch(&IMPORT_URL_ANNOTATIONS)?;
+        scope.err_if_interrupted()?;
+    }
+
     log::debug!("Committing...");
     tx.commit()?;
 
@@ -161,6 +178,29 @@ lazy_static::lazy_static! {
             LEFT JOIN temp.fennecHistoryStaging t on v.history_guid = t.guid"
     ;
 
+    // Fennec's URL annotations become page annotations, prefixed with
+    // `fennec/` so they don't clash with ours. We only keep annotations for
+    // pages we know about.
+    static ref IMPORT_URL_ANNOTATIONS: &'static str =
+        "INSERT OR IGNORE INTO main.moz_anno_attributes(name)
+            SELECT DISTINCT 'fennec/' || sanitize_utf8(a.key)
+            FROM fennec.urlannotations a
+            WHERE a.key IS NOT NULL AND a.key != '';
+
+        INSERT OR REPLACE INTO main.moz_annos(place_id, anno_attribute_id, content,
+                                              dateAdded, lastModified)
+            SELECT
+                p.id,
+                n.id,
+                sanitize_utf8(a.value),
+                sanitize_timestamp(a.created),
+                max(sanitize_timestamp(a.created), sanitize_timestamp(a.modified))
+            FROM fennec.urlannotations a
+            JOIN main.moz_places p
+                ON p.url_hash = hash(validate_url(a.url)) AND p.url = validate_url(a.url)
+            JOIN main.moz_anno_attributes n ON n.name = 'fennec/' || sanitize_utf8(a.key)"
+    ;
+
     // Count Fennec history visits
     static ref COUNT_FENNEC_HISTORY_VISITS: &'static str =
         "SELECT COUNT(*) FROM fennec.visits"
@@ -8,7 +8,7 @@ use crate::bookmark_sync::{
     SyncedBookmarkKind,
 };
 use crate::error::*;
-use crate::import::common::{attached_database, ExecuteOnDrop};
+use crate::import::common::{attached_database, import_bookmark_descriptions, ExecuteOnDrop};
 use crate::types::SyncStatus;
 use rusqlite::{named_params, NO_PARAMS};
 use sql_support::ConnExt;
@@ -152,6 +152,9 @@ fn do_import_ios_bookmarks(places_api: &PlacesApi, ios_db_file_url: Url) -> Resu
     log::debug!("Fixing up bookmarks");
     conn.execute_batch(&FIXUP_MOZ_BOOKMARKS)?;
     scope.err_if_interrupted()?;
+    log::debug!("Importing descriptions");
+    import_bookmark_descriptions(&conn, "temp.iosBookmarksStaging")?;
+    scope.err_if_interrupted()?;
     log::debug!("Cleaning up mirror...");
     clear_mirror_on_drop.execute_now()?;
     log::debug!("Committing...");
@@ -368,6 +371,7 @@ lazy_static::lazy_static! {
             bmkUri,
             keyword,
             tags,
+            description,
             date_added,
             modified,
             isLocal
@@ -385,6 +389,7 @@ lazy_static::lazy_static! {
             END as uri,
             b.keyword,
             b.tags,
+            b.description,
             sanitize_timestamp(b.date_added),
             sanitize_timestamp(b.server_modified),
             0
@@ -408,6 +413,7 @@ lazy_static::lazy_static! {
             bmkUri,
             keyword,
             tags,
+            description,
             date_added,
             modified,
             isLocal
@@ -421,6 +427,7 @@ lazy_static::lazy_static! {
             validate_url(l.bmkUri) as uri,
             l.keyword,
             l.tags,
+            l.description,
             sanitize_timestamp(l.date_added),
             sanitize_timestamp(l.local_modified),
             1
@@ -446,6 +453,7 @@ lazy_static::lazy_static! {
                 CHECK(type != {ios_bookmark_type} OR validate_url(bmkUri) == bmkUri),
             keyword TEXT,
             tags TEXT,
+            description TEXT,
             date_added INTEGER NOT NULL,
             modified INTEGER NOT NULL,
             isLocal TINYINT NOT NULL
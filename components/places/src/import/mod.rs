@@ -2,6 +2,11 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
+pub mod bookmarks_html;
+pub use bookmarks_html::{export_html_bookmarks, import_html_bookmarks};
+pub mod chromium;
+pub use chromium::import_bookmarks as import_chromium_bookmarks;
+pub use chromium::import_history as import_chromium_history;
 pub mod common;
 pub mod fennec;
 pub use fennec::import_bookmarks as import_fennec_bookmarks;
@@ -9,3 +14,6 @@ pub use fennec::import_history as import_fennec_history;
 pub use fennec::import_pinned_sites as import_fennec_pinned_sites;
 pub mod ios_bookmarks;
 pub use ios_bookmarks::import_ios_bookmarks;
+pub mod safari;
+pub use safari::import_bookmarks as import_safari_bookmarks;
+pub use safari::import_history as import_safari_history;
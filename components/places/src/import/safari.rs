@@ -0,0 +1,20 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Imports history and bookmarks from Safari, which keeps them in
+//! `~/Library/Safari`:
+//!
+//! - `History.db` is an SQLite database with `history_items` (pages) and
+//!   `history_visits` tables.
+//! - `Bookmarks.plist` is a binary property list holding the bookmarks tree.
+//!
+//! Safari stores times as seconds since 2001-01-01 UTC (the Core Data epoch).
+//! Reading `~/Library/Safari` needs Full Disk Access on recent versions of
+//! macOS, so callers will usually import from files the user has exported.
+
+pub mod bookmarks;
+pub mod history;
+pub mod plist;
+pub use bookmarks::import as import_bookmarks;
+pub use history::import as import_history;
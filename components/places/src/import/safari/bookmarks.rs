@@ -0,0 +1,271 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use super::plist::{self, Value};
+use crate::api::places_api::PlacesApi;
+use crate::error::*;
+use crate::import::common::{insert_bookmark_roots, validate_url};
+use crate::import::fennec::bookmarks::BookmarksMigrationResult;
+use crate::storage::bookmarks::{BookmarkNode, BookmarkRootGuid, BookmarkTreeNode, FolderNode};
+use std::time::Instant;
+
+const TYPE_KEY: &str = "WebBookmarkType";
+const TYPE_LIST: &str = "WebBookmarkTypeList";
+const TYPE_LEAF: &str = "WebBookmarkTypeLeaf";
+// Proxies are placeholders for Safari's history and Bonjour sites.
+const TYPE_PROXY: &str = "WebBookmarkTypeProxy";
+
+const TOOLBAR_TITLE: &str = "BookmarksBar";
+const MENU_TITLE: &str = "BookmarksMenu";
+const READING_LIST_TITLE: &str = "com.apple.ReadingList";
+
+/// Imports bookmarks from a Safari `Bookmarks.plist` file, appending them to
+/// our roots: the favorites bar goes to the toolbar, the bookmarks menu to the
+/// menu, and any other top-level items to unfiled. The reading list isn't
+/// imported. The import happens in a single transaction.
+///
+/// Safari doesn't record when bookmarks were added, so they get the time of
+/// the import. Items we can't import (bookmarks with invalid URLs, for
+/// example) are skipped and counted in `num_failed`.
+pub fn import(
+    places_api: &PlacesApi,
+    path: impl AsRef<std::path::Path>,
+) -> Result<BookmarksMigrationResult> {
+    let data = std::fs::read(path)?;
+    do_import(places_api, &data)
+}
+
+fn do_import(places_api: &PlacesApi, data: &[u8]) -> Result<BookmarksMigrationResult> {
+    let import_start = Instant::now();
+    log::debug!("Parsing Safari bookmarks");
+    let root = plist::parse(data)?;
+    if root.get(TYPE_KEY).and_then(Value::as_str) != Some(TYPE_LIST) {
+        return Err(ErrorKind::InvalidImportFile("not a Safari bookmarks file".into()).into());
+    }
+
+    let mut converter = Converter::default();
+    let mut menu = FolderNode {
+        guid: Some(BookmarkRootGuid::Menu.as_guid()),
+        ..Default::default()
+    };
+    let mut toolbar = FolderNode {
+        guid: Some(BookmarkRootGuid::Toolbar.as_guid()),
+        ..Default::default()
+    };
+    let mut unfiled = FolderNode {
+        guid: Some(BookmarkRootGuid::Unfiled.as_guid()),
+        ..Default::default()
+    };
+    for child in children(&root) {
+        let is_list = child.get(TYPE_KEY).and_then(Value::as_str) == Some(TYPE_LIST);
+        match child.get("Title").and_then(Value::as_str) {
+            Some(TOOLBAR_TITLE) if is_list => {
+                toolbar.children = converter.convert_children(child);
+            }
+            Some(MENU_TITLE) if is_list => {
+                menu.children = converter.convert_children(child);
+            }
+            Some(READING_LIST_TITLE) if is_list => {}
+            _ => unfiled.children.extend(converter.convert(child)),
+        }
+    }
+
+    let conn = places_api.open_sync_connection()?;
+    insert_bookmark_roots(&conn, &[menu, toolbar, unfiled])?;
+
+    let metrics = BookmarksMigrationResult {
+        num_total: converter.num_total,
+        num_succeeded: converter.num_total - converter.num_failed,
+        num_failed: converter.num_failed,
+        total_duration: import_start.elapsed().as_millis(),
+    };
+    log::info!("Successfully imported bookmarks: {:?}", metrics);
+    Ok(metrics)
+}
+
+fn children(list: &Value) -> &[Value] {
+    list.get("Children")
+        .and_then(Value::as_array)
+        .unwrap_or_default()
+}
+
+#[derive(Default)]
+struct Converter {
+    num_total: u32,
+    num_failed: u32,
+}
+
+impl Converter {
+    fn convert_children(&mut self, list: &Value) -> Vec<BookmarkTreeNode> {
+        children(list)
+            .iter()
+            .filter_map(|child| self.convert(child))
+            .collect()
+    }
+
+    fn convert(&mut self, item: &Value) -> Option<BookmarkTreeNode> {
+        match item.get(TYPE_KEY).and_then(Value::as_str) {
+            Some(TYPE_PROXY) => None,
+            Some(TYPE_LIST) => {
+                self.num_total += 1;
+                Some(
+                    FolderNode {
+                        title: title(item.get("Title")),
+                        children: self.convert_children(item),
+                        ..Default::default()
+                    }
+                    .into(),
+                )
+            }
+            Some(TYPE_LEAF) => {
+                self.num_total += 1;
+                match item
+                    .get("URLString")
+                    .and_then(Value::as_str)
+                    .and_then(validate_url)
+                {
+                    Some(url) => Some(
+                        BookmarkNode {
+                            guid: None,
+                            date_added: None,
+                            last_modified: None,
+                            title: title(item.get("URIDictionary").and_then(|d| d.get("title"))),
+                            url,
+                        }
+                        .into(),
+                    ),
+                    None => {
+                        self.num_failed += 1;
+                        None
+                    }
+                }
+            }
+            _ => {
+                self.num_total += 1;
+                self.num_failed += 1;
+                None
+            }
+        }
+    }
+}
+
+fn title(value: Option<&Value>) -> Option<String> {
+    value
+        .and_then(Value::as_str)
+        .filter(|title| !title.is_empty())
+        .map(ToString::to_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::places_api::{test::new_mem_api, ConnectionType};
+    use crate::storage::bookmarks::{fetch_tree, FetchDepth};
+    use pretty_assertions::assert_eq;
+    use std::collections::HashMap;
+    use url::Url;
+
+    fn dict(entries: Vec<(&str, Value)>) -> Value {
+        Value::Dictionary(
+            entries
+                .into_iter()
+                .map(|(k, v)| (k.to_string(), v))
+                .collect::<HashMap<_, _>>(),
+        )
+    }
+
+    fn list(title: &str, children: Vec<Value>) -> Value {
+        dict(vec![
+            (TYPE_KEY, Value::String(TYPE_LIST.into())),
+            ("Title", Value::String(title.into())),
+            ("Children", Value::Array(children)),
+        ])
+    }
+
+    fn leaf(url: &str, title: &str) -> Value {
+        dict(vec![
+            (TYPE_KEY, Value::String(TYPE_LEAF.into())),
+            ("URLString", Value::String(url.into())),
+            (
+                "URIDictionary",
+                dict(vec![("title", Value::String(title.into()))]),
+            ),
+        ])
+    }
+
+    fn fetch_children(api: &PlacesApi, root: BookmarkRootGuid) -> Result<Vec<BookmarkTreeNode>> {
+        let conn = api.open_connection(ConnectionType::ReadOnly)?;
+        Ok(
+            match fetch_tree(&conn, &root.into(), &FetchDepth::Deepest)? {
+                Some((BookmarkTreeNode::Folder(f), _, _)) => f.children,
+                _ => panic!("{:?} should be a folder", root),
+            },
+        )
+    }
+
+    #[test]
+    fn test_import() -> Result<()> {
+        let api = new_mem_api();
+        let root = list(
+            "",
+            vec![
+                dict(vec![
+                    (TYPE_KEY, Value::String(TYPE_PROXY.into())),
+                    ("Title", Value::String("History".into())),
+                ]),
+                list(
+                    TOOLBAR_TITLE,
+                    vec![
+                        leaf("https://www.example.com/", "Example"),
+                        list("Folder", vec![leaf("https://www.mozilla.org/", "Mozilla")]),
+                    ],
+                ),
+                list(MENU_TITLE, vec![leaf("not a url", "Invalid")]),
+                list(
+                    READING_LIST_TITLE,
+                    vec![leaf("https://www.example.com/read", "Later")],
+                ),
+                leaf("https://www.example.org/", "Top level"),
+            ],
+        );
+        let metrics = do_import(&api, &plist::test::write(&root))?;
+        assert_eq!(metrics.num_total, 5);
+        assert_eq!(metrics.num_succeeded, 4);
+        assert_eq!(metrics.num_failed, 1);
+
+        let toolbar = fetch_children(&api, BookmarkRootGuid::Toolbar)?;
+        assert_eq!(toolbar.len(), 2);
+        match &toolbar[0] {
+            BookmarkTreeNode::Bookmark(b) => {
+                assert_eq!(b.url, Url::parse("https://www.example.com/")?);
+                assert_eq!(b.title.as_deref(), Some("Example"));
+            }
+            n => panic!("Unexpected node: {:?}", n),
+        }
+        match &toolbar[1] {
+            BookmarkTreeNode::Folder(f) => {
+                assert_eq!(f.title.as_deref(), Some("Folder"));
+                assert_eq!(f.children.len(), 1);
+            }
+            n => panic!("Unexpected node: {:?}", n),
+        }
+        assert!(fetch_children(&api, BookmarkRootGuid::Menu)?.is_empty());
+        let unfiled = fetch_children(&api, BookmarkRootGuid::Unfiled)?;
+        assert_eq!(unfiled.len(), 1);
+        match &unfiled[0] {
+            BookmarkTreeNode::Bookmark(b) => {
+                assert_eq!(b.url, Url::parse("https://www.example.org/")?)
+            }
+            n => panic!("Unexpected node: {:?}", n),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_not_bookmarks() {
+        let api = new_mem_api();
+        let data = plist::test::write(&Value::Array(vec![]));
+        assert!(do_import(&api, &data).is_err());
+    }
+}
@@ -0,0 +1,196 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use crate::api::places_api::PlacesApi;
+use crate::bookmark_sync::engine::BookmarksEngine;
+use crate::db::db::PlacesDb;
+use crate::error::*;
+use crate::import::common::attached_database;
+use crate::import::fennec::history::{select_count, HistoryMigrationResult};
+use crate::types::VisitTransition;
+use rusqlite::{Connection, NO_PARAMS};
+use sql_support::ConnExt;
+use std::time::Instant;
+use url::Url;
+
+/// Seconds between the Unix epoch and the Core Data epoch.
+const CORE_DATA_EPOCH_OFFSET_SECS: i64 = 978_307_200;
+
+/// Imports history from a Safari `History.db` database. Visits are added to
+/// existing history, and visits we already have are skipped. Pages get the
+/// title of their most recent visit.
+pub fn import(
+    places_api: &PlacesApi,
+    path: impl AsRef<std::path::Path>,
+) -> Result<HistoryMigrationResult> {
+    let url = crate::util::ensure_url_path(path)?;
+    do_import(places_api, url)
+}
+
+fn safari_table_exists(conn: &PlacesDb, name: &str) -> Result<bool> {
+    Ok(conn
+        .try_query_one::<i64>(
+            "SELECT 1 FROM safari.sqlite_master WHERE type = 'table' AND name = :name",
+            &[(":name", &name)],
+            false,
+        )?
+        .is_some())
+}
+
+fn do_import(places_api: &PlacesApi, safari_db_file_url: Url) -> Result<HistoryMigrationResult> {
+    let conn = places_api.open_sync_connection()?;
+
+    let scope = conn.begin_interrupt_scope();
+
+    define_sql_functions(&conn)?;
+
+    let import_start = Instant::now();
+    log::trace!("Attaching database {}", safari_db_file_url);
+    let auto_detach = attached_database(&conn, &safari_db_file_url, "safari")?;
+
+    // Safari doesn't version its history database, so check for the tables
+    // we need instead.
+    if !safari_table_exists(&conn, "history_items")?
+        || !safari_table_exists(&conn, "history_visits")?
+    {
+        return Err(ErrorKind::InvalidImportFile("not a Safari history database".into()).into());
+    }
+
+    let tx = conn.begin_transaction()?;
+
+    log::debug!("Counting Safari history visits");
+    let num_total = select_count(&conn, &COUNT_SAFARI_HISTORY_VISITS);
+
+    log::debug!("Creating and populating staging table");
+    conn.execute_batch(&CREATE_STAGING_TABLE)?;
+    conn.execute_batch(&FILL_STAGING)?;
+    scope.err_if_interrupted()?;
+
+    log::debug!("Populating missing entries in moz_places");
+    conn.execute_batch(&FILL_MOZ_PLACES)?;
+    scope.err_if_interrupted()?;
+
+    log::debug!("Inserting the history visits");
+    let num_succeeded = conn.execute(&INSERT_HISTORY_VISITS, NO_PARAMS)? as u32;
+    scope.err_if_interrupted()?;
+
+    log::debug!("Committing...");
+    tx.commit()?;
+
+    // Note: update_frecencies manages its own transaction, which is fine,
+    // since nothing that bad will happen if it is aborted.
+    log::debug!("Updating frecencies");
+    let engine = BookmarksEngine::new(&conn, &scope);
+    engine.update_frecencies()?;
+
+    auto_detach.execute_now()?;
+
+    let metrics = HistoryMigrationResult {
+        num_total,
+        num_succeeded,
+        // Visits we already had are counted as failures.
+        num_failed: num_total.saturating_sub(num_succeeded),
+        total_duration: import_start.elapsed().as_millis(),
+    };
+    log::info!("Successfully imported history visits: {:?}", metrics);
+
+    Ok(metrics)
+}
+
+lazy_static::lazy_static! {
+    // Keyed by Safari's `history_items.id`, which is what
+    // `history_visits.history_item` refers to.
+    static ref CREATE_STAGING_TABLE: &'static str = "
+        CREATE TEMP TABLE temp.safariHistoryStaging(
+            id INTEGER PRIMARY KEY,
+            url TEXT NOT NULL,
+            url_hash INTEGER NOT NULL,
+            title TEXT
+        )"
+    ;
+
+    // Safari stores titles per visit, rather than per page.
+    static ref FILL_STAGING: &'static str = "
+        INSERT OR IGNORE INTO temp.safariHistoryStaging(id, url, url_hash, title)
+            SELECT id, url, hash(url), title
+            FROM (SELECT i.id,
+                         validate_url(i.url) AS url,
+                         (SELECT sanitize_utf8(v.title) FROM safari.history_visits v
+                          WHERE v.history_item = i.id AND v.title IS NOT NULL AND v.title != ''
+                          ORDER BY v.visit_time DESC
+                          LIMIT 1) AS title
+                  FROM safari.history_items i)
+            WHERE url IS NOT NULL"
+    ;
+
+    static ref FILL_MOZ_PLACES: &'static str =
+        "INSERT OR IGNORE INTO main.moz_places(guid, url, url_hash, title, frecency, sync_change_counter)
+            SELECT
+                IFNULL(
+                    (SELECT p.guid FROM main.moz_places p WHERE p.url_hash = t.url_hash AND p.url = t.url),
+                    generate_guid()
+                ),
+                t.url,
+                t.url_hash,
+                t.title,
+                -1,
+                1
+            FROM temp.safariHistoryStaging t"
+    ;
+
+    // Visits with `redirect_source` set are the destinations of redirects.
+    // `origin` is 0 for local visits, and 1 for visits synced through iCloud.
+    // We skip failed loads, visits Safari synthesized from its daily visit
+    // counts, and visits we already have for a page at the same time.
+    static ref INSERT_HISTORY_VISITS: String = format!(
+        "INSERT INTO main.moz_historyvisits(from_visit, place_id, visit_date, visit_type, is_local)
+            SELECT NULL, n.place_id, n.visit_date, n.visit_type, n.is_local
+            FROM (SELECT
+                      p.id AS place_id,
+                      sanitize_timestamp(CAST((v.visit_time + {epoch_offset}) * 1000 AS INTEGER))
+                          AS visit_date,
+                      CASE WHEN v.redirect_source IS NOT NULL
+                           THEN {redirect_type}
+                           ELSE {link_type}
+                      END AS visit_type,
+                      v.origin = 0 AS is_local
+                  FROM safari.history_visits v
+                  JOIN temp.safariHistoryStaging t ON t.id = v.history_item
+                  JOIN main.moz_places p ON p.url_hash = t.url_hash AND p.url = t.url
+                  WHERE v.load_successful AND NOT v.synthesized) n
+            WHERE NOT EXISTS(SELECT 1 FROM main.moz_historyvisits e
+                             WHERE e.place_id = n.place_id AND e.visit_date = n.visit_date)",
+        epoch_offset = CORE_DATA_EPOCH_OFFSET_SECS,
+        redirect_type = VisitTransition::RedirectTemporary as u8,
+        link_type = VisitTransition::Link as u8,
+    );
+
+    static ref COUNT_SAFARI_HISTORY_VISITS: &'static str =
+        "SELECT COUNT(*) FROM safari.history_visits v
+         WHERE v.load_successful AND NOT v.synthesized"
+    ;
+}
+
+fn define_sql_functions(c: &Connection) -> Result<()> {
+    use rusqlite::functions::FunctionFlags;
+    c.create_scalar_function(
+        "validate_url",
+        1,
+        FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+        crate::import::common::sql_fns::validate_url,
+    )?;
+    c.create_scalar_function(
+        "sanitize_timestamp",
+        1,
+        FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+        crate::import::common::sql_fns::sanitize_timestamp,
+    )?;
+    c.create_scalar_function(
+        "sanitize_utf8",
+        1,
+        FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+        crate::import::common::sql_fns::sanitize_utf8,
+    )?;
+    Ok(())
+}
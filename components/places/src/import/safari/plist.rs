@@ -0,0 +1,382 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! A minimal reader for Apple's binary property list format (`bplist00`),
+//! which Safari uses for `Bookmarks.plist`. It reads the whole file into a
+//! tree of `Value`s.
+//!
+//! A binary plist is a header, a flat list of objects, a table with the
+//! offset of each object, and a trailer pointing at the table and the top
+//! object. Arrays and dictionaries refer to their members by index into the
+//! offset table.
+
+use crate::error::*;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+const MAGIC: &[u8] = b"bplist00";
+const TRAILER_LEN: usize = 32;
+// Bookmark trees aren't this deep; this just keeps malicious files from
+// overflowing the stack.
+const MAX_DEPTH: usize = 256;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Boolean(bool),
+    Integer(i64),
+    Real(f64),
+    /// Seconds since 2001-01-01 UTC.
+    Date(f64),
+    Data(Vec<u8>),
+    String(String),
+    Array(Vec<Value>),
+    Dictionary(HashMap<String, Value>),
+}
+
+impl Value {
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Value]> {
+        match self {
+            Value::Array(a) => Some(a),
+            _ => None,
+        }
+    }
+
+    pub fn as_dictionary(&self) -> Option<&HashMap<String, Value>> {
+        match self {
+            Value::Dictionary(d) => Some(d),
+            _ => None,
+        }
+    }
+
+    /// Looks up `key` if this is a dictionary.
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.as_dictionary().and_then(|d| d.get(key))
+    }
+}
+
+fn invalid() -> Error {
+    ErrorKind::InvalidImportFile("invalid binary property list".into()).into()
+}
+
+/// Parses a binary property list.
+pub fn parse(data: &[u8]) -> Result<Value> {
+    if !data.starts_with(MAGIC) {
+        return Err(ErrorKind::InvalidImportFile(
+            "only binary property lists are supported".into(),
+        )
+        .into());
+    }
+    if data.len() < MAGIC.len() + TRAILER_LEN {
+        return Err(invalid());
+    }
+    let trailer = &data[data.len() - TRAILER_LEN..];
+    let offset_size = trailer[6] as usize;
+    let ref_size = trailer[7] as usize;
+    if !(1..=8).contains(&offset_size) || !(1..=8).contains(&ref_size) {
+        return Err(invalid());
+    }
+    let num_objects = read_uint(&trailer[8..16])?;
+    let top_object = read_uint(&trailer[16..24])?;
+    let table_offset = read_uint(&trailer[24..32])?;
+    let table_len = num_objects.checked_mul(offset_size).ok_or_else(invalid)?;
+    let table = data
+        .get(table_offset..table_offset.checked_add(table_len).ok_or_else(invalid)?)
+        .ok_or_else(invalid)?;
+    let reader = Reader {
+        data,
+        table,
+        offset_size,
+        ref_size,
+        num_objects,
+    };
+    reader.read_object(top_object, 0)
+}
+
+/// Reads a big-endian integer of up to 8 bytes.
+fn read_u64(bytes: &[u8]) -> Result<u64> {
+    if bytes.is_empty() || bytes.len() > 8 {
+        return Err(invalid());
+    }
+    Ok(bytes.iter().fold(0u64, |v, &b| (v << 8) | u64::from(b)))
+}
+
+fn read_uint(bytes: &[u8]) -> Result<usize> {
+    usize::try_from(read_u64(bytes)?).map_err(|_| invalid())
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    table: &'a [u8],
+    offset_size: usize,
+    ref_size: usize,
+    num_objects: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&self, start: usize, len: usize) -> Result<&'a [u8]> {
+        self.data
+            .get(start..start.checked_add(len).ok_or_else(invalid)?)
+            .ok_or_else(invalid)
+    }
+
+    fn object_offset(&self, index: usize) -> Result<usize> {
+        if index >= self.num_objects {
+            return Err(invalid());
+        }
+        read_uint(&self.table[index * self.offset_size..(index + 1) * self.offset_size])
+    }
+
+    /// Reads the length of an object from its marker, which is either in the
+    /// low nibble, or in an integer object following the marker. Returns the
+    /// length and the offset of the object's contents.
+    fn length(&self, offset: usize, marker: u8) -> Result<(usize, usize)> {
+        let nibble = marker & 0x0F;
+        if nibble != 0x0F {
+            return Ok((nibble as usize, offset + 1));
+        }
+        let int_marker = *self.data.get(offset + 1).ok_or_else(invalid)?;
+        if int_marker & 0xF0 != 0x10 {
+            return Err(invalid());
+        }
+        let int_len = 1usize << (int_marker & 0x0F);
+        let len = read_uint(self.bytes(offset + 2, int_len)?)?;
+        Ok((len, offset + 2 + int_len))
+    }
+
+    fn read_refs(&self, start: usize, count: usize) -> Result<Vec<usize>> {
+        let bytes = self.bytes(start, count.checked_mul(self.ref_size).ok_or_else(invalid)?)?;
+        bytes.chunks(self.ref_size).map(read_uint).collect()
+    }
+
+    fn read_object(&self, index: usize, depth: usize) -> Result<Value> {
+        if depth > MAX_DEPTH {
+            return Err(invalid());
+        }
+        let offset = self.object_offset(index)?;
+        let marker = *self.data.get(offset).ok_or_else(invalid)?;
+        Ok(match marker >> 4 {
+            0x0 => match marker {
+                0x08 => Value::Boolean(false),
+                0x09 => Value::Boolean(true),
+                // Null and fill bytes, which Safari doesn't write.
+                _ => return Err(invalid()),
+            },
+            0x1 => {
+                // 1, 2 and 4 byte integers are unsigned, and 8 byte ones are
+                // signed, so this cast does the right thing for all of them.
+                let len = 1usize << (marker & 0x0F);
+                Value::Integer(read_u64(self.bytes(offset + 1, len)?)? as i64)
+            }
+            0x2 | 0x3 => {
+                let len = 1usize << (marker & 0x0F);
+                let bytes = self.bytes(offset + 1, len)?;
+                let v = match len {
+                    4 => f64::from(f32::from_bits(read_u64(bytes)? as u32)),
+                    8 => f64::from_bits(read_u64(bytes)?),
+                    _ => return Err(invalid()),
+                };
+                if marker >> 4 == 0x2 {
+                    Value::Real(v)
+                } else {
+                    Value::Date(v)
+                }
+            }
+            0x4 => {
+                let (len, start) = self.length(offset, marker)?;
+                Value::Data(self.bytes(start, len)?.to_vec())
+            }
+            0x5 => {
+                let (len, start) = self.length(offset, marker)?;
+                Value::String(String::from_utf8_lossy(self.bytes(start, len)?).into_owned())
+            }
+            0x6 => {
+                let (len, start) = self.length(offset, marker)?;
+                let bytes = self.bytes(start, len.checked_mul(2).ok_or_else(invalid)?)?;
+                let units = bytes
+                    .chunks(2)
+                    .map(|c| u16::from_be_bytes([c[0], c[1]]))
+                    .collect::<Vec<_>>();
+                Value::String(String::from_utf16_lossy(&units))
+            }
+            0xA => {
+                let (len, start) = self.length(offset, marker)?;
+                let refs = self.read_refs(start, len)?;
+                Value::Array(
+                    refs.into_iter()
+                        .map(|r| self.read_object(r, depth + 1))
+                        .collect::<Result<_>>()?,
+                )
+            }
+            0xD => {
+                let (len, start) = self.length(offset, marker)?;
+                let refs = self.read_refs(start, len.checked_mul(2).ok_or_else(invalid)?)?;
+                let (keys, values) = refs.split_at(len);
+                let mut dict = HashMap::with_capacity(len);
+                for (&key, &value) in keys.iter().zip(values) {
+                    let key = match self.read_object(key, depth + 1)? {
+                        Value::String(key) => key,
+                        _ => return Err(invalid()),
+                    };
+                    dict.insert(key, self.read_object(value, depth + 1)?);
+                }
+                Value::Dictionary(dict)
+            }
+            _ => return Err(invalid()),
+        })
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    //! A binary plist writer, for tests.
+    use super::*;
+
+    pub fn write(value: &Value) -> Vec<u8> {
+        let mut objects = Vec::new();
+        flatten(value, &mut objects);
+        let mut data = MAGIC.to_vec();
+        let mut offsets = Vec::new();
+        for object in &objects {
+            offsets.push(data.len() as u64);
+            data.extend_from_slice(object);
+        }
+        let table_offset = data.len() as u64;
+        for offset in &offsets {
+            data.extend_from_slice(&offset.to_be_bytes());
+        }
+        data.extend_from_slice(&[0; 6]);
+        // 8 byte offsets, and 2 byte object references.
+        data.push(8);
+        data.push(2);
+        data.extend_from_slice(&(objects.len() as u64).to_be_bytes());
+        data.extend_from_slice(&0u64.to_be_bytes());
+        data.extend_from_slice(&table_offset.to_be_bytes());
+        data
+    }
+
+    fn marker(kind: u8, len: usize) -> Vec<u8> {
+        if len < 0x0F {
+            vec![(kind << 4) | len as u8]
+        } else {
+            let mut m = vec![(kind << 4) | 0x0F, 0x13];
+            m.extend_from_slice(&(len as u64).to_be_bytes());
+            m
+        }
+    }
+
+    // Writes `value` and its members, depth first. Returns the index of
+    // `value`.
+    fn flatten(value: &Value, objects: &mut Vec<Vec<u8>>) -> u16 {
+        let index = objects.len();
+        objects.push(Vec::new());
+        let object = match value {
+            Value::Boolean(b) => vec![if *b { 0x09 } else { 0x08 }],
+            Value::Integer(i) => {
+                let mut o = vec![0x13];
+                o.extend_from_slice(&i.to_be_bytes());
+                o
+            }
+            Value::Real(r) | Value::Date(r) => {
+                let kind = if matches!(value, Value::Real(_)) {
+                    0x23
+                } else {
+                    0x33
+                };
+                let mut o = vec![kind];
+                o.extend_from_slice(&r.to_bits().to_be_bytes());
+                o
+            }
+            Value::Data(d) => {
+                let mut o = marker(0x4, d.len());
+                o.extend_from_slice(d);
+                o
+            }
+            Value::String(s) if s.is_ascii() => {
+                let mut o = marker(0x5, s.len());
+                o.extend_from_slice(s.as_bytes());
+                o
+            }
+            Value::String(s) => {
+                let units = s.encode_utf16().collect::<Vec<_>>();
+                let mut o = marker(0x6, units.len());
+                for unit in units {
+                    o.extend_from_slice(&unit.to_be_bytes());
+                }
+                o
+            }
+            Value::Array(a) => {
+                let refs = a.iter().map(|v| flatten(v, objects)).collect::<Vec<_>>();
+                let mut o = marker(0xA, refs.len());
+                for r in refs {
+                    o.extend_from_slice(&r.to_be_bytes());
+                }
+                o
+            }
+            Value::Dictionary(d) => {
+                let mut keys = Vec::new();
+                let mut values = Vec::new();
+                for (k, v) in d {
+                    keys.push(flatten(&Value::String(k.clone()), objects));
+                    values.push(flatten(v, objects));
+                }
+                let mut o = marker(0xD, keys.len());
+                for r in keys.into_iter().chain(values) {
+                    o.extend_from_slice(&r.to_be_bytes());
+                }
+                o
+            }
+        };
+        objects[index] = object;
+        index as u16
+    }
+
+    #[test]
+    fn test_round_trip() -> Result<()> {
+        let mut dict = HashMap::new();
+        dict.insert("ascii".to_string(), Value::String("hello".into()));
+        dict.insert("utf16".to_string(), Value::String("héllo 🦊".into()));
+        dict.insert(
+            "long".to_string(),
+            Value::String("a string longer than fifteen bytes".into()),
+        );
+        dict.insert("int".to_string(), Value::Integer(-42));
+        dict.insert("real".to_string(), Value::Real(1.5));
+        dict.insert("date".to_string(), Value::Date(652_233_600.0));
+        dict.insert("data".to_string(), Value::Data(vec![1, 2, 3]));
+        dict.insert("bool".to_string(), Value::Boolean(true));
+        dict.insert(
+            "array".to_string(),
+            Value::Array(vec![Value::Integer(1), Value::Array(vec![])]),
+        );
+        let value = Value::Dictionary(dict);
+        assert_eq!(parse(&write(&value))?, value);
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(parse(b"<?xml version=\"1.0\"?><plist></plist>").is_err());
+        assert!(parse(b"bplist00").is_err());
+        // A valid plist, truncated.
+        let data = write(&Value::Array(vec![Value::Integer(1)]));
+        assert!(parse(&data[..data.len() - 4]).is_err());
+        // An array that contains itself: one object, 1 byte offsets and 2
+        // byte references.
+        let mut data = MAGIC.to_vec();
+        data.extend_from_slice(&[0xA1, 0, 0]);
+        data.push(MAGIC.len() as u8);
+        data.extend_from_slice(&[0, 0, 0, 0, 0, 0, 1, 2]);
+        data.extend_from_slice(&1u64.to_be_bytes());
+        data.extend_from_slice(&0u64.to_be_bytes());
+        data.extend_from_slice(&((MAGIC.len() + 3) as u64).to_be_bytes());
+        assert!(parse(&data).is_err());
+    }
+}
@@ -14,6 +14,7 @@ pub mod db;
 pub mod ffi;
 pub mod frecency;
 pub mod hash;
+pub mod history_metadata_sync;
 pub mod history_sync;
 // match_impl is pub mostly for benchmarks (which have to run as a separate pseudo-crate).
 pub mod import;
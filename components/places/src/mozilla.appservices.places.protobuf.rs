@@ -204,4 +204,6 @@ pub enum SearchResultReason {
     /// If we get real tag support, just add `optional string tags` to SearchResult below, but
     /// for now expose that it was because of tags.
     Tag = 6,
+    /// The query was a keyword search, like `w rust` for a Wikipedia search.
+    KeywordSearch = 7,
 }
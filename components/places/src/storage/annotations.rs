@@ -0,0 +1,759 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+// Annotations are typed name-value pairs attached to a page or a bookmark
+// item, modeled on Desktop's `moz_annos` and `moz_items_annos`:
+//
+// - `moz_anno_attributes` interns annotation names.
+// - `moz_annos` holds page annotations, keyed by `moz_places` row.
+// - `moz_items_annos` holds item annotations, keyed by `moz_bookmarks` row.
+//
+// Annotations aren't synced. Page annotations are removed along with their
+// page, and item annotations along with their bookmark.
+
+use super::{delete_pending_temp_tables, fetch_page_info, new_page_info, RowId};
+use crate::db::PlacesDb;
+use crate::error::{ErrorKind, InvalidPlaceInfo, Result};
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, Value, ValueRef};
+use rusqlite::Result as RusqliteResult;
+use rusqlite::Row;
+use sql_support::ConnExt;
+use std::time::Duration;
+use sync_guid::Guid as SyncGuid;
+use types::Timestamp;
+use url::Url;
+
+/// The annotation Desktop and Fennec use for bookmark descriptions.
+pub const DESCRIPTION_ANNO: &str = "bookmarkProperties/description";
+
+/// Names longer than this are rejected. Desktop declares the column as
+/// `VARCHAR(32)`, but doesn't enforce it, and some of its names are longer.
+pub const ANNOTATION_NAME_MAX: usize = 256;
+
+// Desktop's `nsIAnnotationService` type codes. We never write `TYPE_INT32`,
+// but imported annotations might use it.
+const TYPE_INT32: u8 = 1;
+const TYPE_DOUBLE: u8 = 2;
+const TYPE_STRING: u8 = 3;
+const TYPE_INT64: u8 = 5;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AnnotationValue {
+    Integer(i64),
+    Double(f64),
+    Text(String),
+}
+
+impl AnnotationValue {
+    fn type_code(&self) -> u8 {
+        match self {
+            AnnotationValue::Integer(_) => TYPE_INT64,
+            AnnotationValue::Double(_) => TYPE_DOUBLE,
+            AnnotationValue::Text(_) => TYPE_STRING,
+        }
+    }
+
+    // Annotations we write keep their SQLite type, but imported ones might
+    // store numbers as text, so we use the type column to parse those.
+    fn from_content(type_code: u8, content: Value) -> Self {
+        match content {
+            Value::Integer(i) => AnnotationValue::Integer(i),
+            Value::Real(d) => AnnotationValue::Double(d),
+            Value::Text(s) => match type_code {
+                TYPE_INT32 | TYPE_INT64 => s
+                    .parse()
+                    .map(AnnotationValue::Integer)
+                    .unwrap_or(AnnotationValue::Text(s)),
+                TYPE_DOUBLE => s
+                    .parse()
+                    .map(AnnotationValue::Double)
+                    .unwrap_or(AnnotationValue::Text(s)),
+                _ => AnnotationValue::Text(s),
+            },
+            Value::Blob(b) => AnnotationValue::Text(String::from_utf8_lossy(&b).into_owned()),
+            Value::Null => AnnotationValue::Text(String::new()),
+        }
+    }
+}
+
+impl ToSql for AnnotationValue {
+    fn to_sql(&self) -> RusqliteResult<ToSqlOutput<'_>> {
+        Ok(match self {
+            AnnotationValue::Integer(i) => ToSqlOutput::from(*i),
+            AnnotationValue::Double(d) => ToSqlOutput::from(*d),
+            AnnotationValue::Text(s) => ToSqlOutput::from(s.as_str()),
+        })
+    }
+}
+
+/// When an annotation is removed. The values match Desktop's
+/// `nsIAnnotationService` constants. Time-based policies count from the
+/// annotation's last modification, and are applied by `run_maintenance`.
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AnnotationExpiration {
+    /// Removed 7 days after it was last modified.
+    Days = 6,
+    /// Removed 30 days after it was last modified.
+    Weeks = 2,
+    /// Removed 180 days after it was last modified.
+    Months = 3,
+    /// Only removed with its page or bookmark.
+    Never = 4,
+    /// Removed once its page has no visits left. Only meaningful for page
+    /// annotations; item annotations with this policy never expire.
+    WithHistory = 5,
+}
+
+impl AnnotationExpiration {
+    pub fn from_u8(v: u8) -> Option<Self> {
+        Some(match v {
+            2 => AnnotationExpiration::Weeks,
+            3 => AnnotationExpiration::Months,
+            4 => AnnotationExpiration::Never,
+            5 => AnnotationExpiration::WithHistory,
+            6 => AnnotationExpiration::Days,
+            _ => return None,
+        })
+    }
+
+    fn max_age(self) -> Option<Duration> {
+        const DAY: u64 = 24 * 60 * 60;
+        match self {
+            AnnotationExpiration::Days => Some(Duration::from_secs(7 * DAY)),
+            AnnotationExpiration::Weeks => Some(Duration::from_secs(30 * DAY)),
+            AnnotationExpiration::Months => Some(Duration::from_secs(180 * DAY)),
+            AnnotationExpiration::Never | AnnotationExpiration::WithHistory => None,
+        }
+    }
+}
+
+impl FromSql for AnnotationExpiration {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        let v = value.as_i64()?;
+        if v < 0 || v > i64::from(u8::max_value()) {
+            return Err(FromSqlError::OutOfRange(v));
+        }
+        // Desktop's old session expiration (0) doesn't exist anymore; treat
+        // unknown policies as `Never` rather than failing the whole query.
+        Ok(AnnotationExpiration::from_u8(v as u8).unwrap_or(AnnotationExpiration::Never))
+    }
+}
+
+impl ToSql for AnnotationExpiration {
+    fn to_sql(&self) -> RusqliteResult<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(*self as u8))
+    }
+}
+
+/// What an annotation is attached to.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AnnotationTarget {
+    Page(Url),
+    /// A bookmark, folder or separator, identified by its GUID.
+    Item(SyncGuid),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Annotation {
+    pub target: AnnotationTarget,
+    pub name: String,
+    pub value: AnnotationValue,
+    pub expiration: AnnotationExpiration,
+    pub date_added: Timestamp,
+    pub last_modified: Timestamp,
+}
+
+impl Annotation {
+    fn from_row(row: &Row<'_>) -> Result<Self> {
+        let target = match row.get::<_, Option<String>>("page_url")? {
+            Some(url) => AnnotationTarget::Page(Url::parse(&url)?),
+            None => AnnotationTarget::Item(row.get("item_guid")?),
+        };
+        Ok(Self {
+            target,
+            name: row.get("name")?,
+            value: AnnotationValue::from_content(row.get("type")?, row.get("content")?),
+            expiration: row.get("expiration")?,
+            date_added: row.get("dateAdded")?,
+            last_modified: row.get("lastModified")?,
+        })
+    }
+}
+
+const PAGE_ANNOS_SQL: &str = "
+    SELECT h.url AS page_url, NULL AS item_guid, n.name, a.content, a.type,
+           a.expiration, a.dateAdded, a.lastModified
+    FROM moz_annos a
+    JOIN moz_anno_attributes n ON n.id = a.anno_attribute_id
+    JOIN moz_places h ON h.id = a.place_id";
+
+const ITEM_ANNOS_SQL: &str = "
+    SELECT NULL AS page_url, b.guid AS item_guid, n.name, a.content, a.type,
+           a.expiration, a.dateAdded, a.lastModified
+    FROM moz_items_annos a
+    JOIN moz_anno_attributes n ON n.id = a.anno_attribute_id
+    JOIN moz_bookmarks b ON b.id = a.item_id";
+
+fn validate_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.len() > ANNOTATION_NAME_MAX {
+        return Err(ErrorKind::InvalidPlaceInfo(InvalidPlaceInfo::InvalidAnnotationName).into());
+    }
+    Ok(())
+}
+
+/// Sets an annotation, replacing any existing annotation with the same name
+/// on the same target. Annotating a page that isn't in history adds it, like
+/// bookmarking it would; annotating an unknown item is an error.
+pub fn set_annotation(
+    db: &PlacesDb,
+    target: &AnnotationTarget,
+    name: &str,
+    value: &AnnotationValue,
+    expiration: AnnotationExpiration,
+) -> Result<()> {
+    validate_name(name)?;
+    let tx = db.begin_transaction()?;
+    set_annotation_in_tx(db, target, name, value, expiration)?;
+    tx.commit()?;
+    Ok(())
+}
+
+pub(crate) fn set_annotation_in_tx(
+    db: &PlacesDb,
+    target: &AnnotationTarget,
+    name: &str,
+    value: &AnnotationValue,
+    expiration: AnnotationExpiration,
+) -> Result<()> {
+    let now = Timestamp::now();
+    db.execute_named_cached(
+        "INSERT OR IGNORE INTO moz_anno_attributes(name) VALUES(:name)",
+        &[(":name", &name)],
+    )?;
+    let (table, id_column, target_id) = match target {
+        AnnotationTarget::Page(url) => {
+            let place_id = match fetch_page_info(db, url)? {
+                Some(info) => info.page.row_id,
+                None => {
+                    let row_id = new_page_info(db, url, None)?.row_id;
+                    delete_pending_temp_tables(db)?;
+                    row_id
+                }
+            };
+            ("moz_annos", "place_id", place_id)
+        }
+        AnnotationTarget::Item(guid) => {
+            let item_id = item_row_id(db, guid)?.ok_or_else(|| {
+                ErrorKind::InvalidPlaceInfo(InvalidPlaceInfo::NoSuchGuid(guid.to_string()))
+            })?;
+            ("moz_items_annos", "item_id", item_id)
+        }
+    };
+    db.execute_named_cached(
+        &format!(
+            "INSERT INTO {table}({id_column}, anno_attribute_id, content, expiration,
+                                 type, dateAdded, lastModified)
+             VALUES(:target_id,
+                    (SELECT id FROM moz_anno_attributes WHERE name = :name),
+                    :content, :expiration, :type, :now, :now)
+             ON CONFLICT({id_column}, anno_attribute_id) DO UPDATE SET
+                 content = excluded.content,
+                 expiration = excluded.expiration,
+                 type = excluded.type,
+                 lastModified = excluded.lastModified",
+            table = table,
+            id_column = id_column
+        ),
+        &[
+            (":target_id", &target_id),
+            (":name", &name),
+            (":content", value),
+            (":expiration", &expiration),
+            (":type", &value.type_code()),
+            (":now", &now),
+        ],
+    )?;
+    Ok(())
+}
+
+fn item_row_id(db: &PlacesDb, guid: &SyncGuid) -> Result<Option<RowId>> {
+    db.try_query_row(
+        "SELECT id FROM moz_bookmarks WHERE guid = :guid",
+        &[(":guid", guid)],
+        |row| row.get::<_, RowId>(0),
+        true,
+    )
+}
+
+// Returns the SQL selecting a target's annotations, along with its
+// parameter. Pages are matched by URL, and items by GUID.
+fn target_query(target: &AnnotationTarget) -> (String, String) {
+    match target {
+        AnnotationTarget::Page(url) => (
+            format!(
+                "{} WHERE h.url_hash = hash(:target) AND h.url = :target",
+                PAGE_ANNOS_SQL
+            ),
+            url.as_str().to_owned(),
+        ),
+        AnnotationTarget::Item(guid) => (
+            format!("{} WHERE b.guid = :target", ITEM_ANNOS_SQL),
+            guid.as_str().to_owned(),
+        ),
+    }
+}
+
+/// Returns the annotation with the given name on a page or item.
+pub fn get_annotation(
+    db: &PlacesDb,
+    target: &AnnotationTarget,
+    name: &str,
+) -> Result<Option<Annotation>> {
+    let (sql, target) = target_query(target);
+    db.try_query_row(
+        &format!("{} AND n.name = :name", sql),
+        &[(":target", &target), (":name", &name)],
+        Annotation::from_row,
+        true,
+    )
+}
+
+/// Returns all annotations on a page or item, ordered by name.
+pub fn get_annotations(db: &PlacesDb, target: &AnnotationTarget) -> Result<Vec<Annotation>> {
+    let (sql, target) = target_query(target);
+    db.query_rows_and_then_named_cached(
+        &format!("{} ORDER BY n.name", sql),
+        &[(":target", &target)],
+        Annotation::from_row,
+    )
+}
+
+/// Returns every page and item annotation with the given name. Page
+/// annotations come first, then item annotations, each in the order they
+/// were added.
+pub fn get_annotations_with_name(db: &PlacesDb, name: &str) -> Result<Vec<Annotation>> {
+    let mut annos = db.query_rows_and_then_named_cached(
+        &format!("{} WHERE n.name = :name ORDER BY a.id", PAGE_ANNOS_SQL),
+        &[(":name", &name)],
+        Annotation::from_row,
+    )?;
+    annos.extend(db.query_rows_and_then_named_cached(
+        &format!("{} WHERE n.name = :name ORDER BY a.id", ITEM_ANNOS_SQL),
+        &[(":name", &name)],
+        Annotation::from_row,
+    )?);
+    Ok(annos)
+}
+
+fn delete_target_sql(target: &AnnotationTarget) -> (&'static str, String) {
+    match target {
+        AnnotationTarget::Page(url) => (
+            "DELETE FROM moz_annos
+             WHERE place_id = (SELECT id FROM moz_places
+                               WHERE url_hash = hash(:target) AND url = :target)",
+            url.as_str().to_owned(),
+        ),
+        AnnotationTarget::Item(guid) => (
+            "DELETE FROM moz_items_annos
+             WHERE item_id = (SELECT id FROM moz_bookmarks WHERE guid = :target)",
+            guid.as_str().to_owned(),
+        ),
+    }
+}
+
+/// Removes the annotation with the given name from a page or item. Returns
+/// whether there was one to remove.
+pub fn remove_annotation(db: &PlacesDb, target: &AnnotationTarget, name: &str) -> Result<bool> {
+    let (sql, target) = delete_target_sql(target);
+    let removed = db.execute_named_cached(
+        &format!(
+            "{} AND anno_attribute_id = (SELECT id FROM moz_anno_attributes
+                                         WHERE name = :name)",
+            sql
+        ),
+        &[(":target", &target), (":name", &name)],
+    )?;
+    Ok(removed > 0)
+}
+
+/// Removes all annotations from a page or item. Returns the number removed.
+pub fn remove_annotations(db: &PlacesDb, target: &AnnotationTarget) -> Result<usize> {
+    let (sql, target) = delete_target_sql(target);
+    Ok(db.execute_named_cached(sql, &[(":target", &target)])?)
+}
+
+/// Removes annotations whose expiration policy says they should be gone by
+/// `now`, and annotation names which are no longer used. Returns the number
+/// of annotations removed. This is called as part of `run_maintenance`.
+pub fn expire_annotations(db: &PlacesDb, now: Timestamp) -> Result<usize> {
+    let tx = db.begin_transaction()?;
+    let mut removed = 0;
+    for &expiration in &[
+        AnnotationExpiration::Days,
+        AnnotationExpiration::Weeks,
+        AnnotationExpiration::Months,
+    ] {
+        let max_age = expiration
+            .max_age()
+            .expect("time-based policies have an age");
+        let cutoff = now.checked_sub(max_age).unwrap_or_default();
+        for table in &["moz_annos", "moz_items_annos"] {
+            removed += db.execute_named_cached(
+                &format!(
+                    "DELETE FROM {}
+                     WHERE expiration = :expiration AND lastModified < :cutoff",
+                    table
+                ),
+                &[(":expiration", &expiration), (":cutoff", &cutoff)],
+            )?;
+        }
+    }
+    removed += db.execute_named_cached(
+        "DELETE FROM moz_annos
+         WHERE expiration = :expiration
+           AND place_id IN (SELECT id FROM moz_places
+                            WHERE last_visit_date_local = 0
+                              AND last_visit_date_remote = 0)",
+        &[(":expiration", &AnnotationExpiration::WithHistory)],
+    )?;
+    db.execute_batch(
+        "DELETE FROM moz_anno_attributes
+         WHERE NOT EXISTS(SELECT 1 FROM moz_annos
+                          WHERE anno_attribute_id = moz_anno_attributes.id)
+           AND NOT EXISTS(SELECT 1 FROM moz_items_annos
+                          WHERE anno_attribute_id = moz_anno_attributes.id)",
+    )?;
+    tx.commit()?;
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::places_api::test::new_mem_connection;
+    use crate::storage::bookmarks::{
+        delete_bookmark, insert_bookmark, BookmarkPosition, BookmarkRootGuid, InsertableBookmark,
+        InsertableItem,
+    };
+    use pretty_assertions::assert_eq;
+
+    fn page(url: &str) -> AnnotationTarget {
+        AnnotationTarget::Page(Url::parse(url).unwrap())
+    }
+
+    fn text(s: &str) -> AnnotationValue {
+        AnnotationValue::Text(s.into())
+    }
+
+    fn count(conn: &PlacesDb, table: &str) -> i64 {
+        conn.query_one::<i64>(&format!("SELECT COUNT(*) FROM {}", table))
+            .unwrap()
+    }
+
+    fn insert_test_bookmark(conn: &PlacesDb, url: &str) -> SyncGuid {
+        insert_bookmark(
+            conn,
+            &InsertableItem::Bookmark(InsertableBookmark {
+                parent_guid: BookmarkRootGuid::Unfiled.into(),
+                position: BookmarkPosition::Append,
+                date_added: None,
+                last_modified: None,
+                guid: None,
+                url: Url::parse(url).unwrap(),
+                title: None,
+            }),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_page_annotations() {
+        let conn = new_mem_connection();
+        let target = page("https://www.example.com/");
+        assert!(get_annotation(&conn, &target, "reader/progress")
+            .unwrap()
+            .is_none());
+
+        set_annotation(
+            &conn,
+            &target,
+            "reader/progress",
+            &AnnotationValue::Double(0.5),
+            AnnotationExpiration::Never,
+        )
+        .unwrap();
+        set_annotation(
+            &conn,
+            &target,
+            "reader/added",
+            &AnnotationValue::Integer(1_500_000_000_000),
+            AnnotationExpiration::Never,
+        )
+        .unwrap();
+        // Annotating a page adds it, like a bookmark would.
+        assert_eq!(count(&conn, "moz_places"), 1);
+
+        let anno = get_annotation(&conn, &target, "reader/progress")
+            .unwrap()
+            .expect("should have annotation");
+        assert_eq!(anno.target, target);
+        assert_eq!(anno.value, AnnotationValue::Double(0.5));
+        assert_eq!(anno.expiration, AnnotationExpiration::Never);
+
+        // Setting it again replaces the value and type, but keeps the date
+        // it was added.
+        set_annotation(
+            &conn,
+            &target,
+            "reader/progress",
+            &text("done"),
+            AnnotationExpiration::Months,
+        )
+        .unwrap();
+        let updated = get_annotation(&conn, &target, "reader/progress")
+            .unwrap()
+            .unwrap();
+        assert_eq!(updated.value, text("done"));
+        assert_eq!(updated.expiration, AnnotationExpiration::Months);
+        assert_eq!(updated.date_added, anno.date_added);
+
+        let names = get_annotations(&conn, &target)
+            .unwrap()
+            .into_iter()
+            .map(|a| a.name)
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["reader/added", "reader/progress"]);
+
+        assert!(remove_annotation(&conn, &target, "reader/added").unwrap());
+        assert!(!remove_annotation(&conn, &target, "reader/added").unwrap());
+        assert_eq!(remove_annotations(&conn, &target).unwrap(), 1);
+        assert_eq!(count(&conn, "moz_annos"), 0);
+    }
+
+    #[test]
+    fn test_item_annotations() {
+        let conn = new_mem_connection();
+        let guid = insert_test_bookmark(&conn, "https://www.example.com/");
+        let target = AnnotationTarget::Item(guid.clone());
+
+        set_annotation(
+            &conn,
+            &target,
+            DESCRIPTION_ANNO,
+            &text("A description"),
+            AnnotationExpiration::Never,
+        )
+        .unwrap();
+        let anno = get_annotation(&conn, &target, DESCRIPTION_ANNO)
+            .unwrap()
+            .unwrap();
+        assert_eq!(anno.value, text("A description"));
+
+        // Item annotations are separate from the bookmarked page's.
+        assert!(get_annotations(&conn, &page("https://www.example.com/"))
+            .unwrap()
+            .is_empty());
+
+        // Unknown items can't be annotated.
+        let err = set_annotation(
+            &conn,
+            &AnnotationTarget::Item(SyncGuid::random()),
+            DESCRIPTION_ANNO,
+            &text("nope"),
+            AnnotationExpiration::Never,
+        )
+        .expect_err("should fail for an unknown item");
+        match err.kind() {
+            ErrorKind::InvalidPlaceInfo(InvalidPlaceInfo::NoSuchGuid(_)) => {}
+            e => panic!("Unexpected error: {:?}", e),
+        }
+
+        // Removing the bookmark removes its annotations.
+        delete_bookmark(&conn, &guid).unwrap();
+        assert_eq!(count(&conn, "moz_items_annos"), 0);
+    }
+
+    #[test]
+    fn test_annotations_with_name() {
+        let conn = new_mem_connection();
+        let guid = insert_test_bookmark(&conn, "https://www.example.com/1");
+        for target in &[
+            page("https://www.example.com/2"),
+            AnnotationTarget::Item(guid.clone()),
+            page("https://www.example.com/3"),
+        ] {
+            set_annotation(
+                &conn,
+                target,
+                "test/anno",
+                &AnnotationValue::Integer(1),
+                AnnotationExpiration::Never,
+            )
+            .unwrap();
+        }
+        set_annotation(
+            &conn,
+            &page("https://www.example.com/4"),
+            "test/other",
+            &AnnotationValue::Integer(1),
+            AnnotationExpiration::Never,
+        )
+        .unwrap();
+
+        let targets = get_annotations_with_name(&conn, "test/anno")
+            .unwrap()
+            .into_iter()
+            .map(|a| a.target)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            targets,
+            vec![
+                page("https://www.example.com/2"),
+                page("https://www.example.com/3"),
+                AnnotationTarget::Item(guid),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_wipe_local_keeps_annotated_pages() {
+        let conn = new_mem_connection();
+        let kept = page("https://www.example.com/1");
+        let removed = page("https://www.example.com/2");
+        set_annotation(
+            &conn,
+            &kept,
+            "test/anno",
+            &text("value"),
+            AnnotationExpiration::Never,
+        )
+        .unwrap();
+        set_annotation(
+            &conn,
+            &removed,
+            "test/anno",
+            &text("value"),
+            AnnotationExpiration::WithHistory,
+        )
+        .unwrap();
+
+        crate::storage::history::wipe_local(&conn).unwrap();
+        assert!(get_annotation(&conn, &kept, "test/anno").unwrap().is_some());
+        assert!(get_annotation(&conn, &removed, "test/anno")
+            .unwrap()
+            .is_none());
+        assert_eq!(count(&conn, "moz_places"), 1);
+    }
+
+    #[test]
+    fn test_invalid_name() {
+        let conn = new_mem_connection();
+        for name in &["".to_string(), "a".repeat(ANNOTATION_NAME_MAX + 1)] {
+            let err = set_annotation(
+                &conn,
+                &page("https://www.example.com/"),
+                name,
+                &text("value"),
+                AnnotationExpiration::Never,
+            )
+            .expect_err("should reject invalid name");
+            match err.kind() {
+                ErrorKind::InvalidPlaceInfo(InvalidPlaceInfo::InvalidAnnotationName) => {}
+                e => panic!("Unexpected error: {:?}", e),
+            }
+        }
+    }
+
+    #[test]
+    fn test_imported_content() {
+        let conn = new_mem_connection();
+        let target = page("https://www.example.com/");
+        set_annotation(&conn, &target, "a", &text(""), AnnotationExpiration::Never).unwrap();
+        set_annotation(&conn, &target, "b", &text(""), AnnotationExpiration::Never).unwrap();
+        // Desktop and Fennec store numbers as text.
+        conn.execute_batch(
+            "UPDATE moz_annos SET content = '42', type = 5
+             WHERE anno_attribute_id = (SELECT id FROM moz_anno_attributes WHERE name = 'a');
+             UPDATE moz_annos SET content = 'not a number', type = 2
+             WHERE anno_attribute_id = (SELECT id FROM moz_anno_attributes WHERE name = 'b');",
+        )
+        .unwrap();
+        let values = get_annotations(&conn, &target)
+            .unwrap()
+            .into_iter()
+            .map(|a| a.value)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            values,
+            vec![AnnotationValue::Integer(42), text("not a number")]
+        );
+    }
+
+    #[test]
+    fn test_expire_annotations() {
+        let conn = new_mem_connection();
+        let guid = insert_test_bookmark(&conn, "https://www.example.com/bookmarked");
+        let item = AnnotationTarget::Item(guid);
+        let visited = page("https://www.example.com/visited");
+        let unvisited = page("https://www.example.com/unvisited");
+
+        set_annotation(&conn, &item, "days", &text(""), AnnotationExpiration::Days).unwrap();
+        set_annotation(
+            &conn,
+            &item,
+            "months",
+            &text(""),
+            AnnotationExpiration::Months,
+        )
+        .unwrap();
+        set_annotation(
+            &conn,
+            &item,
+            "never",
+            &text(""),
+            AnnotationExpiration::Never,
+        )
+        .unwrap();
+        set_annotation(
+            &conn,
+            &visited,
+            "history",
+            &text(""),
+            AnnotationExpiration::WithHistory,
+        )
+        .unwrap();
+        set_annotation(
+            &conn,
+            &unvisited,
+            "history",
+            &text(""),
+            AnnotationExpiration::WithHistory,
+        )
+        .unwrap();
+        conn.execute_batch(
+            "UPDATE moz_places SET last_visit_date_local = 1
+             WHERE url = 'https://www.example.com/visited'",
+        )
+        .unwrap();
+
+        let in_ten_days = Timestamp(Timestamp::now().0 + 10 * 24 * 60 * 60 * 1000);
+        assert_eq!(expire_annotations(&conn, in_ten_days).unwrap(), 2);
+
+        let names = get_annotations(&conn, &item)
+            .unwrap()
+            .into_iter()
+            .map(|a| a.name)
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["months", "never"]);
+        assert!(get_annotation(&conn, &visited, "history")
+            .unwrap()
+            .is_some());
+        assert!(get_annotation(&conn, &unvisited, "history")
+            .unwrap()
+            .is_none());
+        // Unused names are cleaned up.
+        assert_eq!(count(&conn, "moz_anno_attributes"), 3);
+    }
+}
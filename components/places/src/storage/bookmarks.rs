@@ -11,6 +11,7 @@ use crate::bookmark_sync::engine::{
 use crate::db::PlacesDb;
 use crate::error::*;
 use crate::types::{BookmarkType, SyncStatus};
+use journal::JournalOp;
 use rusqlite::types::ToSql;
 use rusqlite::{self, Connection, Row};
 use serde::{
@@ -28,10 +29,14 @@ use sync_guid::Guid as SyncGuid;
 use types::Timestamp;
 use url::Url;
 
+pub use journal::{redo, undo};
 pub use public_node::PublicNode;
 pub use root_guid::{BookmarkRootGuid, USER_CONTENT_ROOTS};
 
+pub mod backup;
 mod conversions;
+pub mod duplicates;
+pub mod journal;
 pub mod public_node;
 mod root_guid;
 
@@ -249,7 +254,10 @@ impl InsertableItem {
 
 pub fn insert_bookmark(db: &PlacesDb, bm: &InsertableItem) -> Result<SyncGuid> {
     let tx = db.begin_transaction()?;
-    let result = insert_bookmark_in_tx(db, bm);
+    let result = insert_bookmark_in_tx(db, bm).and_then(|guid| {
+        journal::record(db, vec![JournalOp::Remove { guid: guid.clone() }])?;
+        Ok(guid)
+    });
     super::delete_pending_temp_tables(db)?;
     match result {
         Ok(_) => tx.commit()?,
@@ -264,7 +272,7 @@ pub fn maybe_truncate_title<'a>(t: &Option<&'a str>) -> Option<&'a str> {
     t.map(|title| slice_up_to(title, TITLE_LENGTH_MAX))
 }
 
-fn insert_bookmark_in_tx(db: &PlacesDb, bm: &InsertableItem) -> Result<SyncGuid> {
+pub(crate) fn insert_bookmark_in_tx(db: &PlacesDb, bm: &InsertableItem) -> Result<SyncGuid> {
     // find the row ID of the parent.
     if bm.parent_guid() == BookmarkRootGuid::Root {
         return Err(InvalidPlaceInfo::CannotUpdateRoot(BookmarkRootGuid::Root).into());
@@ -372,7 +380,13 @@ fn insert_bookmark_in_tx(db: &PlacesDb, bm: &InsertableItem) -> Result<SyncGuid>
 /// existed and was deleted, false otherwise.
 pub fn delete_bookmark(db: &PlacesDb, guid: &SyncGuid) -> Result<bool> {
     let tx = db.begin_transaction()?;
-    let result = delete_bookmark_in_tx(db, guid);
+    let result = JournalOp::inverse_of_remove(db, guid).and_then(|inverse| {
+        let deleted = delete_bookmark_in_tx(db, guid)?;
+        if let Some(inverse) = inverse {
+            journal::record(db, vec![inverse])?;
+        }
+        Ok(deleted)
+    });
     match result {
         Ok(_) => tx.commit()?,
         Err(_) => tx.rollback()?,
@@ -380,7 +394,7 @@ pub fn delete_bookmark(db: &PlacesDb, guid: &SyncGuid) -> Result<bool> {
     result
 }
 
-fn delete_bookmark_in_tx(db: &PlacesDb, guid: &SyncGuid) -> Result<bool> {
+pub(crate) fn delete_bookmark_in_tx(db: &PlacesDb, guid: &SyncGuid) -> Result<bool> {
     // Can't delete a root.
     if let Some(root) = BookmarkRootGuid::well_known(guid.as_str()) {
         return Err(InvalidPlaceInfo::CannotUpdateRoot(root).into());
@@ -495,14 +509,16 @@ pub fn update_bookmark(db: &PlacesDb, guid: &SyncGuid, item: &UpdatableItem) ->
     let tx = db.begin_transaction()?;
     let existing = get_raw_bookmark(db, guid)?
         .ok_or_else(|| InvalidPlaceInfo::NoSuchGuid(guid.to_string()))?;
-    let result = update_bookmark_in_tx(db, guid, item, existing);
+    let inverse = JournalOp::inverse_of_update(&existing, item);
+    let result = update_bookmark_in_tx(db, guid, item, existing)
+        .and_then(|_| journal::record(db, vec![inverse]));
     super::delete_pending_temp_tables(db)?;
     // Note: `tx` automatically rolls back on drop if we don't commit
     tx.commit()?;
     result
 }
 
-fn update_bookmark_in_tx(
+pub(crate) fn update_bookmark_in_tx(
     db: &PlacesDb,
     guid: &SyncGuid,
     item: &UpdatableItem,
@@ -908,7 +924,8 @@ impl<'de> Deserialize<'de> for BookmarkTreeNode {
     }
 }
 
-/// Get the URL of the bookmark matching a keyword
+/// Get the URL of the bookmark matching a keyword, without substituting any
+/// search terms. Use `keywords::resolve_keyword_search` for keyword searches.
 pub fn bookmarks_get_url_for_keyword(db: &PlacesDb, keyword: &str) -> Result<Option<Url>> {
     let bookmark_url = db.try_query_row(
         "SELECT h.url FROM moz_keywords k
@@ -932,6 +949,18 @@ pub fn bookmarks_get_url_for_keyword(db: &PlacesDb, keyword: &str) -> Result<Opt
     }
 }
 
+/// Get the keyword for a bookmarked URL, if it has one.
+pub fn bookmarks_get_keyword_for_url(db: &PlacesDb, url: &Url) -> Result<Option<String>> {
+    Ok(db.try_query_row(
+        "SELECT k.keyword FROM moz_keywords k
+         JOIN moz_places h ON h.id = k.place_id
+         WHERE h.url_hash = hash(:url) AND h.url = :url",
+        &[(":url", &url.as_str())],
+        |row| row.get::<_, String>("keyword"),
+        true,
+    )?)
+}
+
 #[cfg(test)]
 mod test_serialize {
     use super::*;
@@ -1093,11 +1122,34 @@ pub fn delete_everything(db: &PlacesDb) -> Result<()> {
         BookmarkRootGuid::Unfiled.as_str(),
     ))?;
     reset_in_tx(db, &EngineSyncAssociation::Disconnected)?;
+    journal::clear_journal(db)?;
     tx.commit()?;
     Ok(())
 }
 
 pub fn insert_tree(db: &PlacesDb, tree: &FolderNode) -> Result<()> {
+    let tx = db.begin_transaction()?;
+    let guids = insert_tree_in_tx(db, tree)?;
+    // Removing the top-level items removes everything else, too. They need to
+    // be removed in the opposite order, so that restoring them on redo puts
+    // them back in the right positions.
+    journal::record(
+        db,
+        guids
+            .into_iter()
+            .rev()
+            .map(|guid| JournalOp::Remove { guid })
+            .collect(),
+    )?;
+    super::delete_pending_temp_tables(db)?;
+    tx.commit()?;
+    Ok(())
+}
+
+/// Like `insert_tree`, but the caller is responsible for the transaction and
+/// for calling `delete_pending_temp_tables` afterwards. Returns the GUIDs of
+/// the inserted children of `tree`, but not their descendants.
+pub(crate) fn insert_tree_in_tx(db: &PlacesDb, tree: &FolderNode) -> Result<Vec<SyncGuid>> {
     let parent_guid = match &tree.guid {
         Some(guid) => guid,
         None => return Err(InvalidPlaceInfo::InvalidParent("<no guid>".into()).into()),
@@ -1106,14 +1158,15 @@ pub fn insert_tree(db: &PlacesDb, tree: &FolderNode) -> Result<()> {
     let mut insert_infos: Vec<InsertableItem> = Vec::new();
     add_subtree_infos(parent_guid, tree, &mut insert_infos);
     log::info!("insert_tree inserting {} records", insert_infos.len());
-    let tx = db.begin_transaction()?;
 
+    let mut guids = Vec::with_capacity(tree.children.len());
     for insertable in insert_infos {
-        insert_bookmark_in_tx(db, &insertable)?;
+        let guid = insert_bookmark_in_tx(db, &insertable)?;
+        if insertable.parent_guid() == parent_guid {
+            guids.push(guid);
+        }
     }
-    super::delete_pending_temp_tables(db)?;
-    tx.commit()?;
-    Ok(())
+    Ok(guids)
 }
 
 #[derive(Debug)]
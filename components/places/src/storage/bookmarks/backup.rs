@@ -0,0 +1,955 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Bookmark backups in the same format Desktop writes to its
+//! `bookmarkbackups` directory, so backups can be moved between Desktop and
+//! our consumers.
+//!
+//! A backup is the entire tree as JSON, compressed with Desktop's `mozLz4`
+//! format and named `bookmarks-<date>_<count>_<hash>.jsonlz4`. The hash is of
+//! the uncompressed JSON, which lets us skip writing a backup that's identical
+//! to the most recent one.
+
+use super::journal::clear_journal;
+use super::{
+    bookmarks_get_keyword_for_url, fetch_tree, insert_tree_in_tx, BookmarkNode, BookmarkRootGuid,
+    BookmarkTreeNode, FetchDepth, FolderNode, SeparatorNode, USER_CONTENT_ROOTS,
+};
+use crate::db::PlacesDb;
+use crate::error::*;
+use crate::import::fennec::bookmarks::BookmarksMigrationResult;
+use crate::storage::keywords::{get_keyword, set_keyword_in_tx};
+use crate::storage::tags::{get_tags_for_url, tag_url_in_tx, validate_tag, ValidatedTag};
+use crate::storage::{delete_pending_temp_tables, URL_LENGTH_MAX};
+use crate::types::BookmarkType;
+use serde_derive::*;
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Instant, SystemTime};
+use sync_guid::Guid as SyncGuid;
+use types::Timestamp;
+use url::Url;
+
+mod md5;
+mod mozlz4;
+
+const BACKUP_PREFIX: &str = "bookmarks-";
+const COMPRESSED_SUFFIX: &str = ".jsonlz4";
+const UNCOMPRESSED_SUFFIX: &str = ".json";
+
+// The `type` Desktop writes alongside the `typeCode`. Very old backups only
+// have the `type`.
+const TYPE_BOOKMARK: &str = "text/x-moz-place";
+const TYPE_FOLDER: &str = "text/x-moz-place-container";
+const TYPE_SEPARATOR: &str = "text/x-moz-place-separator";
+
+// Backups from before Desktop stored tags as URL attributes have a tags
+// root, with a folder per tag.
+const LEGACY_TAGS_ROOT: &str = "tagsFolder";
+
+/// A node in a Desktop backup. This is a union of the fields for all node
+/// types; Desktop writes other fields (like `id` and `iconUri`) which we
+/// ignore.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct BackupNode {
+    guid: Option<String>,
+    title: String,
+    index: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    date_added: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_modified: Option<u64>,
+    type_code: u8,
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    root: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    uri: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tags: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    keyword: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    post_data: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    children: Option<Vec<BackupNode>>,
+}
+
+impl BackupNode {
+    fn bookmark_type(&self) -> Option<BookmarkType> {
+        BookmarkType::from_u8(self.type_code).or_else(|| match self.kind.as_str() {
+            TYPE_BOOKMARK => Some(BookmarkType::Bookmark),
+            TYPE_FOLDER => Some(BookmarkType::Folder),
+            TYPE_SEPARATOR => Some(BookmarkType::Separator),
+            _ => None,
+        })
+    }
+}
+
+fn root_name(root: BookmarkRootGuid) -> &'static str {
+    match root {
+        BookmarkRootGuid::Root => "placesRoot",
+        BookmarkRootGuid::Menu => "bookmarksMenuFolder",
+        BookmarkRootGuid::Toolbar => "toolbarFolder",
+        BookmarkRootGuid::Unfiled => "unfiledBookmarksFolder",
+        BookmarkRootGuid::Mobile => "mobileFolder",
+    }
+}
+
+fn root_for_node(node: &BackupNode) -> Option<BookmarkRootGuid> {
+    node.guid
+        .as_deref()
+        .and_then(BookmarkRootGuid::well_known)
+        .or_else(|| {
+            USER_CONTENT_ROOTS
+                .iter()
+                .copied()
+                .find(|root| node.root.as_deref() == Some(root_name(*root)))
+        })
+}
+
+// Desktop's timestamps are in microseconds.
+fn to_micros(ts: Timestamp) -> u64 {
+    ts.as_millis().saturating_mul(1000)
+}
+
+fn from_micros(micros: u64) -> Timestamp {
+    Timestamp(micros / 1000)
+}
+
+/// A serialized backup of the bookmarks tree.
+#[derive(Debug, Clone)]
+pub struct BookmarksBackup {
+    /// The backup, as uncompressed JSON.
+    pub json: String,
+    /// The number of items in the backup, not counting the roots.
+    pub item_count: u32,
+    /// The hash of `json`, as used in backup file names.
+    pub hash: String,
+}
+
+impl BookmarksBackup {
+    /// Returns the backup compressed in Desktop's `.jsonlz4` format.
+    pub fn compress(&self) -> Vec<u8> {
+        mozlz4::compress(self.json.as_bytes())
+    }
+}
+
+/// Serialize the entire bookmarks tree, including tags and keywords, in
+/// Desktop's backup format.
+pub fn backup_bookmarks(db: &PlacesDb) -> Result<BookmarksBackup> {
+    let tree = match fetch_tree(db, &BookmarkRootGuid::Root.into(), &FetchDepth::Deepest)? {
+        Some((tree, _, _)) => tree,
+        None => return Err(Corruption::InvalidLocalRoots.into()),
+    };
+    let mut item_count = 0;
+    let root = to_backup_node(db, &tree, 0, &mut item_count)?;
+    let json = serde_json::to_string(&root)?;
+    let hash = md5::backup_hash(json.as_bytes());
+    Ok(BookmarksBackup {
+        json,
+        item_count,
+        hash,
+    })
+}
+
+fn to_backup_node(
+    db: &PlacesDb,
+    node: &BookmarkTreeNode,
+    index: u32,
+    item_count: &mut u32,
+) -> Result<BackupNode> {
+    let (date_added, last_modified) = node.created_modified();
+    let mut backup_node = BackupNode {
+        guid: Some(node.guid().to_string()),
+        index,
+        date_added: Some(to_micros(date_added)),
+        last_modified: Some(to_micros(last_modified)),
+        type_code: node.node_type() as u8,
+        ..Default::default()
+    };
+    match BookmarkRootGuid::from_guid(node.guid()) {
+        Some(root) => backup_node.root = Some(root_name(root).to_string()),
+        None => *item_count += 1,
+    }
+    match node {
+        BookmarkTreeNode::Bookmark(b) => {
+            backup_node.kind = TYPE_BOOKMARK.into();
+            backup_node.title = b.title.clone().unwrap_or_default();
+            backup_node.uri = Some(b.url.to_string());
+            let mut tags = get_tags_for_url(db, &b.url)?;
+            if !tags.is_empty() {
+                tags.sort();
+                backup_node.tags = Some(tags.join(","));
+            }
+            if let Some(keyword) = bookmarks_get_keyword_for_url(db, &b.url)? {
+                backup_node.post_data = get_keyword(db, &keyword)?.and_then(|k| k.post_data);
+                backup_node.keyword = Some(keyword);
+            }
+        }
+        BookmarkTreeNode::Separator(_) => {
+            backup_node.kind = TYPE_SEPARATOR.into();
+        }
+        BookmarkTreeNode::Folder(f) => {
+            backup_node.kind = TYPE_FOLDER.into();
+            backup_node.title = f.title.clone().unwrap_or_default();
+            let mut children = Vec::with_capacity(f.children.len());
+            for (child_index, child) in f.children.iter().enumerate() {
+                children.push(to_backup_node(db, child, child_index as u32, item_count)?);
+            }
+            backup_node.children = Some(children);
+        }
+    }
+    Ok(backup_node)
+}
+
+/// A backup file in a backup directory.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BackupFile {
+    pub path: PathBuf,
+    /// The date the backup was made, as `YYYY-MM-DD`.
+    pub date: String,
+    /// The number of items in the backup. Only set if the file name includes
+    /// it.
+    pub item_count: Option<u32>,
+    /// The content hash. Only set if the file name includes it.
+    pub hash: Option<String>,
+    modified: SystemTime,
+}
+
+impl BackupFile {
+    fn from_path(path: PathBuf) -> Option<Self> {
+        let name = path.file_name()?.to_str()?;
+        let stem = name
+            .strip_suffix(COMPRESSED_SUFFIX)
+            .or_else(|| name.strip_suffix(UNCOMPRESSED_SUFFIX))?
+            .strip_prefix(BACKUP_PREFIX)?;
+        let mut parts = stem.split('_');
+        let date = parts.next()?.to_string();
+        if date.len() != 10 || !date.chars().all(|c| c.is_ascii_digit() || c == '-') {
+            return None;
+        }
+        let item_count = parts.next().and_then(|c| c.parse().ok());
+        let hash = parts.next().map(ToString::to_string);
+        let modified = fs::metadata(&path).and_then(|m| m.modified()).ok()?;
+        Some(Self {
+            path,
+            date,
+            item_count,
+            hash,
+            modified,
+        })
+    }
+}
+
+/// List the backups in a directory, newest first. A missing directory has no
+/// backups.
+pub fn list_backups(dir: impl AsRef<Path>) -> Result<Vec<BackupFile>> {
+    let entries = match fs::read_dir(dir.as_ref()) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut backups = Vec::new();
+    for entry in entries {
+        if let Some(backup) = BackupFile::from_path(entry?.path()) {
+            backups.push(backup);
+        }
+    }
+    backups.sort_by(|a, b| {
+        b.date
+            .cmp(&a.date)
+            .then_with(|| b.modified.cmp(&a.modified))
+    });
+    Ok(backups)
+}
+
+/// Write a compressed backup into `dir`, unless the most recent backup there
+/// is identical. Afterwards, the oldest backups are removed so that at most
+/// `max_backups` remain (but the new backup is always kept). Returns the new
+/// backup, or `None` if it was skipped.
+///
+/// This is intended to be called before risky operations, like the first
+/// bookmark sync, so there's always something to restore from.
+pub fn create_rolling_backup(
+    db: &PlacesDb,
+    dir: impl AsRef<Path>,
+    max_backups: usize,
+) -> Result<Option<BackupFile>> {
+    let dir = dir.as_ref();
+    let backup = backup_bookmarks(db)?;
+    let existing = list_backups(dir)?;
+    if existing.first().and_then(|b| b.hash.as_ref()) == Some(&backup.hash) {
+        log::info!("Skipping bookmark backup identical to the most recent one");
+        return Ok(None);
+    }
+
+    fs::create_dir_all(dir)?;
+    let file_name = format!(
+        "{}{}_{}_{}{}",
+        BACKUP_PREFIX,
+        format_date(Timestamp::now()),
+        backup.item_count,
+        backup.hash,
+        COMPRESSED_SUFFIX
+    );
+    // Write to a temporary file first, so we never leave a partial backup
+    // that looks like a real one.
+    let temp_path = dir.join(format!("{}.tmp", file_name));
+    fs::write(&temp_path, backup.compress())?;
+    let path = dir.join(&file_name);
+    fs::rename(&temp_path, &path)?;
+    log::info!("Wrote bookmark backup with {} items", backup.item_count);
+
+    for old in list_backups(dir)?.into_iter().skip(max_backups.max(1)) {
+        if old.path != path {
+            log::debug!("Removing old bookmark backup from {}", old.date);
+            fs::remove_file(&old.path)?;
+        }
+    }
+    Ok(BackupFile::from_path(path))
+}
+
+/// Formats a timestamp as a `YYYY-MM-DD` UTC date.
+fn format_date(ts: Timestamp) -> String {
+    // Howard Hinnant's `civil_from_days`.
+    let days = (ts.as_millis() / 1000 / 86_400) as i64;
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// Restore a backup file written by us or by Desktop, either compressed or
+/// not. See `restore_backup`.
+pub fn restore_backup_file(
+    db: &PlacesDb,
+    path: impl AsRef<Path>,
+) -> Result<BookmarksMigrationResult> {
+    let data = fs::read(path.as_ref())?;
+    restore_backup(db, &data)
+}
+
+/// Replace everything under the roots, along with all tags and keywords, with
+/// the contents of a backup. This happens in a single transaction.
+///
+/// Restored items keep their GUIDs from the backup. Everything is marked as
+/// changed, and items that were synced but aren't in the backup get
+/// tombstones, so the next sync uploads the restored tree.
+///
+/// Items we can't restore (bookmarks with invalid URLs, for example) are
+/// skipped and counted in `num_failed`.
+pub fn restore_backup(db: &PlacesDb, data: &[u8]) -> Result<BookmarksMigrationResult> {
+    let restore_start = Instant::now();
+    let json = if mozlz4::is_compressed(data) {
+        Cow::Owned(mozlz4::decompress(data)?)
+    } else {
+        Cow::Borrowed(data)
+    };
+    let root: BackupNode = serde_json::from_slice(&json)?;
+    if root_for_node(&root) != Some(BookmarkRootGuid::Root)
+        && root.root.as_deref() != Some(root_name(BookmarkRootGuid::Root))
+    {
+        return Err(ErrorKind::InvalidBackup("the backup isn't of the places root".into()).into());
+    }
+    let mut converter = RestoreConverter::default();
+    let roots = converter.convert_roots(root.children.unwrap_or_default());
+
+    let scope = db.begin_interrupt_scope();
+    let tx = db.begin_transaction()?;
+    // Removing synced items writes tombstones for them. Items in the backup
+    // with the same GUIDs have their tombstones removed when they're
+    // reinserted.
+    db.execute_batch(&format!(
+        "DELETE FROM moz_bookmarks
+         WHERE guid NOT IN ('{root}', '{menu}', '{mobile}', '{toolbar}', '{unfiled}');
+         DELETE FROM moz_keywords;
+         DELETE FROM moz_tags_relation;
+         DELETE FROM moz_tags;
+         UPDATE moz_bookmarks SET
+             syncChangeCounter = syncChangeCounter + 1
+         WHERE guid IN ('{menu}', '{mobile}', '{toolbar}', '{unfiled}');",
+        root = BookmarkRootGuid::Root.as_str(),
+        menu = BookmarkRootGuid::Menu.as_str(),
+        mobile = BookmarkRootGuid::Mobile.as_str(),
+        toolbar = BookmarkRootGuid::Toolbar.as_str(),
+        unfiled = BookmarkRootGuid::Unfiled.as_str(),
+    ))?;
+    scope.err_if_interrupted()?;
+
+    for root in &roots {
+        if !root.children.is_empty() {
+            log::debug!("Restoring bookmarks into {:?}", root.guid);
+            insert_tree_in_tx(db, root)?;
+            scope.err_if_interrupted()?;
+        }
+    }
+
+    log::debug!("Restoring tags and keywords");
+    for annotations in &converter.annotations {
+        let url = &annotations.url;
+        for tag in &annotations.tags {
+            match validate_tag(tag) {
+                ValidatedTag::Invalid(_) => log::warn!("Ignoring invalid tag"),
+                ValidatedTag::Normalized(t) | ValidatedTag::Original(t) => {
+                    tag_url_in_tx(db, url, t)?
+                }
+            }
+        }
+        if let Some(keyword) = &annotations.keyword {
+            match set_keyword_in_tx(db, url, keyword, annotations.post_data.as_deref()) {
+                Ok(()) => {}
+                // A backup can use the same keyword for more than one URL;
+                // the first one wins.
+                Err(e) if matches!(e.kind(), ErrorKind::InvalidPlaceInfo(_)) => {
+                    log::warn!("Ignoring keyword: {}", e)
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        scope.err_if_interrupted()?;
+    }
+    // The journal's entries don't apply to the restored tree.
+    clear_journal(db)?;
+    delete_pending_temp_tables(db)?;
+    tx.commit()?;
+
+    let metrics = BookmarksMigrationResult {
+        num_total: converter.num_total,
+        num_succeeded: converter.num_total - converter.num_failed,
+        num_failed: converter.num_failed,
+        total_duration: restore_start.elapsed().as_millis(),
+    };
+    log::info!("Successfully restored bookmarks: {:?}", metrics);
+    Ok(metrics)
+}
+
+/// The tags and keyword for a restored URL. Like Desktop, we apply these to
+/// URLs, not bookmarks, after inserting the tree.
+struct UrlAnnotations {
+    url: Url,
+    tags: Vec<String>,
+    keyword: Option<String>,
+    post_data: Option<String>,
+}
+
+/// Converts a backup into trees we can insert, collecting the tags and
+/// keywords to apply afterwards.
+#[derive(Default)]
+struct RestoreConverter {
+    seen_guids: HashSet<SyncGuid>,
+    annotations: Vec<UrlAnnotations>,
+    num_total: u32,
+    num_failed: u32,
+}
+
+impl RestoreConverter {
+    /// Returns a folder for each of `USER_CONTENT_ROOTS`, in that order.
+    fn convert_roots(&mut self, children: Vec<BackupNode>) -> Vec<FolderNode> {
+        let mut roots: Vec<FolderNode> = USER_CONTENT_ROOTS
+            .iter()
+            .map(|root| FolderNode {
+                guid: Some(root.as_guid()),
+                ..Default::default()
+            })
+            .collect();
+        for child in children {
+            let index = root_for_node(&child)
+                .and_then(|root| USER_CONTENT_ROOTS.iter().position(|r| *r == root));
+            match index {
+                Some(index) => {
+                    let converted = self.convert_children(child.children.unwrap_or_default());
+                    roots[index].children.extend(converted);
+                }
+                None if child.root.as_deref() == Some(LEGACY_TAGS_ROOT) => {
+                    self.convert_legacy_tags(child)
+                }
+                None => {
+                    // Something we don't expect at the top level; keep it
+                    // somewhere the user can find it.
+                    log::warn!("Restoring unexpected top-level item into unfiled");
+                    if let Some(node) = self.convert_node(child) {
+                        let unfiled = USER_CONTENT_ROOTS
+                            .iter()
+                            .position(|r| *r == BookmarkRootGuid::Unfiled)
+                            .expect("unfiled is a user content root");
+                        roots[unfiled].children.push(node);
+                    }
+                }
+            }
+        }
+        roots
+    }
+
+    fn convert_children(&mut self, children: Vec<BackupNode>) -> Vec<BookmarkTreeNode> {
+        children
+            .into_iter()
+            .filter_map(|child| self.convert_node(child))
+            .collect()
+    }
+
+    fn convert_node(&mut self, node: BackupNode) -> Option<BookmarkTreeNode> {
+        self.num_total += 1;
+        let guid = self.unique_guid(node.guid.as_deref());
+        let date_added = node.date_added.map(from_micros);
+        let last_modified = node.last_modified.map(from_micros);
+        let title = Some(node.title.clone()).filter(|title| !title.is_empty());
+        Some(match node.bookmark_type() {
+            Some(BookmarkType::Bookmark) => {
+                let url = match node.uri.as_deref().map(Url::parse) {
+                    Some(Ok(url)) if url.as_str().len() <= URL_LENGTH_MAX => url,
+                    _ => {
+                        log::warn!("Ignoring bookmark with a missing or invalid URL");
+                        self.num_failed += 1;
+                        return None;
+                    }
+                };
+                let tags: Vec<String> = node
+                    .tags
+                    .as_deref()
+                    .unwrap_or_default()
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|t| !t.is_empty())
+                    .map(ToString::to_string)
+                    .collect();
+                let keyword = node
+                    .keyword
+                    .map(|k| k.trim().to_lowercase())
+                    .filter(|k| !k.is_empty());
+                if !tags.is_empty() || keyword.is_some() {
+                    self.annotations.push(UrlAnnotations {
+                        url: url.clone(),
+                        tags,
+                        keyword,
+                        post_data: node.post_data,
+                    });
+                }
+                BookmarkNode {
+                    guid,
+                    date_added,
+                    last_modified,
+                    title,
+                    url,
+                }
+                .into()
+            }
+            Some(BookmarkType::Separator) => SeparatorNode {
+                guid,
+                date_added,
+                last_modified,
+            }
+            .into(),
+            Some(BookmarkType::Folder) => FolderNode {
+                guid,
+                date_added,
+                last_modified,
+                title,
+                children: self.convert_children(node.children.unwrap_or_default()),
+            }
+            .into(),
+            None => {
+                log::warn!("Ignoring item with unsupported type {}", node.type_code);
+                self.num_failed += 1;
+                return None;
+            }
+        })
+    }
+
+    /// Returns the GUID to use for a restored item, or `None` if we should
+    /// generate a new one because it's invalid, a root, or a duplicate.
+    fn unique_guid(&mut self, guid: Option<&str>) -> Option<SyncGuid> {
+        let guid = SyncGuid::from(guid?);
+        if !guid.is_valid_for_places()
+            || BookmarkRootGuid::from_guid(&guid).is_some()
+            || !self.seen_guids.insert(guid.clone())
+        {
+            return None;
+        }
+        Some(guid)
+    }
+
+    fn convert_legacy_tags(&mut self, tags_root: BackupNode) {
+        for tag_folder in tags_root.children.unwrap_or_default() {
+            let tag = tag_folder.title.trim().to_string();
+            for tagged in tag_folder.children.unwrap_or_default() {
+                if let Some(Ok(url)) = tagged.uri.as_deref().map(Url::parse) {
+                    self.annotations.push(UrlAnnotations {
+                        url,
+                        tags: vec![tag.clone()],
+                        keyword: None,
+                        post_data: None,
+                    });
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::places_api::test::new_mem_connection;
+    use crate::db::PlacesDb;
+    use crate::storage::bookmarks::bookmarks_get_url_for_keyword;
+    use crate::tests::{assert_json_tree, insert_json_tree};
+    use crate::types::SyncStatus;
+    use rusqlite::NO_PARAMS;
+    use serde_json::json;
+    use sql_support::ConnExt;
+
+    fn populate(conn: &PlacesDb) {
+        insert_json_tree(
+            conn,
+            json!({
+                "guid": &BookmarkRootGuid::Toolbar.as_guid(),
+                "children": [
+                    {
+                        "guid": "bookmark1___",
+                        "title": "the bookmark",
+                        "url": "https://www.example.com/"
+                    },
+                    {
+                        "guid": "folder1_____",
+                        "title": "A folder",
+                        "children": [
+                            {
+                                "guid": "separator1__",
+                                "type": BookmarkType::Separator as u8,
+                            },
+                            {
+                                "guid": "bookmark2___",
+                                "title": "another bookmark",
+                                "url": "https://www.example.org/"
+                            },
+                        ]
+                    },
+                ]
+            }),
+        );
+        let url = Url::parse("https://www.example.com/").unwrap();
+        crate::storage::tags::tag_url(conn, &url, "tag1").unwrap();
+        crate::storage::tags::tag_url(conn, &url, "tag2").unwrap();
+        crate::storage::keywords::set_keyword(conn, &url, "ex", None).unwrap();
+    }
+
+    #[test]
+    fn test_format_date() {
+        assert_eq!(format_date(Timestamp(0)), "1970-01-01");
+        assert_eq!(format_date(Timestamp(951_782_400_000)), "2000-02-29");
+        assert_eq!(format_date(Timestamp(1_634_515_200_000)), "2021-10-18");
+    }
+
+    #[test]
+    fn test_backup_format() -> Result<()> {
+        let conn = new_mem_connection();
+        populate(&conn);
+        let backup = backup_bookmarks(&conn)?;
+        assert_eq!(backup.item_count, 4);
+        assert_eq!(backup.hash.len(), 24);
+
+        let value: serde_json::Value = serde_json::from_str(&backup.json)?;
+        assert_eq!(value["guid"], "root________");
+        assert_eq!(value["root"], "placesRoot");
+        let toolbar = &value["children"][1];
+        assert_eq!(toolbar["root"], "toolbarFolder");
+        let bookmark = &toolbar["children"][0];
+        assert_eq!(bookmark["typeCode"], 1);
+        assert_eq!(bookmark["type"], TYPE_BOOKMARK);
+        assert_eq!(bookmark["uri"], "https://www.example.com/");
+        assert_eq!(bookmark["tags"], "tag1,tag2");
+        assert_eq!(bookmark["keyword"], "ex");
+        let folder = &toolbar["children"][1];
+        assert_eq!(folder["index"], 1);
+        assert_eq!(folder["children"][0]["type"], TYPE_SEPARATOR);
+
+        // Backing up again without changes gives the same hash.
+        assert_eq!(backup_bookmarks(&conn)?.hash, backup.hash);
+        Ok(())
+    }
+
+    #[test]
+    fn test_restore() -> Result<()> {
+        let conn = new_mem_connection();
+        populate(&conn);
+        let backup = backup_bookmarks(&conn)?;
+
+        // Pretend everything has been synced, then change the tree.
+        conn.execute(
+            "UPDATE moz_bookmarks SET syncChangeCounter = 0, syncStatus = 2",
+            NO_PARAMS,
+        )?;
+        crate::storage::bookmarks::delete_bookmark(&conn, &SyncGuid::from("folder1_____"))?;
+        insert_json_tree(
+            &conn,
+            json!({
+                "guid": &BookmarkRootGuid::Unfiled.as_guid(),
+                "children": [
+                    {
+                        "guid": "bookmark3___",
+                        "title": "not in the backup",
+                        "url": "https://www.example.net/"
+                    },
+                ]
+            }),
+        );
+
+        let result = restore_backup(&conn, &backup.compress())?;
+        assert_eq!(result.num_total, 4);
+        assert_eq!(result.num_succeeded, 4);
+        assert_eq!(result.num_failed, 0);
+
+        assert_json_tree(
+            &conn,
+            &BookmarkRootGuid::Toolbar.into(),
+            json!({
+                "guid": &BookmarkRootGuid::Toolbar.as_guid(),
+                "children": [
+                    {
+                        "guid": "bookmark1___",
+                        "title": "the bookmark",
+                        "url": "https://www.example.com/"
+                    },
+                    {
+                        "guid": "folder1_____",
+                        "title": "A folder",
+                        "children": [
+                            {
+                                "guid": "separator1__",
+                                "type": BookmarkType::Separator as u8,
+                            },
+                            {
+                                "guid": "bookmark2___",
+                                "title": "another bookmark",
+                                "url": "https://www.example.org/"
+                            },
+                        ]
+                    },
+                ]
+            }),
+        );
+        assert_json_tree(
+            &conn,
+            &BookmarkRootGuid::Unfiled.into(),
+            json!({
+                "guid": &BookmarkRootGuid::Unfiled.as_guid(),
+                "children": [],
+            }),
+        );
+        let url = Url::parse("https://www.example.com/")?;
+        let mut tags = get_tags_for_url(&conn, &url)?;
+        tags.sort();
+        assert_eq!(tags, vec!["tag1".to_string(), "tag2".to_string()]);
+        assert_eq!(bookmarks_get_url_for_keyword(&conn, "ex")?, Some(url));
+
+        // Everything restored needs to be uploaded, including the roots, and
+        // the tombstones for restored items are gone. "bookmark3___" was
+        // never synced, so it doesn't need one.
+        let unchanged: u32 = conn.query_one(
+            "SELECT COUNT(*) FROM moz_bookmarks
+             WHERE syncChangeCounter = 0 AND guid <> 'root________'",
+        )?;
+        assert_eq!(unchanged, 0);
+        let new_items: u32 = conn.query_row_and_then_named(
+            "SELECT COUNT(*) FROM moz_bookmarks WHERE syncStatus = :status",
+            &[(":status", &SyncStatus::New)],
+            |row| row.get(0),
+            false,
+        )?;
+        assert_eq!(new_items, 4);
+        let tombstones: u32 = conn.query_one("SELECT COUNT(*) FROM moz_bookmarks_deleted")?;
+        assert_eq!(tombstones, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_restore_desktop_backup() -> Result<()> {
+        let conn = new_mem_connection();
+        // A trimmed down, uncompressed backup as written by an old Desktop,
+        // with a tags root, a duplicate GUID and an invalid URL.
+        let backup = r#"{
+            "guid": "root________", "title": "", "index": 0, "id": 1,
+            "dateAdded": 1600000000000000, "lastModified": 1600000000000000,
+            "type": "text/x-moz-place-container", "root": "placesRoot",
+            "children": [
+                {
+                    "guid": "menu________", "title": "menu", "index": 0, "id": 2,
+                    "type": "text/x-moz-place-container", "root": "bookmarksMenuFolder",
+                    "children": [
+                        {
+                            "guid": "bookmarkAAAA", "title": "Mozilla", "index": 0,
+                            "dateAdded": 1600000001000000, "typeCode": 1,
+                            "uri": "https://www.mozilla.org/",
+                            "iconUri": "https://www.mozilla.org/favicon.ico"
+                        },
+                        {
+                            "guid": "bookmarkAAAA", "title": "Duplicate", "index": 1,
+                            "typeCode": 1, "uri": "https://example.com/"
+                        },
+                        {
+                            "guid": "bookmarkBBBB", "title": "Broken", "index": 2,
+                            "typeCode": 1, "uri": "not a url"
+                        }
+                    ]
+                },
+                {
+                    "guid": "tags________", "title": "tags", "index": 2,
+                    "type": "text/x-moz-place-container", "root": "tagsFolder",
+                    "children": [
+                        {
+                            "title": "mozilla", "type": "text/x-moz-place-container",
+                            "children": [
+                                { "typeCode": 1, "uri": "https://www.mozilla.org/" }
+                            ]
+                        }
+                    ]
+                }
+            ]
+        }"#;
+        let result = restore_backup(&conn, backup.as_bytes())?;
+        assert_eq!(result.num_total, 3);
+        assert_eq!(result.num_failed, 1);
+
+        let menu = match fetch_tree(&conn, &BookmarkRootGuid::Menu.into(), &FetchDepth::Deepest)? {
+            Some((BookmarkTreeNode::Folder(f), _, _)) => f,
+            _ => panic!("menu should be a folder"),
+        };
+        assert_eq!(menu.children.len(), 2);
+        assert_eq!(menu.children[0].guid(), "bookmarkAAAA");
+        assert_eq!(
+            menu.children[0].created_modified().0,
+            Timestamp(1_600_000_001_000)
+        );
+        // The duplicate got a new GUID.
+        assert_ne!(menu.children[1].guid(), "bookmarkAAAA");
+        assert_eq!(
+            get_tags_for_url(&conn, &Url::parse("https://www.mozilla.org/")?)?,
+            vec!["mozilla".to_string()]
+        );
+        Ok(())
+    }
+
+    fn foreign_count(conn: &PlacesDb, url: &str) -> Result<i64> {
+        Ok(conn.query_row_and_then_named(
+            "SELECT foreign_count FROM moz_places
+             WHERE url_hash = hash(:url) AND url = :url",
+            &[(":url", &url)],
+            |row| row.get(0),
+            false,
+        )?)
+    }
+
+    #[test]
+    fn test_restore_keywords() -> Result<()> {
+        let conn = new_mem_connection();
+        populate(&conn);
+        assert_eq!(foreign_count(&conn, "https://www.example.com/")?, 2);
+
+        // Two bookmarks for the same search URL, with a keyword and POST
+        // data, and a third that reuses the keyword for a different URL.
+        let backup = r#"{
+            "guid": "root________", "title": "", "index": 0,
+            "type": "text/x-moz-place-container", "root": "placesRoot",
+            "children": [
+                {
+                    "guid": "menu________", "title": "menu", "index": 0,
+                    "type": "text/x-moz-place-container", "root": "bookmarksMenuFolder",
+                    "children": [
+                        {
+                            "guid": "bookmarkAAAA", "title": "Search", "index": 0,
+                            "typeCode": 1, "uri": "https://example.com/search",
+                            "keyword": "s", "postData": "q=%s"
+                        },
+                        {
+                            "guid": "bookmarkBBBB", "title": "Search again", "index": 1,
+                            "typeCode": 1, "uri": "https://example.com/search",
+                            "keyword": "s", "postData": "q=%s"
+                        },
+                        {
+                            "guid": "bookmarkCCCC", "title": "Other", "index": 2,
+                            "typeCode": 1, "uri": "https://example.org/",
+                            "keyword": "s"
+                        }
+                    ]
+                }
+            ]
+        }"#;
+        restore_backup(&conn, backup.as_bytes())?;
+
+        let keyword = get_keyword(&conn, "s")?.expect("should restore keyword");
+        assert_eq!(keyword.url.as_str(), "https://example.com/search");
+        assert_eq!(keyword.post_data.as_deref(), Some("q=%s"));
+        assert_eq!(foreign_count(&conn, "https://example.com/search")?, 3);
+        assert_eq!(foreign_count(&conn, "https://example.org/")?, 1);
+        // The old bookmark and keyword are gone.
+        assert_eq!(foreign_count(&conn, "https://www.example.com/")?, 0);
+        assert_eq!(bookmarks_get_url_for_keyword(&conn, "ex")?, None);
+
+        // And the POST data round-trips through our own backups.
+        let value: serde_json::Value = serde_json::from_str(&backup_bookmarks(&conn)?.json)?;
+        let search = &value["children"][0]["children"][0];
+        assert_eq!(search["keyword"], "s");
+        assert_eq!(search["postData"], "q=%s");
+        Ok(())
+    }
+
+    #[test]
+    fn test_restore_invalid() {
+        let conn = new_mem_connection();
+        assert!(restore_backup(&conn, b"not json").is_err());
+        assert!(restore_backup(
+            &conn,
+            json!({"guid": "menu________"}).to_string().as_bytes()
+        )
+        .is_err());
+        assert!(restore_backup(&conn, b"mozLz40\0garbage").is_err());
+    }
+
+    #[test]
+    fn test_rolling_backups() -> Result<()> {
+        let conn = new_mem_connection();
+        let dir = tempfile::tempdir()?;
+        populate(&conn);
+
+        let first = create_rolling_backup(&conn, dir.path(), 2)?.expect("should write a backup");
+        assert_eq!(first.item_count, Some(4));
+        assert_eq!(first.date, format_date(Timestamp::now()));
+        // Nothing changed, so there's nothing to back up.
+        assert!(create_rolling_backup(&conn, dir.path(), 2)?.is_none());
+
+        let restored = restore_backup_file(&conn, &first.path)?;
+        assert_eq!(restored.num_succeeded, 4);
+
+        for i in 0..3 {
+            insert_json_tree(
+                &conn,
+                json!({
+                    "guid": &BookmarkRootGuid::Mobile.as_guid(),
+                    "children": [{
+                        "title": format!("bookmark {}", i),
+                        "url": format!("https://www.example.com/{}", i),
+                    }]
+                }),
+            );
+            create_rolling_backup(&conn, dir.path(), 2)?.expect("should write a backup");
+        }
+        let backups = list_backups(dir.path())?;
+        assert_eq!(backups.len(), 2);
+        assert_eq!(backups[0].item_count, Some(7));
+        assert_eq!(backups[1].item_count, Some(6));
+        // No temporary files left behind.
+        assert_eq!(fs::read_dir(dir.path())?.count(), 2);
+        Ok(())
+    }
+}
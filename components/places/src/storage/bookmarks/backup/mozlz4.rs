@@ -0,0 +1,219 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Desktop's `mozLz4` file format, used for `.jsonlz4` bookmark backups.
+//!
+//! A `mozLz4` file is an 8 byte magic number, the decompressed size as a
+//! little-endian `u32`, and then a single LZ4 block. We only need to handle
+//! files of a few megabytes, so this is a straightforward implementation of
+//! the LZ4 block format rather than a fast one.
+
+use crate::error::*;
+
+pub const MAGIC: &[u8] = b"mozLz40\0";
+
+const MIN_MATCH: usize = 4;
+// The LZ4 block format requires the last 5 bytes to be literals, and the last
+// match to start at least 12 bytes before the end of the block.
+const LAST_LITERALS: usize = 5;
+const MF_LIMIT: usize = 12;
+const MAX_OFFSET: usize = 65535;
+const HASH_LOG: u32 = 16;
+
+pub fn is_compressed(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+pub fn compress(input: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(MAGIC.len() + 4 + input.len() / 2);
+    out.extend_from_slice(MAGIC);
+    out.extend_from_slice(&(input.len() as u32).to_le_bytes());
+
+    let len = input.len();
+    let mut anchor = 0;
+    if len > MF_LIMIT {
+        let mut table = vec![usize::MAX; 1 << HASH_LOG];
+        let match_limit = len - MF_LIMIT;
+        let mut i = 0;
+        while i < match_limit {
+            let seq = read_u32(input, i);
+            let hash = (seq.wrapping_mul(2_654_435_761) >> (32 - HASH_LOG)) as usize;
+            let candidate = table[hash];
+            table[hash] = i;
+            if candidate != usize::MAX
+                && i - candidate <= MAX_OFFSET
+                && read_u32(input, candidate) == seq
+            {
+                let mut match_len = MIN_MATCH;
+                while i + match_len < len - LAST_LITERALS
+                    && input[candidate + match_len] == input[i + match_len]
+                {
+                    match_len += 1;
+                }
+                write_sequence(
+                    &mut out,
+                    &input[anchor..i],
+                    Some((i - candidate, match_len)),
+                );
+                i += match_len;
+                anchor = i;
+            } else {
+                i += 1;
+            }
+        }
+    }
+    write_sequence(&mut out, &input[anchor..], None);
+    out
+}
+
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>> {
+    if !is_compressed(data) || data.len() < MAGIC.len() + 4 {
+        return Err(corrupt());
+    }
+    let mut size_bytes = [0u8; 4];
+    size_bytes.copy_from_slice(&data[MAGIC.len()..MAGIC.len() + 4]);
+    let size = u32::from_le_bytes(size_bytes) as usize;
+    let src = &data[MAGIC.len() + 4..];
+
+    // Don't trust the size in the header enough to allocate more than the
+    // data could possibly decompress to.
+    let mut out: Vec<u8> = Vec::with_capacity(size.min(src.len().saturating_mul(255)));
+    let mut i = 0;
+    while i < src.len() {
+        let token = src[i];
+        i += 1;
+
+        let literal_len = read_length(src, &mut i, (token >> 4) as usize)?;
+        let literals = src.get(i..i + literal_len).ok_or_else(corrupt)?;
+        out.extend_from_slice(literals);
+        i += literal_len;
+        if i == src.len() {
+            // The last sequence has no match.
+            break;
+        }
+
+        let offset = match src.get(i..i + 2) {
+            Some(b) => b[0] as usize | (b[1] as usize) << 8,
+            None => return Err(corrupt()),
+        };
+        i += 2;
+        if offset == 0 || offset > out.len() {
+            return Err(corrupt());
+        }
+        let match_len = read_length(src, &mut i, (token & 0xF) as usize)? + MIN_MATCH;
+        if out.len() + match_len > size {
+            return Err(corrupt());
+        }
+        // Matches may overlap the bytes they produce, so copy byte by byte.
+        let start = out.len() - offset;
+        for j in 0..match_len {
+            let b = out[start + j];
+            out.push(b);
+        }
+    }
+    if out.len() != size {
+        return Err(corrupt());
+    }
+    Ok(out)
+}
+
+fn corrupt() -> Error {
+    ErrorKind::InvalidBackup("invalid mozLz4 data".into()).into()
+}
+
+fn read_u32(input: &[u8], pos: usize) -> u32 {
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(&input[pos..pos + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn read_length(src: &[u8], i: &mut usize, initial: usize) -> Result<usize> {
+    let mut len = initial;
+    if initial == 15 {
+        loop {
+            let b = *src.get(*i).ok_or_else(corrupt)?;
+            *i += 1;
+            len += b as usize;
+            if b != 255 {
+                break;
+            }
+        }
+    }
+    Ok(len)
+}
+
+fn write_length(out: &mut Vec<u8>, mut len: usize) {
+    while len >= 255 {
+        out.push(255);
+        len -= 255;
+    }
+    out.push(len as u8);
+}
+
+fn write_sequence(out: &mut Vec<u8>, literals: &[u8], matched: Option<(usize, usize)>) {
+    let extra_match_len = matched.map(|(_, len)| len - MIN_MATCH);
+    let token = (literals.len().min(15) << 4) as u8 | extra_match_len.unwrap_or(0).min(15) as u8;
+    out.push(token);
+    if literals.len() >= 15 {
+        write_length(out, literals.len() - 15);
+    }
+    out.extend_from_slice(literals);
+    if let Some((offset, _)) = matched {
+        out.extend_from_slice(&(offset as u16).to_le_bytes());
+        if let Some(extra) = extra_match_len.filter(|&extra| extra >= 15) {
+            write_length(out, extra - 15);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(input: &[u8]) {
+        let compressed = compress(input);
+        assert!(is_compressed(&compressed));
+        assert_eq!(decompress(&compressed).expect("should decompress"), input);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        round_trip(b"");
+        round_trip(b"short");
+        round_trip(b"aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa");
+        let json = r#"{"guid":"root________","title":"","children":[]}"#.repeat(500);
+        round_trip(json.as_bytes());
+        assert!(compress(json.as_bytes()).len() < json.len() / 10);
+        // Something that doesn't compress well.
+        let noise: Vec<u8> = (0..10_000u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8)
+            .collect();
+        round_trip(&noise);
+    }
+
+    #[test]
+    fn test_decompress_known() {
+        // 6 literals, then an overlapping match of 4 + 7 = 11 bytes at offset
+        // 6, then a final literal.
+        let mut data = MAGIC.to_vec();
+        data.extend_from_slice(&[18, 0, 0, 0]);
+        data.extend_from_slice(&[0x67]);
+        data.extend_from_slice(b"hello ");
+        data.extend_from_slice(&[6, 0]);
+        data.extend_from_slice(&[0x10, b'!']);
+        assert_eq!(decompress(&data).unwrap(), b"hello hello hello!");
+    }
+
+    #[test]
+    fn test_decompress_invalid() {
+        assert!(decompress(b"not lz4").is_err());
+        let mut data = compress(b"hello hello hello hello hello hello");
+        // Claim a different decompressed size.
+        data[MAGIC.len()] += 1;
+        assert!(decompress(&data).is_err());
+        // Truncate it.
+        let data = compress(b"hello hello hello hello hello hello");
+        assert!(decompress(&data[..data.len() - 3]).is_err());
+    }
+}
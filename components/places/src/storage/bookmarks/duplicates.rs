@@ -0,0 +1,466 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Finding and merging duplicate bookmarks and folders.
+//!
+//! `find_duplicates` returns a preview of what would be merged: bookmarks
+//! with the same URL, in the same folder or anywhere in the tree, and sibling
+//! folders with the same title. In each group, we keep the oldest item.
+//! `merge_duplicates` then removes the duplicate bookmarks, and moves the
+//! children of duplicate folders into the folder we keep, before removing
+//! them. Tags and keywords belong to URLs, not bookmarks, so they're kept
+//! with the remaining bookmark.
+//!
+//! The merge is made as normal local changes, so it uploads with the next
+//! bookmark sync, and can be reversed with a single `undo`. Merging folders
+//! can make new duplicates in the merged folder, so it's worth finding
+//! duplicates again after a merge.
+
+use super::{
+    delete_bookmark_in_tx, get_raw_bookmark, journal, update_bookmark_in_tx, BookmarkPosition,
+    BookmarkRootGuid, JournalOp, RawBookmark, UpdatableBookmark, UpdatableFolder, UpdatableItem,
+    UpdatableSeparator, UpdateTreeLocation,
+};
+use crate::db::PlacesDb;
+use crate::error::*;
+use crate::storage::delete_pending_temp_tables;
+use crate::storage::top_sites::PINNED_SITES_FOLDER_GUID;
+use crate::types::BookmarkType;
+use sql_support::ConnExt;
+use sync_guid::Guid as SyncGuid;
+use url::Url;
+
+/// Where to look for bookmarks with the same URL.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DuplicateScope {
+    /// Only bookmarks in the same folder are duplicates.
+    Folder,
+    /// Bookmarks anywhere in the tree are duplicates.
+    Global,
+}
+
+/// Bookmarks with the same URL.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DuplicateBookmarks {
+    pub url: Url,
+    /// The bookmark to keep.
+    pub keep: SyncGuid,
+    /// The bookmarks to remove.
+    pub duplicates: Vec<SyncGuid>,
+}
+
+/// Sibling folders with the same title.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DuplicateFolders {
+    pub parent_guid: SyncGuid,
+    pub title: String,
+    /// The folder to keep, which the children of the duplicates move into.
+    pub keep: SyncGuid,
+    /// The folders to remove.
+    pub duplicates: Vec<SyncGuid>,
+}
+
+/// A preview of the duplicates that `merge_duplicates` would merge.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct DuplicatesPreview {
+    pub bookmarks: Vec<DuplicateBookmarks>,
+    pub folders: Vec<DuplicateFolders>,
+}
+
+impl DuplicatesPreview {
+    pub fn is_empty(&self) -> bool {
+        self.bookmarks.is_empty() && self.folders.is_empty()
+    }
+}
+
+/// What `merge_duplicates` changed.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct MergeDuplicatesResult {
+    pub bookmarks_removed: u32,
+    pub folders_removed: u32,
+    pub items_moved: u32,
+}
+
+/// Finds duplicate bookmarks and folders, without changing anything.
+/// Bookmarks in the pinned sites folder are skipped, since they're top
+/// sites, not duplicates.
+pub fn find_duplicates(db: &PlacesDb, scope: DuplicateScope) -> Result<DuplicatesPreview> {
+    Ok(DuplicatesPreview {
+        bookmarks: find_duplicate_bookmarks(db, scope)?,
+        folders: find_duplicate_folders(db)?,
+    })
+}
+
+fn find_duplicate_bookmarks(
+    db: &PlacesDb,
+    scope: DuplicateScope,
+) -> Result<Vec<DuplicateBookmarks>> {
+    let same_parent = match scope {
+        DuplicateScope::Folder => "AND d.parent = b.parent",
+        DuplicateScope::Global => "",
+    };
+    // Candidates are ordered so that each group is contiguous, with the
+    // oldest bookmark first.
+    let rows = db.query_rows_and_then_named(
+        &format!(
+            "SELECT b.guid, b.parent, h.url
+             FROM moz_bookmarks b
+             JOIN moz_bookmarks p ON p.id = b.parent
+             JOIN moz_places h ON h.id = b.fk
+             WHERE b.type = {bookmark_type} AND
+                   p.guid <> '{pinned}' AND
+                   EXISTS(SELECT 1 FROM moz_bookmarks d
+                          JOIN moz_bookmarks dp ON dp.id = d.parent
+                          WHERE d.fk = b.fk AND d.id <> b.id AND
+                                dp.guid <> '{pinned}'
+                                {same_parent})
+             ORDER BY h.id, {group_parent} b.dateAdded, b.id",
+            bookmark_type = BookmarkType::Bookmark as u8,
+            pinned = PINNED_SITES_FOLDER_GUID,
+            same_parent = same_parent,
+            group_parent = match scope {
+                DuplicateScope::Folder => "b.parent,",
+                DuplicateScope::Global => "",
+            },
+        ),
+        &[],
+        |row| -> Result<_> {
+            Ok((
+                row.get::<_, SyncGuid>("guid")?,
+                row.get::<_, i64>("parent")?,
+                row.get::<_, String>("url")?,
+            ))
+        },
+    )?;
+
+    let mut groups: Vec<(DuplicateBookmarks, i64)> = Vec::new();
+    for (guid, parent, url) in rows {
+        match groups.last_mut() {
+            Some((group, group_parent))
+                if group.url.as_str() == url
+                    && (scope == DuplicateScope::Global || *group_parent == parent) =>
+            {
+                group.duplicates.push(guid);
+            }
+            _ => groups.push((
+                DuplicateBookmarks {
+                    url: Url::parse(&url)?,
+                    keep: guid,
+                    duplicates: Vec::new(),
+                },
+                parent,
+            )),
+        }
+    }
+    Ok(groups.into_iter().map(|(group, _)| group).collect())
+}
+
+fn find_duplicate_folders(db: &PlacesDb) -> Result<Vec<DuplicateFolders>> {
+    let rows = db.query_rows_and_then_named(
+        &format!(
+            "SELECT b.guid, p.guid AS parentGuid, b.title
+             FROM moz_bookmarks b
+             JOIN moz_bookmarks p ON p.id = b.parent
+             WHERE b.type = {folder_type} AND
+                   b.title NOT NULL AND b.title <> '' AND
+                   b.guid <> '{pinned}' AND
+                   EXISTS(SELECT 1 FROM moz_bookmarks d
+                          WHERE d.parent = b.parent AND d.id <> b.id AND
+                                d.type = {folder_type} AND d.title = b.title)
+             ORDER BY b.parent, b.title, b.dateAdded, b.id",
+            folder_type = BookmarkType::Folder as u8,
+            pinned = PINNED_SITES_FOLDER_GUID,
+        ),
+        &[],
+        |row| -> Result<_> {
+            Ok((
+                row.get::<_, SyncGuid>("guid")?,
+                row.get::<_, SyncGuid>("parentGuid")?,
+                row.get::<_, String>("title")?,
+            ))
+        },
+    )?;
+
+    let mut groups: Vec<DuplicateFolders> = Vec::new();
+    for (guid, parent_guid, title) in rows {
+        // The roots have fixed GUIDs, so they can't be merged.
+        if BookmarkRootGuid::well_known(guid.as_str()).is_some() {
+            continue;
+        }
+        match groups.last_mut() {
+            Some(group) if group.parent_guid == parent_guid && group.title == title => {
+                group.duplicates.push(guid);
+            }
+            _ => groups.push(DuplicateFolders {
+                parent_guid,
+                title,
+                keep: guid,
+                duplicates: Vec::new(),
+            }),
+        }
+    }
+    groups.retain(|group| !group.duplicates.is_empty());
+    Ok(groups)
+}
+
+/// Merges the duplicates in a preview returned by `find_duplicates`. Items
+/// that changed since the preview, so that they're no longer duplicates,
+/// are skipped.
+pub fn merge_duplicates(
+    db: &PlacesDb,
+    preview: &DuplicatesPreview,
+) -> Result<MergeDuplicatesResult> {
+    let tx = db.begin_transaction()?;
+    let mut inverse = Vec::new();
+    let result = merge_duplicates_in_tx(db, preview, &mut inverse).and_then(|result| {
+        // The operations that reverse the merge must be applied in the
+        // opposite order.
+        inverse.reverse();
+        journal::record(db, inverse)?;
+        Ok(result)
+    });
+    delete_pending_temp_tables(db)?;
+    match result {
+        Ok(_) => tx.commit()?,
+        Err(_) => tx.rollback()?,
+    }
+    result
+}
+
+fn merge_duplicates_in_tx(
+    db: &PlacesDb,
+    preview: &DuplicatesPreview,
+    inverse: &mut Vec<JournalOp>,
+) -> Result<MergeDuplicatesResult> {
+    let mut result = MergeDuplicatesResult::default();
+    for group in &preview.bookmarks {
+        let keep = match get_raw_bookmark(db, &group.keep)? {
+            Some(raw) if raw.url.as_ref() == Some(&group.url) => raw,
+            _ => continue,
+        };
+        let mut title = None;
+        for guid in &group.duplicates {
+            let duplicate = match get_raw_bookmark(db, guid)? {
+                Some(raw) if raw.url.as_ref() == Some(&group.url) => raw,
+                _ => continue,
+            };
+            if title.is_none() {
+                title = duplicate.title.clone();
+            }
+            if let Some(op) = JournalOp::inverse_of_remove(db, guid)? {
+                inverse.push(op);
+            }
+            if delete_bookmark_in_tx(db, guid)? {
+                result.bookmarks_removed += 1;
+            }
+        }
+        // Keep a title from a duplicate if the bookmark we keep doesn't
+        // have one.
+        if let (None, Some(title)) = (&keep.title, title) {
+            let item: UpdatableItem = UpdatableBookmark {
+                title: Some(title),
+                ..UpdatableBookmark::default()
+            }
+            .into();
+            inverse.push(JournalOp::inverse_of_update(&keep, &item));
+            let guid = keep.guid.clone();
+            update_bookmark_in_tx(db, &guid, &item, keep)?;
+        }
+    }
+
+    for group in &preview.folders {
+        let is_match = |raw: &RawBookmark| {
+            raw.bookmark_type == BookmarkType::Folder
+                && raw.parent_guid.as_ref() == Some(&group.parent_guid)
+                && raw.title.as_deref() == Some(group.title.as_str())
+        };
+        match get_raw_bookmark(db, &group.keep)? {
+            Some(raw) if is_match(&raw) => {}
+            _ => continue,
+        }
+        for guid in &group.duplicates {
+            match get_raw_bookmark(db, guid)? {
+                Some(raw) if is_match(&raw) => {}
+                _ => continue,
+            }
+            let children = db.query_rows_and_then_named(
+                "SELECT b.guid FROM moz_bookmarks b
+                 JOIN moz_bookmarks p ON p.id = b.parent
+                 WHERE p.guid = :guid
+                 ORDER BY b.position",
+                &[(":guid", guid)],
+                |row| row.get::<_, SyncGuid>(0),
+            )?;
+            for child_guid in children {
+                let child = get_raw_bookmark(db, &child_guid)?
+                    .ok_or_else(|| InvalidPlaceInfo::NoSuchGuid(child_guid.to_string()))?;
+                let location =
+                    UpdateTreeLocation::Parent(group.keep.clone(), BookmarkPosition::Append);
+                let item: UpdatableItem = match child.bookmark_type {
+                    BookmarkType::Bookmark => UpdatableBookmark {
+                        location,
+                        ..UpdatableBookmark::default()
+                    }
+                    .into(),
+                    BookmarkType::Folder => UpdatableFolder {
+                        location,
+                        ..UpdatableFolder::default()
+                    }
+                    .into(),
+                    BookmarkType::Separator => UpdatableSeparator { location }.into(),
+                };
+                inverse.push(JournalOp::inverse_of_update(&child, &item));
+                update_bookmark_in_tx(db, &child_guid, &item, child)?;
+                result.items_moved += 1;
+            }
+            if let Some(op) = JournalOp::inverse_of_remove(db, guid)? {
+                inverse.push(op);
+            }
+            if delete_bookmark_in_tx(db, guid)? {
+                result.folders_removed += 1;
+            }
+        }
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::places_api::test::new_mem_connection;
+    use crate::storage::bookmarks::{insert_bookmark, undo, InsertableBookmark, InsertableFolder};
+    use crate::storage::keywords::{get_keyword, set_keyword};
+    use crate::storage::tags::{get_tags_for_url, tag_url};
+    use types::Timestamp;
+
+    fn insert(db: &PlacesDb, parent: &SyncGuid, url: &str, title: Option<&str>) -> SyncGuid {
+        insert_bookmark(
+            db,
+            &InsertableBookmark {
+                parent_guid: parent.clone(),
+                position: BookmarkPosition::Append,
+                date_added: None,
+                last_modified: None,
+                guid: None,
+                url: Url::parse(url).unwrap(),
+                title: title.map(String::from),
+            }
+            .into(),
+        )
+        .expect("should insert bookmark")
+    }
+
+    fn insert_folder(db: &PlacesDb, parent: &SyncGuid, title: &str, added: u64) -> SyncGuid {
+        insert_bookmark(
+            db,
+            &InsertableFolder {
+                parent_guid: parent.clone(),
+                position: BookmarkPosition::Append,
+                date_added: Some(Timestamp(added)),
+                last_modified: Some(Timestamp(added)),
+                guid: None,
+                title: Some(title.into()),
+            }
+            .into(),
+        )
+        .expect("should insert folder")
+    }
+
+    fn children(db: &PlacesDb, parent: &SyncGuid) -> Vec<SyncGuid> {
+        db.query_rows_and_then_named(
+            "SELECT b.guid FROM moz_bookmarks b
+             JOIN moz_bookmarks p ON p.id = b.parent
+             WHERE p.guid = :guid
+             ORDER BY b.position",
+            &[(":guid", parent)],
+            |row| row.get::<_, SyncGuid>(0),
+        )
+        .expect("should fetch children")
+    }
+
+    #[test]
+    fn test_find_duplicate_bookmarks() -> Result<()> {
+        let conn = new_mem_connection();
+        let unfiled = BookmarkRootGuid::Unfiled.as_guid();
+        let menu = BookmarkRootGuid::Menu.as_guid();
+        let a1 = insert(&conn, &unfiled, "https://example.com/a", None);
+        let a2 = insert(&conn, &unfiled, "https://example.com/a", Some("A"));
+        let a3 = insert(&conn, &menu, "https://example.com/a", None);
+        insert(&conn, &unfiled, "https://example.com/b", None);
+
+        let preview = find_duplicates(&conn, DuplicateScope::Folder)?;
+        assert_eq!(
+            preview.bookmarks,
+            vec![DuplicateBookmarks {
+                url: Url::parse("https://example.com/a")?,
+                keep: a1.clone(),
+                duplicates: vec![a2.clone()],
+            }]
+        );
+        assert!(preview.folders.is_empty());
+
+        let preview = find_duplicates(&conn, DuplicateScope::Global)?;
+        assert_eq!(preview.bookmarks.len(), 1);
+        assert_eq!(preview.bookmarks[0].keep, a1);
+        assert_eq!(preview.bookmarks[0].duplicates, vec![a2, a3]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_duplicates() -> Result<()> {
+        let conn = new_mem_connection();
+        let unfiled = BookmarkRootGuid::Unfiled.as_guid();
+        let url = Url::parse("https://example.com/a")?;
+
+        let keep_folder = insert_folder(&conn, &unfiled, "Recipes", 1_600_000_000_000);
+        let dupe_folder = insert_folder(&conn, &unfiled, "Recipes", 1_600_000_001_000);
+        let pie = insert(&conn, &keep_folder, "https://example.com/pie", None);
+        let soup = insert(&conn, &dupe_folder, "https://example.com/soup", None);
+
+        let a1 = insert(&conn, &unfiled, url.as_str(), None);
+        let a2 = insert(&conn, &unfiled, url.as_str(), Some("A"));
+        tag_url(&conn, &url, "tagged")?;
+        set_keyword(&conn, &url, "a", None)?;
+        conn.execute_batch("UPDATE moz_bookmarks SET syncChangeCounter = 0, syncStatus = 2")?;
+
+        let preview = find_duplicates(&conn, DuplicateScope::Folder)?;
+        assert_eq!(preview.folders.len(), 1);
+        assert_eq!(preview.folders[0].keep, keep_folder);
+        let result = merge_duplicates(&conn, &preview)?;
+        assert_eq!(
+            result,
+            MergeDuplicatesResult {
+                bookmarks_removed: 1,
+                folders_removed: 1,
+                items_moved: 1,
+            }
+        );
+
+        assert_eq!(children(&conn, &keep_folder), vec![pie, soup.clone()]);
+        assert_eq!(
+            children(&conn, &unfiled),
+            vec![keep_folder.clone(), a1.clone()]
+        );
+        let kept = get_raw_bookmark(&conn, &a1)?.expect("should keep bookmark");
+        assert_eq!(kept.title.as_deref(), Some("A"));
+        assert_eq!(get_tags_for_url(&conn, &url)?, vec!["tagged".to_string()]);
+        assert_eq!(get_keyword(&conn, "a")?.map(|k| k.url), Some(url.clone()));
+
+        // The merge uploads as local changes, with tombstones for the
+        // removed items.
+        let tombstones = conn.query_one::<i64>("SELECT COUNT(*) FROM moz_bookmarks_deleted")?;
+        assert_eq!(tombstones, 2);
+        let changed = conn
+            .query_one::<i64>("SELECT COUNT(*) FROM moz_bookmarks WHERE syncChangeCounter > 0")?;
+        assert!(changed >= 3);
+
+        assert!(find_duplicates(&conn, DuplicateScope::Global)?.is_empty());
+
+        // A single undo reverses the whole merge.
+        assert!(undo(&conn)?);
+        assert_eq!(children(&conn, &dupe_folder), vec![soup]);
+        assert!(get_raw_bookmark(&conn, &a2)?.is_some());
+        Ok(())
+    }
+}
@@ -0,0 +1,633 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! An undo and redo journal for bookmark changes.
+//!
+//! `insert_bookmark`, `update_bookmark`, `delete_bookmark` and `insert_tree`
+//! record the operations that reverse each change in
+//! `moz_bookmarks_journal_temp`, in the same transaction as the change.
+//! `undo` applies the most recent entry, and records the operations that
+//! reverse *that* as a redo entry. Making a new change clears the redo stack.
+//!
+//! Removed items are restored with their original GUIDs, positions, dates and
+//! sync statuses, and with their change counters bumped. This means undoing a
+//! removal syncs as a change to the existing items, which revives them on
+//! other devices, instead of as new items that duplicate them.
+//!
+//! The journal belongs to the connection, and isn't persisted. Changes from
+//! Sync, imports and backup restores aren't recorded.
+
+use super::{
+    delete_bookmark_in_tx, get_raw_bookmark, resolve_pos_for_insert, update_bookmark_in_tx,
+    BookmarkPosition, BookmarkRootGuid, RawBookmark, UpdatableBookmark, UpdatableFolder,
+    UpdatableItem, UpdatableSeparator, UpdateTreeLocation,
+};
+use crate::db::PlacesDb;
+use crate::error::*;
+use crate::storage::{delete_pending_temp_tables, fetch_page_info, new_page_info};
+use crate::types::BookmarkType;
+use serde_derive::*;
+use sql_support::ConnExt;
+use sync_guid::Guid as SyncGuid;
+use types::Timestamp;
+use url::Url;
+
+/// The number of undo entries we keep. Older entries are discarded.
+const MAX_UNDO_ENTRIES: u32 = 100;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(u8)]
+enum Stack {
+    Undo = 0,
+    Redo = 1,
+}
+
+/// A removed item, with everything we need to put it back as it was.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct JournalItem {
+    guid: SyncGuid,
+    parent_guid: SyncGuid,
+    position: u32,
+    #[serde(rename = "type")]
+    item_type: u8,
+    title: Option<String>,
+    url: Option<Url>,
+    date_added: Timestamp,
+    last_modified: Timestamp,
+    sync_status: u8,
+    sync_change_counter: u32,
+}
+
+/// An operation that reverses part of a change. Applying an operation
+/// returns the operation that reverses it in turn.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "camelCase")]
+pub(super) enum JournalOp {
+    /// Removes an item, and its descendants.
+    Remove { guid: SyncGuid },
+
+    /// Puts back a removed item and its descendants. Parents come before
+    /// their children, so the removed item is always first.
+    Restore { items: Vec<JournalItem> },
+
+    /// Sets an item's location, title or URL. `None` leaves it unchanged, and
+    /// an empty title clears the title.
+    Update {
+        guid: SyncGuid,
+        location: Option<(SyncGuid, u32)>,
+        title: Option<String>,
+        url: Option<Url>,
+    },
+}
+
+impl JournalOp {
+    /// Returns the operation that reverses `item` for the existing `raw` item.
+    /// This must be called before applying `item`.
+    pub(super) fn inverse_of_update(raw: &RawBookmark, item: &UpdatableItem) -> Self {
+        let (title, url) = match item {
+            UpdatableItem::Bookmark(b) => (&b.title, &b.url),
+            UpdatableItem::Folder(f) => (&f.title, &None),
+            UpdatableItem::Separator(_) => (&None, &None),
+        };
+        JournalOp::Update {
+            guid: raw.guid.clone(),
+            location: match item.location() {
+                UpdateTreeLocation::None => None,
+                _ => raw
+                    .parent_guid
+                    .clone()
+                    .map(|parent_guid| (parent_guid, raw.position)),
+            },
+            title: title
+                .as_ref()
+                .map(|_| raw.title.clone().unwrap_or_default()),
+            url: url.as_ref().and_then(|_| raw.url.clone()),
+        }
+    }
+
+    /// Returns the operation that restores the item with the given GUID and
+    /// its descendants, or `None` if the item doesn't exist. This must be
+    /// called before removing the item.
+    pub(super) fn inverse_of_remove(db: &PlacesDb, guid: &SyncGuid) -> Result<Option<Self>> {
+        if let Some(root) = BookmarkRootGuid::well_known(guid.as_str()) {
+            return Err(InvalidPlaceInfo::CannotUpdateRoot(root).into());
+        }
+        let items = db.query_rows_and_then_named_cached(
+            "WITH RECURSIVE
+             descendants(id, level) AS (
+                 SELECT id, 0 FROM moz_bookmarks
+                 WHERE guid = :guid
+                 UNION ALL
+                 SELECT b.id, d.level + 1 FROM moz_bookmarks b
+                 JOIN descendants d ON b.parent = d.id
+             )
+             SELECT b.guid, p.guid AS parentGuid, b.position, b.type, b.title,
+                    h.url, b.dateAdded, b.lastModified, b.syncStatus,
+                    b.syncChangeCounter
+             FROM descendants d
+             JOIN moz_bookmarks b ON b.id = d.id
+             JOIN moz_bookmarks p ON p.id = b.parent
+             LEFT JOIN moz_places h ON h.id = b.fk
+             ORDER BY d.level, b.parent, b.position",
+            &[(":guid", guid)],
+            |row| -> Result<_> {
+                Ok(JournalItem {
+                    guid: row.get::<_, String>("guid")?.into(),
+                    parent_guid: row.get::<_, String>("parentGuid")?.into(),
+                    position: row.get("position")?,
+                    item_type: row.get("type")?,
+                    title: row.get("title")?,
+                    url: match row.get::<_, Option<String>>("url")? {
+                        Some(s) => Some(Url::parse(&s)?),
+                        None => None,
+                    },
+                    date_added: row.get("dateAdded")?,
+                    last_modified: row.get("lastModified")?,
+                    sync_status: row.get("syncStatus")?,
+                    sync_change_counter: row.get("syncChangeCounter")?,
+                })
+            },
+        )?;
+        Ok(if items.is_empty() {
+            None
+        } else {
+            Some(JournalOp::Restore { items })
+        })
+    }
+
+    fn apply(&self, db: &PlacesDb) -> Result<JournalOp> {
+        match self {
+            JournalOp::Remove { guid } => {
+                let inverse = JournalOp::inverse_of_remove(db, guid)?
+                    .ok_or_else(|| InvalidPlaceInfo::NoSuchGuid(guid.to_string()))?;
+                delete_bookmark_in_tx(db, guid)?;
+                Ok(inverse)
+            }
+            JournalOp::Restore { items } => restore(db, items),
+            JournalOp::Update {
+                guid,
+                location,
+                title,
+                url,
+            } => {
+                let raw = get_raw_bookmark(db, guid)?
+                    .ok_or_else(|| InvalidPlaceInfo::NoSuchGuid(guid.to_string()))?;
+                let location = match location {
+                    None => UpdateTreeLocation::None,
+                    Some((parent_guid, position)) => {
+                        let position = BookmarkPosition::Specific(*position);
+                        if raw.parent_guid.as_ref() == Some(parent_guid) {
+                            UpdateTreeLocation::Position(position)
+                        } else {
+                            UpdateTreeLocation::Parent(parent_guid.clone(), position)
+                        }
+                    }
+                };
+                let item: UpdatableItem = match raw.bookmark_type {
+                    BookmarkType::Bookmark => UpdatableBookmark {
+                        location,
+                        url: url.clone(),
+                        title: title.clone(),
+                    }
+                    .into(),
+                    BookmarkType::Folder => UpdatableFolder {
+                        location,
+                        title: title.clone(),
+                    }
+                    .into(),
+                    BookmarkType::Separator => UpdatableSeparator { location }.into(),
+                };
+                let inverse = JournalOp::inverse_of_update(&raw, &item);
+                update_bookmark_in_tx(db, guid, &item, raw)?;
+                Ok(inverse)
+            }
+        }
+    }
+}
+
+fn restore(db: &PlacesDb, items: &[JournalItem]) -> Result<JournalOp> {
+    let (first, _) = items
+        .split_first()
+        .ok_or_else(|| InvalidPlaceInfo::NoSuchGuid("<no items>".into()))?;
+    for (index, item) in items.iter().enumerate() {
+        let parent = get_raw_bookmark(db, &item.parent_guid)?
+            .ok_or_else(|| InvalidPlaceInfo::NoSuchGuid(item.parent_guid.to_string()))?;
+        if parent.bookmark_type != BookmarkType::Folder {
+            return Err(InvalidPlaceInfo::InvalidParent(item.parent_guid.to_string()).into());
+        }
+        let position = if index == 0 {
+            // The removed item goes back between its old siblings, so we
+            // need to make room for it, and bump the parent's change counter
+            // like inserting does. Its descendants all come back together,
+            // so they can use their old positions as-is.
+            let position =
+                resolve_pos_for_insert(db, BookmarkPosition::Specific(item.position), &parent)?;
+            db.execute_named_cached(
+                "UPDATE moz_bookmarks SET syncChangeCounter = syncChangeCounter + 1
+                 WHERE id = :parent_id",
+                &[(":parent_id", &parent.row_id)],
+            )?;
+            position
+        } else {
+            item.position
+        };
+        let fk = match &item.url {
+            Some(url) => Some(match fetch_page_info(db, url)? {
+                Some(info) => info.page.row_id,
+                None => new_page_info(db, url, None)?.row_id,
+            }),
+            None => None,
+        };
+        // Inserting an item removes its tombstone, and bumping the change
+        // counter makes sure we upload it again, even if it was already
+        // synced.
+        db.execute_named_cached(
+            "INSERT INTO moz_bookmarks
+                 (fk, type, parent, position, title, dateAdded, lastModified,
+                  guid, syncStatus, syncChangeCounter)
+             VALUES
+                 (:fk, :type, :parent, :position, :title, :dateAdded, :lastModified,
+                  :guid, :syncStatus, :syncChangeCounter + 1)",
+            &[
+                (":fk", &fk),
+                (":type", &item.item_type),
+                (":parent", &parent.row_id),
+                (":position", &position),
+                (":title", &item.title),
+                (":dateAdded", &item.date_added),
+                (":lastModified", &item.last_modified),
+                (":guid", &item.guid),
+                (":syncStatus", &item.sync_status),
+                (":syncChangeCounter", &item.sync_change_counter),
+            ],
+        )?;
+    }
+    Ok(JournalOp::Remove {
+        guid: first.guid.clone(),
+    })
+}
+
+/// Records the operations that reverse a change as a new undo entry, and
+/// clears the redo stack. This must be called in the same transaction as the
+/// change.
+pub(super) fn record(db: &PlacesDb, ops: Vec<JournalOp>) -> Result<()> {
+    if ops.is_empty() {
+        return Ok(());
+    }
+    db.execute_named_cached(
+        "DELETE FROM moz_bookmarks_journal_temp WHERE isRedo = :redo",
+        &[(":redo", &(Stack::Redo as u8))],
+    )?;
+    push(db, Stack::Undo, &ops)?;
+    db.execute_named_cached(
+        "DELETE FROM moz_bookmarks_journal_temp
+         WHERE isRedo = :undo AND id NOT IN (
+             SELECT id FROM moz_bookmarks_journal_temp
+             WHERE isRedo = :undo
+             ORDER BY id DESC
+             LIMIT :max_entries
+         )",
+        &[
+            (":undo", &(Stack::Undo as u8)),
+            (":max_entries", &MAX_UNDO_ENTRIES),
+        ],
+    )?;
+    Ok(())
+}
+
+fn push(db: &PlacesDb, stack: Stack, ops: &[JournalOp]) -> Result<()> {
+    db.execute_named_cached(
+        "INSERT INTO moz_bookmarks_journal_temp(isRedo, ops) VALUES(:stack, :ops)",
+        &[
+            (":stack", &(stack as u8)),
+            (":ops", &serde_json::to_string(ops)?),
+        ],
+    )?;
+    Ok(())
+}
+
+fn pop(db: &PlacesDb, stack: Stack) -> Result<Option<(i64, Vec<JournalOp>)>> {
+    let entry = db.try_query_row(
+        "SELECT id, ops FROM moz_bookmarks_journal_temp
+         WHERE isRedo = :stack
+         ORDER BY id DESC
+         LIMIT 1",
+        &[(":stack", &(stack as u8))],
+        |row| -> Result<_> { Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?)) },
+        true,
+    )?;
+    Ok(match entry {
+        Some((id, ops)) => {
+            db.execute_named_cached(
+                "DELETE FROM moz_bookmarks_journal_temp WHERE id = :id",
+                &[(":id", &id)],
+            )?;
+            Some((id, serde_json::from_str(&ops)?))
+        }
+        None => None,
+    })
+}
+
+fn replay(db: &PlacesDb, from: Stack, to: Stack) -> Result<bool> {
+    let tx = db.begin_transaction()?;
+    let (id, ops) = match pop(db, from)? {
+        Some(entry) => entry,
+        None => return Ok(false),
+    };
+    let result = ops
+        .iter()
+        .map(|op| op.apply(db))
+        .collect::<Result<Vec<_>>>();
+    delete_pending_temp_tables(db)?;
+    match result {
+        Ok(mut inverse) => {
+            // The operations that reverse these must be applied in the
+            // opposite order.
+            inverse.reverse();
+            push(db, to, &inverse)?;
+            tx.commit()?;
+            Ok(true)
+        }
+        Err(e) => {
+            tx.rollback()?;
+            // The entry doesn't apply to the tree anymore; for example,
+            // because Sync removed a folder that we wanted to restore an item
+            // into. Discard it, so that it doesn't block older entries.
+            log::warn!("Discarding bookmark journal entry that failed to apply");
+            db.execute_named_cached(
+                "DELETE FROM moz_bookmarks_journal_temp WHERE id = :id",
+                &[(":id", &id)],
+            )?;
+            Err(e)
+        }
+    }
+}
+
+/// Reverses the most recent bookmark change. Returns false if there's
+/// nothing to undo. If the change can't be reversed anymore, returns an error
+/// and discards it.
+pub fn undo(db: &PlacesDb) -> Result<bool> {
+    replay(db, Stack::Undo, Stack::Redo)
+}
+
+/// Reapplies the most recently undone change. Returns false if there's
+/// nothing to redo.
+pub fn redo(db: &PlacesDb) -> Result<bool> {
+    replay(db, Stack::Redo, Stack::Undo)
+}
+
+fn has_entries(db: &PlacesDb, stack: Stack) -> Result<bool> {
+    Ok(db.query_row_and_then_named(
+        "SELECT EXISTS(SELECT 1 FROM moz_bookmarks_journal_temp WHERE isRedo = :stack)",
+        &[(":stack", &(stack as u8))],
+        |row| row.get::<_, bool>(0),
+        true,
+    )?)
+}
+
+pub fn can_undo(db: &PlacesDb) -> Result<bool> {
+    has_entries(db, Stack::Undo)
+}
+
+pub fn can_redo(db: &PlacesDb) -> Result<bool> {
+    has_entries(db, Stack::Redo)
+}
+
+/// Discards all undo and redo entries.
+pub fn clear_journal(db: &PlacesDb) -> Result<()> {
+    db.execute_batch("DELETE FROM moz_bookmarks_journal_temp")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::places_api::test::new_mem_connection;
+    use crate::storage::bookmarks::{
+        delete_bookmark, insert_bookmark, insert_tree, update_bookmark, BookmarkNode,
+        BookmarkTreeNode, FolderNode, InsertableBookmark, InsertableItem, SeparatorNode,
+    };
+    use crate::tests::{assert_json_tree, insert_json_tree};
+    use crate::types::SyncStatus;
+    use rusqlite::NO_PARAMS;
+    use serde_json::json;
+
+    #[test]
+    fn test_insert_update_undo_redo() -> Result<()> {
+        let conn = new_mem_connection();
+        assert!(!can_undo(&conn)?);
+        assert!(!undo(&conn)?);
+
+        let guid = insert_bookmark(
+            &conn,
+            &InsertableItem::Bookmark(InsertableBookmark {
+                parent_guid: BookmarkRootGuid::Unfiled.into(),
+                position: BookmarkPosition::Append,
+                date_added: None,
+                last_modified: None,
+                guid: Some(SyncGuid::from("bookmarkAAAA")),
+                url: Url::parse("https://www.example.com/")?,
+                title: Some("Example".into()),
+            }),
+        )?;
+        update_bookmark(
+            &conn,
+            &guid,
+            &UpdatableBookmark {
+                location: UpdateTreeLocation::Parent(
+                    BookmarkRootGuid::Toolbar.into(),
+                    BookmarkPosition::Append,
+                ),
+                url: None,
+                title: Some("Renamed".into()),
+            }
+            .into(),
+        )?;
+        let updated_tree = json!({
+            "guid": &BookmarkRootGuid::Toolbar.as_guid(),
+            "children": [{
+                "guid": "bookmarkAAAA",
+                "title": "Renamed",
+                "url": "https://www.example.com/",
+            }],
+        });
+        assert_json_tree(
+            &conn,
+            &BookmarkRootGuid::Toolbar.into(),
+            updated_tree.clone(),
+        );
+
+        // Undo the update.
+        assert!(undo(&conn)?);
+        let raw = get_raw_bookmark(&conn, &guid)?.unwrap();
+        assert_eq!(raw.parent_guid, Some(BookmarkRootGuid::Unfiled.into()));
+        assert_eq!(raw.title.as_deref(), Some("Example"));
+
+        // Undo the insert.
+        assert!(undo(&conn)?);
+        assert!(get_raw_bookmark(&conn, &guid)?.is_none());
+        assert!(!can_undo(&conn)?);
+        assert!(can_redo(&conn)?);
+
+        // Redo both.
+        assert!(redo(&conn)?);
+        let raw = get_raw_bookmark(&conn, &guid)?.unwrap();
+        assert_eq!(raw.parent_guid, Some(BookmarkRootGuid::Unfiled.into()));
+        assert!(redo(&conn)?);
+        assert_json_tree(&conn, &BookmarkRootGuid::Toolbar.into(), updated_tree);
+        assert!(!redo(&conn)?);
+
+        // A new change clears the redo stack.
+        assert!(undo(&conn)?);
+        assert!(can_redo(&conn)?);
+        delete_bookmark(&conn, &guid)?;
+        assert!(!can_redo(&conn)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_undo_delete_restores_sync_state() -> Result<()> {
+        let conn = new_mem_connection();
+        let tree = json!({
+            "guid": &BookmarkRootGuid::Menu.as_guid(),
+            "children": [
+                {
+                    "guid": "bookmarkAAAA",
+                    "title": "A",
+                    "url": "http://example.com/a",
+                },
+                {
+                    "guid": "folderBBBBBB",
+                    "title": "B",
+                    "children": [
+                        {
+                            "guid": "bookmarkCCCC",
+                            "title": "C",
+                            "url": "http://example.com/c",
+                        },
+                        {
+                            "guid": "folderDDDDDD",
+                            "title": "D",
+                            "children": [{
+                                "guid": "bookmarkEEEE",
+                                "title": "E",
+                                "url": "http://example.com/e",
+                            }],
+                        },
+                    ],
+                },
+                {
+                    "guid": "bookmarkFFFF",
+                    "title": "F",
+                    "url": "http://example.com/f",
+                },
+            ],
+        });
+        insert_json_tree(&conn, tree.clone());
+        // Pretend everything was synced.
+        conn.execute(
+            &format!(
+                "UPDATE moz_bookmarks SET syncStatus = {}, syncChangeCounter = 0",
+                SyncStatus::Normal as u8
+            ),
+            NO_PARAMS,
+        )?;
+        clear_journal(&conn)?;
+
+        assert!(delete_bookmark(&conn, &SyncGuid::from("folderBBBBBB"))?);
+        let tombstones: u32 = conn.query_one("SELECT COUNT(*) FROM moz_bookmarks_deleted")?;
+        assert_eq!(tombstones, 4);
+
+        assert!(undo(&conn)?);
+        assert_json_tree(&conn, &BookmarkRootGuid::Menu.into(), tree);
+        let tombstones: u32 = conn.query_one("SELECT COUNT(*) FROM moz_bookmarks_deleted")?;
+        assert_eq!(tombstones, 0);
+        for guid in &[
+            "folderBBBBBB",
+            "bookmarkCCCC",
+            "folderDDDDDD",
+            "bookmarkEEEE",
+        ] {
+            let raw = get_raw_bookmark(&conn, &SyncGuid::from(*guid))?.unwrap();
+            assert_eq!(raw.sync_status, SyncStatus::Normal, "{}", guid);
+            assert_eq!(raw.sync_change_counter, 1, "{}", guid);
+        }
+        let menu = get_raw_bookmark(&conn, &BookmarkRootGuid::Menu.into())?.unwrap();
+        assert_eq!(menu.sync_change_counter, 1);
+
+        // Redo removes them again.
+        assert!(redo(&conn)?);
+        assert!(get_raw_bookmark(&conn, &SyncGuid::from("bookmarkEEEE"))?.is_none());
+        let raw = get_raw_bookmark(&conn, &SyncGuid::from("bookmarkFFFF"))?.unwrap();
+        assert_eq!(raw.position, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_undo_insert_tree() -> Result<()> {
+        let conn = new_mem_connection();
+        insert_tree(
+            &conn,
+            &FolderNode {
+                guid: Some(BookmarkRootGuid::Unfiled.into()),
+                children: vec![
+                    BookmarkNode {
+                        guid: None,
+                        date_added: None,
+                        last_modified: None,
+                        title: Some("A".into()),
+                        url: Url::parse("https://www.example.com/a")?,
+                    }
+                    .into(),
+                    SeparatorNode::default().into(),
+                    FolderNode {
+                        title: Some("B".into()),
+                        children: vec![BookmarkTreeNode::Separator(SeparatorNode::default())],
+                        ..Default::default()
+                    }
+                    .into(),
+                ],
+                ..Default::default()
+            },
+        )?;
+        let unfiled = get_raw_bookmark(&conn, &BookmarkRootGuid::Unfiled.into())?.unwrap();
+        assert_eq!(unfiled.child_count, 3);
+
+        assert!(undo(&conn)?);
+        let unfiled = get_raw_bookmark(&conn, &BookmarkRootGuid::Unfiled.into())?.unwrap();
+        assert_eq!(unfiled.child_count, 0);
+
+        assert!(redo(&conn)?);
+        let unfiled = get_raw_bookmark(&conn, &BookmarkRootGuid::Unfiled.into())?.unwrap();
+        assert_eq!(unfiled.child_count, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_discards_stale_entries() -> Result<()> {
+        let conn = new_mem_connection();
+        let guid = insert_bookmark(
+            &conn,
+            &InsertableItem::Bookmark(InsertableBookmark {
+                parent_guid: BookmarkRootGuid::Unfiled.into(),
+                position: BookmarkPosition::Append,
+                date_added: None,
+                last_modified: None,
+                guid: None,
+                url: Url::parse("https://www.example.com/")?,
+                title: None,
+            }),
+        )?;
+        // Remove the bookmark without recording it, like Sync would.
+        conn.execute_named(
+            "DELETE FROM moz_bookmarks WHERE guid = :guid",
+            &[(":guid", &guid)],
+        )?;
+        assert!(undo(&conn).is_err());
+        assert!(!can_undo(&conn)?);
+        assert!(!can_redo(&conn)?);
+        Ok(())
+    }
+}
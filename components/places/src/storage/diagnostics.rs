@@ -0,0 +1,386 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Statistics about the places database, to help figure out why a database
+//! is large or slow. `get_database_stats` only reads from the database, and
+//! the stats don't include any URLs or titles, so they're safe to ask users
+//! for in bug reports. `places-utils` prints them as JSON.
+
+use super::get_meta;
+use crate::bookmark_sync::engine as bookmarks_engine;
+use crate::db::PlacesDb;
+use crate::error::*;
+use crate::history_sync::engine as history_engine;
+use crate::storage::bookmarks::BookmarkRootGuid;
+use crate::types::{BookmarkType, VisitTransition};
+use serde_derive::*;
+use sql_support::ConnExt;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use types::Timestamp;
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DatabaseStats {
+    /// The number of rows in each table, by table name.
+    pub table_rows: BTreeMap<String, usize>,
+    pub history: HistoryStats,
+    pub bookmarks: BookmarkStats,
+    pub orphans: OrphanStats,
+    pub history_sync: SyncStats,
+    pub bookmarks_sync: SyncStats,
+    pub file: FileStats,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HistoryStats {
+    pub pages: usize,
+    /// Pages without any visits, like bookmarked pages that were never
+    /// visited.
+    pub unvisited_pages: usize,
+    pub visits: usize,
+    /// The number of visits of each transition type, by type name, like
+    /// "link" or "typed". Visits with an unknown type are counted as
+    /// "unknown".
+    pub visits_by_transition: BTreeMap<String, usize>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BookmarkStats {
+    pub bookmarks: usize,
+    pub folders: usize,
+    pub separators: usize,
+    /// The number of levels below the root. The user content roots, like the
+    /// menu, are at level 1.
+    pub tree_depth: u32,
+    /// The number of children of the largest folder.
+    pub largest_folder: usize,
+}
+
+/// Rows that should have been removed, or that point to rows that don't
+/// exist. `check_and_fix_database` fixes most of these.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OrphanStats {
+    pub bookmarks_without_places: usize,
+    /// Items whose parents are missing, or aren't folders.
+    pub bookmarks_without_parents: usize,
+    pub visits_without_places: usize,
+    /// Pages that aren't visited, bookmarked, tagged or keyworded, and don't
+    /// have any history metadata. These are usually removed along with their
+    /// last visit or bookmark.
+    pub unreferenced_places: usize,
+    pub origins_without_places: usize,
+}
+
+/// The sync state for one engine.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SyncStats {
+    /// The server time of the last sync, or `None` if the engine never synced.
+    pub last_sync: Option<Timestamp>,
+    pub global_sync_id: Option<String>,
+    pub collection_sync_id: Option<String>,
+    /// Items with changes that haven't been uploaded yet.
+    pub pending_changes: usize,
+    /// Tombstones for removed items that haven't been uploaded yet.
+    pub pending_tombstones: usize,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FileStats {
+    /// The path to the database file, or `None` for in-memory databases.
+    pub path: Option<PathBuf>,
+    pub page_size: usize,
+    pub page_count: usize,
+    /// Unused pages, which `VACUUM` would remove.
+    pub freelist_count: usize,
+    /// The sizes of the database and write-ahead log files, in bytes, or
+    /// `None` if they don't exist.
+    pub database_size: Option<u64>,
+    pub wal_size: Option<u64>,
+}
+
+/// Collects statistics about the database.
+pub fn get_database_stats(db: &PlacesDb) -> Result<DatabaseStats> {
+    Ok(DatabaseStats {
+        table_rows: get_table_rows(db)?,
+        history: get_history_stats(db)?,
+        bookmarks: get_bookmark_stats(db)?,
+        orphans: get_orphan_stats(db)?,
+        history_sync: SyncStats {
+            last_sync: get_meta::<i64>(db, history_engine::LAST_SYNC_META_KEY)?
+                .map(|millis| Timestamp(millis.max(0) as u64)),
+            global_sync_id: get_meta(db, history_engine::GLOBAL_SYNCID_META_KEY)?,
+            collection_sync_id: get_meta(db, history_engine::COLLECTION_SYNCID_META_KEY)?,
+            pending_changes: count(
+                db,
+                "SELECT COUNT(*) FROM moz_places WHERE sync_change_counter > 0",
+            )?,
+            pending_tombstones: count(
+                db,
+                "SELECT (SELECT COUNT(*) FROM moz_places_tombstones) +
+                        (SELECT COUNT(*) FROM moz_historyvisit_tombstones)",
+            )?,
+        },
+        bookmarks_sync: SyncStats {
+            last_sync: get_meta::<i64>(db, bookmarks_engine::LAST_SYNC_META_KEY)?
+                .map(|millis| Timestamp(millis.max(0) as u64)),
+            global_sync_id: get_meta(db, bookmarks_engine::GLOBAL_SYNCID_META_KEY)?,
+            collection_sync_id: get_meta(db, bookmarks_engine::COLLECTION_SYNCID_META_KEY)?,
+            pending_changes: count(
+                db,
+                "SELECT COUNT(*) FROM moz_bookmarks WHERE syncChangeCounter > 0",
+            )?,
+            pending_tombstones: count(db, "SELECT COUNT(*) FROM moz_bookmarks_deleted")?,
+        },
+        file: get_file_stats(db)?,
+    })
+}
+
+fn count(db: &PlacesDb, sql: &str) -> Result<usize> {
+    Ok(db.query_one::<i64>(sql)? as usize)
+}
+
+fn get_table_rows(db: &PlacesDb) -> Result<BTreeMap<String, usize>> {
+    // Virtual tables, like the full-text search index, are skipped, since
+    // counting their rows can be slow. Their shadow tables are counted
+    // instead.
+    let tables = db.query_rows_and_then_named(
+        "SELECT name FROM sqlite_master
+         WHERE type = 'table' AND
+               name NOT LIKE 'sqlite_%' AND
+               sql NOT LIKE 'CREATE VIRTUAL TABLE%'
+         ORDER BY name",
+        &[],
+        |row| row.get::<_, String>(0),
+    )?;
+    let mut rows = BTreeMap::new();
+    for table in tables {
+        // Table names come from SQLite, but quote them anyway.
+        let sql = format!("SELECT COUNT(*) FROM \"{}\"", table.replace('"', "\"\""));
+        rows.insert(table, count(db, &sql)?);
+    }
+    Ok(rows)
+}
+
+fn get_history_stats(db: &PlacesDb) -> Result<HistoryStats> {
+    let mut visits_by_transition = BTreeMap::new();
+    let rows = db.query_rows_and_then_named(
+        "SELECT visit_type, COUNT(*) FROM moz_historyvisits GROUP BY visit_type",
+        &[],
+        |row| -> Result<_> { Ok((row.get::<_, u8>(0)?, row.get::<_, i64>(1)?)) },
+    )?;
+    for (visit_type, visits) in rows {
+        let name = match VisitTransition::from_primitive(visit_type) {
+            Some(VisitTransition::Link) => "link",
+            Some(VisitTransition::Typed) => "typed",
+            Some(VisitTransition::Bookmark) => "bookmark",
+            Some(VisitTransition::Embed) => "embed",
+            Some(VisitTransition::RedirectPermanent) => "redirectPermanent",
+            Some(VisitTransition::RedirectTemporary) => "redirectTemporary",
+            Some(VisitTransition::Download) => "download",
+            Some(VisitTransition::FramedLink) => "framedLink",
+            Some(VisitTransition::Reload) => "reload",
+            None => "unknown",
+        };
+        *visits_by_transition.entry(name.to_string()).or_default() += visits as usize;
+    }
+    Ok(HistoryStats {
+        pages: count(db, "SELECT COUNT(*) FROM moz_places")?,
+        unvisited_pages: count(
+            db,
+            "SELECT COUNT(*) FROM moz_places
+             WHERE visit_count_local + visit_count_remote = 0",
+        )?,
+        visits: visits_by_transition.values().sum(),
+        visits_by_transition,
+    })
+}
+
+fn get_bookmark_stats(db: &PlacesDb) -> Result<BookmarkStats> {
+    let count_type = |bookmark_type: BookmarkType| {
+        count(
+            db,
+            &format!(
+                "SELECT COUNT(*) FROM moz_bookmarks WHERE type = {}",
+                bookmark_type as u8
+            ),
+        )
+    };
+    Ok(BookmarkStats {
+        bookmarks: count_type(BookmarkType::Bookmark)?,
+        folders: count_type(BookmarkType::Folder)?,
+        separators: count_type(BookmarkType::Separator)?,
+        tree_depth: db.query_one::<u32>(&format!(
+            "WITH RECURSIVE tree(id, level) AS (
+               SELECT id, 0 FROM moz_bookmarks
+               WHERE guid = '{root}'
+               UNION ALL
+               SELECT b.id, tree.level + 1 FROM moz_bookmarks b
+               JOIN tree ON b.parent = tree.id
+             )
+             SELECT IFNULL(MAX(level), 0) FROM tree",
+            root = BookmarkRootGuid::Root.as_str(),
+        ))?,
+        largest_folder: count(
+            db,
+            "SELECT IFNULL(MAX(children), 0) FROM (
+               SELECT COUNT(*) AS children FROM moz_bookmarks
+               WHERE parent NOT NULL
+               GROUP BY parent
+             )",
+        )?,
+    })
+}
+
+fn get_orphan_stats(db: &PlacesDb) -> Result<OrphanStats> {
+    Ok(OrphanStats {
+        bookmarks_without_places: count(
+            db,
+            &format!(
+                "SELECT COUNT(*) FROM moz_bookmarks b
+                 WHERE b.type = {} AND
+                       NOT EXISTS(SELECT 1 FROM moz_places h WHERE h.id = b.fk)",
+                BookmarkType::Bookmark as u8
+            ),
+        )?,
+        bookmarks_without_parents: count(
+            db,
+            &format!(
+                "SELECT COUNT(*) FROM moz_bookmarks b
+                 WHERE b.guid <> '{root}' AND
+                       NOT EXISTS(SELECT 1 FROM moz_bookmarks p
+                                  WHERE p.id = b.parent AND p.type = {folder})",
+                root = BookmarkRootGuid::Root.as_str(),
+                folder = BookmarkType::Folder as u8,
+            ),
+        )?,
+        visits_without_places: count(
+            db,
+            "SELECT COUNT(*) FROM moz_historyvisits v
+             WHERE NOT EXISTS(SELECT 1 FROM moz_places h WHERE h.id = v.place_id)",
+        )?,
+        unreferenced_places: count(
+            db,
+            "SELECT COUNT(*) FROM moz_places h
+             WHERE h.visit_count_local + h.visit_count_remote = 0 AND
+                   h.foreign_count = 0 AND
+                   NOT EXISTS(SELECT 1 FROM moz_places_metadata m
+                              WHERE m.place_id = h.id)",
+        )?,
+        origins_without_places: count(
+            db,
+            "SELECT COUNT(*) FROM moz_origins o
+             WHERE NOT EXISTS(SELECT 1 FROM moz_places h WHERE h.origin_id = o.id)",
+        )?,
+    })
+}
+
+fn get_file_stats(db: &PlacesDb) -> Result<FileStats> {
+    // In-memory databases have an empty file name.
+    let path = db
+        .try_query_one::<String>(
+            "SELECT file FROM pragma_database_list WHERE name = 'main'",
+            &[],
+            false,
+        )?
+        .filter(|file| !file.is_empty())
+        .map(PathBuf::from);
+    let file_size = |path: PathBuf| fs::metadata(path).ok().map(|metadata| metadata.len());
+    let (database_size, wal_size) = match &path {
+        Some(path) => {
+            let mut wal_path = path.clone().into_os_string();
+            wal_path.push("-wal");
+            (file_size(path.clone()), file_size(wal_path.into()))
+        }
+        None => (None, None),
+    };
+    Ok(FileStats {
+        path,
+        page_size: count(db, "PRAGMA page_size")?,
+        page_count: count(db, "PRAGMA page_count")?,
+        freelist_count: count(db, "PRAGMA freelist_count")?,
+        database_size,
+        wal_size,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::places_api::test::new_mem_connection;
+    use crate::observation::VisitObservation;
+    use crate::storage::bookmarks::{insert_bookmark, BookmarkPosition, InsertableFolder};
+    use crate::storage::history::apply_observation;
+    use url::Url;
+
+    #[test]
+    fn test_database_stats() -> Result<()> {
+        let conn = new_mem_connection();
+        let url = Url::parse("https://www.example.com")?;
+        apply_observation(
+            &conn,
+            VisitObservation::new(url.clone()).with_visit_type(VisitTransition::Link),
+        )?;
+        apply_observation(
+            &conn,
+            VisitObservation::new(url).with_visit_type(VisitTransition::Typed),
+        )?;
+        let folder = insert_bookmark(
+            &conn,
+            &InsertableFolder {
+                parent_guid: BookmarkRootGuid::Unfiled.into(),
+                position: BookmarkPosition::Append,
+                date_added: None,
+                last_modified: None,
+                guid: None,
+                title: Some("Folder".into()),
+            }
+            .into(),
+        )?;
+        insert_bookmark(
+            &conn,
+            &InsertableFolder {
+                parent_guid: folder,
+                position: BookmarkPosition::Append,
+                date_added: None,
+                last_modified: None,
+                guid: None,
+                title: Some("Subfolder".into()),
+            }
+            .into(),
+        )?;
+        // A visit for a page that doesn't exist.
+        conn.execute_batch(
+            "PRAGMA foreign_keys = OFF;
+             INSERT INTO moz_historyvisits(place_id, visit_date, visit_type, is_local)
+             VALUES(12345, 1, 1, 1);
+             PRAGMA foreign_keys = ON;",
+        )?;
+
+        let stats = get_database_stats(&conn)?;
+        assert_eq!(stats.table_rows.get("moz_places"), Some(&1));
+        assert_eq!(stats.table_rows.get("moz_historyvisits"), Some(&3));
+        assert_eq!(stats.history.pages, 1);
+        assert_eq!(stats.history.visits, 3);
+        assert_eq!(stats.history.visits_by_transition.get("link"), Some(&2));
+        assert_eq!(stats.history.visits_by_transition.get("typed"), Some(&1));
+        // The root, the 4 user content roots, and the 2 new folders.
+        assert_eq!(stats.bookmarks.folders, 7);
+        assert_eq!(stats.bookmarks.tree_depth, 3);
+        assert_eq!(stats.bookmarks.largest_folder, 4);
+        assert_eq!(stats.orphans.visits_without_places, 1);
+        assert_eq!(stats.bookmarks_sync.pending_changes, 7);
+        assert_eq!(stats.history_sync.last_sync, None);
+        assert_eq!(stats.history_sync.pending_changes, 1);
+        assert_eq!(stats.file.path, None);
+        assert!(stats.file.page_count > 0);
+        Ok(())
+    }
+}
@@ -0,0 +1,541 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+// Favicon storage. This is modeled on Desktop's `favicons.sqlite`, but lives
+// in the main places database:
+//
+// - `moz_icons` holds one row per size of each icon URL.
+// - `moz_icons_data` holds the payloads, deduplicated by content.
+// - `moz_pages_w_icons` and `moz_icons_to_pages` map page URLs to icons.
+//
+// "Root" icons, like `/favicon.ico`, are also keyed by their origin, so that
+// pages we've never seen an icon for can still get one.
+
+use super::{RowId, URL_LENGTH_MAX};
+use crate::db::PlacesDb;
+use crate::error::{ErrorKind, InvalidPlaceInfo, Result};
+use crate::hash;
+use rusqlite::Row;
+use sql_support::{self, ConnExt};
+use std::time::Duration;
+use types::Timestamp;
+use url::Url;
+
+/// From https://searchfox.org/mozilla-central/rev/93905b660f/toolkit/components/places/FaviconHelpers.h#21
+pub const MAX_ICON_DATA_SIZE: usize = 65536;
+
+/// How long we keep an icon if the caller doesn't give us an expiration time.
+pub const DEFAULT_ICON_EXPIRATION: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct InsertableIcon {
+    pub icon_url: Url,
+    /// The width of the icon, in pixels. Icons are assumed to be square.
+    pub width: u32,
+    pub mime_type: String,
+    pub data: Vec<u8>,
+    /// Root icons, like `/favicon.ico`, apply to every page on the icon's
+    /// origin, not just the page they were found on.
+    pub is_root: bool,
+    /// When the icon should be refetched. Defaults to
+    /// `DEFAULT_ICON_EXPIRATION` from now.
+    pub expire_at: Option<Timestamp>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Icon {
+    pub icon_url: Url,
+    pub width: u32,
+    pub mime_type: String,
+    pub data: Vec<u8>,
+    pub expire_at: Timestamp,
+}
+
+impl Icon {
+    fn from_row(row: &Row<'_>) -> Result<Self> {
+        Ok(Self {
+            icon_url: Url::parse(&row.get::<_, String>("icon_url")?)?,
+            width: row.get("width")?,
+            mime_type: row.get("mime_type")?,
+            data: row.get("data")?,
+            expire_at: row.get("expire_at")?,
+        })
+    }
+}
+
+const ICON_COLUMNS: &str = "i.icon_url, i.width, i.expire_at, d.mime_type, d.data";
+
+/// Stores an icon for a page, replacing any existing icon with the same URL
+/// and width.
+pub fn set_page_icon(db: &PlacesDb, page_url: &Url, icon: &InsertableIcon) -> Result<()> {
+    if page_url.as_str().len() > URL_LENGTH_MAX || icon.icon_url.as_str().len() > URL_LENGTH_MAX {
+        return Err(ErrorKind::InvalidPlaceInfo(InvalidPlaceInfo::UrlTooLong).into());
+    }
+    if icon.data.len() > MAX_ICON_DATA_SIZE {
+        return Err(ErrorKind::InvalidPlaceInfo(InvalidPlaceInfo::IconTooLarge).into());
+    }
+    let expire_at = match icon.expire_at {
+        Some(expire_at) => expire_at,
+        None => Timestamp(Timestamp::now().0 + DEFAULT_ICON_EXPIRATION.as_millis() as u64),
+    };
+    let root_origin = if icon.is_root {
+        let origin = icon.icon_url.origin();
+        if origin.is_tuple() {
+            Some(origin.ascii_serialization())
+        } else {
+            None
+        }
+    } else {
+        None
+    };
+
+    let tx = db.begin_transaction()?;
+    let data_id = get_or_insert_icon_data(db, &icon.mime_type, &icon.data)?;
+    db.execute_named_cached(
+        "INSERT INTO moz_icons(icon_url, icon_url_hash, width, root_origin, expire_at, data_id)
+         VALUES(:icon_url, hash(:icon_url), :width, :root_origin, :expire_at, :data_id)
+         ON CONFLICT(icon_url_hash, icon_url, width) DO UPDATE SET
+             root_origin = excluded.root_origin,
+             expire_at = excluded.expire_at,
+             data_id = excluded.data_id",
+        &[
+            (":icon_url", &icon.icon_url.as_str()),
+            (":width", &icon.width),
+            (":root_origin", &root_origin),
+            (":expire_at", &expire_at),
+            (":data_id", &data_id),
+        ],
+    )?;
+    let icon_id: RowId = db.query_row_and_then_named(
+        "SELECT id FROM moz_icons
+         WHERE icon_url_hash = hash(:icon_url) AND icon_url = :icon_url AND width = :width",
+        &[
+            (":icon_url", &icon.icon_url.as_str()),
+            (":width", &icon.width),
+        ],
+        |row| row.get(0),
+        true,
+    )?;
+    let page_id = get_or_insert_page(db, page_url)?;
+    db.execute_named_cached(
+        "INSERT OR IGNORE INTO moz_icons_to_pages(page_id, icon_id)
+         VALUES(:page_id, :icon_id)",
+        &[(":page_id", &page_id), (":icon_id", &icon_id)],
+    )?;
+    // If we replaced an icon's payload, the old one might not be used anymore.
+    delete_orphaned_icon_data(db)?;
+    tx.commit()?;
+    Ok(())
+}
+
+/// Returns the payload's row ID, reusing an existing row with identical
+/// contents if we have one.
+fn get_or_insert_icon_data(db: &PlacesDb, mime_type: &str, data: &[u8]) -> Result<RowId> {
+    let data_hash = hash::hash_bytes(data);
+    let existing = db.try_query_row(
+        "SELECT id FROM moz_icons_data
+         WHERE data_hash = :data_hash AND mime_type = :mime_type AND data = :data",
+        &[
+            (":data_hash", &data_hash),
+            (":mime_type", &mime_type),
+            (":data", &data),
+        ],
+        |row| row.get::<_, RowId>(0),
+        true,
+    )?;
+    if let Some(id) = existing {
+        return Ok(id);
+    }
+    db.execute_named_cached(
+        "INSERT INTO moz_icons_data(data_hash, mime_type, data)
+         VALUES(:data_hash, :mime_type, :data)",
+        &[
+            (":data_hash", &data_hash),
+            (":mime_type", &mime_type),
+            (":data", &data),
+        ],
+    )?;
+    Ok(RowId(db.conn().last_insert_rowid()))
+}
+
+fn get_or_insert_page(db: &PlacesDb, page_url: &Url) -> Result<RowId> {
+    let existing = db.try_query_row(
+        "SELECT id FROM moz_pages_w_icons
+         WHERE page_url_hash = hash(:page_url) AND page_url = :page_url",
+        &[(":page_url", &page_url.as_str())],
+        |row| row.get::<_, RowId>(0),
+        true,
+    )?;
+    if let Some(id) = existing {
+        return Ok(id);
+    }
+    db.execute_named_cached(
+        "INSERT INTO moz_pages_w_icons(page_url, page_url_hash)
+         VALUES(:page_url, hash(:page_url))",
+        &[(":page_url", &page_url.as_str())],
+    )?;
+    Ok(RowId(db.conn().last_insert_rowid()))
+}
+
+/// Returns all icons for a page. If we don't have any icons for the page
+/// itself, returns the root icons for its origin instead.
+pub fn get_icons_for_page(db: &PlacesDb, page_url: &Url) -> Result<Vec<Icon>> {
+    let icons = db.query_rows_and_then_named_cached(
+        &format!(
+            "SELECT {columns}
+             FROM moz_pages_w_icons p
+             JOIN moz_icons_to_pages ip ON ip.page_id = p.id
+             JOIN moz_icons i ON i.id = ip.icon_id
+             JOIN moz_icons_data d ON d.id = i.data_id
+             WHERE p.page_url_hash = hash(:page_url) AND p.page_url = :page_url
+             ORDER BY i.width",
+            columns = ICON_COLUMNS
+        ),
+        &[(":page_url", &page_url.as_str())],
+        Icon::from_row,
+    )?;
+    if !icons.is_empty() {
+        return Ok(icons);
+    }
+    let origin = page_url.origin();
+    if !origin.is_tuple() {
+        return Ok(icons);
+    }
+    db.query_rows_and_then_named_cached(
+        &format!(
+            "SELECT {columns}
+             FROM moz_icons i
+             JOIN moz_icons_data d ON d.id = i.data_id
+             WHERE i.root_origin = :origin
+             ORDER BY i.width",
+            columns = ICON_COLUMNS
+        ),
+        &[(":origin", &origin.ascii_serialization())],
+        Icon::from_row,
+    )
+}
+
+/// Returns the best icon for displaying a page at `size` pixels. Like
+/// Desktop, that's the smallest icon at least `size` wide, or the largest
+/// icon if they're all smaller.
+pub fn get_best_icon_for_page(db: &PlacesDb, page_url: &Url, size: u32) -> Result<Option<Icon>> {
+    let icons = get_icons_for_page(db, page_url)?;
+    Ok(pick_best_size(icons, size))
+}
+
+/// Like `get_best_icon_for_page`, but only returns the icon's URL. This is
+/// what autocomplete and bookmark UIs usually want, since they can then load
+/// the icon lazily.
+pub fn get_best_icon_url_for_page(db: &PlacesDb, page_url: &Url, size: u32) -> Result<Option<Url>> {
+    Ok(get_best_icon_for_page(db, page_url, size)?.map(|icon| icon.icon_url))
+}
+
+// `icons` must be sorted by width.
+fn pick_best_size(icons: Vec<Icon>, size: u32) -> Option<Icon> {
+    let mut largest = None;
+    for icon in icons {
+        if icon.width >= size {
+            return Some(icon);
+        }
+        largest = Some(icon);
+    }
+    largest
+}
+
+/// Removes icons which expired before `now`, along with any payloads and
+/// pages which no longer have icons. Returns the number of icons removed.
+/// This is called as part of `run_maintenance`.
+pub fn expire_icons(db: &PlacesDb, now: Timestamp) -> Result<usize> {
+    let tx = db.begin_transaction()?;
+    let removed = db.execute_named_cached(
+        "DELETE FROM moz_icons WHERE expire_at < :now",
+        &[(":now", &now)],
+    )?;
+    delete_orphaned_icon_data(db)?;
+    db.execute_batch(
+        "DELETE FROM moz_pages_w_icons
+         WHERE NOT EXISTS(SELECT 1 FROM moz_icons_to_pages
+                          WHERE page_id = moz_pages_w_icons.id)",
+    )?;
+    tx.commit()?;
+    Ok(removed)
+}
+
+/// Removes icons for pages which aren't in `moz_places` anymore. Used when
+/// wiping history. Assumes a transaction is already set up by the caller.
+pub(crate) fn delete_icons_for_removed_pages(db: &PlacesDb) -> Result<()> {
+    db.execute_all(&[
+        "DELETE FROM moz_pages_w_icons
+         WHERE NOT EXISTS(SELECT 1 FROM moz_places h
+                          WHERE h.url_hash = moz_pages_w_icons.page_url_hash
+                            AND h.url = moz_pages_w_icons.page_url)",
+        "DELETE FROM moz_icons
+         WHERE NOT EXISTS(SELECT 1 FROM moz_icons_to_pages
+                          WHERE icon_id = moz_icons.id)",
+    ])?;
+    delete_orphaned_icon_data(db)
+}
+
+/// Removes icons for pages whose host matches `matches_host`, and root icons
+/// for origins whose host matches, along with any icons and payloads that
+/// aren't used anymore. Icons for other hosts are kept, even if their pages
+/// aren't in `moz_places`. Used when forgetting a site. Assumes a transaction
+/// is already set up by the caller.
+pub(crate) fn delete_icons_for_hosts(
+    db: &PlacesDb,
+    matches_host: impl Fn(&str) -> bool,
+) -> Result<()> {
+    let url_matches = |url: &str| match Url::parse(url) {
+        Ok(url) => url.host_str().map_or(false, |host| matches_host(host)),
+        Err(_) => false,
+    };
+
+    let page_ids = db
+        .query_rows_and_then_named(
+            "SELECT id, page_url FROM moz_pages_w_icons",
+            &[],
+            |row| -> Result<_> { Ok((row.get::<_, RowId>(0)?, row.get::<_, String>(1)?)) },
+        )?
+        .into_iter()
+        .filter(|(_, page_url)| url_matches(page_url))
+        .map(|(id, _)| id)
+        .collect::<Vec<_>>();
+    sql_support::each_chunk(&page_ids, |chunk, _| -> Result<()> {
+        db.conn().execute(
+            &format!(
+                "DELETE FROM moz_pages_w_icons WHERE id IN ({})",
+                sql_support::repeat_sql_vars(chunk.len())
+            ),
+            chunk,
+        )?;
+        Ok(())
+    })?;
+
+    let root_origins = db
+        .query_rows_and_then_named(
+            "SELECT DISTINCT root_origin FROM moz_icons WHERE root_origin NOT NULL",
+            &[],
+            |row| row.get::<_, String>(0),
+        )?
+        .into_iter()
+        .filter(|origin| url_matches(origin))
+        .collect::<Vec<_>>();
+    sql_support::each_chunk(&root_origins, |chunk, _| -> Result<()> {
+        db.conn().execute(
+            &format!(
+                "DELETE FROM moz_icons WHERE root_origin IN ({})",
+                sql_support::repeat_sql_vars(chunk.len())
+            ),
+            chunk,
+        )?;
+        Ok(())
+    })?;
+
+    // Root icons for other origins apply without a page, so only non-root
+    // icons are orphaned when their last page is removed.
+    db.execute_batch(
+        "DELETE FROM moz_icons
+         WHERE root_origin IS NULL
+           AND NOT EXISTS(SELECT 1 FROM moz_icons_to_pages
+                          WHERE icon_id = moz_icons.id)",
+    )?;
+    delete_orphaned_icon_data(db)
+}
+
+fn delete_orphaned_icon_data(db: &PlacesDb) -> Result<()> {
+    db.execute_batch(
+        "DELETE FROM moz_icons_data
+         WHERE NOT EXISTS(SELECT 1 FROM moz_icons
+                          WHERE data_id = moz_icons_data.id)",
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::places_api::test::new_mem_connection;
+    use pretty_assertions::assert_eq;
+
+    fn icon(url: &str, width: u32, data: &[u8]) -> InsertableIcon {
+        InsertableIcon {
+            icon_url: Url::parse(url).unwrap(),
+            width,
+            mime_type: "image/png".into(),
+            data: data.to_vec(),
+            is_root: false,
+            expire_at: None,
+        }
+    }
+
+    fn count(conn: &PlacesDb, table: &str) -> i64 {
+        conn.query_one::<i64>(&format!("SELECT COUNT(*) FROM {}", table))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_best_icon() {
+        let conn = new_mem_connection();
+        let page = Url::parse("https://www.example.com/page").unwrap();
+        assert!(get_best_icon_for_page(&conn, &page, 16).unwrap().is_none());
+
+        set_page_icon(
+            &conn,
+            &page,
+            &icon("https://www.example.com/16.png", 16, b"a"),
+        )
+        .unwrap();
+        set_page_icon(
+            &conn,
+            &page,
+            &icon("https://www.example.com/32.png", 32, b"b"),
+        )
+        .unwrap();
+        set_page_icon(
+            &conn,
+            &page,
+            &icon("https://www.example.com/64.png", 64, b"c"),
+        )
+        .unwrap();
+
+        let best = |size| {
+            get_best_icon_url_for_page(&conn, &page, size)
+                .unwrap()
+                .expect("should have an icon")
+                .to_string()
+        };
+        assert_eq!(best(8), "https://www.example.com/16.png");
+        assert_eq!(best(16), "https://www.example.com/16.png");
+        assert_eq!(best(24), "https://www.example.com/32.png");
+        assert_eq!(best(128), "https://www.example.com/64.png");
+
+        let icon = get_best_icon_for_page(&conn, &page, 32).unwrap().unwrap();
+        assert_eq!(icon.data, b"b");
+        assert_eq!(icon.mime_type, "image/png");
+    }
+
+    #[test]
+    fn test_root_icons() {
+        let conn = new_mem_connection();
+        let page = Url::parse("https://www.example.com/page").unwrap();
+        set_page_icon(
+            &conn,
+            &page,
+            &InsertableIcon {
+                is_root: true,
+                ..icon("https://www.example.com/favicon.ico", 16, b"root")
+            },
+        )
+        .unwrap();
+
+        let other_page = Url::parse("https://www.example.com/other").unwrap();
+        let icon = get_best_icon_for_page(&conn, &other_page, 16)
+            .unwrap()
+            .expect("should use the root icon");
+        assert_eq!(icon.data, b"root");
+
+        // Different origins don't share root icons.
+        for url in &["http://www.example.com/other", "https://example.com/"] {
+            let url = Url::parse(url).unwrap();
+            assert!(get_best_icon_for_page(&conn, &url, 16).unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn test_dedupe_and_replace() {
+        let conn = new_mem_connection();
+        let page1 = Url::parse("https://www.example.com/1").unwrap();
+        let page2 = Url::parse("https://www.example.org/2").unwrap();
+        set_page_icon(
+            &conn,
+            &page1,
+            &icon("https://www.example.com/a.png", 16, b"same"),
+        )
+        .unwrap();
+        set_page_icon(
+            &conn,
+            &page2,
+            &icon("https://www.example.org/b.png", 16, b"same"),
+        )
+        .unwrap();
+        assert_eq!(count(&conn, "moz_icons"), 2);
+        assert_eq!(count(&conn, "moz_icons_data"), 1);
+
+        // Replacing an icon's payload removes the old payload once nothing
+        // uses it.
+        set_page_icon(
+            &conn,
+            &page1,
+            &icon("https://www.example.com/a.png", 16, b"new"),
+        )
+        .unwrap();
+        set_page_icon(
+            &conn,
+            &page2,
+            &icon("https://www.example.org/b.png", 16, b"new"),
+        )
+        .unwrap();
+        assert_eq!(count(&conn, "moz_icons"), 2);
+        assert_eq!(count(&conn, "moz_icons_data"), 1);
+        assert_eq!(
+            get_best_icon_for_page(&conn, &page1, 16)
+                .unwrap()
+                .unwrap()
+                .data,
+            b"new"
+        );
+
+        let too_large = vec![0u8; MAX_ICON_DATA_SIZE + 1];
+        set_page_icon(
+            &conn,
+            &page1,
+            &icon("https://www.example.com/c.png", 16, &too_large),
+        )
+        .expect_err("should reject large icons");
+    }
+
+    #[test]
+    fn test_expire_icons() {
+        let conn = new_mem_connection();
+        let page = Url::parse("https://www.example.com/page").unwrap();
+        let now = Timestamp::now();
+        set_page_icon(
+            &conn,
+            &page,
+            &InsertableIcon {
+                expire_at: Some(Timestamp(now.0 - 1000)),
+                ..icon("https://www.example.com/old.png", 16, b"old")
+            },
+        )
+        .unwrap();
+        set_page_icon(
+            &conn,
+            &page,
+            &icon("https://www.example.com/new.png", 32, b"new"),
+        )
+        .unwrap();
+        let other = Url::parse("https://www.example.com/other").unwrap();
+        set_page_icon(
+            &conn,
+            &other,
+            &InsertableIcon {
+                expire_at: Some(Timestamp(now.0 - 1000)),
+                ..icon("https://www.example.com/other.png", 16, b"other")
+            },
+        )
+        .unwrap();
+
+        assert_eq!(expire_icons(&conn, now).unwrap(), 2);
+        assert_eq!(count(&conn, "moz_icons"), 1);
+        assert_eq!(count(&conn, "moz_icons_data"), 1);
+        assert_eq!(count(&conn, "moz_pages_w_icons"), 1);
+        assert_eq!(
+            get_best_icon_for_page(&conn, &page, 16)
+                .unwrap()
+                .unwrap()
+                .data,
+            b"new"
+        );
+    }
+}
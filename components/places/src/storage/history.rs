@@ -17,6 +17,7 @@ use crate::msg_types::{
 use crate::observation::VisitObservation;
 use crate::storage::{delete_meta, delete_pending_temp_tables, get_meta, put_meta};
 use crate::types::{SyncStatus, VisitTransition, VisitTransitionSet};
+use interrupt_support::Interruptee;
 use rusqlite::types::ToSql;
 use rusqlite::Result as RusqliteResult;
 use rusqlite::{Row, NO_PARAMS};
@@ -26,6 +27,11 @@ use sync_guid::Guid as SyncGuid;
 use types::Timestamp;
 use url::Url;
 
+pub mod expiration;
+pub mod forget;
+pub mod navigation;
+pub mod query;
+
 /// When `delete_everything` is called (to perform a permanent local deletion), in
 /// addition to performing the deletion as requested, we make a note of the time
 /// when it occurred, and refuse to sync incoming visits from before this time.
@@ -103,7 +109,11 @@ pub fn apply_observation_direct(
 
             let at = visit_ob.at.unwrap_or_else(Timestamp::now);
             let is_remote = visit_ob.is_remote.unwrap_or(false);
-            let row_id = add_visit(db, page_info.row_id, None, at, visit_type, !is_remote)?;
+            let from_visit = match visit_ob.referrer {
+                Some(ref referrer) => navigation::find_referrer_visit(db, referrer, at)?,
+                None => None,
+            };
+            let row_id = add_visit(db, page_info.row_id, from_visit, at, visit_type, !is_remote)?;
             // a new visit implies new frecency except in error cases.
             if !visit_ob.is_error.unwrap_or(false) {
                 update_frec = true;
@@ -153,7 +163,7 @@ pub fn apply_observation_direct(
 pub fn update_frecency(db: &PlacesDb, id: RowId, redirect_boost: Option<bool>) -> Result<()> {
     let score = frecency::calculate_frecency(
         db.conn(),
-        &frecency::DEFAULT_FRECENCY_SETTINGS,
+        db.frecency_settings(),
         id.0, // TODO: calculate_frecency should take a RowId here.
         redirect_boost,
     )?;
@@ -169,6 +179,117 @@ pub fn update_frecency(db: &PlacesDb, id: RowId, redirect_boost: Option<bool>) -
     Ok(())
 }
 
+/// The version of the frecency settings that the frecencies in the database
+/// were calculated with.
+const FRECENCY_SETTINGS_VERSION_META_KEY: &str = "frecency_settings_version";
+
+/// The maximum number of URLs for which to recalculate frecencies at once.
+/// This is a trade-off between write efficiency and transaction time: higher
+/// maximums mean fewer write statements, but longer transactions, possibly
+/// blocking writes from other connections.
+const MAX_FRECENCIES_TO_RECALCULATE_PER_CHUNK: usize = 400;
+
+/// Marks all frecencies as stale if they were calculated with a different
+/// version of the frecency settings than the connection's, and records the
+/// new version. Returns `true` if the frecencies were marked as stale.
+/// Databases without a recorded version were calculated with the default
+/// settings.
+pub fn mark_frecencies_stale_if_settings_changed(db: &PlacesDb) -> Result<bool> {
+    let version = db.frecency_settings().version;
+    let tx = db.begin_transaction()?;
+    let stored_version = get_meta::<u32>(db, FRECENCY_SETTINGS_VERSION_META_KEY)?
+        .unwrap_or(frecency::DEFAULT_FRECENCY_SETTINGS.version);
+    let changed = stored_version != version;
+    if changed {
+        // Pages without visits or bookmarks have a frecency of 0 with any
+        // settings, so we don't need to recalculate theirs.
+        db.execute_named(
+            "REPLACE INTO moz_places_stale_frecencies(place_id, stale_at)
+             SELECT id, :now FROM moz_places
+             WHERE frecency <> 0 OR foreign_count > 0",
+            &[(":now", &Timestamp::now())],
+        )?;
+    }
+    put_meta(db, FRECENCY_SETTINGS_VERSION_META_KEY, &version)?;
+    tx.commit()?;
+    Ok(changed)
+}
+
+/// Recalculates stale frecencies, most recently marked first, in chunks.
+/// Each chunk is committed in its own transaction, so this can run in the
+/// background, and be interrupted without losing the chunks that were
+/// already recalculated. Returns the number of recalculated frecencies.
+pub fn recalculate_stale_frecencies(
+    db: &PlacesDb,
+    interruptee: &impl Interruptee,
+) -> Result<usize> {
+    let mut count = 0;
+    let mut frecencies = Vec::with_capacity(MAX_FRECENCIES_TO_RECALCULATE_PER_CHUNK);
+    loop {
+        let tx = db.begin_transaction()?;
+        let sql = format!(
+            "SELECT place_id FROM moz_places_stale_frecencies
+             ORDER BY stale_at DESC
+             LIMIT {}",
+            MAX_FRECENCIES_TO_RECALCULATE_PER_CHUNK
+        );
+        let mut stmt = db.prepare_maybe_cached(&sql, true)?;
+        let mut results = stmt.query(NO_PARAMS)?;
+        while let Some(row) = results.next()? {
+            let place_id = row.get("place_id")?;
+            // Frecency recalculation runs several statements, so check to
+            // make sure we aren't interrupted before each calculation.
+            interruptee.err_if_interrupted()?;
+            let frecency =
+                frecency::calculate_frecency(db, db.frecency_settings(), place_id, Some(false))?;
+            frecencies.push((place_id, frecency));
+        }
+        drop(results);
+        drop(stmt);
+        if frecencies.is_empty() {
+            tx.commit()?;
+            break;
+        }
+
+        // Update all frecencies in one fell swoop...
+        db.execute_batch(&format!(
+            "WITH frecencies(id, frecency) AS (
+               VALUES {}
+             )
+             UPDATE moz_places SET
+               frecency = (SELECT frecency FROM frecencies f
+                           WHERE f.id = id)
+             WHERE id IN (SELECT f.id FROM frecencies f)",
+            sql_support::repeat_display(frecencies.len(), ",", |index, f| {
+                let (id, frecency) = frecencies[index];
+                write!(f, "({}, {})", id, frecency)
+            })
+        ))?;
+
+        // ...And remove them from the stale table.
+        db.execute_batch(&format!(
+            "DELETE FROM moz_places_stale_frecencies
+             WHERE place_id IN ({})",
+            sql_support::repeat_display(frecencies.len(), ",", |index, f| {
+                let (id, _) = frecencies[index];
+                write!(f, "{}", id)
+            })
+        ))?;
+        tx.commit()?;
+        count += frecencies.len();
+        interruptee.err_if_interrupted()?;
+
+        // If the query returned fewer URLs than the maximum, we're done.
+        // Otherwise, we might have more, so clear the ones we just
+        // recalculated and fetch the next chunk.
+        if frecencies.len() < MAX_FRECENCIES_TO_RECALCULATE_PER_CHUNK {
+            break;
+        }
+        frecencies.clear();
+    }
+    Ok(count)
+}
+
 /// Indicates if and when a URL's frecency was marked as stale.
 pub fn frecency_stale_at(db: &PlacesDb, url: &Url) -> Result<Option<Timestamp>> {
     let result = db.try_query_row(
@@ -381,9 +502,24 @@ pub fn delete_place_visit_at_time_by_href(
     Ok(())
 }
 
-pub fn prune_destructively(db: &PlacesDb) -> Result<()> {
-    // For now, just fall back to wipe_local until we decide how this should work.
-    wipe_local(db)
+/// Aggressively expires history to free up disk space, by halving the used
+/// size of the database. Like all expiration, this never removes bookmarked
+/// or tagged pages. If `sync_deletions` is true, the expired history is also
+/// removed from the server and other devices; otherwise, it's only removed
+/// locally.
+pub fn prune_destructively(db: &PlacesDb, sync_deletions: bool) -> Result<()> {
+    let used_size = expiration::used_db_size(db)?;
+    expiration::expire_history(
+        db,
+        &expiration::ExpirationLimits {
+            max_db_size: Some(used_size / 2),
+            sync_deletions,
+            ..expiration::ExpirationLimits::default()
+        },
+    )?;
+    // Note: SQLite cannot VACUUM within a transaction.
+    db.execute_batch("VACUUM")?;
+    Ok(())
 }
 
 pub fn wipe_local(db: &PlacesDb) -> Result<()> {
@@ -396,11 +532,18 @@ pub fn wipe_local(db: &PlacesDb) -> Result<()> {
 }
 
 fn wipe_local_in_tx(db: &PlacesDb) -> Result<()> {
-    use crate::frecency::DEFAULT_FRECENCY_SETTINGS;
     db.execute_all(&[
-        "DELETE FROM moz_places WHERE foreign_count == 0",
+        // Annotations that expire with history go with it, but pages with
+        // other annotations are kept, like bookmarked pages. 5 is
+        // `AnnotationExpiration::WithHistory`.
+        "DELETE FROM moz_annos WHERE expiration = 5",
+        "DELETE FROM moz_places
+         WHERE foreign_count == 0
+           AND NOT EXISTS(SELECT 1 FROM moz_annos a
+                          WHERE a.place_id = moz_places.id)",
         "DELETE FROM moz_places_metadata",
         "DELETE FROM moz_places_metadata_search_queries",
+        "DELETE FROM moz_places_metadata_tombstones",
         "DELETE FROM moz_historyvisits",
         "DELETE FROM moz_places_tombstones",
         "DELETE FROM moz_inputhistory AS i WHERE NOT EXISTS(
@@ -417,7 +560,7 @@ fn wipe_local_in_tx(db: &PlacesDb) -> Result<()> {
                                  ELSE {unvisited_bookmark_frec}
                             END),
                 sync_change_counter = 0"#,
-            unvisited_bookmark_frec = DEFAULT_FRECENCY_SETTINGS.unvisited_bookmark_bonus
+            unvisited_bookmark_frec = db.frecency_settings().unvisited_bookmark_bonus
         ),
     ])?;
 
@@ -428,6 +571,7 @@ fn wipe_local_in_tx(db: &PlacesDb) -> Result<()> {
     for row_id in need_frecency_update {
         update_frecency(db, row_id, None)?;
     }
+    crate::storage::favicons::delete_icons_for_removed_pages(db)?;
     delete_pending_temp_tables(db)?;
     Ok(())
 }
@@ -1835,6 +1979,47 @@ mod tests {
         // XXX - origins?
     }
 
+    #[test]
+    fn test_frecency_settings_version() -> Result<()> {
+        use crate::frecency::{FrecencyAging, FrecencySettings};
+        use interrupt_support::NeverInterrupts;
+        use std::sync::Arc;
+
+        let mut conn = PlacesDb::open_in_memory(ConnectionType::ReadWrite)?;
+        let url = Url::parse("https://www.example.com")?;
+        let visited = SystemTime::now() - Duration::from_secs(60 * 86_400);
+        apply_observation(
+            &conn,
+            VisitObservation::new(url.clone())
+                .with_visit_type(VisitTransition::Link)
+                .with_at(Some(visited.into())),
+        )?;
+        let page = fetch_page_info(&conn, &url)?.expect("should have the page");
+        assert_eq!(page.page.frecency, 30);
+        assert!(!mark_frecencies_stale_if_settings_changed(&conn)?);
+
+        conn.set_frecency_settings(Arc::new(FrecencySettings {
+            version: 2,
+            aging: FrecencyAging::Decay { half_life_days: 10 },
+            ..FrecencySettings::default()
+        }));
+        assert!(mark_frecencies_stale_if_settings_changed(&conn)?);
+        assert!(frecency_stale_at(&conn, &url)?.is_some());
+        assert!(!mark_frecencies_stale_if_settings_changed(&conn)?);
+
+        // An interrupted recalculation leaves the frecency stale.
+        let scope = conn.begin_interrupt_scope();
+        conn.new_interrupt_handle().interrupt();
+        assert!(recalculate_stale_frecencies(&conn, &scope).is_err());
+        assert!(frecency_stale_at(&conn, &url)?.is_some());
+
+        assert_eq!(recalculate_stale_frecencies(&conn, &NeverInterrupts)?, 1);
+        assert!(frecency_stale_at(&conn, &url)?.is_none());
+        let page = fetch_page_info(&conn, &url)?.expect("should have the page");
+        assert_eq!(page.page.frecency, 2);
+        Ok(())
+    }
+
     #[test]
     fn test_change_counter() -> Result<()> {
         let _ = env_logger::try_init();
@@ -2434,6 +2619,40 @@ mod tests {
         assert_tombstones(&conn, &[(info1.row_id, dates[2])]);
     }
 
+    #[test]
+    fn test_wipe_local_uses_frecency_settings() -> Result<()> {
+        use crate::frecency::FrecencySettings;
+        use crate::storage::bookmarks::{
+            self, BookmarkPosition, BookmarkRootGuid, InsertableBookmark, InsertableItem,
+        };
+        use std::sync::Arc;
+        let mut conn = PlacesDb::open_in_memory(ConnectionType::ReadWrite)?;
+        conn.set_frecency_settings(Arc::new(FrecencySettings {
+            version: 2,
+            unvisited_bookmark_bonus: 500,
+            ..FrecencySettings::default()
+        }));
+        let url = Url::parse("https://www.example.com/")?;
+        bookmarks::insert_bookmark(
+            &conn,
+            &InsertableItem::Bookmark(InsertableBookmark {
+                parent_guid: BookmarkRootGuid::Unfiled.into(),
+                position: BookmarkPosition::Append,
+                date_added: None,
+                last_modified: None,
+                guid: None,
+                url: url.clone(),
+                title: None,
+            }),
+        )?;
+        get_custom_observed_page(&mut conn, url.as_str(), |obs| obs)?;
+
+        wipe_local(&conn)?;
+        let page = fetch_page_info(&conn, &url)?.expect("should keep the bookmarked page");
+        assert_eq!(page.page.frecency, 500);
+        Ok(())
+    }
+
     #[test]
     fn test_wipe_local() {
         use crate::frecency::DEFAULT_FRECENCY_SETTINGS;
@@ -0,0 +1,528 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! History expiration.
+//!
+//! Unlike `delete_visits_between` and friends, which remove history the user
+//! asked us to forget, expiration removes history we've decided we can't
+//! afford to keep - either because the database has grown too large, or
+//! because the visits are too old to be interesting. We expire the oldest,
+//! least frecent visits first, and never touch pages with a non-zero
+//! `foreign_count` (that is, pages which are bookmarked, tagged, or have a
+//! keyword).
+//!
+//! Expiration runs in small chunks, each in its own transaction, so that it
+//! can be interrupted without losing the work done so far, and without
+//! holding the write lock for too long.
+
+use super::{update_frecency, RowId};
+use crate::db::PlacesDb;
+use crate::error::Result;
+use crate::storage::delete_pending_temp_tables;
+use crate::types::SyncStatus;
+use rusqlite::Row;
+use sql_support::{self, ConnExt};
+use std::collections::HashSet;
+use std::time::Duration;
+use types::Timestamp;
+
+/// The maximum number of visits or pages we expire in a single transaction.
+const EXPIRATION_CHUNK_SIZE: usize = 500;
+
+/// A page is "orphaned" if it has no visits, isn't referenced by a bookmark,
+/// tag or keyword, and has no history metadata. History metadata has its own
+/// expiration policy (see `history_metadata::delete_older_than`), so we leave
+/// those pages alone until their metadata is gone.
+const ORPHANED_PAGE_CONDITION: &str = "
+    foreign_count = 0
+    AND last_visit_date_local = 0
+    AND last_visit_date_remote = 0
+    AND NOT EXISTS(SELECT 1 FROM moz_places_metadata m
+                   WHERE m.place_id = moz_places.id
+                      OR m.referrer_place_id = moz_places.id)
+    -- Pages with annotations that outlive history aren't orphans; 5 is
+    -- `AnnotationExpiration::WithHistory`.
+    AND NOT EXISTS(SELECT 1 FROM moz_annos a
+                   WHERE a.place_id = moz_places.id AND a.expiration != 5)";
+
+/// The limits `expire_history` tries to bring the database within. If both
+/// limits are set, we keep expiring until both are satisfied.
+#[derive(Debug, Clone, Default)]
+pub struct ExpirationLimits {
+    /// The maximum size, in bytes, of the used portion of the database. Note
+    /// that expiration doesn't `VACUUM`, so the file itself won't shrink
+    /// until the caller does.
+    pub max_db_size: Option<u64>,
+    /// Visits older than this are expired, regardless of the database size.
+    pub max_visit_age: Option<Duration>,
+    /// If true, we write tombstones for expired visits and pages, so that
+    /// they're also removed from the server and other devices. By default,
+    /// expiration only affects the local database.
+    pub sync_deletions: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ExpirationResult {
+    pub pages_removed: usize,
+    pub visits_removed: usize,
+    /// False if we ran out of things we're allowed to expire before the
+    /// database was within the size limit - for example, because everything
+    /// that's left is bookmarked.
+    pub limits_reached: bool,
+}
+
+#[derive(Debug)]
+struct VisitToExpire {
+    id: RowId,
+    place_id: RowId,
+    visit_date: Timestamp,
+    page_sync_status: SyncStatus,
+}
+
+impl VisitToExpire {
+    fn from_row(row: &Row<'_>) -> Result<Self> {
+        Ok(Self {
+            id: row.get("id")?,
+            place_id: row.get("place_id")?,
+            visit_date: row.get("visit_date")?,
+            page_sync_status: row.get("sync_status")?,
+        })
+    }
+}
+
+/// Expires visits and orphaned pages until the database is within `limits`.
+///
+/// Each chunk is committed as it's expired, so if this is interrupted, the
+/// database will still be consistent, and everything expired up to that
+/// point stays expired.
+pub fn expire_history(db: &PlacesDb, limits: &ExpirationLimits) -> Result<ExpirationResult> {
+    expire_history_in_chunks(db, limits, EXPIRATION_CHUNK_SIZE)
+}
+
+fn expire_history_in_chunks(
+    db: &PlacesDb,
+    limits: &ExpirationLimits,
+    chunk_size: usize,
+) -> Result<ExpirationResult> {
+    let scope = db.begin_interrupt_scope();
+    let mut result = ExpirationResult::default();
+
+    // Orphans are free to remove, so start with those.
+    loop {
+        scope.err_if_interrupted()?;
+        let page_ids = db.query_rows_and_then_named(
+            &format!(
+                "SELECT id FROM moz_places WHERE {orphaned} LIMIT :limit",
+                orphaned = ORPHANED_PAGE_CONDITION
+            ),
+            &[(":limit", &(chunk_size as i64))],
+            |row| row.get::<_, RowId>(0),
+        )?;
+        if page_ids.is_empty() {
+            break;
+        }
+        let tx = db.begin_transaction()?;
+        result.pages_removed += expire_pages(db, &page_ids, limits.sync_deletions)?;
+        delete_pending_temp_tables(db)?;
+        tx.commit()?;
+    }
+
+    let cutoff = limits
+        .max_visit_age
+        .and_then(|age| Timestamp::now().checked_sub(age));
+    result.limits_reached = loop {
+        scope.err_if_interrupted()?;
+        let over_size = match limits.max_db_size {
+            Some(max_db_size) => used_db_size(db)? > max_db_size,
+            None => false,
+        };
+        // If we're over the size limit, the oldest visits go regardless of
+        // their age; otherwise, we only expire visits before the cutoff.
+        let before = if over_size {
+            None
+        } else if cutoff.is_some() {
+            cutoff
+        } else {
+            break true;
+        };
+        let visits = db.query_rows_and_then_named(
+            "SELECT v.id, v.place_id, v.visit_date, h.sync_status
+             FROM moz_historyvisits v
+             JOIN moz_places h ON h.id = v.place_id
+             WHERE h.foreign_count = 0
+               AND (:before IS NULL OR v.visit_date < :before)
+             ORDER BY v.visit_date ASC, h.frecency ASC
+             LIMIT :limit",
+            &[(":before", &before), (":limit", &(chunk_size as i64))],
+            VisitToExpire::from_row,
+        )?;
+        if visits.is_empty() {
+            // Everything that's left is either recent enough to keep, or
+            // belongs to a page we never expire.
+            break !over_size;
+        }
+        let tx = db.begin_transaction()?;
+        let pages_removed = expire_visits(db, &visits, limits.sync_deletions)?;
+        delete_pending_temp_tables(db)?;
+        tx.commit()?;
+        result.visits_removed += visits.len();
+        result.pages_removed += pages_removed;
+    };
+
+    log::info!(
+        "Expired {} visits and {} pages",
+        result.visits_removed,
+        result.pages_removed
+    );
+    Ok(result)
+}
+
+/// Returns the number of bytes used by the database, not counting free pages.
+pub(super) fn used_db_size(db: &PlacesDb) -> Result<u64> {
+    let page_size = db.query_one::<i64>("PRAGMA page_size")?;
+    let page_count = db.query_one::<i64>("PRAGMA page_count")?;
+    let freelist_count = db.query_one::<i64>("PRAGMA freelist_count")?;
+    Ok(((page_count - freelist_count).max(0) * page_size) as u64)
+}
+
+/// Removes a chunk of visits, then removes any pages they orphaned, and
+/// recalculates frecencies for the rest. Returns the number of pages removed.
+/// Assumes a transaction is already set up by the caller.
+fn expire_visits(db: &PlacesDb, visits: &[VisitToExpire], sync_deletions: bool) -> Result<usize> {
+    sql_support::each_chunk_mapped(
+        visits,
+        |visit| visit.id,
+        |chunk, _| -> Result<()> {
+            db.conn().execute(
+                &format!(
+                    "DELETE FROM moz_historyvisits WHERE id IN ({})",
+                    sql_support::repeat_sql_vars(chunk.len()),
+                ),
+                chunk,
+            )?;
+            Ok(())
+        },
+    )?;
+
+    if sync_deletions {
+        // Pages that have never been synced don't have any visits on the
+        // server to delete.
+        let synced_visits: Vec<&VisitToExpire> = visits
+            .iter()
+            .filter(|visit| visit.page_sync_status == SyncStatus::Normal)
+            .collect();
+        sql_support::each_chunk(&synced_visits, |chunk, _| -> Result<()> {
+            db.conn().execute_batch(&format!(
+                "INSERT OR IGNORE INTO moz_historyvisit_tombstones(place_id, visit_date)
+                 VALUES {}",
+                sql_support::repeat_display(chunk.len(), ",", |i, f| {
+                    write!(f, "({},{})", chunk[i].place_id, chunk[i].visit_date)
+                })
+            ))?;
+            Ok(())
+        })?;
+    }
+
+    let mut seen = HashSet::new();
+    let page_ids: Vec<RowId> = visits
+        .iter()
+        .map(|visit| visit.place_id)
+        .filter(|place_id| seen.insert(*place_id))
+        .collect();
+    let pages_removed = expire_pages(db, &page_ids, sync_deletions)?;
+
+    // Any pages we didn't remove still have visits, so their frecencies
+    // need updating.
+    sql_support::each_chunk(&page_ids, |chunk, _| -> Result<()> {
+        let remaining = db.query_rows_and_then_named(
+            &format!(
+                "SELECT id FROM moz_places WHERE id IN ({})",
+                sql_support::repeat_display(chunk.len(), ",", |i, f| write!(f, "{}", chunk[i]))
+            ),
+            &[],
+            |row| row.get::<_, RowId>(0),
+        )?;
+        for page_id in remaining {
+            update_frecency(db, page_id, None)?;
+        }
+        Ok(())
+    })?;
+
+    Ok(pages_removed)
+}
+
+/// Removes any of the given pages which are orphaned, writing tombstones for
+/// them if requested. Returns the number of pages removed. Assumes a
+/// transaction is already set up by the caller.
+fn expire_pages(db: &PlacesDb, page_ids: &[RowId], sync_deletions: bool) -> Result<usize> {
+    let mut pages_removed = 0;
+    sql_support::each_chunk(page_ids, |chunk, _| -> Result<()> {
+        if sync_deletions {
+            db.conn().execute(
+                &format!(
+                    "INSERT OR IGNORE INTO moz_places_tombstones (guid)
+                     SELECT guid FROM moz_places
+                     WHERE id IN ({ids})
+                       AND sync_status = {status}
+                       AND {orphaned}",
+                    ids = sql_support::repeat_sql_vars(chunk.len()),
+                    status = SyncStatus::Normal as u8,
+                    orphaned = ORPHANED_PAGE_CONDITION,
+                ),
+                chunk,
+            )?;
+        }
+        pages_removed += db.conn().execute(
+            &format!(
+                "DELETE FROM moz_places
+                 WHERE id IN ({ids})
+                   AND {orphaned}",
+                ids = sql_support::repeat_sql_vars(chunk.len()),
+                orphaned = ORPHANED_PAGE_CONDITION,
+            ),
+            chunk,
+        )?;
+        Ok(())
+    })?;
+    Ok(pages_removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::places_api::ConnectionType;
+    use crate::observation::VisitObservation;
+    use crate::storage::bookmarks::{
+        self, BookmarkPosition, BookmarkRootGuid, InsertableBookmark, InsertableItem,
+    };
+    use crate::storage::history::apply_observation;
+    use crate::types::VisitTransition;
+    use sync_guid::Guid as SyncGuid;
+    use url::Url;
+
+    const DAY_MS: u64 = 24 * 60 * 60 * 1000;
+
+    fn add_visit(conn: &PlacesDb, url: &str, at: Timestamp) {
+        apply_observation(
+            conn,
+            VisitObservation::new(Url::parse(url).unwrap())
+                .with_at(at)
+                .with_visit_type(VisitTransition::Link),
+        )
+        .expect("should apply visit");
+    }
+
+    fn bookmark(conn: &PlacesDb, url: &str) -> SyncGuid {
+        bookmarks::insert_bookmark(
+            conn,
+            &InsertableItem::Bookmark(InsertableBookmark {
+                parent_guid: BookmarkRootGuid::Unfiled.into(),
+                position: BookmarkPosition::Append,
+                date_added: None,
+                last_modified: None,
+                guid: None,
+                url: Url::parse(url).unwrap(),
+                title: None,
+            }),
+        )
+        .expect("should insert bookmark")
+    }
+
+    fn count(conn: &PlacesDb, sql: &str) -> i64 {
+        conn.query_one::<i64>(sql).unwrap()
+    }
+
+    #[test]
+    fn test_expire_by_age() {
+        let _ = env_logger::try_init();
+        let conn = PlacesDb::open_in_memory(ConnectionType::ReadWrite).unwrap();
+        let now = Timestamp::now();
+        let old = Timestamp(now.0 - 100 * DAY_MS);
+
+        add_visit(&conn, "https://example.com/old", old);
+        add_visit(&conn, "https://example.com/mixed", old);
+        add_visit(&conn, "https://example.com/mixed", now);
+        add_visit(&conn, "https://example.com/new", now);
+        add_visit(&conn, "https://example.com/bookmarked", old);
+        bookmark(&conn, "https://example.com/bookmarked");
+        // Removing a bookmark leaves its page behind, without any visits.
+        let guid = bookmark(&conn, "https://example.com/orphan");
+        assert!(bookmarks::delete_bookmark(&conn, &guid).unwrap());
+
+        let result = expire_history_in_chunks(
+            &conn,
+            &ExpirationLimits {
+                max_visit_age: Some(Duration::from_millis(30 * DAY_MS)),
+                ..ExpirationLimits::default()
+            },
+            1,
+        )
+        .expect("should expire");
+
+        assert_eq!(
+            result,
+            ExpirationResult {
+                pages_removed: 2,
+                visits_removed: 2,
+                limits_reached: true,
+            }
+        );
+        let urls = conn
+            .query_rows_and_then_named("SELECT url FROM moz_places ORDER BY url", &[], |row| {
+                row.get::<_, String>(0)
+            })
+            .unwrap();
+        assert_eq!(
+            urls,
+            vec![
+                "https://example.com/bookmarked",
+                "https://example.com/mixed",
+                "https://example.com/new",
+            ]
+        );
+        // The bookmarked page keeps its old visit.
+        assert_eq!(count(&conn, "SELECT COUNT(*) FROM moz_historyvisits"), 3);
+        // Expiration is local by default.
+        assert_eq!(
+            count(&conn, "SELECT COUNT(*) FROM moz_places_tombstones"),
+            0
+        );
+        assert_eq!(
+            count(&conn, "SELECT COUNT(*) FROM moz_historyvisit_tombstones"),
+            0
+        );
+    }
+
+    #[test]
+    fn test_expire_with_sync_deletions() {
+        let conn = PlacesDb::open_in_memory(ConnectionType::ReadWrite).unwrap();
+        let now = Timestamp::now();
+        let old = Timestamp(now.0 - 100 * DAY_MS);
+
+        add_visit(&conn, "https://example.com/old", old);
+        add_visit(&conn, "https://example.com/mixed", old);
+        add_visit(&conn, "https://example.com/mixed", now);
+        conn.execute_batch(&format!(
+            "UPDATE moz_places SET sync_status = {}",
+            SyncStatus::Normal as u8
+        ))
+        .unwrap();
+
+        let result = expire_history(
+            &conn,
+            &ExpirationLimits {
+                max_visit_age: Some(Duration::from_millis(30 * DAY_MS)),
+                sync_deletions: true,
+                ..ExpirationLimits::default()
+            },
+        )
+        .expect("should expire");
+        assert_eq!(result.visits_removed, 2);
+        assert_eq!(result.pages_removed, 1);
+        assert_eq!(
+            count(&conn, "SELECT COUNT(*) FROM moz_places_tombstones"),
+            1
+        );
+        // The tombstone for the visit to "old" was removed along with the
+        // page, since the page tombstone covers it.
+        assert_eq!(
+            count(&conn, "SELECT COUNT(*) FROM moz_historyvisit_tombstones"),
+            1
+        );
+    }
+
+    #[test]
+    fn test_expire_unsynced_without_tombstones() {
+        let conn = PlacesDb::open_in_memory(ConnectionType::ReadWrite).unwrap();
+        let now = Timestamp::now();
+        let old = Timestamp(now.0 - 100 * DAY_MS);
+
+        add_visit(&conn, "https://example.com/synced", old);
+        add_visit(&conn, "https://example.com/synced", now);
+        add_visit(&conn, "https://example.com/new", old);
+        add_visit(&conn, "https://example.com/new", now);
+        conn.execute_batch(&format!(
+            "UPDATE moz_places SET sync_status = {}
+             WHERE url = 'https://example.com/synced'",
+            SyncStatus::Normal as u8
+        ))
+        .unwrap();
+
+        let result = expire_history(
+            &conn,
+            &ExpirationLimits {
+                max_visit_age: Some(Duration::from_millis(30 * DAY_MS)),
+                sync_deletions: true,
+                ..ExpirationLimits::default()
+            },
+        )
+        .expect("should expire");
+        assert_eq!(result.visits_removed, 2);
+        // Only the synced page's visit needs a tombstone.
+        let tombstoned_urls = conn
+            .query_rows_and_then_named(
+                "SELECT h.url FROM moz_historyvisit_tombstones t
+                 JOIN moz_places h ON h.id = t.place_id",
+                &[],
+                |row| row.get::<_, String>(0),
+            )
+            .unwrap();
+        assert_eq!(tombstoned_urls, vec!["https://example.com/synced"]);
+    }
+
+    #[test]
+    fn test_expire_by_size() {
+        let conn = PlacesDb::open_in_memory(ConnectionType::ReadWrite).unwrap();
+        let now = Timestamp::now();
+        for i in 0..50 {
+            add_visit(
+                &conn,
+                &format!("https://example.com/{}", i),
+                Timestamp(now.0 - i * DAY_MS),
+            );
+        }
+        add_visit(&conn, "https://example.com/bookmarked", now);
+        bookmark(&conn, "https://example.com/bookmarked");
+
+        // Nothing fits in zero bytes, so we expire everything we're allowed
+        // to, and report that we couldn't reach the limit.
+        let result = expire_history_in_chunks(
+            &conn,
+            &ExpirationLimits {
+                max_db_size: Some(0),
+                ..ExpirationLimits::default()
+            },
+            7,
+        )
+        .expect("should expire");
+        assert_eq!(
+            result,
+            ExpirationResult {
+                pages_removed: 50,
+                visits_removed: 50,
+                limits_reached: false,
+            }
+        );
+        assert_eq!(count(&conn, "SELECT COUNT(*) FROM moz_historyvisits"), 1);
+
+        // A generous limit doesn't expire anything.
+        add_visit(&conn, "https://example.com/new", now);
+        let result = expire_history(
+            &conn,
+            &ExpirationLimits {
+                max_db_size: Some(u64::MAX),
+                ..ExpirationLimits::default()
+            },
+        )
+        .expect("should expire");
+        assert_eq!(
+            result,
+            ExpirationResult {
+                pages_removed: 0,
+                visits_removed: 0,
+                limits_reached: true,
+            }
+        );
+    }
+}
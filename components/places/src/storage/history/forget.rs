@@ -0,0 +1,472 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! "Forget about this site": removes everything we know about a site's
+//! pages, including their visits, history metadata, input history, icons,
+//! and (optionally) bookmarks, tags and keywords.
+//!
+//! Like expiration, this runs in chunks, each in its own transaction, so a
+//! site with a huge history doesn't hold the write lock for long, and can be
+//! interrupted without losing the pages already forgotten. Unlike expiration,
+//! the user asked for this, so it writes tombstones to remove the history
+//! from other devices, too.
+
+use super::{delete_visits_for_in_tx, update_frecency, RowId};
+use crate::db::PlacesDb;
+use crate::error::Result;
+use crate::storage::bookmarks::delete_bookmark_in_tx;
+use crate::storage::{delete_pending_temp_tables, favicons};
+use crate::types::SyncStatus;
+use sql_support::{self, ConnExt};
+use sync_guid::Guid as SyncGuid;
+
+/// The maximum number of pages we forget in a single transaction.
+const FORGET_CHUNK_SIZE: usize = 500;
+
+/// Which pages `forget_site` removes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SiteFilter {
+    /// Pages on exactly this host, like `www.example.com`, on any port.
+    Host(String),
+    /// Pages on this domain or any of its subdomains. Pass the base domain
+    /// (eTLD+1), like `example.com`, to forget a whole site.
+    BaseDomain(String),
+}
+
+impl SiteFilter {
+    /// Returns whether a `moz_origins.host` matches this filter.
+    fn matches(&self, host_and_port: &str) -> bool {
+        let host = strip_port(host_and_port);
+        match self {
+            SiteFilter::Host(h) => host.eq_ignore_ascii_case(h),
+            SiteFilter::BaseDomain(domain) => {
+                host.eq_ignore_ascii_case(domain)
+                    || (host.len() > domain.len()
+                        && host.is_char_boundary(host.len() - domain.len() - 1)
+                        && host[host.len() - domain.len() - 1..]
+                            .eq_ignore_ascii_case(&format!(".{}", domain)))
+            }
+        }
+    }
+}
+
+/// Removes the port, if any, from a host. IPv6 hosts are in brackets, so the
+/// port is whatever follows the closing bracket.
+fn strip_port(host_and_port: &str) -> &str {
+    if host_and_port.starts_with('[') {
+        match host_and_port.find(']') {
+            Some(end) => &host_and_port[..=end],
+            None => host_and_port,
+        }
+    } else {
+        host_and_port.split(':').next().unwrap_or(host_and_port)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ForgetSiteResult {
+    pub pages_removed: usize,
+    pub visits_removed: usize,
+    pub bookmarks_removed: usize,
+}
+
+/// Removes all pages that match `site`, with their visits, history metadata,
+/// input history, icons and stale frecencies, and writes tombstones so that
+/// the pages are removed from other devices on the next sync. Origins are
+/// removed along with their last page.
+///
+/// If `remove_bookmarks` is true, bookmarks, tags and keywords for the pages
+/// are removed, too. Otherwise, bookmarked pages are kept, but their visits
+/// are still removed.
+pub fn forget_site(
+    db: &PlacesDb,
+    site: &SiteFilter,
+    remove_bookmarks: bool,
+) -> Result<ForgetSiteResult> {
+    forget_site_in_chunks(db, site, remove_bookmarks, FORGET_CHUNK_SIZE)
+}
+
+fn forget_site_in_chunks(
+    db: &PlacesDb,
+    site: &SiteFilter,
+    remove_bookmarks: bool,
+    chunk_size: usize,
+) -> Result<ForgetSiteResult> {
+    let scope = db.begin_interrupt_scope();
+    let mut result = ForgetSiteResult::default();
+
+    // There are far fewer origins than pages, so it's cheaper to match hosts
+    // here than to parse every page URL in SQL.
+    let origin_ids = db
+        .query_rows_and_then_named_cached(
+            "SELECT id, host FROM moz_origins",
+            &[],
+            |row| -> Result<_> { Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?)) },
+        )?
+        .into_iter()
+        .filter(|(_, host)| site.matches(host))
+        .map(|(id, _)| id)
+        .collect::<Vec<_>>();
+    let origin_ids_sql =
+        sql_support::repeat_display(origin_ids.len(), ",", |i, f| write!(f, "{}", origin_ids[i]))
+            .to_string();
+
+    // Pages we keep because they're bookmarked stay in `moz_places`, so we
+    // page through by id instead of asking for what's left. Icons aren't
+    // tied to `moz_places`, so they're removed by host in the last chunk.
+    let mut last_id = 0i64;
+    loop {
+        scope.err_if_interrupted()?;
+        let tx = db.begin_transaction()?;
+        let pages = db.query_rows_and_then_named(
+            &format!(
+                "SELECT id, guid FROM moz_places
+                 WHERE origin_id IN ({origin_ids}) AND id > :last_id
+                 ORDER BY id
+                 LIMIT :limit",
+                origin_ids = origin_ids_sql
+            ),
+            &[(":last_id", &last_id), (":limit", &(chunk_size as i64))],
+            |row| -> Result<_> { Ok((row.get::<_, RowId>(0)?, row.get::<_, SyncGuid>(1)?)) },
+        )?;
+        if let Some((last_page_id, _)) = pages.last() {
+            last_id = last_page_id.0;
+            forget_pages(db, &pages, remove_bookmarks, &mut result)?;
+            delete_pending_temp_tables(db)?;
+        }
+        let is_last_chunk = pages.len() < chunk_size;
+        if is_last_chunk {
+            favicons::delete_icons_for_hosts(db, |host| site.matches(host))?;
+        }
+        tx.commit()?;
+        if is_last_chunk {
+            break;
+        }
+    }
+
+    log::info!(
+        "Forgot {} pages, {} visits and {} bookmarks",
+        result.pages_removed,
+        result.visits_removed,
+        result.bookmarks_removed
+    );
+    Ok(result)
+}
+
+/// Forgets a chunk of pages. Assumes a transaction is already set up by the
+/// caller.
+fn forget_pages(
+    db: &PlacesDb,
+    pages: &[(RowId, SyncGuid)],
+    remove_bookmarks: bool,
+    result: &mut ForgetSiteResult,
+) -> Result<()> {
+    // Page ids are integers, so they're safe to format into the SQL.
+    let ids = sql_support::repeat_display(pages.len(), ",", |i, f| write!(f, "{}", pages[i].0))
+        .to_string();
+
+    if remove_bookmarks {
+        let bookmark_guids = db.query_rows_and_then_named(
+            &format!("SELECT guid FROM moz_bookmarks WHERE fk IN ({})", ids),
+            &[],
+            |row| row.get::<_, SyncGuid>(0),
+        )?;
+        for guid in &bookmark_guids {
+            if delete_bookmark_in_tx(db, guid)? {
+                result.bookmarks_removed += 1;
+            }
+        }
+        db.execute_batch(&format!(
+            "DELETE FROM moz_tags_relation WHERE place_id IN ({ids});
+             DELETE FROM moz_keywords WHERE place_id IN ({ids});",
+            ids = ids
+        ))?;
+    }
+
+    // Metadata for these pages, or that they referred to. Entries we've
+    // uploaded need tombstones.
+    db.execute_batch(&format!(
+        "INSERT OR IGNORE INTO moz_places_metadata_tombstones(guid)
+         SELECT guid FROM moz_places_metadata
+         WHERE (place_id IN ({ids}) OR referrer_place_id IN ({ids})) AND
+               sync_status = {normal};
+         DELETE FROM moz_places_metadata
+         WHERE place_id IN ({ids}) OR referrer_place_id IN ({ids});
+         DELETE FROM moz_inputhistory WHERE place_id IN ({ids});
+         DELETE FROM moz_places_stale_frecencies WHERE place_id IN ({ids});",
+        ids = ids,
+        normal = SyncStatus::Normal as u8
+    ))?;
+    result.visits_removed += db.query_one::<i64>(&format!(
+        "SELECT COUNT(*) FROM moz_historyvisits WHERE place_id IN ({})",
+        ids
+    ))? as usize;
+
+    for (_, guid) in pages {
+        delete_visits_for_in_tx(db, guid)?;
+    }
+
+    // Pages that are still bookmarked are kept, without their visits.
+    let kept = db.query_rows_and_then_named(
+        &format!("SELECT id FROM moz_places WHERE id IN ({})", ids),
+        &[],
+        |row| row.get::<_, RowId>(0),
+    )?;
+    for id in &kept {
+        update_frecency(db, *id, None)?;
+    }
+    result.pages_removed += pages.len() - kept.len();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::places_api::test::new_mem_connection;
+    use crate::observation::VisitObservation;
+    use crate::storage::bookmarks::{
+        insert_bookmark, BookmarkPosition, BookmarkRootGuid, InsertableBookmark,
+    };
+    use crate::storage::favicons::{get_icons_for_page, set_page_icon, InsertableIcon};
+    use crate::storage::history::apply_observation;
+    use crate::storage::history_metadata::{
+        apply_metadata_observation, HistoryMetadataObservation,
+    };
+    use crate::storage::tags::tag_url;
+    use crate::types::VisitTransition;
+    use url::Url;
+
+    fn visit(db: &PlacesDb, url: &str) {
+        apply_observation(
+            db,
+            VisitObservation::new(Url::parse(url).unwrap()).with_visit_type(VisitTransition::Link),
+        )
+        .expect("should apply visit");
+    }
+
+    fn urls(db: &PlacesDb) -> Vec<String> {
+        db.query_rows_and_then_named("SELECT url FROM moz_places ORDER BY url", &[], |row| {
+            row.get::<_, String>(0)
+        })
+        .unwrap()
+    }
+
+    fn count(db: &PlacesDb, sql: &str) -> i64 {
+        db.query_one(sql).unwrap()
+    }
+
+    #[test]
+    fn test_site_filter() {
+        let domain = SiteFilter::BaseDomain("example.com".into());
+        assert!(domain.matches("example.com"));
+        assert!(domain.matches("www.example.com:8080"));
+        assert!(domain.matches("A.B.EXAMPLE.COM"));
+        assert!(!domain.matches("myexample.com"));
+        assert!(!domain.matches("example.com.evil"));
+
+        let host = SiteFilter::Host("www.example.com".into());
+        assert!(host.matches("www.example.com:443"));
+        assert!(!host.matches("example.com"));
+        assert!(!host.matches("a.www.example.com"));
+
+        assert_eq!(strip_port("[::1]:8080"), "[::1]");
+        assert!(SiteFilter::Host("[::1]".into()).matches("[::1]:8080"));
+    }
+
+    #[test]
+    fn test_forget_site() -> Result<()> {
+        let conn = new_mem_connection();
+        for i in 0..5 {
+            visit(&conn, &format!("https://example.com/{}", i));
+        }
+        visit(&conn, "https://www.example.com/");
+        visit(&conn, "https://example.com/1");
+        visit(&conn, "https://mozilla.org/");
+        visit(&conn, "https://myexample.com/");
+
+        let bookmarked = Url::parse("https://example.com/bookmarked")?;
+        visit(&conn, bookmarked.as_str());
+        insert_bookmark(
+            &conn,
+            &InsertableBookmark {
+                parent_guid: BookmarkRootGuid::Unfiled.into(),
+                position: BookmarkPosition::Append,
+                date_added: None,
+                last_modified: None,
+                guid: None,
+                url: bookmarked.clone(),
+                title: None,
+            }
+            .into(),
+        )?;
+        tag_url(&conn, &bookmarked, "kept")?;
+
+        apply_metadata_observation(
+            &conn,
+            HistoryMetadataObservation {
+                url: "https://mozilla.org/".into(),
+                view_time: Some(10),
+                search_term: None,
+                document_type: None,
+                referrer_url: Some("https://www.example.com/".into()),
+                title: None,
+            },
+        )?;
+        conn.execute_batch(
+            "INSERT INTO moz_inputhistory(place_id, input, use_count)
+             SELECT id, 'ex', 1 FROM moz_places WHERE url = 'https://example.com/0';
+             UPDATE moz_places SET sync_status = 2;",
+        )?;
+
+        // Keep bookmarks, and use a tiny chunk size to exercise chunking.
+        let result = forget_site_in_chunks(
+            &conn,
+            &SiteFilter::BaseDomain("example.com".into()),
+            false,
+            2,
+        )?;
+        assert_eq!(
+            result,
+            ForgetSiteResult {
+                pages_removed: 6,
+                visits_removed: 8,
+                bookmarks_removed: 0,
+            }
+        );
+        assert_eq!(
+            urls(&conn),
+            vec![
+                "https://example.com/bookmarked",
+                "https://mozilla.org/",
+                "https://myexample.com/",
+            ]
+        );
+        // The bookmarked page lost its visits.
+        assert_eq!(
+            count(
+                &conn,
+                "SELECT COUNT(*) FROM moz_historyvisits v
+                 JOIN moz_places h ON h.id = v.place_id
+                 WHERE h.url = 'https://example.com/bookmarked'"
+            ),
+            0
+        );
+        // Synced pages get tombstones, and so do visits to pages we kept.
+        assert_eq!(
+            count(&conn, "SELECT COUNT(*) FROM moz_places_tombstones"),
+            6
+        );
+        assert_eq!(
+            count(&conn, "SELECT COUNT(*) FROM moz_historyvisit_tombstones"),
+            1
+        );
+        assert_eq!(count(&conn, "SELECT COUNT(*) FROM moz_places_metadata"), 0);
+        assert_eq!(count(&conn, "SELECT COUNT(*) FROM moz_inputhistory"), 0);
+        assert_eq!(
+            count(
+                &conn,
+                "SELECT COUNT(*) FROM moz_origins WHERE host = 'www.example.com'"
+            ),
+            0
+        );
+
+        // Now remove the bookmark, too.
+        let result = forget_site(&conn, &SiteFilter::Host("example.com".into()), true)?;
+        assert_eq!(
+            result,
+            ForgetSiteResult {
+                pages_removed: 1,
+                visits_removed: 0,
+                bookmarks_removed: 1,
+            }
+        );
+        assert_eq!(
+            urls(&conn),
+            vec!["https://mozilla.org/", "https://myexample.com/"]
+        );
+        assert_eq!(count(&conn, "SELECT COUNT(*) FROM moz_tags_relation"), 0);
+        assert_eq!(
+            count(
+                &conn,
+                "SELECT COUNT(*) FROM moz_origins WHERE host = 'example.com'"
+            ),
+            0
+        );
+
+        // Forgetting a site we don't know about is fine.
+        assert_eq!(
+            forget_site(
+                &conn,
+                &SiteFilter::BaseDomain("unknown.example".into()),
+                true
+            )?,
+            ForgetSiteResult::default()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_forget_site_icons() -> Result<()> {
+        let conn = new_mem_connection();
+        let set_icon = |page: &str, icon: &str, is_root: bool| -> Result<()> {
+            set_page_icon(
+                &conn,
+                &Url::parse(page)?,
+                &InsertableIcon {
+                    icon_url: Url::parse(icon)?,
+                    width: 16,
+                    mime_type: "image/png".into(),
+                    data: icon.as_bytes().to_vec(),
+                    is_root,
+                    expire_at: None,
+                },
+            )
+        };
+        let icons = |page: &str| -> Result<Vec<String>> {
+            Ok(get_icons_for_page(&conn, &Url::parse(page)?)?
+                .into_iter()
+                .map(|icon| icon.icon_url.to_string())
+                .collect())
+        };
+
+        visit(&conn, "https://example.com/visited");
+        set_icon(
+            "https://example.com/visited",
+            "https://example.com/visited.png",
+            false,
+        )?;
+        // Pages don't need to be in history to have icons.
+        set_icon(
+            "https://www.example.com/unvisited",
+            "https://www.example.com/favicon.ico",
+            true,
+        )?;
+        set_icon(
+            "https://mozilla.org/unvisited",
+            "https://mozilla.org/page.png",
+            false,
+        )?;
+        set_icon(
+            "https://mozilla.org/other",
+            "https://mozilla.org/favicon.ico",
+            true,
+        )?;
+
+        forget_site(&conn, &SiteFilter::BaseDomain("example.com".into()), true)?;
+        assert!(icons("https://example.com/visited")?.is_empty());
+        assert!(icons("https://www.example.com/unvisited")?.is_empty());
+        // The other site's page icon and root icon survive.
+        assert_eq!(
+            icons("https://mozilla.org/unvisited")?,
+            vec!["https://mozilla.org/page.png"]
+        );
+        assert_eq!(
+            icons("https://mozilla.org/never-seen")?,
+            vec!["https://mozilla.org/favicon.ico"]
+        );
+        assert_eq!(count(&conn, "SELECT COUNT(*) FROM moz_icons"), 2);
+        assert_eq!(count(&conn, "SELECT COUNT(*) FROM moz_icons_data"), 2);
+        Ok(())
+    }
+}
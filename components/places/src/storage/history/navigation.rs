@@ -0,0 +1,469 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Functions for following how the user navigated between pages. Each visit
+//! that came from another one, because the user followed a link or was
+//! redirected, points at it with `moz_historyvisits.from_visit`.
+//!
+//! `from_visit` is only set for local visits observed with a referrer; we
+//! don't know where synced visits came from.
+
+use crate::db::PlacesDb;
+use crate::error::Result;
+use crate::storage::RowId;
+use crate::types::VisitTransition;
+use rusqlite::Row;
+use sql_support::ConnExt;
+use std::collections::{HashMap, HashSet};
+use types::Timestamp;
+use url::Url;
+
+/// How long after visiting a referrer a visit can still be linked to it.
+/// Like Desktop, we don't link visits to referrers from much earlier,
+/// because the referrer was probably loaded again since, and that visit
+/// wasn't recorded.
+const REFERRER_VISIT_THRESHOLD_MS: u64 = 15 * 60 * 1000;
+
+/// How many visits we follow when walking a chain, so that a `from_visit`
+/// cycle in a damaged or imported database can't make us loop forever.
+const MAX_CHAIN_LENGTH: i64 = 100;
+
+/// A visit, and the visit it came from.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NavigationVisit {
+    pub visit_id: RowId,
+    pub from_visit: Option<RowId>,
+    pub url: String,
+    pub title: Option<String>,
+    pub visit_date: Timestamp,
+    pub visit_type: VisitTransition,
+}
+
+impl NavigationVisit {
+    fn from_row(row: &Row<'_>) -> Result<Self> {
+        let visit_type: u8 = row.get("visit_type")?;
+        Ok(Self {
+            visit_id: row.get("id")?,
+            from_visit: row.get("from_visit")?,
+            url: row.get("url")?,
+            title: row.get("title")?,
+            visit_date: row.get("visit_date")?,
+            visit_type: VisitTransition::from_primitive(visit_type)
+                .unwrap_or(VisitTransition::Link),
+        })
+    }
+
+    fn is_redirect(&self) -> bool {
+        matches!(
+            self.visit_type,
+            VisitTransition::RedirectPermanent | VisitTransition::RedirectTemporary
+        )
+    }
+
+    /// Replaces this visit with the destination of a redirect from it. The
+    /// destination keeps how the user got to the source.
+    fn redirected_to(self, destination: NavigationVisit) -> Self {
+        Self {
+            from_visit: self.from_visit,
+            visit_type: self.visit_type,
+            ..destination
+        }
+    }
+}
+
+/// A visit, and all the visits that came from it, oldest first.
+#[derive(Clone, Debug, PartialEq)]
+pub struct VisitTreeNode {
+    pub visit: NavigationVisit,
+    pub children: Vec<VisitTreeNode>,
+}
+
+const NAVIGATION_VISIT_COLUMNS: &str =
+    "v.id, v.from_visit, h.url, h.title, v.visit_date, v.visit_type";
+
+/// Returns the most recent visit to `url`, if there is one.
+pub fn get_latest_visit(db: &PlacesDb, url: &Url) -> Result<Option<NavigationVisit>> {
+    db.try_query_row(
+        &format!(
+            "SELECT {columns}
+             FROM moz_historyvisits v
+             JOIN moz_places h ON h.id = v.place_id
+             WHERE h.url_hash = hash(:url) AND h.url = :url
+             ORDER BY v.visit_date DESC, v.id DESC
+             LIMIT 1",
+            columns = NAVIGATION_VISIT_COLUMNS
+        ),
+        &[(":url", &url.as_str())],
+        NavigationVisit::from_row,
+        true,
+    )
+}
+
+/// Returns the chain of visits that led to `visit_id`, starting with the
+/// visit that began it and ending with `visit_id` itself. Returns an empty
+/// chain if the visit doesn't exist.
+///
+/// If `collapse_redirects` is true, visits that redirected are replaced by
+/// where they redirected to, so the chain only has pages the user saw.
+pub fn get_referrer_chain(
+    db: &PlacesDb,
+    visit_id: RowId,
+    collapse_redirects: bool,
+) -> Result<Vec<NavigationVisit>> {
+    let visits: Vec<NavigationVisit> = db.query_rows_and_then_named_cached(
+        &format!(
+            "WITH RECURSIVE chain(id, depth) AS (
+               SELECT :visit_id, 0
+               UNION ALL
+               SELECT v.from_visit, c.depth + 1
+               FROM moz_historyvisits v
+               JOIN chain c ON c.id = v.id
+               WHERE v.from_visit NOT NULL AND c.depth < :max_length
+             )
+             SELECT {columns}
+             FROM chain c
+             JOIN moz_historyvisits v ON v.id = c.id
+             JOIN moz_places h ON h.id = v.place_id
+             ORDER BY c.depth",
+            columns = NAVIGATION_VISIT_COLUMNS
+        ),
+        &[(":visit_id", &visit_id), (":max_length", &MAX_CHAIN_LENGTH)],
+        NavigationVisit::from_row,
+    )?;
+    // The query returns the chain newest first. Walk it backward to the
+    // start, stopping if it loops.
+    let mut seen = HashSet::new();
+    let mut chain = visits
+        .into_iter()
+        .take_while(|visit| seen.insert(visit.visit_id.0))
+        .collect::<Vec<_>>();
+    chain.reverse();
+    if !collapse_redirects {
+        return Ok(chain);
+    }
+    let mut collapsed: Vec<NavigationVisit> = Vec::with_capacity(chain.len());
+    for visit in chain {
+        match collapsed.pop() {
+            Some(source) if visit.is_redirect() => collapsed.push(source.redirected_to(visit)),
+            Some(source) => {
+                collapsed.push(source);
+                collapsed.push(visit);
+            }
+            None => collapsed.push(visit),
+        }
+    }
+    Ok(collapsed)
+}
+
+/// Follows redirects from `visit_id`, and returns the visit where they
+/// ended. Returns the visit itself if it didn't redirect, or `None` if it
+/// doesn't exist. If the page redirected more than once from the same
+/// visit, the most recent redirect wins.
+pub fn get_redirect_destination(db: &PlacesDb, visit_id: RowId) -> Result<Option<NavigationVisit>> {
+    db.try_query_row(
+        &format!(
+            "WITH RECURSIVE redirects(id, depth) AS (
+               SELECT :visit_id, 0
+               UNION ALL
+               SELECT v.id, r.depth + 1
+               FROM moz_historyvisits v
+               JOIN redirects r ON v.from_visit = r.id
+               WHERE v.visit_type IN (:redirect_permanent, :redirect_temporary) AND
+                     r.depth < :max_length
+             )
+             SELECT {columns}
+             FROM redirects r
+             JOIN moz_historyvisits v ON v.id = r.id
+             JOIN moz_places h ON h.id = v.place_id
+             ORDER BY r.depth DESC, v.visit_date DESC, v.id DESC
+             LIMIT 1",
+            columns = NAVIGATION_VISIT_COLUMNS
+        ),
+        &[
+            (":visit_id", &visit_id),
+            (":redirect_permanent", &VisitTransition::RedirectPermanent),
+            (":redirect_temporary", &VisitTransition::RedirectTemporary),
+            (":max_length", &MAX_CHAIN_LENGTH),
+        ],
+        NavigationVisit::from_row,
+        true,
+    )
+}
+
+/// Returns the tree of visits that came from `visit_id`, like all the pages
+/// the user opened from a search results page, and the pages they opened
+/// from those. Returns `None` if the visit doesn't exist.
+///
+/// If `collapse_redirects` is true, visits that redirected are replaced by
+/// where they redirected to.
+pub fn get_visit_tree(
+    db: &PlacesDb,
+    visit_id: RowId,
+    collapse_redirects: bool,
+) -> Result<Option<VisitTreeNode>> {
+    // `UNION` (unlike `UNION ALL`) skips visits we've already seen, so this
+    // stops even if `from_visit` loops.
+    let visits: Vec<NavigationVisit> = db.query_rows_and_then_named_cached(
+        &format!(
+            "WITH RECURSIVE tree(id) AS (
+               SELECT :visit_id
+               UNION
+               SELECT v.id
+               FROM moz_historyvisits v
+               JOIN tree t ON v.from_visit = t.id
+             )
+             SELECT {columns}
+             FROM tree t
+             JOIN moz_historyvisits v ON v.id = t.id
+             JOIN moz_places h ON h.id = v.place_id
+             ORDER BY v.visit_date, v.id",
+            columns = NAVIGATION_VISIT_COLUMNS
+        ),
+        &[(":visit_id", &visit_id)],
+        NavigationVisit::from_row,
+    )?;
+    let mut children: HashMap<i64, Vec<NavigationVisit>> = HashMap::new();
+    let mut root = None;
+    for visit in visits {
+        if visit.visit_id == visit_id {
+            root = Some(visit);
+        } else if let Some(from_visit) = visit.from_visit {
+            children.entry(from_visit.0).or_default().push(visit);
+        }
+    }
+    let mut seen = HashSet::new();
+    Ok(root.map(|root| build_tree(root, &mut children, &mut seen, collapse_redirects)))
+}
+
+fn build_tree(
+    mut visit: NavigationVisit,
+    children: &mut HashMap<i64, Vec<NavigationVisit>>,
+    seen: &mut HashSet<i64>,
+    collapse_redirects: bool,
+) -> VisitTreeNode {
+    seen.insert(visit.visit_id.0);
+    let mut child_visits = children.remove(&visit.visit_id.0).unwrap_or_default();
+    if collapse_redirects {
+        // Fold each redirect into the node, and adopt the destination's
+        // children, until there are no more redirects to follow.
+        while let Some(index) = child_visits
+            .iter()
+            .position(|child| child.is_redirect() && !seen.contains(&child.visit_id.0))
+        {
+            let destination = child_visits.remove(index);
+            seen.insert(destination.visit_id.0);
+            if let Some(grandchildren) = children.remove(&destination.visit_id.0) {
+                child_visits.extend(grandchildren);
+            }
+            visit = visit.redirected_to(destination);
+        }
+        child_visits.sort_by_key(|child| (child.visit_date, child.visit_id));
+    }
+    let mut nodes = Vec::with_capacity(child_visits.len());
+    for child in child_visits {
+        if !seen.contains(&child.visit_id.0) {
+            nodes.push(build_tree(child, children, seen, collapse_redirects));
+        }
+    }
+    VisitTreeNode {
+        visit,
+        children: nodes,
+    }
+}
+
+/// Finds the visit to `referrer` that a visit at `visit_date` most likely
+/// came from: its most recent visit at or before `visit_date`, if that's
+/// recent enough.
+pub(crate) fn find_referrer_visit(
+    db: &PlacesDb,
+    referrer: &str,
+    visit_date: Timestamp,
+) -> Result<Option<RowId>> {
+    let earliest = Timestamp(visit_date.0.saturating_sub(REFERRER_VISIT_THRESHOLD_MS));
+    Ok(db.try_query_one(
+        "SELECT v.id
+         FROM moz_historyvisits v
+         JOIN moz_places h ON h.id = v.place_id
+         WHERE h.url_hash = hash(:url) AND h.url = :url AND
+               v.visit_date BETWEEN :earliest AND :visit_date
+         ORDER BY v.visit_date DESC, v.id DESC
+         LIMIT 1",
+        &[
+            (":url", &referrer),
+            (":earliest", &earliest),
+            (":visit_date", &visit_date),
+        ],
+        true,
+    )?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::places_api::test::new_mem_connection;
+    use crate::observation::VisitObservation;
+    use crate::storage::history::apply_observation;
+
+    const NOW: u64 = 1_622_548_800_000;
+
+    fn visit(
+        db: &PlacesDb,
+        url: &str,
+        referrer: Option<&str>,
+        at: u64,
+        visit_type: VisitTransition,
+    ) -> RowId {
+        apply_observation(
+            db,
+            VisitObservation::new(Url::parse(url).unwrap())
+                .with_referrer(referrer.map(|r| Url::parse(r).unwrap()))
+                .with_at(Timestamp(at))
+                .with_visit_type(visit_type),
+        )
+        .expect("should apply visit")
+        .expect("should add visit")
+    }
+
+    fn urls(visits: &[NavigationVisit]) -> Vec<&str> {
+        visits.iter().map(|v| v.url.as_str()).collect()
+    }
+
+    fn tree_urls(node: &VisitTreeNode) -> String {
+        if node.children.is_empty() {
+            node.visit.url.clone()
+        } else {
+            format!(
+                "{} [{}]",
+                node.visit.url,
+                node.children
+                    .iter()
+                    .map(tree_urls)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+        }
+    }
+
+    #[test]
+    fn test_referrer_chain() {
+        let conn = new_mem_connection();
+        let search = "https://search.example/?q=rust";
+        let short = "https://short.example/abc";
+        let target = "https://www.rust-lang.org/";
+        let docs = "https://doc.rust-lang.org/";
+        visit(&conn, search, None, NOW, VisitTransition::Typed);
+        let short_id = visit(&conn, short, Some(search), NOW + 1, VisitTransition::Link);
+        visit(
+            &conn,
+            target,
+            Some(short),
+            NOW + 2,
+            VisitTransition::RedirectTemporary,
+        );
+        let docs_id = visit(&conn, docs, Some(target), NOW + 3, VisitTransition::Link);
+
+        let chain = get_referrer_chain(&conn, docs_id, false).unwrap();
+        assert_eq!(urls(&chain), vec![search, short, target, docs]);
+        assert_eq!(chain[0].from_visit, None);
+
+        let collapsed = get_referrer_chain(&conn, docs_id, true).unwrap();
+        assert_eq!(urls(&collapsed), vec![search, target, docs]);
+        // The redirect destination keeps how the user got to the source.
+        assert_eq!(collapsed[1].visit_type, VisitTransition::Link);
+
+        let destination = get_redirect_destination(&conn, short_id).unwrap().unwrap();
+        assert_eq!(destination.url, target);
+        let not_redirected = get_redirect_destination(&conn, docs_id).unwrap().unwrap();
+        assert_eq!(not_redirected.visit_id, docs_id);
+        assert_eq!(get_redirect_destination(&conn, RowId(999)).unwrap(), None);
+        assert!(get_referrer_chain(&conn, RowId(999), false)
+            .unwrap()
+            .is_empty());
+
+        // A referrer visited too long ago isn't linked.
+        let late = visit(
+            &conn,
+            "https://late.example/",
+            Some(docs),
+            NOW + 3 + REFERRER_VISIT_THRESHOLD_MS + 1,
+            VisitTransition::Link,
+        );
+        assert_eq!(
+            urls(&get_referrer_chain(&conn, late, false).unwrap()),
+            vec!["https://late.example/"]
+        );
+    }
+
+    #[test]
+    fn test_visit_tree() {
+        let conn = new_mem_connection();
+        let search = "https://search.example/?q=rust";
+        let search_id = visit(&conn, search, None, NOW, VisitTransition::Typed);
+        visit(
+            &conn,
+            "https://a.example/",
+            Some(search),
+            NOW + 1,
+            VisitTransition::Link,
+        );
+        visit(
+            &conn,
+            "https://a.example/more",
+            Some("https://a.example/"),
+            NOW + 2,
+            VisitTransition::Link,
+        );
+        visit(
+            &conn,
+            "https://short.example/",
+            Some(search),
+            NOW + 3,
+            VisitTransition::Link,
+        );
+        visit(
+            &conn,
+            "https://b.example/",
+            Some("https://short.example/"),
+            NOW + 4,
+            VisitTransition::RedirectPermanent,
+        );
+        visit(
+            &conn,
+            "https://b.example/next",
+            Some("https://b.example/"),
+            NOW + 5,
+            VisitTransition::Link,
+        );
+        // Visits that didn't come from the search page aren't included.
+        visit(
+            &conn,
+            "https://c.example/",
+            None,
+            NOW + 6,
+            VisitTransition::Typed,
+        );
+
+        let tree = get_visit_tree(&conn, search_id, false).unwrap().unwrap();
+        assert_eq!(
+            tree_urls(&tree),
+            "https://search.example/?q=rust [https://a.example/ [https://a.example/more], \
+             https://short.example/ [https://b.example/ [https://b.example/next]]]"
+        );
+
+        let tree = get_visit_tree(&conn, search_id, true).unwrap().unwrap();
+        assert_eq!(
+            tree_urls(&tree),
+            "https://search.example/?q=rust [https://a.example/ [https://a.example/more], \
+             https://b.example/ [https://b.example/next]]"
+        );
+
+        assert_eq!(
+            get_latest_visit(&conn, &Url::parse(search).unwrap())
+                .unwrap()
+                .map(|v| v.visit_id),
+            Some(search_id)
+        );
+        assert_eq!(get_visit_tree(&conn, RowId(999), true).unwrap(), None);
+    }
+}
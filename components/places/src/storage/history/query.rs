@@ -0,0 +1,750 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! A composable query over history visits, for history views that need more
+//! than `get_visit_page`: filtering by site, visit type, time, text and
+//! where the visit happened, grouping by day, site or search term, and
+//! cursor-based pagination.
+//!
+//! Pages are keyed on visits, not offsets, and each cursor remembers the
+//! newest visit that existed when the first page was fetched. Fetching the
+//! next page never repeats or skips results, even if visits were added
+//! (locally or by sync) in the meantime.
+
+use crate::db::PlacesDb;
+use crate::error::Result;
+use crate::types::{VisitTransition, VisitTransitionSet};
+use rusqlite::types::ToSql;
+use rusqlite::Row;
+use serde_derive::*;
+use sql_support::ConnExt;
+use types::Timestamp;
+
+/// Which visits a query should include, based on where they happened.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VisitSource {
+    /// Visits from this device, and from other devices via sync.
+    Any,
+    /// Only visits from this device.
+    Local,
+    /// Only visits from other devices.
+    Remote,
+}
+
+impl Default for VisitSource {
+    fn default() -> Self {
+        VisitSource::Any
+    }
+}
+
+/// How `HistoryQuery::fetch_groups` groups visits.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GroupBy {
+    /// Groups visits by the local calendar day they happened on. The key is
+    /// the date, formatted as `YYYY-MM-DD`.
+    Day,
+    /// Groups visits by the host (and port, if any) of the visited page.
+    Site,
+    /// Groups visits by the search term that led to the page, as recorded in
+    /// history metadata. Visits to pages without a search term aren't in any
+    /// group.
+    SearchTerm,
+}
+
+/// A visit returned by `HistoryQuery::fetch_visits`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HistoryVisit {
+    pub url: String,
+    pub title: Option<String>,
+    pub visit_date: Timestamp,
+    pub visit_type: VisitTransition,
+    pub is_local: bool,
+    pub preview_image_url: Option<String>,
+}
+
+impl HistoryVisit {
+    fn from_row(row: &Row<'_>) -> Result<Self> {
+        let visit_type: u8 = row.get("visit_type")?;
+        Ok(Self {
+            url: row.get("url")?,
+            title: row.get("title")?,
+            visit_date: row.get("visit_date")?,
+            // Unknown visit types are filtered out by the query.
+            visit_type: VisitTransition::from_primitive(visit_type)
+                .unwrap_or(VisitTransition::Link),
+            is_local: row.get("is_local")?,
+            preview_image_url: row.get("preview_image_url")?,
+        })
+    }
+}
+
+/// A group returned by `HistoryQuery::fetch_groups`. To list the visits in a
+/// group, narrow the query with `with_time_range`, `with_host` or
+/// `with_search_term`, and call `fetch_visits`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HistoryGroup {
+    pub key: String,
+    /// The number of matching visits in the group.
+    pub visit_count: u32,
+    /// The number of distinct pages visited in the group.
+    pub page_count: u32,
+    /// The date of the most recent matching visit in the group. Groups are
+    /// ordered by this, newest first.
+    pub latest_visit: Timestamp,
+}
+
+/// Where the next page of visits starts.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VisitCursor {
+    max_visit_id: i64,
+    visit_date: u64,
+    visit_id: i64,
+}
+
+/// Where the next page of groups starts.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GroupCursor {
+    max_visit_id: i64,
+    latest_visit: u64,
+    key: String,
+}
+
+/// One page of results. `next` is `None` on the last page.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HistoryPage<T, C> {
+    pub items: Vec<T>,
+    pub next: Option<C>,
+}
+
+/// A query over history visits, built up with the `with_*` methods. By
+/// default, it matches all visits to pages that aren't hidden.
+#[derive(Clone, Debug)]
+pub struct HistoryQuery {
+    host: Option<String>,
+    base_domain: Option<String>,
+    transitions: Option<VisitTransitionSet>,
+    start: Option<Timestamp>,
+    end: Option<Timestamp>,
+    text: Option<String>,
+    search_term: Option<String>,
+    source: VisitSource,
+    include_hidden: bool,
+    limit: u32,
+}
+
+impl Default for HistoryQuery {
+    fn default() -> Self {
+        Self {
+            host: None,
+            base_domain: None,
+            transitions: None,
+            start: None,
+            end: None,
+            text: None,
+            search_term: None,
+            source: VisitSource::Any,
+            include_hidden: false,
+            limit: 100,
+        }
+    }
+}
+
+// The SQL expressions that `GroupBy` groups on.
+const DAY_KEY_SQL: &str = "date(v.visit_date / 1000, 'unixepoch', 'localtime')";
+const SITE_KEY_SQL: &str = "IFNULL(o.host, '')";
+const SEARCH_TERM_KEY_SQL: &str = "(SELECT q.term
+   FROM moz_places_metadata m
+   JOIN moz_places_metadata_search_queries q ON q.id = m.search_query_id
+   WHERE m.place_id = v.place_id
+   ORDER BY m.updated_at DESC
+   LIMIT 1)";
+
+impl HistoryQuery {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only matches visits to pages on exactly this host, like
+    /// `www.example.com`. If the page's URL has a port, it must be included.
+    pub fn with_host(mut self, host: impl Into<String>) -> Self {
+        self.host = Some(host.into().to_lowercase());
+        self
+    }
+
+    /// Only matches visits to pages on this domain or any of its subdomains.
+    /// `example.com` matches `example.com` and `www.example.com`, but not
+    /// `myexample.com`.
+    pub fn with_base_domain(mut self, domain: impl Into<String>) -> Self {
+        self.base_domain = Some(domain.into().to_lowercase());
+        self
+    }
+
+    /// Only matches visits with one of these transition types.
+    pub fn with_transitions(mut self, transitions: VisitTransitionSet) -> Self {
+        self.transitions = Some(transitions);
+        self
+    }
+
+    /// Only matches visits between `start` and `end`, inclusive.
+    pub fn with_time_range(mut self, start: Timestamp, end: Timestamp) -> Self {
+        self.start = Some(start);
+        self.end = Some(end);
+        self
+    }
+
+    /// Only matches visits to pages whose URL or title contains `text`,
+    /// ignoring ASCII case.
+    pub fn with_text(mut self, text: impl Into<String>) -> Self {
+        self.text = Some(like_pattern_for(&text.into()));
+        self
+    }
+
+    /// Only matches visits to pages that history metadata says were reached
+    /// by searching for `term`.
+    pub fn with_search_term(mut self, term: impl Into<String>) -> Self {
+        self.search_term = Some(term.into());
+        self
+    }
+
+    pub fn with_source(mut self, source: VisitSource) -> Self {
+        self.source = source;
+        self
+    }
+
+    /// Also matches visits to hidden pages, like redirect sources and
+    /// embedded frames.
+    pub fn with_hidden(mut self, include_hidden: bool) -> Self {
+        self.include_hidden = include_hidden;
+        self
+    }
+
+    /// Sets the maximum number of visits or groups per page.
+    pub fn with_limit(mut self, limit: u32) -> Self {
+        self.limit = limit.max(1);
+        self
+    }
+
+    /// Fetches a page of matching visits, newest first. Pass `None` for the
+    /// first page, and the previous page's `next` cursor after that.
+    pub fn fetch_visits(
+        &self,
+        db: &PlacesDb,
+        after: Option<&VisitCursor>,
+    ) -> Result<HistoryPage<HistoryVisit, VisitCursor>> {
+        let max_visit_id = match after {
+            Some(cursor) => cursor.max_visit_id,
+            None => max_visit_id(db)?,
+        };
+        // Fetch one extra row to find out if there's another page.
+        let limit = i64::from(self.limit) + 1;
+        let cursor_date = after.map(|cursor| cursor.visit_date as i64);
+        let mut conditions = self.conditions();
+        let mut params = self.params();
+        params.push((":max_visit_id", &max_visit_id));
+        params.push((":limit", &limit));
+        if let (Some(cursor), Some(cursor_date)) = (after, &cursor_date) {
+            conditions.push("(v.visit_date, v.id) < (:cursor_date, :cursor_id)".into());
+            params.push((":cursor_date", cursor_date));
+            params.push((":cursor_id", &cursor.visit_id));
+        }
+        let sql = format!(
+            "SELECT v.id AS visit_id, h.url, h.title, v.visit_date, v.visit_type,
+                    v.is_local, h.preview_image_url
+             FROM moz_historyvisits v
+             JOIN moz_places h ON h.id = v.place_id
+             LEFT JOIN moz_origins o ON o.id = h.origin_id
+             WHERE {conditions}
+             ORDER BY v.visit_date DESC, v.id DESC
+             LIMIT :limit",
+            conditions = conditions.join(" AND "),
+        );
+        let mut rows = db.query_rows_and_then_named(&sql, &params, |row| -> Result<_> {
+            Ok((row.get::<_, i64>("visit_id")?, HistoryVisit::from_row(row)?))
+        })?;
+        let next = if rows.len() > self.limit as usize {
+            rows.truncate(self.limit as usize);
+            rows.last().map(|(visit_id, visit)| VisitCursor {
+                max_visit_id,
+                visit_date: visit.visit_date.0,
+                visit_id: *visit_id,
+            })
+        } else {
+            None
+        };
+        Ok(HistoryPage {
+            items: rows.into_iter().map(|(_, visit)| visit).collect(),
+            next,
+        })
+    }
+
+    /// Fetches a page of groups of matching visits, ordered by their most
+    /// recent visit. Pass `None` for the first page, and the previous page's
+    /// `next` cursor after that.
+    pub fn fetch_groups(
+        &self,
+        db: &PlacesDb,
+        group_by: GroupBy,
+        after: Option<&GroupCursor>,
+    ) -> Result<HistoryPage<HistoryGroup, GroupCursor>> {
+        let max_visit_id = match after {
+            Some(cursor) => cursor.max_visit_id,
+            None => max_visit_id(db)?,
+        };
+        let key_sql = match group_by {
+            GroupBy::Day => DAY_KEY_SQL,
+            GroupBy::Site => SITE_KEY_SQL,
+            GroupBy::SearchTerm => SEARCH_TERM_KEY_SQL,
+        };
+        let limit = i64::from(self.limit) + 1;
+        let cursor_latest = after.map(|cursor| cursor.latest_visit as i64);
+        let conditions = self.conditions();
+        let mut params = self.params();
+        params.push((":max_visit_id", &max_visit_id));
+        params.push((":limit", &limit));
+        let mut having = "groupKey NOT NULL".to_string();
+        if let (Some(cursor), Some(cursor_latest)) = (after, &cursor_latest) {
+            having.push_str(" AND (latestVisit, groupKey) < (:cursor_latest, :cursor_key)");
+            params.push((":cursor_latest", cursor_latest));
+            params.push((":cursor_key", &cursor.key));
+        }
+        let sql = format!(
+            "SELECT {key} AS groupKey, COUNT(*) AS visitCount,
+                    COUNT(DISTINCT v.place_id) AS pageCount,
+                    MAX(v.visit_date) AS latestVisit
+             FROM moz_historyvisits v
+             JOIN moz_places h ON h.id = v.place_id
+             LEFT JOIN moz_origins o ON o.id = h.origin_id
+             WHERE {conditions}
+             GROUP BY groupKey
+             HAVING {having}
+             ORDER BY latestVisit DESC, groupKey DESC
+             LIMIT :limit",
+            key = key_sql,
+            conditions = conditions.join(" AND "),
+            having = having,
+        );
+        let mut groups = db.query_rows_and_then_named(&sql, &params, |row| -> Result<_> {
+            Ok(HistoryGroup {
+                key: row.get("groupKey")?,
+                visit_count: row.get("visitCount")?,
+                page_count: row.get("pageCount")?,
+                latest_visit: row.get("latestVisit")?,
+            })
+        })?;
+        let next = if groups.len() > self.limit as usize {
+            groups.truncate(self.limit as usize);
+            groups.last().map(|group| GroupCursor {
+                max_visit_id,
+                latest_visit: group.latest_visit.0,
+                key: group.key.clone(),
+            })
+        } else {
+            None
+        };
+        Ok(HistoryPage {
+            items: groups,
+            next,
+        })
+    }
+
+    fn conditions(&self) -> Vec<String> {
+        // Visits added after the first page was fetched have bigger ids, so
+        // this keeps later pages consistent with the first.
+        let mut conditions = vec!["v.id <= :max_visit_id".to_string()];
+        if !self.include_hidden {
+            conditions.push("NOT h.hidden".into());
+        }
+        if self.host.is_some() {
+            conditions.push("o.host = :host".into());
+        }
+        if self.base_domain.is_some() {
+            conditions.push(
+                "(o.host = :base_domain OR
+                  substr(o.host, -length(:base_domain) - 1) = '.' || :base_domain)"
+                    .into(),
+            );
+        }
+        if self.transitions.is_some() {
+            conditions.push("((1 << v.visit_type) & :allowed_types) != 0".into());
+        }
+        if self.start.is_some() {
+            conditions.push("v.visit_date >= :start".into());
+        }
+        if self.end.is_some() {
+            conditions.push("v.visit_date <= :end".into());
+        }
+        if self.text.is_some() {
+            conditions
+                .push("(h.url LIKE :text ESCAPE '\\' OR h.title LIKE :text ESCAPE '\\')".into());
+        }
+        if self.search_term.is_some() {
+            conditions.push(format!("{} = :search_term", SEARCH_TERM_KEY_SQL));
+        }
+        match self.source {
+            VisitSource::Any => {}
+            VisitSource::Local => conditions.push("v.is_local".into()),
+            VisitSource::Remote => conditions.push("NOT v.is_local".into()),
+        }
+        conditions
+    }
+
+    fn params(&self) -> Vec<(&str, &dyn ToSql)> {
+        let mut params: Vec<(&str, &dyn ToSql)> = Vec::new();
+        if let Some(host) = &self.host {
+            params.push((":host", host));
+        }
+        if let Some(base_domain) = &self.base_domain {
+            params.push((":base_domain", base_domain));
+        }
+        if let Some(transitions) = &self.transitions {
+            params.push((":allowed_types", transitions));
+        }
+        if let Some(start) = &self.start {
+            params.push((":start", start));
+        }
+        if let Some(end) = &self.end {
+            params.push((":end", end));
+        }
+        if let Some(text) = &self.text {
+            params.push((":text", text));
+        }
+        if let Some(search_term) = &self.search_term {
+            params.push((":search_term", search_term));
+        }
+        params
+    }
+}
+
+/// Returns a `LIKE` pattern that matches strings containing `text`, with
+/// `\` as the escape character.
+fn like_pattern_for(text: &str) -> String {
+    let mut pattern = String::with_capacity(text.len() + 2);
+    pattern.push('%');
+    for c in text.chars() {
+        if matches!(c, '%' | '_' | '\\') {
+            pattern.push('\\');
+        }
+        pattern.push(c);
+    }
+    pattern.push('%');
+    pattern
+}
+
+fn max_visit_id(db: &PlacesDb) -> Result<i64> {
+    Ok(db.query_one("SELECT IFNULL(MAX(id), 0) FROM moz_historyvisits")?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::places_api::test::new_mem_connection;
+    use crate::observation::VisitObservation;
+    use crate::storage::history::apply_observation;
+    use crate::storage::history_metadata::{
+        apply_metadata_observation, HistoryMetadataObservation,
+    };
+    use url::Url;
+
+    // 2021-06-01T12:00:00Z.
+    const NOON: u64 = 1_622_548_800_000;
+    const DAY: u64 = 86_400_000;
+
+    fn visit(db: &PlacesDb, url: &str, at: u64, visit_type: VisitTransition, is_remote: bool) {
+        apply_observation(
+            db,
+            VisitObservation::new(Url::parse(url).unwrap())
+                .with_title(format!("Title of {}", url))
+                .with_at(Timestamp(at))
+                .with_visit_type(visit_type)
+                .with_is_remote(is_remote),
+        )
+        .expect("should apply visit");
+    }
+
+    fn urls(page: &HistoryPage<HistoryVisit, VisitCursor>) -> Vec<&str> {
+        page.items.iter().map(|v| v.url.as_str()).collect()
+    }
+
+    fn fetch_urls(db: &PlacesDb, query: HistoryQuery) -> Vec<String> {
+        query
+            .fetch_visits(db, None)
+            .expect("should fetch visits")
+            .items
+            .into_iter()
+            .map(|v| v.url)
+            .collect()
+    }
+
+    #[test]
+    fn test_filters() {
+        let conn = new_mem_connection();
+        visit(
+            &conn,
+            "https://example.com/",
+            NOON,
+            VisitTransition::Typed,
+            false,
+        );
+        visit(
+            &conn,
+            "https://www.example.com/a_b",
+            NOON + 1,
+            VisitTransition::Link,
+            false,
+        );
+        visit(
+            &conn,
+            "https://myexample.com/",
+            NOON + 2,
+            VisitTransition::Link,
+            true,
+        );
+        visit(
+            &conn,
+            "https://mozilla.org/",
+            NOON + DAY,
+            VisitTransition::Bookmark,
+            false,
+        );
+
+        assert_eq!(
+            fetch_urls(&conn, HistoryQuery::new()),
+            vec![
+                "https://mozilla.org/",
+                "https://myexample.com/",
+                "https://www.example.com/a_b",
+                "https://example.com/",
+            ]
+        );
+        assert_eq!(
+            fetch_urls(&conn, HistoryQuery::new().with_host("www.example.com")),
+            vec!["https://www.example.com/a_b"]
+        );
+        assert_eq!(
+            fetch_urls(&conn, HistoryQuery::new().with_base_domain("example.com")),
+            vec!["https://www.example.com/a_b", "https://example.com/"]
+        );
+        assert_eq!(
+            fetch_urls(
+                &conn,
+                HistoryQuery::new().with_transitions(VisitTransitionSet::for_specific(&[
+                    VisitTransition::Typed,
+                    VisitTransition::Bookmark,
+                ]))
+            ),
+            vec!["https://mozilla.org/", "https://example.com/"]
+        );
+        assert_eq!(
+            fetch_urls(
+                &conn,
+                HistoryQuery::new().with_time_range(Timestamp(NOON + 1), Timestamp(NOON + 2))
+            ),
+            vec!["https://myexample.com/", "https://www.example.com/a_b"]
+        );
+        // `_` is matched literally, and not as a wildcard.
+        assert_eq!(
+            fetch_urls(&conn, HistoryQuery::new().with_text("A_B")),
+            vec!["https://www.example.com/a_b"]
+        );
+        assert!(fetch_urls(&conn, HistoryQuery::new().with_text("a%b")).is_empty());
+        assert_eq!(
+            fetch_urls(&conn, HistoryQuery::new().with_source(VisitSource::Remote)),
+            vec!["https://myexample.com/"]
+        );
+        assert_eq!(
+            fetch_urls(
+                &conn,
+                HistoryQuery::new()
+                    .with_base_domain("example.com")
+                    .with_source(VisitSource::Local)
+                    .with_text("title")
+            )
+            .len(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_pagination_is_stable() {
+        let conn = new_mem_connection();
+        for i in 0..5 {
+            visit(
+                &conn,
+                &format!("https://example.com/{}", i),
+                NOON + i,
+                VisitTransition::Link,
+                false,
+            );
+        }
+        // Two visits with the same date still get paged in a consistent order.
+        visit(
+            &conn,
+            "https://example.com/same",
+            NOON + 2,
+            VisitTransition::Link,
+            false,
+        );
+
+        let query = HistoryQuery::new().with_limit(2);
+        let first = query.fetch_visits(&conn, None).unwrap();
+        assert_eq!(
+            urls(&first),
+            vec!["https://example.com/4", "https://example.com/3"]
+        );
+
+        // New visits, including one that's older than the cursor, don't change
+        // the pages after the first.
+        visit(
+            &conn,
+            "https://example.com/new",
+            NOON + 10,
+            VisitTransition::Link,
+            false,
+        );
+        visit(
+            &conn,
+            "https://example.com/late",
+            NOON + 1,
+            VisitTransition::Link,
+            true,
+        );
+
+        let second = query.fetch_visits(&conn, first.next.as_ref()).unwrap();
+        assert_eq!(
+            urls(&second),
+            vec!["https://example.com/same", "https://example.com/2"]
+        );
+        let third = query.fetch_visits(&conn, second.next.as_ref()).unwrap();
+        assert_eq!(
+            urls(&third),
+            vec!["https://example.com/1", "https://example.com/0"]
+        );
+        assert_eq!(third.next, None);
+
+        // A fresh query sees the new visits.
+        let all = HistoryQuery::new().fetch_visits(&conn, None).unwrap();
+        assert_eq!(all.items.len(), 8);
+        assert_eq!(all.next, None);
+    }
+
+    #[test]
+    fn test_groups() {
+        let conn = new_mem_connection();
+        visit(
+            &conn,
+            "https://example.com/1",
+            NOON,
+            VisitTransition::Link,
+            false,
+        );
+        visit(
+            &conn,
+            "https://example.com/1",
+            NOON + 1,
+            VisitTransition::Link,
+            false,
+        );
+        visit(
+            &conn,
+            "https://example.com/2",
+            NOON + DAY,
+            VisitTransition::Link,
+            false,
+        );
+        visit(
+            &conn,
+            "https://mozilla.org/",
+            NOON + DAY + 1,
+            VisitTransition::Link,
+            false,
+        );
+        visit(
+            &conn,
+            "https://rust-lang.org/",
+            NOON + 2 * DAY,
+            VisitTransition::Link,
+            false,
+        );
+
+        let query = HistoryQuery::new().with_limit(2);
+        let first = query.fetch_groups(&conn, GroupBy::Site, None).unwrap();
+        assert_eq!(
+            first.items,
+            vec![
+                HistoryGroup {
+                    key: "rust-lang.org".into(),
+                    visit_count: 1,
+                    page_count: 1,
+                    latest_visit: Timestamp(NOON + 2 * DAY),
+                },
+                HistoryGroup {
+                    key: "mozilla.org".into(),
+                    visit_count: 1,
+                    page_count: 1,
+                    latest_visit: Timestamp(NOON + DAY + 1),
+                },
+            ]
+        );
+        // A new visit to a site on the next page doesn't move it to the first.
+        visit(
+            &conn,
+            "https://example.com/3",
+            NOON + 3 * DAY,
+            VisitTransition::Link,
+            false,
+        );
+        let second = query
+            .fetch_groups(&conn, GroupBy::Site, first.next.as_ref())
+            .unwrap();
+        assert_eq!(
+            second.items,
+            vec![HistoryGroup {
+                key: "example.com".into(),
+                visit_count: 3,
+                page_count: 2,
+                latest_visit: Timestamp(NOON + DAY),
+            }]
+        );
+        assert_eq!(second.next, None);
+
+        let days = HistoryQuery::new()
+            .fetch_groups(&conn, GroupBy::Day, None)
+            .unwrap();
+        assert_eq!(
+            days.items
+                .iter()
+                .map(|group| group.visit_count)
+                .collect::<Vec<_>>(),
+            vec![1, 1, 2, 2]
+        );
+
+        apply_metadata_observation(
+            &conn,
+            HistoryMetadataObservation {
+                url: "https://rust-lang.org/".into(),
+                view_time: None,
+                search_term: Some("rust".into()),
+                document_type: None,
+                referrer_url: None,
+                title: None,
+            },
+        )
+        .unwrap();
+        let terms = HistoryQuery::new()
+            .fetch_groups(&conn, GroupBy::SearchTerm, None)
+            .unwrap();
+        assert_eq!(
+            terms
+                .items
+                .iter()
+                .map(|group| group.key.as_str())
+                .collect::<Vec<_>>(),
+            vec!["rust"]
+        );
+        assert_eq!(
+            fetch_urls(&conn, HistoryQuery::new().with_search_term("rust")),
+            vec!["https://rust-lang.org/"]
+        );
+    }
+}
@@ -4,6 +4,7 @@
 
 use crate::db::{PlacesDb, PlacesTransaction};
 use crate::error::Result;
+use crate::types::SyncStatus;
 use rusqlite::types::{FromSql, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
 use sql_support::ConnExt;
 use std::vec::Vec;
@@ -522,14 +523,29 @@ pub fn delete_metadata(
         }
     };
 
-    let sql = format!(
-        "DELETE FROM moz_places_metadata WHERE {} AND {} AND {}",
+    let where_clause = format!(
+        "{} AND {} AND {}",
         place_entry.to_where_arg("place_id"),
         referrer_entry.to_where_arg("referrer_place_id"),
         search_query_entry.to_where_arg("search_query_id")
     );
 
-    tx.execute_named_cached(&sql, &[])?;
+    // Entries that we've uploaded need tombstones, so that other devices
+    // delete them, too.
+    tx.execute_named_cached(
+        &format!(
+            "INSERT OR IGNORE INTO moz_places_metadata_tombstones(guid)
+             SELECT guid FROM moz_places_metadata
+             WHERE {} AND sync_status = {}",
+            where_clause,
+            SyncStatus::Normal as u8
+        ),
+        &[],
+    )?;
+    tx.execute_named_cached(
+        &format!("DELETE FROM moz_places_metadata WHERE {}", where_clause),
+        &[],
+    )?;
     tx.commit()?;
 
     Ok(())
@@ -607,7 +623,8 @@ fn apply_metadata_observation_impl(
                         SET
                             document_type = :document_type,
                             total_view_time = total_view_time + :view_time_delta,
-                            updated_at = :updated_at
+                            updated_at = :updated_at,
+                            sync_change_counter = sync_change_counter + 1
                         WHERE id = :id",
                         rusqlite::named_params! {
                             ":id": metadata_id,
@@ -626,7 +643,8 @@ fn apply_metadata_observation_impl(
                             moz_places_metadata
                         SET
                             total_view_time = total_view_time + :view_time_delta,
-                            updated_at = :updated_at
+                            updated_at = :updated_at,
+                            sync_change_counter = sync_change_counter + 1
                         WHERE id = :id",
                         rusqlite::named_params! {
                             ":id": metadata_id,
@@ -664,13 +682,14 @@ fn insert_metadata_in_tx(
     let place_id = key.place_entry.get_or_insert(tx)?;
 
     let sql = "INSERT INTO moz_places_metadata
-        (place_id, created_at, updated_at, total_view_time, search_query_id, document_type, referrer_place_id)
+        (guid, place_id, created_at, updated_at, total_view_time, search_query_id, document_type, referrer_place_id)
     VALUES
-        (:place_id, :created_at, :updated_at, :total_view_time, :search_query_id, :document_type, :referrer_place_id)";
+        (:guid, :place_id, :created_at, :updated_at, :total_view_time, :search_query_id, :document_type, :referrer_place_id)";
 
     tx.execute_named_cached(
         sql,
         &[
+            (":guid", &SyncGuid::random()),
             (":place_id", &place_id),
             (":created_at", &now),
             (":updated_at", &now),
@@ -687,6 +706,334 @@ fn insert_metadata_in_tx(
     Ok(())
 }
 
+// Support for Sync - in its own module, like `history::history_sync`.
+pub mod history_metadata_sync {
+    use super::*;
+    use crate::history_metadata_sync::engine::{
+        COLLECTION_SYNCID_META_KEY, GLOBAL_SYNCID_META_KEY, LAST_SYNC_META_KEY,
+    };
+    use crate::history_metadata_sync::record::HistoryMetadataRecord;
+    use crate::storage::{delete_meta, put_meta};
+    use rusqlite::{Row, NO_PARAMS};
+    use sync15::EngineSyncAssociation;
+
+    /// What we need to know about a local metadata entry to merge an incoming
+    /// record into it.
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct LocalMetadata {
+        pub id: i64,
+        pub created_at: Timestamp,
+        pub updated_at: Timestamp,
+        pub total_view_time: i64,
+        pub synced_view_time: i64,
+        pub document_type: DocumentType,
+    }
+
+    impl LocalMetadata {
+        fn from_row(row: &Row<'_>) -> Result<Self> {
+            Ok(Self {
+                id: row.get("id")?,
+                created_at: row.get("created_at")?,
+                updated_at: row.get("updated_at")?,
+                total_view_time: row.get("total_view_time")?,
+                synced_view_time: row.get("synced_view_time")?,
+                document_type: row.get("document_type")?,
+            })
+        }
+    }
+
+    /// The merged values for an entry we have locally.
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct MergedMetadata {
+        pub id: i64,
+        pub created_at: Timestamp,
+        pub updated_at: Timestamp,
+        pub total_view_time: i64,
+        pub document_type: DocumentType,
+        /// The view time on the server, after applying this record.
+        pub synced_view_time: i64,
+        /// Whether the merged entry is different from the server's, and so
+        /// needs to be uploaded.
+        pub needs_upload: bool,
+    }
+
+    pub fn fetch_local(db: &PlacesDb, guid: &SyncGuid) -> Result<Option<LocalMetadata>> {
+        db.try_query_row(
+            "SELECT id, created_at, updated_at, total_view_time, synced_view_time,
+                    document_type
+             FROM moz_places_metadata
+             WHERE guid = :guid",
+            &[(":guid", guid)],
+            LocalMetadata::from_row,
+            true,
+        )
+    }
+
+    pub fn has_tombstone(db: &PlacesDb, guid: &SyncGuid) -> Result<bool> {
+        Ok(db.query_row_and_then_named(
+            "SELECT EXISTS(SELECT 1 FROM moz_places_metadata_tombstones
+                           WHERE guid = :guid)",
+            &[(":guid", guid)],
+            |row| row.get::<_, bool>(0),
+            true,
+        )?)
+    }
+
+    /// Deletes an entry that was deleted on another device. We don't need
+    /// a tombstone for it, because the server already has one.
+    pub fn apply_synced_deletion(db: &PlacesDb, guid: &SyncGuid) -> Result<()> {
+        db.execute_named_cached(
+            "DELETE FROM moz_places_metadata WHERE guid = :guid",
+            &[(":guid", guid)],
+        )?;
+        db.execute_named_cached(
+            "DELETE FROM moz_places_metadata_tombstones WHERE guid = :guid",
+            &[(":guid", guid)],
+        )?;
+        Ok(())
+    }
+
+    /// Inserts an entry that we've never seen before. The caller is expected
+    /// to have checked that the URLs are valid.
+    pub fn apply_synced_insertion(
+        tx: &PlacesTransaction<'_>,
+        record: &HistoryMetadataRecord,
+        document_type: DocumentType,
+    ) -> Result<()> {
+        let place_id =
+            PlaceEntry::fetch(&record.url, tx, record.title.clone())?.get_or_insert(tx)?;
+        let referrer_place_id = match &record.referrer_url {
+            Some(referrer_url) => {
+                Some(PlaceEntry::fetch(referrer_url, tx, None)?.get_or_insert(tx)?)
+            }
+            None => None,
+        };
+        let search_query_id = match &record.search_term {
+            Some(search_term) => Some(SearchQueryEntry::from(search_term, tx)?.get_or_insert(tx)?),
+            None => None,
+        };
+        tx.execute_named_cached(
+            &format!(
+                "INSERT INTO moz_places_metadata
+                    (guid, place_id, created_at, updated_at, total_view_time,
+                     search_query_id, document_type, referrer_place_id,
+                     sync_status, sync_change_counter, synced_view_time)
+                 VALUES
+                    (:guid, :place_id, :created_at, :updated_at, :total_view_time,
+                     :search_query_id, :document_type, :referrer_place_id,
+                     {status}, 0, :total_view_time)",
+                status = SyncStatus::Normal as u8
+            ),
+            rusqlite::named_params! {
+                ":guid": record.id,
+                ":place_id": place_id,
+                ":created_at": Timestamp(record.created_at),
+                ":updated_at": Timestamp(record.updated_at),
+                ":total_view_time": record.total_view_time,
+                ":search_query_id": search_query_id,
+                ":document_type": document_type,
+                ":referrer_place_id": referrer_place_id,
+            },
+        )?;
+        Ok(())
+    }
+
+    /// Writes the result of merging an incoming record into a local entry.
+    pub fn apply_synced_merge(db: &PlacesDb, merged: &MergedMetadata) -> Result<()> {
+        db.execute_named_cached(
+            &format!(
+                "UPDATE moz_places_metadata SET
+                    created_at = :created_at,
+                    updated_at = :updated_at,
+                    total_view_time = :total_view_time,
+                    document_type = :document_type,
+                    synced_view_time = :synced_view_time,
+                    sync_status = {status},
+                    sync_change_counter = CASE WHEN :needs_upload
+                                               THEN sync_change_counter + 1
+                                               ELSE 0
+                                          END
+                 WHERE id = :id",
+                status = SyncStatus::Normal as u8
+            ),
+            rusqlite::named_params! {
+                ":id": merged.id,
+                ":created_at": merged.created_at,
+                ":updated_at": merged.updated_at,
+                ":total_view_time": merged.total_view_time,
+                ":document_type": merged.document_type,
+                ":synced_view_time": merged.synced_view_time,
+                ":needs_upload": merged.needs_upload,
+            },
+        )?;
+        Ok(())
+    }
+
+    #[derive(Debug)]
+    pub enum OutgoingInfo {
+        Record(HistoryMetadataRecord),
+        Tombstone(SyncGuid),
+    }
+
+    /// Fetches tombstones, and then changed entries updated since
+    /// `updated_since`, up to `max_records` in total. Entries older than that
+    /// will have expired on the server, so there's no point uploading them.
+    pub fn fetch_outgoing(
+        db: &PlacesDb,
+        max_records: usize,
+        updated_since: Timestamp,
+    ) -> Result<Vec<OutgoingInfo>> {
+        // Like history, we note what we're uploading in temp tables, which
+        // `finish_outgoing` uses to update the change counters, and to remove
+        // only the tombstones we uploaded.
+        create_outgoing_temp_tables(db)?;
+
+        let tombstones = db.query_rows_and_then_named(
+            "SELECT guid FROM moz_places_metadata_tombstones LIMIT :max_records",
+            &[(":max_records", &(max_records as u32))],
+            |row| -> rusqlite::Result<SyncGuid> { row.get("guid") },
+        )?;
+        let mut result = Vec::with_capacity(tombstones.len());
+        for guid in tombstones {
+            db.execute_named_cached(
+                "INSERT OR IGNORE INTO temp_sync_uploaded_metadata_tombstones
+                 VALUES (:guid)",
+                &[(":guid", &guid)],
+            )?;
+            result.push(OutgoingInfo::Tombstone(guid));
+        }
+
+        let sql = format!(
+            "SELECT m.id, m.guid, m.created_at, m.updated_at, m.total_view_time,
+                    m.document_type, m.sync_change_counter, p.url, p.title,
+                    o.url AS referrer_url, s.term AS search_term
+             FROM moz_places_metadata m
+             JOIN moz_places p ON p.id = m.place_id
+             LEFT JOIN moz_places o ON o.id = m.referrer_place_id
+             LEFT JOIN moz_places_metadata_search_queries s ON s.id = m.search_query_id
+             WHERE (m.sync_change_counter > 0 OR m.sync_status != {status}) AND
+                   m.updated_at >= :updated_since
+             ORDER BY m.updated_at DESC
+             LIMIT :max_records",
+            status = SyncStatus::Normal as u8
+        );
+        let rows = db.query_rows_and_then_named(
+            &sql,
+            rusqlite::named_params! {
+                ":updated_since": updated_since,
+                ":max_records": (max_records - result.len()) as u32,
+            },
+            |row| -> Result<(i64, i64, HistoryMetadataRecord)> {
+                let document_type: DocumentType = row.get("document_type")?;
+                let record = HistoryMetadataRecord {
+                    id: row.get("guid")?,
+                    url: row.get("url")?,
+                    title: row.get("title")?,
+                    referrer_url: row.get("referrer_url")?,
+                    search_term: row.get("search_term")?,
+                    document_type: document_type as u8,
+                    total_view_time: row.get("total_view_time")?,
+                    created_at: row.get::<_, Timestamp>("created_at")?.as_millis(),
+                    updated_at: row.get::<_, Timestamp>("updated_at")?.as_millis(),
+                };
+                Ok((row.get("id")?, row.get("sync_change_counter")?, record))
+            },
+        )?;
+        for (id, change_delta, record) in rows {
+            db.execute_named_cached(
+                "INSERT INTO temp_sync_updated_metadata
+                 VALUES (:id, :change_delta, :view_time)",
+                rusqlite::named_params! {
+                    ":id": id,
+                    ":change_delta": change_delta,
+                    ":view_time": record.total_view_time,
+                },
+            )?;
+            result.push(OutgoingInfo::Record(record));
+        }
+        Ok(result)
+    }
+
+    /// Marks everything `fetch_outgoing` returned as uploaded. Entries that
+    /// changed again during the sync keep the rest of their change counter,
+    /// so they'll be uploaded next time. Tombstones we didn't upload, because
+    /// there were too many or they were written during the sync, are kept.
+    pub fn finish_outgoing(db: &PlacesDb) -> Result<()> {
+        create_outgoing_temp_tables(db)?;
+        db.execute_all(&[
+            &format!(
+                "UPDATE moz_places_metadata SET
+                    sync_change_counter = MAX(sync_change_counter -
+                        (SELECT change_delta FROM temp_sync_updated_metadata u
+                         WHERE u.id = moz_places_metadata.id), 0),
+                    synced_view_time =
+                        (SELECT view_time FROM temp_sync_updated_metadata u
+                         WHERE u.id = moz_places_metadata.id),
+                    sync_status = {}
+                 WHERE id IN (SELECT id FROM temp_sync_updated_metadata)",
+                SyncStatus::Normal as u8
+            ),
+            "DELETE FROM temp_sync_updated_metadata",
+            "DELETE FROM moz_places_metadata_tombstones
+             WHERE guid IN (SELECT guid FROM temp_sync_uploaded_metadata_tombstones)",
+            "DELETE FROM temp_sync_uploaded_metadata_tombstones",
+        ])?;
+        Ok(())
+    }
+
+    fn create_outgoing_temp_tables(db: &PlacesDb) -> Result<()> {
+        db.execute_all(&[
+            "CREATE TEMP TABLE IF NOT EXISTS temp_sync_updated_metadata
+                    (id INTEGER PRIMARY KEY,
+                     change_delta INTEGER NOT NULL,
+                     view_time INTEGER NOT NULL)",
+            "CREATE TEMP TABLE IF NOT EXISTS temp_sync_uploaded_metadata_tombstones
+                    (guid TEXT PRIMARY KEY) WITHOUT ROWID",
+        ])?;
+        Ok(())
+    }
+
+    /// Removes all metadata, and any tombstones we haven't uploaded yet.
+    pub fn delete_everything(db: &PlacesDb) -> Result<()> {
+        let tx = db.begin_transaction()?;
+        db.execute_all(&[
+            "DELETE FROM moz_places_metadata",
+            "DELETE FROM moz_places_metadata_tombstones",
+        ])?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Resets all sync metadata, so that the next sync uploads everything and
+    /// merges it with what's on the server. `synced_view_time` is kept, so
+    /// that if the server still has our entries, we don't add view time twice.
+    pub(crate) fn reset(db: &PlacesDb, assoc: &EngineSyncAssociation) -> Result<()> {
+        let tx = db.begin_transaction()?;
+        db.execute_cached(
+            &format!(
+                "UPDATE moz_places_metadata
+                 SET sync_change_counter = 0,
+                     sync_status = {}",
+                SyncStatus::New as u8
+            ),
+            NO_PARAMS,
+        )?;
+        put_meta(db, LAST_SYNC_META_KEY, &0)?;
+        match assoc {
+            EngineSyncAssociation::Disconnected => {
+                delete_meta(db, GLOBAL_SYNCID_META_KEY)?;
+                delete_meta(db, COLLECTION_SYNCID_META_KEY)?;
+            }
+            EngineSyncAssociation::Connected(ids) => {
+                put_meta(db, GLOBAL_SYNCID_META_KEY, &ids.global)?;
+                put_meta(db, COLLECTION_SYNCID_META_KEY, &ids.coll)?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
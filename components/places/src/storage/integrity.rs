@@ -0,0 +1,406 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Finds and fixes inconsistencies in the places database, like Desktop's
+//! `PlacesDBUtils`. These shouldn't happen, but older versions, crashes, and
+//! bugs in Sync or the importers can leave them behind.
+//!
+//! Every bookmark we change has its change counter bumped, and removed
+//! bookmarks get tombstones, so that the fixes are uploaded on the next sync.
+
+use super::history::update_frecency;
+use super::{delete_pending_temp_tables, RowId};
+use crate::db::schema::{
+    MOZ_META_KEY_ORIGIN_FRECENCY_COUNT, MOZ_META_KEY_ORIGIN_FRECENCY_SUM,
+    MOZ_META_KEY_ORIGIN_FRECENCY_SUM_OF_SQUARES,
+};
+use crate::db::PlacesDb;
+use crate::error::*;
+use crate::storage::bookmarks::BookmarkRootGuid;
+use crate::types::BookmarkType;
+use rusqlite::NO_PARAMS;
+use serde_derive::*;
+use sql_support::ConnExt;
+
+/// What `check_and_fix_database` found. Everything it found was also fixed,
+/// except for a failed `quick_check`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct IntegrityReport {
+    /// False if SQLite's `PRAGMA quick_check` found the database file itself
+    /// damaged. We can't fix that, so we don't run any of the other checks.
+    pub database_ok: bool,
+    /// Bookmarks whose URLs were missing from `moz_places`. These are removed.
+    pub bookmarks_without_places: usize,
+    /// Items whose parents were missing, or weren't folders. These are moved
+    /// to the end of the unfiled root.
+    pub orphaned_bookmarks: usize,
+    /// Folders whose children's positions had gaps or duplicates. The
+    /// children are renumbered, keeping their order.
+    pub folders_with_bad_positions: usize,
+    /// Pages whose `foreign_count` didn't match the number of bookmarks,
+    /// tags and keywords for them.
+    pub bad_foreign_counts: usize,
+    /// Origins without any pages. These are removed.
+    pub orphaned_origins: usize,
+    /// Pages left in `moz_places_stale_frecencies`. Their frecencies are
+    /// recalculated.
+    pub stale_frecencies: usize,
+}
+
+impl IntegrityReport {
+    /// Returns true if nothing needed fixing.
+    pub fn is_clean(&self) -> bool {
+        *self
+            == IntegrityReport {
+                database_ok: true,
+                ..IntegrityReport::default()
+            }
+    }
+}
+
+/// Checks the database for problems, and fixes the ones that we can. This
+/// reads the whole database, so it isn't part of `run_maintenance`; apps call
+/// it separately, and much less often.
+pub fn check_and_fix_database(db: &PlacesDb) -> Result<IntegrityReport> {
+    let mut report = IntegrityReport::default();
+    let quick_check: String = db.query_one("PRAGMA quick_check")?;
+    if quick_check != "ok" {
+        log::warn!("Places database failed quick_check: {}", quick_check);
+        return Ok(report);
+    }
+    report.database_ok = true;
+
+    let tx = db.begin_transaction()?;
+    report.bookmarks_without_places = remove_bookmarks_without_places(db)?;
+    report.orphaned_bookmarks = fix_orphaned_bookmarks(db)?;
+    // This needs to come after the other bookmark fixes, since removing and
+    // moving items leaves gaps.
+    report.folders_with_bad_positions = fix_positions(db)?;
+    report.bad_foreign_counts = fix_foreign_counts(db)?;
+    report.orphaned_origins = remove_orphaned_origins(db)?;
+    report.stale_frecencies = update_stale_frecencies(db)?;
+    delete_pending_temp_tables(db)?;
+    tx.commit()?;
+
+    if !report.is_clean() {
+        log::info!("Fixed places database problems: {:?}", report);
+    }
+    Ok(report)
+}
+
+fn remove_bookmarks_without_places(db: &PlacesDb) -> Result<usize> {
+    // Removing the bookmarks changes their parents' children.
+    db.execute_named_cached(
+        "UPDATE moz_bookmarks SET
+             syncChangeCounter = syncChangeCounter + 1
+         WHERE id IN (SELECT b.parent FROM moz_bookmarks b
+                      WHERE b.type = :type AND
+                            NOT EXISTS(SELECT 1 FROM moz_places h
+                                       WHERE h.id = b.fk))",
+        &[(":type", &BookmarkType::Bookmark)],
+    )?;
+    Ok(db.execute_named_cached(
+        "DELETE FROM moz_bookmarks
+         WHERE type = :type AND
+               NOT EXISTS(SELECT 1 FROM moz_places h
+                          WHERE h.id = moz_bookmarks.fk)",
+        &[(":type", &BookmarkType::Bookmark)],
+    )?)
+}
+
+fn fix_orphaned_bookmarks(db: &PlacesDb) -> Result<usize> {
+    let orphans = db.query_rows_and_then_named_cached(
+        "SELECT b.id FROM moz_bookmarks b
+         LEFT JOIN moz_bookmarks p ON p.id = b.parent
+         WHERE b.guid <> :root_guid AND
+               (p.id IS NULL OR p.type <> :folder_type)
+         ORDER BY b.id",
+        &[
+            (":root_guid", &BookmarkRootGuid::Root.as_guid()),
+            (":folder_type", &BookmarkType::Folder),
+        ],
+        |row| row.get::<_, RowId>(0),
+    )?;
+    if orphans.is_empty() {
+        return Ok(0);
+    }
+    let unfiled_id: RowId = db.query_row_and_then_named(
+        "SELECT id FROM moz_bookmarks WHERE guid = :guid",
+        &[(":guid", &BookmarkRootGuid::Unfiled.as_guid())],
+        |row| row.get(0),
+        true,
+    )?;
+    for id in &orphans {
+        db.execute_named_cached(
+            "UPDATE moz_bookmarks SET
+                 parent = :unfiled_id,
+                 position = (SELECT COUNT(*) FROM moz_bookmarks
+                             WHERE parent = :unfiled_id),
+                 syncChangeCounter = syncChangeCounter + 1
+             WHERE id = :id",
+            &[(":unfiled_id", &unfiled_id), (":id", id)],
+        )?;
+    }
+    db.execute_named_cached(
+        "UPDATE moz_bookmarks SET
+             syncChangeCounter = syncChangeCounter + 1
+         WHERE id = :unfiled_id",
+        &[(":unfiled_id", &unfiled_id)],
+    )?;
+    Ok(orphans.len())
+}
+
+fn fix_positions(db: &PlacesDb) -> Result<usize> {
+    // Children keep their relative order, and we break ties for duplicate
+    // positions by ID.
+    let moves = db.query_rows_and_then_named_cached(
+        "SELECT id, parent, newPosition FROM (
+             SELECT id, parent, position,
+                    ROW_NUMBER() OVER (PARTITION BY parent
+                                       ORDER BY position, id) - 1 AS newPosition
+             FROM moz_bookmarks
+             WHERE parent NOT NULL
+         )
+         WHERE position <> newPosition",
+        &[],
+        |row| -> rusqlite::Result<_> {
+            Ok((
+                row.get::<_, RowId>(0)?,
+                row.get::<_, RowId>(1)?,
+                row.get::<_, u32>(2)?,
+            ))
+        },
+    )?;
+    let mut parents = Vec::new();
+    for (id, parent, position) in &moves {
+        db.execute_named_cached(
+            "UPDATE moz_bookmarks SET
+                 position = :position,
+                 syncChangeCounter = syncChangeCounter + 1
+             WHERE id = :id",
+            &[(":position", position), (":id", id)],
+        )?;
+        if !parents.contains(parent) {
+            parents.push(*parent);
+        }
+    }
+    for parent in &parents {
+        db.execute_named_cached(
+            "UPDATE moz_bookmarks SET
+                 syncChangeCounter = syncChangeCounter + 1
+             WHERE id = :id",
+            &[(":id", parent)],
+        )?;
+    }
+    Ok(parents.len())
+}
+
+// This should match the triggers in `create_shared_triggers.sql` that change
+// `foreign_count`.
+const FOREIGN_COUNT_SQL: &str = "(
+    (SELECT COUNT(*) FROM moz_bookmarks b WHERE b.fk = moz_places.id) +
+    (SELECT COUNT(*) FROM moz_bookmarks_synced s WHERE s.placeId = moz_places.id) +
+    (SELECT COUNT(*) FROM moz_tags_relation t WHERE t.place_id = moz_places.id) +
+    (SELECT COUNT(*) FROM moz_keywords k WHERE k.place_id = moz_places.id)
+)";
+
+fn fix_foreign_counts(db: &PlacesDb) -> Result<usize> {
+    Ok(db.execute(
+        &format!(
+            "UPDATE moz_places SET
+                 foreign_count = {count}
+             WHERE foreign_count <> {count}",
+            count = FOREIGN_COUNT_SQL
+        ),
+        NO_PARAMS,
+    )?)
+}
+
+fn remove_orphaned_origins(db: &PlacesDb) -> Result<usize> {
+    let removed = db.execute(
+        "DELETE FROM moz_origins
+         WHERE NOT EXISTS(SELECT 1 FROM moz_places h
+                          WHERE h.origin_id = moz_origins.id)",
+        NO_PARAMS,
+    )?;
+    if removed > 0 {
+        // The triggers that keep the origin frecency stats up to date don't
+        // know about the origins we just removed, so recalculate them.
+        db.execute_named_cached(
+            "REPLACE INTO moz_meta(key, value)
+             SELECT :count_key, IFNULL(SUM(frecency > 0), 0) FROM moz_origins
+             UNION ALL
+             SELECT :sum_key, IFNULL(SUM(MAX(frecency, 0)), 0) FROM moz_origins
+             UNION ALL
+             SELECT :sum_of_squares_key,
+                    IFNULL(SUM(MAX(frecency, 0) * MAX(frecency, 0)), 0)
+             FROM moz_origins",
+            &[
+                (":count_key", &MOZ_META_KEY_ORIGIN_FRECENCY_COUNT),
+                (":sum_key", &MOZ_META_KEY_ORIGIN_FRECENCY_SUM),
+                (
+                    ":sum_of_squares_key",
+                    &MOZ_META_KEY_ORIGIN_FRECENCY_SUM_OF_SQUARES,
+                ),
+            ],
+        )?;
+    }
+    Ok(removed)
+}
+
+fn update_stale_frecencies(db: &PlacesDb) -> Result<usize> {
+    // Rows for pages that don't exist anymore can just be dropped.
+    let mut count = db.execute(
+        "DELETE FROM moz_places_stale_frecencies
+         WHERE NOT EXISTS(SELECT 1 FROM moz_places h
+                          WHERE h.id = moz_places_stale_frecencies.place_id)",
+        NO_PARAMS,
+    )?;
+    let stale = db.query_rows_and_then_named_cached(
+        "SELECT place_id FROM moz_places_stale_frecencies",
+        &[],
+        |row| row.get::<_, RowId>(0),
+    )?;
+    for place_id in &stale {
+        update_frecency(db, *place_id, None)?;
+    }
+    db.execute_batch("DELETE FROM moz_places_stale_frecencies")?;
+    count += stale.len();
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::places_api::test::new_mem_connection;
+    use crate::observation::VisitObservation;
+    use crate::storage::bookmarks::{
+        get_raw_bookmark, insert_tree, BookmarkNode, BookmarkTreeNode, FolderNode,
+    };
+    use crate::storage::history::apply_observation;
+    use crate::types::SyncStatus;
+    use sync_guid::Guid as SyncGuid;
+    use url::Url;
+
+    #[test]
+    fn test_clean_database() -> Result<()> {
+        let conn = new_mem_connection();
+        apply_observation(
+            &conn,
+            VisitObservation::new(Url::parse("https://www.example.com/")?),
+        )?;
+        let report = check_and_fix_database(&conn)?;
+        assert!(report.is_clean(), "{:?}", report);
+        Ok(())
+    }
+
+    #[test]
+    fn test_fixes_problems() -> Result<()> {
+        let conn = new_mem_connection();
+        let bookmark = |guid: &str, url: &str| -> Result<BookmarkTreeNode> {
+            Ok(BookmarkNode {
+                guid: Some(SyncGuid::from(guid)),
+                date_added: None,
+                last_modified: None,
+                title: None,
+                url: Url::parse(url)?,
+            }
+            .into())
+        };
+        insert_tree(
+            &conn,
+            &FolderNode {
+                guid: Some(BookmarkRootGuid::Menu.into()),
+                children: vec![
+                    bookmark("bookmarkAAAA", "https://example.com/a")?,
+                    FolderNode {
+                        guid: Some(SyncGuid::from("folderBBBBBB")),
+                        children: vec![bookmark("bookmarkCCCC", "https://example.com/c")?],
+                        ..Default::default()
+                    }
+                    .into(),
+                    bookmark("bookmarkDDDD", "https://example.com/d")?,
+                    bookmark("bookmarkEEEE", "https://example.com/e")?,
+                ],
+                ..Default::default()
+            },
+        )?;
+        conn.execute(
+            &format!(
+                "UPDATE moz_bookmarks SET syncStatus = {}, syncChangeCounter = 0",
+                SyncStatus::Normal as u8
+            ),
+            NO_PARAMS,
+        )?;
+
+        // Break things, without the triggers or foreign keys getting in the
+        // way.
+        conn.execute_batch(
+            "PRAGMA foreign_keys = OFF;
+             -- A bookmark whose page is missing.
+             DELETE FROM moz_places WHERE url = 'https://example.com/a';
+             -- An item whose parent is a bookmark.
+             UPDATE moz_bookmarks SET
+                 parent = (SELECT id FROM moz_bookmarks WHERE guid = 'bookmarkEEEE')
+             WHERE guid = 'bookmarkCCCC';
+             -- A duplicate position.
+             UPDATE moz_bookmarks SET position = 1 WHERE guid = 'bookmarkDDDD';
+             PRAGMA foreign_keys = ON;
+             -- A bad foreign count.
+             UPDATE moz_places SET foreign_count = 5 WHERE url = 'https://example.com/d';
+             -- An origin without pages.
+             INSERT INTO moz_origins(prefix, host, rev_host, frecency)
+             VALUES('https://', 'orphan.example.com', 'moc.elpmaxe.nahpro.', 0);
+             -- A stale frecency.
+             INSERT INTO moz_places_stale_frecencies(place_id, stale_at)
+             SELECT id, 1 FROM moz_places WHERE url = 'https://example.com/e';",
+        )?;
+
+        let report = check_and_fix_database(&conn)?;
+        assert_eq!(
+            report,
+            IntegrityReport {
+                database_ok: true,
+                bookmarks_without_places: 1,
+                orphaned_bookmarks: 1,
+                // The menu, which had a gap and a duplicate.
+                folders_with_bad_positions: 1,
+                bad_foreign_counts: 1,
+                orphaned_origins: 1,
+                stale_frecencies: 1,
+            }
+        );
+
+        assert!(get_raw_bookmark(&conn, &SyncGuid::from("bookmarkAAAA"))?.is_none());
+        let tombstones: u32 = conn.query_one("SELECT COUNT(*) FROM moz_bookmarks_deleted")?;
+        assert_eq!(tombstones, 1);
+
+        let orphan = get_raw_bookmark(&conn, &SyncGuid::from("bookmarkCCCC"))?.unwrap();
+        assert_eq!(orphan.parent_guid, Some(BookmarkRootGuid::Unfiled.into()));
+        assert_eq!(orphan.position, 0);
+        assert!(orphan.sync_change_counter > 0);
+
+        let positions = ["folderBBBBBB", "bookmarkDDDD", "bookmarkEEEE"]
+            .iter()
+            .map(|guid| {
+                Ok(get_raw_bookmark(&conn, &SyncGuid::from(*guid))?
+                    .unwrap()
+                    .position)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(positions, vec![0, 1, 2]);
+        let menu = get_raw_bookmark(&conn, &BookmarkRootGuid::Menu.into())?.unwrap();
+        assert!(menu.sync_change_counter > 0);
+
+        let foreign_count: i64 = conn.query_one(
+            "SELECT foreign_count FROM moz_places WHERE url = 'https://example.com/d'",
+        )?;
+        assert_eq!(foreign_count, 1);
+
+        // Running it again finds nothing.
+        assert!(check_and_fix_database(&conn)?.is_clean());
+        Ok(())
+    }
+}
@@ -0,0 +1,457 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Keyword search shortcuts. A keyword is a short alias for a bookmarked URL,
+//! like `w` for a Wikipedia search. When the user types `w rust language`,
+//! `resolve_keyword_search` finds the URL for `w`, and replaces `%s` in the
+//! URL with the URL-encoded search terms, and `%S` with the terms as typed.
+//! If the URL ends with `&mozcharset=<charset>`, `%s` is encoded using that
+//! charset instead of UTF-8, for older search forms that don't understand
+//! UTF-8.
+//!
+//! A keyword can also have POST data, with the same placeholders, for
+//! search forms that need a POST request. Like Desktop, we associate
+//! keywords with URLs, not bookmarks, so each URL has at most one keyword,
+//! and each keyword belongs to at most one URL. Sync and Fennec associate
+//! keywords with bookmarks instead, so changing a keyword flags all
+//! bookmarks for its URL for upload. POST data isn't synced.
+
+use crate::db::PlacesDb;
+use crate::error::{InvalidPlaceInfo, Result};
+use percent_encoding::{percent_encode, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use sql_support::ConnExt;
+use types::Timestamp;
+use url::Url;
+
+/// The characters to escape in search terms. This matches JavaScript's
+/// `encodeURIComponent`, which is what Desktop uses.
+const SEARCH_TERMS_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'!')
+    .remove(b'~')
+    .remove(b'*')
+    .remove(b'\'')
+    .remove(b'(')
+    .remove(b')');
+
+/// The suffix that sets the charset for a keyword URL. Desktop strips this
+/// from the URL before substituting the search terms.
+const CHARSET_PARAM: &str = "&mozcharset=";
+
+/// A keyword and the URL it belongs to.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Keyword {
+    pub keyword: String,
+    pub url: Url,
+    pub post_data: Option<String>,
+}
+
+/// The result of resolving a keyword search: the URL to load, with the search
+/// terms substituted, and the POST data to send, if any.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct KeywordSearch {
+    pub keyword: String,
+    pub url: Url,
+    pub post_data: Option<String>,
+    /// The title of the bookmark with the keyword, or the page title if the
+    /// bookmark doesn't have one.
+    pub title: String,
+    pub frecency: i64,
+}
+
+/// Checks the validity of a keyword, returning the normalized keyword.
+/// Keywords are case-insensitive, and are stored in lowercase without
+/// leading or trailing whitespace, like Desktop and Sync. Since the first
+/// word of the search string is the keyword, a keyword can't contain
+/// whitespace.
+pub fn validate_keyword(keyword: &str) -> Result<String> {
+    let k = keyword.trim();
+    if k.is_empty() || k.chars().any(char::is_whitespace) {
+        return Err(InvalidPlaceInfo::InvalidKeyword.into());
+    }
+    Ok(k.to_lowercase())
+}
+
+/// Sets the keyword and POST data for a bookmarked URL, replacing any
+/// existing keyword for the URL.
+///
+/// # Arguments
+///
+/// * `conn` - A database connection on which to operate.
+///
+/// * `url` - The URL for the keyword. The URL must be bookmarked.
+///
+/// * `keyword` - The keyword to set for the URL.
+///
+/// * `post_data` - The POST data to send when searching with the keyword.
+///
+/// # Returns
+///
+/// There is no success return value. Returns `InvalidPlaceInfo::NoSuchUrl`
+/// if the URL isn't bookmarked, and `InvalidPlaceInfo::KeywordInUse` if the
+/// keyword is already used for a different URL.
+pub fn set_keyword(db: &PlacesDb, url: &Url, keyword: &str, post_data: Option<&str>) -> Result<()> {
+    let tx = db.begin_transaction()?;
+    set_keyword_in_tx(db, url, keyword, post_data)?;
+    tx.commit()?;
+    Ok(())
+}
+
+/// Like `set_keyword`, but the caller is responsible for the transaction.
+pub(crate) fn set_keyword_in_tx(
+    db: &PlacesDb,
+    url: &Url,
+    keyword: &str,
+    post_data: Option<&str>,
+) -> Result<()> {
+    let keyword = validate_keyword(keyword)?;
+    let place_id = match db.try_query_one::<i64>(
+        "SELECT h.id FROM moz_places h
+         WHERE h.url_hash = hash(:url) AND h.url = :url AND
+               EXISTS(SELECT 1 FROM moz_bookmarks b WHERE b.fk = h.id)",
+        &[(":url", &url.as_str())],
+        true,
+    )? {
+        Some(id) => id,
+        None => return Err(InvalidPlaceInfo::NoSuchUrl.into()),
+    };
+
+    let owner = db.try_query_one::<i64>(
+        "SELECT place_id FROM moz_keywords WHERE keyword = :keyword",
+        &[(":keyword", &keyword)],
+        true,
+    )?;
+    match owner {
+        Some(id) if id != place_id => return Err(InvalidPlaceInfo::KeywordInUse.into()),
+        Some(_) => {
+            // Same keyword for the same URL, so only the POST data might
+            // change. We don't sync POST data, so there's nothing to upload.
+            db.execute_named_cached(
+                "UPDATE moz_keywords SET post_data = :post_data
+                 WHERE place_id = :place_id",
+                &[(":post_data", &post_data), (":place_id", &place_id)],
+            )?;
+        }
+        None => {
+            db.execute_named_cached(
+                "INSERT INTO moz_keywords(place_id, keyword, post_data)
+                 VALUES(:place_id, :keyword, :post_data)
+                 ON CONFLICT(place_id) DO UPDATE SET
+                   keyword = excluded.keyword,
+                   post_data = excluded.post_data",
+                &[
+                    (":place_id", &place_id),
+                    (":keyword", &keyword),
+                    (":post_data", &post_data),
+                ],
+            )?;
+            bump_bookmarks_for_place(db, place_id)?;
+        }
+    }
+    Ok(())
+}
+
+/// Removes a keyword.
+///
+/// # Returns
+///
+/// `true` if the keyword existed and was removed; `false` otherwise.
+pub fn remove_keyword(db: &PlacesDb, keyword: &str) -> Result<bool> {
+    let keyword = validate_keyword(keyword)?;
+    let tx = db.begin_transaction()?;
+    let place_id = db.try_query_one::<i64>(
+        "SELECT place_id FROM moz_keywords WHERE keyword = :keyword",
+        &[(":keyword", &keyword)],
+        true,
+    )?;
+    if let Some(place_id) = place_id {
+        db.execute_named_cached(
+            "DELETE FROM moz_keywords WHERE place_id = :place_id",
+            &[(":place_id", &place_id)],
+        )?;
+        bump_bookmarks_for_place(db, place_id)?;
+    }
+    tx.commit()?;
+    Ok(place_id.is_some())
+}
+
+/// Flags all bookmarks for a URL for upload, after its keyword changes.
+fn bump_bookmarks_for_place(db: &PlacesDb, place_id: i64) -> Result<()> {
+    db.execute_named_cached(
+        "UPDATE moz_bookmarks SET
+           syncChangeCounter = syncChangeCounter + 1,
+           lastModified = :now
+         WHERE fk = :place_id",
+        &[(":now", &Timestamp::now()), (":place_id", &place_id)],
+    )?;
+    Ok(())
+}
+
+/// Returns the URL and POST data for a keyword, without substituting the
+/// search terms.
+pub fn get_keyword(db: &PlacesDb, keyword: &str) -> Result<Option<Keyword>> {
+    let keyword = match validate_keyword(keyword) {
+        Ok(k) => k,
+        Err(_) => return Ok(None),
+    };
+    let found = db.try_query_row(
+        "SELECT h.url, k.post_data FROM moz_keywords k
+         JOIN moz_places h ON h.id = k.place_id
+         WHERE k.keyword = :keyword",
+        &[(":keyword", &keyword)],
+        |row| -> Result<_> {
+            Ok((
+                row.get::<_, String>("url")?,
+                row.get::<_, Option<String>>("post_data")?,
+            ))
+        },
+        true,
+    )?;
+    Ok(match found {
+        Some((url, post_data)) => Some(Keyword {
+            keyword,
+            url: Url::parse(&url)?,
+            post_data,
+        }),
+        None => None,
+    })
+}
+
+/// Resolves a keyword search. The first word of `search_string` is the
+/// keyword, and the rest are the search terms.
+///
+/// # Returns
+///
+/// The URL and POST data to load, or `None` if the first word isn't a
+/// keyword, or if there are search terms but the keyword's URL and POST data
+/// don't have a `%s` or `%S` placeholder for them.
+pub fn resolve_keyword_search(db: &PlacesDb, search_string: &str) -> Result<Option<KeywordSearch>> {
+    let search_string = search_string.trim();
+    let (keyword, terms) = match search_string.find(char::is_whitespace) {
+        Some(index) => {
+            let (keyword, terms) = search_string.split_at(index);
+            (keyword, terms.trim_start())
+        }
+        None => (search_string, ""),
+    };
+    if keyword.is_empty() {
+        return Ok(None);
+    }
+    let keyword = keyword.to_lowercase();
+    let found = db.try_query_row(
+        "SELECT h.url, k.post_data, h.frecency,
+                IFNULL((SELECT b.title FROM moz_bookmarks b
+                        WHERE b.fk = h.id AND b.title NOT NULL
+                        ORDER BY b.lastModified DESC
+                        LIMIT 1), h.title) AS title
+         FROM moz_keywords k
+         JOIN moz_places h ON h.id = k.place_id
+         WHERE k.keyword = :keyword",
+        &[(":keyword", &keyword)],
+        |row| -> Result<_> {
+            Ok((
+                row.get::<_, String>("url")?,
+                row.get::<_, Option<String>>("post_data")?,
+                row.get::<_, i64>("frecency")?,
+                row.get::<_, Option<String>>("title")?,
+            ))
+        },
+        true,
+    )?;
+    let (template, post_data, frecency, title) = match found {
+        Some(found) => found,
+        None => return Ok(None),
+    };
+    let (url, post_data) = match substitute_search_terms(&template, post_data.as_deref(), terms) {
+        Some(result) => result,
+        None => return Ok(None),
+    };
+    match Url::parse(&url) {
+        Ok(url) => Ok(Some(KeywordSearch {
+            keyword,
+            url,
+            post_data,
+            title: title.unwrap_or_default(),
+            frecency,
+        })),
+        Err(e) => {
+            // The keyword and search terms are PII, so only log the error.
+            log::warn!("ignoring invalid keyword search url: {:?}", e);
+            Ok(None)
+        }
+    }
+}
+
+/// Substitutes the search terms into a keyword URL and POST data, following
+/// Desktop's `KeywordUtils.parseUrlAndPostData`. Returns `None` if there are
+/// search terms, but nowhere to put them.
+fn substitute_search_terms(
+    template: &str,
+    post_data: Option<&str>,
+    terms: &str,
+) -> Option<(String, Option<String>)> {
+    let (template, charset) = split_charset(template);
+    let has_placeholder = |s: &str| s.contains("%s") || s.contains("%S");
+    if !has_placeholder(template) && !post_data.map_or(false, has_placeholder) {
+        if terms.is_empty() {
+            return Some((template.to_string(), post_data.map(str::to_string)));
+        }
+        return None;
+    }
+    let encoded = encode_search_terms(terms, charset);
+    let substitute = |s: &str| s.replace("%s", &encoded).replace("%S", terms);
+    Some((substitute(template), post_data.map(substitute)))
+}
+
+/// Splits a trailing `&mozcharset=<charset>` parameter off a keyword URL.
+fn split_charset(template: &str) -> (&str, Option<&str>) {
+    let lower = template.to_ascii_lowercase();
+    if let Some(index) = lower.rfind(CHARSET_PARAM) {
+        let charset = template[index + CHARSET_PARAM.len()..].trim_end();
+        let mut chars = charset.chars();
+        let valid = chars.next().map_or(false, |c| c.is_ascii_alphabetic())
+            && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+        if valid {
+            return (&template[..index], Some(charset));
+        }
+    }
+    (template, None)
+}
+
+/// URL-encodes the search terms, first converting them to `charset` if it's
+/// a known charset other than UTF-8.
+fn encode_search_terms(terms: &str, charset: Option<&str>) -> String {
+    match charset.and_then(|c| encoding_rs::Encoding::for_label(c.as_bytes())) {
+        Some(encoding) if encoding != encoding_rs::UTF_8 => {
+            let (bytes, _, _) = encoding.encode(terms);
+            percent_encode(&bytes, SEARCH_TERMS_ENCODE_SET).to_string()
+        }
+        _ => utf8_percent_encode(terms, SEARCH_TERMS_ENCODE_SET).to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::places_api::test::new_mem_connection;
+    use crate::error::ErrorKind;
+    use crate::storage::bookmarks::{
+        insert_bookmark, BookmarkPosition, BookmarkRootGuid, InsertableBookmark,
+    };
+
+    fn bookmark(db: &PlacesDb, url: &str, title: &str) -> Url {
+        let url = Url::parse(url).unwrap();
+        insert_bookmark(
+            db,
+            &InsertableBookmark {
+                parent_guid: BookmarkRootGuid::Unfiled.into(),
+                position: BookmarkPosition::Append,
+                date_added: None,
+                last_modified: None,
+                guid: None,
+                url: url.clone(),
+                title: Some(title.into()),
+            }
+            .into(),
+        )
+        .expect("should insert bookmark");
+        url
+    }
+
+    #[test]
+    fn test_substitute_search_terms() {
+        assert_eq!(
+            substitute_search_terms("https://example.com/?q=%s", None, "a b&c"),
+            Some(("https://example.com/?q=a%20b%26c".into(), None))
+        );
+        assert_eq!(
+            substitute_search_terms("https://example.com/%S", None, "a/b"),
+            Some(("https://example.com/a/b".into(), None))
+        );
+        assert_eq!(
+            substitute_search_terms("https://example.com/", Some("q=%s&raw=%S"), "é"),
+            Some(("https://example.com/".into(), Some("q=%C3%A9&raw=é".into())))
+        );
+        assert_eq!(
+            substitute_search_terms("https://example.com/?q=%s&mozcharset=ISO-8859-1", None, "é"),
+            Some(("https://example.com/?q=%E9".into(), None))
+        );
+        assert_eq!(
+            substitute_search_terms("https://example.com/", None, ""),
+            Some(("https://example.com/".into(), None))
+        );
+        assert_eq!(
+            substitute_search_terms("https://example.com/", None, "terms"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_keywords() -> Result<()> {
+        let conn = new_mem_connection();
+        let search = bookmark(&conn, "https://search.example/?q=%s", "Search");
+        let post = bookmark(&conn, "https://post.example/search", "Post");
+        let unbookmarked = Url::parse("https://example.com/")?;
+
+        set_keyword(&conn, &search, " S ", None)?;
+        set_keyword(&conn, &post, "p", Some("query=%s"))?;
+
+        assert!(matches!(
+            set_keyword(&conn, &post, "s", None).unwrap_err().kind(),
+            ErrorKind::InvalidPlaceInfo(InvalidPlaceInfo::KeywordInUse)
+        ));
+        assert!(matches!(
+            set_keyword(&conn, &unbookmarked, "e", None)
+                .unwrap_err()
+                .kind(),
+            ErrorKind::InvalidPlaceInfo(InvalidPlaceInfo::NoSuchUrl)
+        ));
+        assert!(matches!(
+            set_keyword(&conn, &post, "two words", None)
+                .unwrap_err()
+                .kind(),
+            ErrorKind::InvalidPlaceInfo(InvalidPlaceInfo::InvalidKeyword)
+        ));
+
+        let found = resolve_keyword_search(&conn, "S rust lang")?.expect("should resolve");
+        assert_eq!(found.url.as_str(), "https://search.example/?q=rust%20lang");
+        assert_eq!(found.title, "Search");
+        assert_eq!(found.post_data, None);
+
+        let found = resolve_keyword_search(&conn, "p rust")?.expect("should resolve");
+        assert_eq!(found.url.as_str(), "https://post.example/search");
+        assert_eq!(found.post_data.as_deref(), Some("query=rust"));
+
+        assert_eq!(resolve_keyword_search(&conn, "nope rust")?, None);
+
+        // Changing the POST data keeps the keyword.
+        set_keyword(&conn, &post, "p", None)?;
+        assert_eq!(
+            get_keyword(&conn, "P")?,
+            Some(Keyword {
+                keyword: "p".into(),
+                url: post.clone(),
+                post_data: None,
+            })
+        );
+        // ...And there's nowhere to put the search terms now.
+        assert_eq!(resolve_keyword_search(&conn, "p rust")?, None);
+
+        // Setting a new keyword for the URL replaces the old one, and flags
+        // the bookmark for upload.
+        conn.execute_batch("UPDATE moz_bookmarks SET syncChangeCounter = 0")?;
+        set_keyword(&conn, &post, "post", None)?;
+        assert_eq!(get_keyword(&conn, "p")?, None);
+        let changed = conn
+            .query_one::<i64>("SELECT COUNT(*) FROM moz_bookmarks WHERE syncChangeCounter > 0")?;
+        assert_eq!(changed, 1);
+
+        assert!(remove_keyword(&conn, "post")?);
+        assert!(!remove_keyword(&conn, "post")?);
+        assert_eq!(get_keyword(&conn, "post")?, None);
+        Ok(())
+    }
+}
@@ -5,10 +5,17 @@
 // A "storage" module - this module is intended to be the layer between the
 // API and the database.
 
+pub mod annotations;
 pub mod bookmarks;
+pub mod diagnostics;
+pub mod favicons;
 pub mod history;
 pub mod history_metadata;
+pub mod integrity;
+pub mod keywords;
+pub mod search_index;
 pub mod tags;
+pub mod top_sites;
 
 use crate::db::PlacesDb;
 use crate::error::{ErrorKind, InvalidPlaceInfo, Result};
@@ -210,7 +217,14 @@ impl TopFrecentSiteInfo {
     }
 }
 
+/// Runs periodic maintenance. This recalculates stale frecencies first, like
+/// the ones marked stale when the frecency settings change, in chunks, so
+/// interrupting it keeps the chunks that were already recalculated.
 pub fn run_maintenance(conn: &PlacesDb) -> Result<()> {
+    history::recalculate_stale_frecencies(conn, &conn.begin_interrupt_scope())?;
+    favicons::expire_icons(conn, Timestamp::now())?;
+    annotations::expire_annotations(conn, Timestamp::now())?;
+    search_index::optimize_search_index(conn)?;
     conn.execute_all(&[
         "VACUUM",
         "PRAGMA optimize",
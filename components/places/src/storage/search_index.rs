@@ -0,0 +1,219 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! An optional FTS5 index over page URLs and titles, bookmark titles, tags
+//! and keywords, which `SearchMode::FullText` uses to find autocomplete
+//! matches without scanning every page.
+//!
+//! The index isn't part of the schema: it's created by `enable_search_index`,
+//! and kept in sync by triggers from then on (see
+//! `sql/create_search_index.sql`).
+
+use crate::db::PlacesDb;
+use crate::error::*;
+use sql_support::ConnExt;
+
+const CREATE_SEARCH_INDEX_SQL: &str = include_str!("../../sql/create_search_index.sql");
+
+const SEARCH_INDEX_TRIGGERS: [&str; 12] = [
+    "moz_places_fts_afterinsert_trigger",
+    "moz_places_fts_afterupdate_trigger",
+    "moz_places_fts_afterdelete_trigger",
+    "moz_bookmarks_fts_afterinsert_trigger",
+    "moz_bookmarks_fts_afterupdate_trigger",
+    "moz_bookmarks_fts_afterdelete_trigger",
+    "moz_tags_relation_fts_afterinsert_trigger",
+    "moz_tags_relation_fts_afterdelete_trigger",
+    "moz_tags_fts_afterupdate_trigger",
+    "moz_keywords_fts_afterinsert_trigger",
+    "moz_keywords_fts_afterupdate_trigger",
+    "moz_keywords_fts_afterdelete_trigger",
+];
+
+/// Returns whether the search index has been created.
+pub fn is_search_index_enabled(db: &PlacesDb) -> Result<bool> {
+    Ok(db
+        .try_query_one::<i64>(
+            "SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'moz_places_fts'",
+            &[],
+            true,
+        )?
+        .is_some())
+}
+
+/// Creates the search index, and indexes all existing pages. Does nothing if
+/// the index already exists. This can take a while for large databases, but
+/// only needs to happen once.
+pub fn enable_search_index(db: &PlacesDb) -> Result<()> {
+    if is_search_index_enabled(db)? {
+        return Ok(());
+    }
+    let tx = db.begin_transaction()?;
+    db.execute_batch(CREATE_SEARCH_INDEX_SQL)?;
+    populate_search_index(db)?;
+    tx.commit()?;
+    Ok(())
+}
+
+/// Removes the search index and its triggers. `SearchMode::FullText` falls
+/// back to scanning after this.
+pub fn disable_search_index(db: &PlacesDb) -> Result<()> {
+    let tx = db.begin_transaction()?;
+    for trigger in SEARCH_INDEX_TRIGGERS.iter() {
+        db.execute_batch(&format!("DROP TRIGGER IF EXISTS {}", trigger))?;
+    }
+    db.execute_batch(
+        "DROP VIEW IF EXISTS moz_places_fts_content;
+         DROP TABLE IF EXISTS moz_places_fts;",
+    )?;
+    tx.commit()?;
+    Ok(())
+}
+
+/// Reindexes every page, in case the index has somehow gotten out of sync
+/// with the database.
+pub fn rebuild_search_index(db: &PlacesDb) -> Result<()> {
+    if !is_search_index_enabled(db)? {
+        return Ok(());
+    }
+    let tx = db.begin_transaction()?;
+    db.execute_batch("DELETE FROM moz_places_fts")?;
+    populate_search_index(db)?;
+    tx.commit()?;
+    Ok(())
+}
+
+fn populate_search_index(db: &PlacesDb) -> Result<()> {
+    db.execute_batch(
+        "INSERT INTO moz_places_fts(rowid, url, title, tags, keyword)
+         SELECT id, url, title, tags, keyword FROM moz_places_fts_content",
+    )?;
+    Ok(())
+}
+
+/// Merges the index's internal b-trees, which makes queries faster. Called
+/// from `run_maintenance`.
+pub(crate) fn optimize_search_index(db: &PlacesDb) -> Result<()> {
+    if is_search_index_enabled(db)? {
+        db.execute_batch("INSERT INTO moz_places_fts(moz_places_fts) VALUES('optimize')")?;
+    }
+    Ok(())
+}
+
+/// Converts an autocomplete search string into an FTS5 query that matches
+/// pages with a token starting with each word in the search string, or
+/// returns `None` if the search string doesn't have any words.
+pub(crate) fn fts_query_for_search_string(search_string: &str) -> Option<String> {
+    let words = search_string
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        // Words are only made of alphanumeric characters, so they don't need
+        // escaping inside quotes.
+        .map(|word| format!("\"{}\"*", word))
+        .collect::<Vec<_>>();
+    if words.is_empty() {
+        None
+    } else {
+        Some(words.join(" "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::places_api::test::new_mem_connection;
+    use crate::observation::VisitObservation;
+    use crate::storage::bookmarks::{
+        delete_bookmark, insert_bookmark, BookmarkPosition, BookmarkRootGuid, InsertableBookmark,
+        InsertableItem,
+    };
+    use crate::storage::history::apply_observation;
+    use crate::storage::tags::{tag_url, untag_url};
+    use url::Url;
+
+    fn indexed(db: &PlacesDb, query: &str) -> Result<Vec<String>> {
+        db.query_rows_and_then_named(
+            "SELECT url FROM moz_places_fts WHERE moz_places_fts MATCH :query ORDER BY url",
+            &[(":query", &fts_query_for_search_string(query).unwrap())],
+            |row| row.get::<_, String>(0),
+        )
+    }
+
+    #[test]
+    fn test_fts_query() {
+        assert_eq!(
+            fts_query_for_search_string("moz app-serv").as_deref(),
+            Some("\"moz\"* \"app\"* \"serv\"*")
+        );
+        assert_eq!(
+            fts_query_for_search_string("\"café\"").as_deref(),
+            Some("\"café\"*")
+        );
+        assert_eq!(fts_query_for_search_string(" ./ "), None);
+    }
+
+    #[test]
+    fn test_index_kept_in_sync() -> Result<()> {
+        let conn = new_mem_connection();
+        let url = Url::parse("https://www.example.com/page")?;
+        apply_observation(
+            &conn,
+            VisitObservation::new(url.clone()).with_title("Before enabling".to_string()),
+        )?;
+
+        assert!(!is_search_index_enabled(&conn)?);
+        enable_search_index(&conn)?;
+        assert!(is_search_index_enabled(&conn)?);
+        // Existing pages are indexed.
+        assert_eq!(indexed(&conn, "befo")?, vec![url.to_string()]);
+
+        // Title changes.
+        apply_observation(
+            &conn,
+            VisitObservation::new(url.clone()).with_title("Renamed".to_string()),
+        )?;
+        assert!(indexed(&conn, "before")?.is_empty());
+        assert_eq!(indexed(&conn, "renamed")?, vec![url.to_string()]);
+
+        // Bookmark titles.
+        let guid = insert_bookmark(
+            &conn,
+            &InsertableItem::Bookmark(InsertableBookmark {
+                parent_guid: BookmarkRootGuid::Unfiled.into(),
+                position: BookmarkPosition::Append,
+                date_added: None,
+                last_modified: None,
+                guid: None,
+                url: url.clone(),
+                title: Some("Favorite".into()),
+            }),
+        )?;
+        assert_eq!(indexed(&conn, "fav")?, vec![url.to_string()]);
+        delete_bookmark(&conn, &guid)?;
+        assert!(indexed(&conn, "fav")?.is_empty());
+
+        // Tags.
+        tag_url(&conn, &url, "reading")?;
+        assert_eq!(indexed(&conn, "read")?, vec![url.to_string()]);
+        untag_url(&conn, &url, "reading")?;
+        assert!(indexed(&conn, "read")?.is_empty());
+
+        // New pages.
+        let other = Url::parse("https://other.example.com/")?;
+        apply_observation(&conn, VisitObservation::new(other.clone()))?;
+        assert_eq!(
+            indexed(&conn, "example")?,
+            vec![other.to_string(), url.to_string()]
+        );
+
+        disable_search_index(&conn)?;
+        assert!(!is_search_index_enabled(&conn)?);
+        // Writing after the index is gone still works.
+        apply_observation(
+            &conn,
+            VisitObservation::new(url).with_title("After disabling".to_string()),
+        )?;
+        Ok(())
+    }
+}
@@ -0,0 +1,728 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Top sites, for the new tab page. `get_top_sites` puts the user's pinned
+//! sites in their tiles, then fills the other tiles with their most frecent
+//! sites, then the default sites the app ships with.
+//!
+//! Pinned sites are bookmarks in a "Pinned Sites" folder in the mobile root,
+//! so they sync with the rest of the bookmarks. The folder always has the
+//! same GUID, so each device's folder merges into the same one. Each pin's
+//! tile is stored in an item annotation, which isn't synced; pins without
+//! one, like pins from other devices, take the first free tiles, in the order
+//! of the bookmarks in the folder.
+//!
+//! Removing a top site adds it to a blocklist, which isn't synced.
+
+use crate::db::PlacesDb;
+use crate::error::*;
+use crate::storage::annotations::{
+    set_annotation_in_tx, AnnotationExpiration, AnnotationTarget, AnnotationValue,
+};
+use crate::storage::bookmarks::{
+    delete_bookmark_in_tx, get_raw_bookmark, insert_bookmark_in_tx, update_bookmark_in_tx,
+    BookmarkPosition, BookmarkRootGuid, InsertableBookmark, InsertableFolder, UpdatableBookmark,
+    UpdateTreeLocation,
+};
+use crate::storage::delete_pending_temp_tables;
+use crate::types::{VisitTransition, VisitTransitionSet};
+use sql_support::ConnExt;
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use sync_guid::Guid as SyncGuid;
+use types::Timestamp;
+use url::Url;
+
+/// The GUID of the folder that holds pinned sites.
+pub const PINNED_SITES_FOLDER_GUID: &str = "pinnedsites_";
+
+const PINNED_SITES_FOLDER_TITLE: &str = "Pinned Sites";
+
+/// The item annotation that holds a pin's tile index.
+const PINNED_TILE_ANNO: &str = "topSites/tileIndex";
+
+/// Where a top site came from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TopSiteKind {
+    Pinned,
+    Frecent,
+    Default,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TopSite {
+    pub url: String,
+    pub title: Option<String>,
+    pub kind: TopSiteKind,
+}
+
+/// A site the app suggests when the user doesn't have enough pinned and
+/// frecent sites to fill the tiles, like a partner site.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DefaultTopSite {
+    pub url: Url,
+    pub title: String,
+}
+
+// Turns `moz_origins.host` into the site we dedupe and block by.
+const SITE_FOR_HOST_SQL: &str =
+    "CASE WHEN substr(o.host, 1, 4) = 'www.' THEN substr(o.host, 5) ELSE o.host END";
+
+/// Returns the site for a URL: its host and port, without a leading "www.".
+/// This matches the sites we get from `moz_origins`.
+fn site_for_url(url: &Url) -> Option<String> {
+    let host = url.host_str()?.to_lowercase();
+    let host = host.strip_prefix("www.").unwrap_or(&host);
+    Some(match url.port() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host.to_string(),
+    })
+}
+
+/// Returns up to `limit` top sites. Pinned sites stay in their tiles, and
+/// the other tiles are filled, in order, with the most frecent pages with a
+/// frecency of at least `frecency_threshold`, then `defaults`. Frecent and
+/// default sites are deduped by site, so that, for example, only the most
+/// frecent page on `example.com` is included, and skip sites that are pinned
+/// or blocked. Pins in tiles past `limit` aren't included, and if there
+/// aren't enough sites to fill the tiles before a pin, it moves up.
+pub fn get_top_sites(
+    db: &PlacesDb,
+    limit: usize,
+    frecency_threshold: i64,
+    defaults: &[DefaultTopSite],
+) -> Result<Vec<TopSite>> {
+    let mut tiles: Vec<Option<TopSite>> = vec![None; limit];
+    let mut seen_sites = HashSet::new();
+    for (tile, pin) in assign_tiles(fetch_pins(db)?) {
+        if let Some(slot) = tiles.get_mut(tile as usize) {
+            if let Some(site) = Url::parse(&pin.url).ok().and_then(|url| site_for_url(&url)) {
+                seen_sites.insert(site);
+            }
+            *slot = Some(pin.into());
+        }
+    }
+    let free_tiles = tiles.iter().filter(|tile| tile.is_none()).count();
+
+    let mut others = Vec::with_capacity(free_tiles);
+    if free_tiles > 0 {
+        // Pinned sites can't push out more frecent sites than there are pins,
+        // so this fetches enough to fill the free tiles.
+        let frecent = fetch_frecent_sites(db, limit, frecency_threshold)?;
+        for (site, url, title) in frecent {
+            if others.len() >= free_tiles {
+                break;
+            }
+            if seen_sites.insert(site) {
+                others.push(TopSite {
+                    url,
+                    title,
+                    kind: TopSiteKind::Frecent,
+                });
+            }
+        }
+    }
+
+    if others.len() < free_tiles {
+        let blocked = get_blocked_sites(db)?.into_iter().collect::<HashSet<_>>();
+        for default in defaults {
+            if others.len() >= free_tiles {
+                break;
+            }
+            let site = match site_for_url(&default.url) {
+                Some(site) => site,
+                None => continue,
+            };
+            if !blocked.contains(&site) && seen_sites.insert(site) {
+                others.push(TopSite {
+                    url: default.url.to_string(),
+                    title: Some(default.title.clone()),
+                    kind: TopSiteKind::Default,
+                });
+            }
+        }
+    }
+
+    let mut others = others.into_iter();
+    Ok(tiles
+        .into_iter()
+        .filter_map(|tile| tile.or_else(|| others.next()))
+        .collect())
+}
+
+/// Returns the most frecent page for each site that isn't blocked, as
+/// `(site, url, title)`.
+fn fetch_frecent_sites(
+    db: &PlacesDb,
+    limit: usize,
+    frecency_threshold: i64,
+) -> Result<Vec<(String, String, Option<String>)>> {
+    // The same visit types as `get_top_frecent_site_infos`.
+    let allowed_types = VisitTransitionSet::for_specific(&[
+        VisitTransition::Download,
+        VisitTransition::Embed,
+        VisitTransition::RedirectPermanent,
+        VisitTransition::RedirectTemporary,
+        VisitTransition::FramedLink,
+        VisitTransition::Reload,
+    ])
+    .complement();
+    let limit = limit as i64;
+    db.query_rows_and_then_named_cached(
+        &format!(
+            "SELECT site, url, title
+             FROM (
+               SELECT {site} AS site, h.id, h.url, h.title, h.frecency,
+                      ROW_NUMBER() OVER (PARTITION BY {site}
+                                         ORDER BY h.frecency DESC, h.id) AS siteRank
+               FROM moz_places h
+               JOIN moz_origins o ON o.id = h.origin_id
+               WHERE EXISTS (
+                 SELECT 1
+                 FROM moz_historyvisits v
+                 WHERE h.id = v.place_id
+                   AND (SUBSTR(h.url, 1, 6) == 'https:' OR SUBSTR(h.url, 1, 5) == 'http:')
+                   AND (h.last_visit_date_local + h.last_visit_date_remote) != 0
+                   AND ((1 << v.visit_type) & :allowed_types) != 0
+                   AND h.frecency >= :frecency_threshold
+                   AND NOT h.hidden
+               )
+             )
+             WHERE siteRank = 1 AND
+                   site NOT IN (SELECT site FROM moz_topsites_blocklist)
+             ORDER BY frecency DESC, id
+             LIMIT :limit",
+            site = SITE_FOR_HOST_SQL
+        ),
+        rusqlite::named_params! {
+            ":allowed_types": allowed_types,
+            ":frecency_threshold": frecency_threshold,
+            ":limit": limit,
+        },
+        |row| -> Result<_> { Ok((row.get(0)?, row.get(1)?, row.get(2)?)) },
+    )
+}
+
+/// Returns the pinned sites, in tile order.
+pub fn get_pinned_sites(db: &PlacesDb) -> Result<Vec<TopSite>> {
+    Ok(assign_tiles(fetch_pins(db)?)
+        .into_iter()
+        .map(|(_, pin)| pin.into())
+        .collect())
+}
+
+/// A pinned bookmark.
+#[derive(Debug)]
+struct Pin {
+    guid: SyncGuid,
+    url: String,
+    title: Option<String>,
+    /// The stored tile index, if the pin has one.
+    tile: Option<u32>,
+}
+
+impl From<Pin> for TopSite {
+    fn from(pin: Pin) -> Self {
+        TopSite {
+            url: pin.url,
+            title: pin.title,
+            kind: TopSiteKind::Pinned,
+        }
+    }
+}
+
+/// Returns the pinned bookmarks, in folder order.
+fn fetch_pins(db: &PlacesDb) -> Result<Vec<Pin>> {
+    db.query_rows_and_then_named_cached(
+        "SELECT b.guid, h.url, b.title, a.content
+         FROM moz_bookmarks b
+         JOIN moz_bookmarks p ON p.id = b.parent
+         JOIN moz_places h ON h.id = b.fk
+         LEFT JOIN moz_items_annos a ON a.item_id = b.id AND
+                   a.anno_attribute_id = (SELECT id FROM moz_anno_attributes
+                                          WHERE name = :tile_anno)
+         WHERE p.guid = :folder_guid
+         ORDER BY b.position",
+        &[
+            (":folder_guid", &PINNED_SITES_FOLDER_GUID),
+            (":tile_anno", &PINNED_TILE_ANNO),
+        ],
+        |row| -> Result<_> {
+            let tile: Option<i64> = row.get(3)?;
+            Ok(Pin {
+                guid: row.get(0)?,
+                url: row.get(1)?,
+                title: row.get(2)?,
+                tile: tile.and_then(|tile| u32::try_from(tile).ok()),
+            })
+        },
+    )
+}
+
+/// Gives each pin a tile. Pins keep their stored tile, unless another pin
+/// already has it; the rest take the first free tiles, in folder order.
+/// Returns the pins sorted by tile.
+fn assign_tiles(pins: Vec<Pin>) -> Vec<(u32, Pin)> {
+    let mut taken = HashSet::new();
+    let mut assigned = Vec::with_capacity(pins.len());
+    let mut unassigned = Vec::new();
+    for pin in pins {
+        match pin.tile {
+            Some(tile) if taken.insert(tile) => assigned.push((tile, pin)),
+            _ => unassigned.push(pin),
+        }
+    }
+    for pin in unassigned {
+        let tile = first_free_tile(&taken);
+        taken.insert(tile);
+        assigned.push((tile, pin));
+    }
+    assigned.sort_by_key(|(tile, _)| *tile);
+    assigned
+}
+
+fn first_free_tile(taken: &HashSet<u32>) -> u32 {
+    (0..).find(|tile| !taken.contains(tile)).unwrap()
+}
+
+fn set_pin_tile(db: &PlacesDb, guid: &SyncGuid, tile: u32) -> Result<()> {
+    set_annotation_in_tx(
+        db,
+        &AnnotationTarget::Item(guid.clone()),
+        PINNED_TILE_ANNO,
+        &AnnotationValue::Integer(tile.into()),
+        AnnotationExpiration::Never,
+    )
+}
+
+/// Pins `url` in the tile at index `tile`, or, if `tile` is `None`, in the
+/// first free tile. If `url` is already pinned, this moves it and updates
+/// its title instead, and `None` keeps it in its tile. A pin already in the
+/// tile moves to the first free one. Pinning a site removes it from the
+/// blocklist.
+pub fn pin_site(db: &PlacesDb, url: &Url, title: Option<String>, tile: Option<u32>) -> Result<()> {
+    let tx = db.begin_transaction()?;
+    let result = pin_site_in_tx(db, url, title, tile);
+    delete_pending_temp_tables(db)?;
+    match result {
+        Ok(_) => tx.commit()?,
+        Err(_) => tx.rollback()?,
+    }
+    result
+}
+
+fn pin_site_in_tx(
+    db: &PlacesDb,
+    url: &Url,
+    title: Option<String>,
+    tile: Option<u32>,
+) -> Result<()> {
+    if let Some(site) = site_for_url(url) {
+        db.execute_named_cached(
+            "DELETE FROM moz_topsites_blocklist WHERE site = :site",
+            &[(":site", &site)],
+        )?;
+    }
+    let mut pins = assign_tiles(fetch_pins(db)?);
+    let existing = pins
+        .iter()
+        .position(|(_, pin)| pin.url == url.as_str())
+        .map(|index| pins.remove(index));
+    let mut taken = pins.iter().map(|(tile, _)| *tile).collect::<HashSet<_>>();
+    let tile = match (tile, &existing) {
+        (Some(tile), _) => tile,
+        (None, Some((tile, _))) => *tile,
+        (None, None) => first_free_tile(&taken),
+    };
+    taken.insert(tile);
+    if let Some(displaced) = pins.iter_mut().find(|(other, _)| *other == tile) {
+        displaced.0 = first_free_tile(&taken);
+        taken.insert(displaced.0);
+    }
+    // Store the tiles of pins that moved or didn't have one yet, so that
+    // they stay put.
+    for (tile, pin) in &pins {
+        if pin.tile != Some(*tile) {
+            set_pin_tile(db, &pin.guid, *tile)?;
+        }
+    }
+
+    // Keep the folder in tile order, for other devices.
+    let position =
+        BookmarkPosition::Specific(pins.iter().filter(|(other, _)| *other < tile).count() as u32);
+    let guid = match existing {
+        Some((_, pin)) => {
+            let raw = get_raw_bookmark(db, &pin.guid)?
+                .ok_or_else(|| InvalidPlaceInfo::NoSuchGuid(pin.guid.to_string()))?;
+            update_bookmark_in_tx(
+                db,
+                &pin.guid,
+                &UpdatableBookmark {
+                    location: UpdateTreeLocation::Position(position),
+                    url: None,
+                    title,
+                }
+                .into(),
+                raw,
+            )?;
+            pin.guid
+        }
+        None => {
+            let folder_guid = ensure_pinned_sites_folder(db)?;
+            insert_bookmark_in_tx(
+                db,
+                &InsertableBookmark {
+                    parent_guid: folder_guid,
+                    position,
+                    date_added: None,
+                    last_modified: None,
+                    guid: None,
+                    url: url.clone(),
+                    title,
+                }
+                .into(),
+            )?
+        }
+    };
+    set_pin_tile(db, &guid, tile)
+}
+
+/// Unpins `url`. Returns whether it was pinned.
+pub fn unpin_site(db: &PlacesDb, url: &Url) -> Result<bool> {
+    let tx = db.begin_transaction()?;
+    let mut unpinned = false;
+    for pin in fetch_pins(db)? {
+        if pin.url == url.as_str() {
+            unpinned |= delete_bookmark_in_tx(db, &pin.guid)?;
+        }
+    }
+    tx.commit()?;
+    Ok(unpinned)
+}
+
+/// Removes the site for `url` from the top sites, by unpinning any of its
+/// pages and adding it to the blocklist.
+pub fn block_site(db: &PlacesDb, url: &Url) -> Result<()> {
+    let site = match site_for_url(url) {
+        Some(site) => site,
+        None => return Ok(()),
+    };
+    let tx = db.begin_transaction()?;
+    for pin in fetch_pins(db)? {
+        let pinned_site = Url::parse(&pin.url).ok().and_then(|url| site_for_url(&url));
+        if pinned_site.as_ref() == Some(&site) {
+            delete_bookmark_in_tx(db, &pin.guid)?;
+        }
+    }
+    db.execute_named_cached(
+        "INSERT OR REPLACE INTO moz_topsites_blocklist(site, blocked_at)
+         VALUES(:site, :now)",
+        &[(":site", &site), (":now", &Timestamp::now())],
+    )?;
+    tx.commit()?;
+    Ok(())
+}
+
+/// Removes the site for `url` from the blocklist. Returns whether it was
+/// blocked.
+pub fn unblock_site(db: &PlacesDb, url: &Url) -> Result<bool> {
+    let site = match site_for_url(url) {
+        Some(site) => site,
+        None => return Ok(false),
+    };
+    let changes = db.execute_named_cached(
+        "DELETE FROM moz_topsites_blocklist WHERE site = :site",
+        &[(":site", &site)],
+    )?;
+    Ok(changes > 0)
+}
+
+/// Returns the blocked sites, most recently blocked first.
+pub fn get_blocked_sites(db: &PlacesDb) -> Result<Vec<String>> {
+    db.query_rows_and_then_named_cached(
+        "SELECT site FROM moz_topsites_blocklist ORDER BY blocked_at DESC, site",
+        &[],
+        |row| -> Result<_> { Ok(row.get(0)?) },
+    )
+}
+
+pub fn clear_blocked_sites(db: &PlacesDb) -> Result<()> {
+    db.execute_batch("DELETE FROM moz_topsites_blocklist")?;
+    Ok(())
+}
+
+/// Returns the GUID of the pinned sites folder, creating the folder if it
+/// doesn't exist.
+fn ensure_pinned_sites_folder(db: &PlacesDb) -> Result<SyncGuid> {
+    let guid = SyncGuid::from(PINNED_SITES_FOLDER_GUID);
+    if get_raw_bookmark(db, &guid)?.is_none() {
+        insert_bookmark_in_tx(
+            db,
+            &InsertableFolder {
+                parent_guid: BookmarkRootGuid::Mobile.into(),
+                position: BookmarkPosition::Append,
+                date_added: None,
+                last_modified: None,
+                guid: Some(guid.clone()),
+                title: Some(PINNED_SITES_FOLDER_TITLE.into()),
+            }
+            .into(),
+        )?;
+    }
+    Ok(guid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::places_api::test::new_mem_connection;
+    use crate::observation::VisitObservation;
+    use crate::storage::bookmarks::insert_bookmark;
+    use crate::storage::history::apply_observation;
+
+    fn visit_times(db: &PlacesDb, url: &str, times: usize) {
+        for _ in 0..times {
+            apply_observation(
+                db,
+                VisitObservation::new(Url::parse(url).unwrap())
+                    .with_title(format!("Title of {}", url))
+                    .with_visit_type(VisitTransition::Typed),
+            )
+            .expect("should apply visit");
+        }
+    }
+
+    fn urls(sites: &[TopSite]) -> Vec<(&str, TopSiteKind)> {
+        sites.iter().map(|s| (s.url.as_str(), s.kind)).collect()
+    }
+
+    #[test]
+    fn test_site_for_url() {
+        let site = |url: &str| site_for_url(&Url::parse(url).unwrap());
+        assert_eq!(
+            site("https://www.Example.com/a").as_deref(),
+            Some("example.com")
+        );
+        assert_eq!(
+            site("http://example.com:8080/").as_deref(),
+            Some("example.com:8080")
+        );
+        assert_eq!(
+            site("https://example.com:443/").as_deref(),
+            Some("example.com")
+        );
+        assert_eq!(site("about:blank"), None);
+    }
+
+    #[test]
+    fn test_top_sites() -> Result<()> {
+        let conn = new_mem_connection();
+        visit_times(&conn, "https://www.example.com/", 5);
+        visit_times(&conn, "https://example.com/page", 3);
+        visit_times(&conn, "https://mozilla.org/", 4);
+        visit_times(&conn, "https://rust-lang.org/", 2);
+        visit_times(&conn, "https://blocked.example/", 6);
+
+        let defaults = [
+            DefaultTopSite {
+                url: Url::parse("https://www.mozilla.org/")?,
+                title: "Mozilla".into(),
+            },
+            DefaultTopSite {
+                url: Url::parse("https://partner.example/")?,
+                title: "Partner".into(),
+            },
+            DefaultTopSite {
+                url: Url::parse("https://blocked.example/")?,
+                title: "Blocked".into(),
+            },
+        ];
+
+        block_site(&conn, &Url::parse("https://blocked.example/some/page")?)?;
+        assert_eq!(get_blocked_sites(&conn)?, vec!["blocked.example"]);
+
+        pin_site(
+            &conn,
+            &Url::parse("https://rust-lang.org/")?,
+            Some("Rust".into()),
+            None,
+        )?;
+        pin_site(
+            &conn,
+            &Url::parse("https://pinned.example/")?,
+            None,
+            Some(0),
+        )?;
+
+        let top_sites = get_top_sites(&conn, 10, 0, &defaults)?;
+        assert_eq!(
+            urls(&top_sites),
+            vec![
+                ("https://pinned.example/", TopSiteKind::Pinned),
+                ("https://rust-lang.org/", TopSiteKind::Pinned),
+                // Only the most frecent page on example.com.
+                ("https://www.example.com/", TopSiteKind::Frecent),
+                ("https://mozilla.org/", TopSiteKind::Frecent),
+                // www.mozilla.org is the same site as mozilla.org.
+                ("https://partner.example/", TopSiteKind::Default),
+            ]
+        );
+        assert_eq!(top_sites[1].title.as_deref(), Some("Rust"));
+
+        // Pins keep their place, even with fewer tiles.
+        assert_eq!(
+            urls(&get_top_sites(&conn, 3, 0, &defaults)?),
+            vec![
+                ("https://pinned.example/", TopSiteKind::Pinned),
+                ("https://rust-lang.org/", TopSiteKind::Pinned),
+                ("https://www.example.com/", TopSiteKind::Frecent),
+            ]
+        );
+
+        // Moving a pin.
+        pin_site(&conn, &Url::parse("https://rust-lang.org/")?, None, Some(0))?;
+        assert_eq!(
+            urls(&get_pinned_sites(&conn)?),
+            vec![
+                ("https://rust-lang.org/", TopSiteKind::Pinned),
+                ("https://pinned.example/", TopSiteKind::Pinned),
+            ]
+        );
+
+        // Blocking a pinned site unpins it, and unpinning a frecent site
+        // puts it back with the others.
+        block_site(&conn, &Url::parse("https://pinned.example/")?)?;
+        assert!(unpin_site(&conn, &Url::parse("https://rust-lang.org/")?)?);
+        assert!(!unpin_site(&conn, &Url::parse("https://rust-lang.org/")?)?);
+        assert_eq!(
+            urls(&get_top_sites(&conn, 10, 0, &[])?),
+            vec![
+                ("https://www.example.com/", TopSiteKind::Frecent),
+                ("https://mozilla.org/", TopSiteKind::Frecent),
+                ("https://rust-lang.org/", TopSiteKind::Frecent),
+            ]
+        );
+
+        // Pinning a blocked site unblocks it.
+        pin_site(&conn, &Url::parse("https://blocked.example/")?, None, None)?;
+        assert_eq!(get_blocked_sites(&conn)?, vec!["pinned.example"]);
+        assert!(unblock_site(
+            &conn,
+            &Url::parse("https://pinned.example/")?
+        )?);
+        clear_blocked_sites(&conn)?;
+        assert!(get_blocked_sites(&conn)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_pinned_tiles() -> Result<()> {
+        let conn = new_mem_connection();
+        visit_times(&conn, "https://a.example/", 5);
+        visit_times(&conn, "https://b.example/", 4);
+        visit_times(&conn, "https://c.example/", 3);
+        visit_times(&conn, "https://d.example/", 2);
+
+        pin_site(&conn, &Url::parse("https://x.example/")?, None, Some(2))?;
+        pin_site(&conn, &Url::parse("https://y.example/")?, None, Some(4))?;
+        assert_eq!(
+            urls(&get_top_sites(&conn, 6, 0, &[])?),
+            vec![
+                ("https://a.example/", TopSiteKind::Frecent),
+                ("https://b.example/", TopSiteKind::Frecent),
+                ("https://x.example/", TopSiteKind::Pinned),
+                ("https://c.example/", TopSiteKind::Frecent),
+                ("https://y.example/", TopSiteKind::Pinned),
+                ("https://d.example/", TopSiteKind::Frecent),
+            ]
+        );
+        // Pins past the last tile aren't shown.
+        assert_eq!(
+            urls(&get_top_sites(&conn, 4, 0, &[])?),
+            vec![
+                ("https://a.example/", TopSiteKind::Frecent),
+                ("https://b.example/", TopSiteKind::Frecent),
+                ("https://x.example/", TopSiteKind::Pinned),
+                ("https://c.example/", TopSiteKind::Frecent),
+            ]
+        );
+        // The folder is kept in tile order.
+        pin_site(&conn, &Url::parse("https://v.example/")?, None, Some(3))?;
+        assert_eq!(
+            fetch_pins(&conn)?
+                .iter()
+                .map(|pin| pin.url.as_str())
+                .collect::<Vec<_>>(),
+            vec![
+                "https://x.example/",
+                "https://v.example/",
+                "https://y.example/"
+            ]
+        );
+        assert!(unpin_site(&conn, &Url::parse("https://v.example/")?)?);
+
+        // A pin from another device doesn't have a tile, so it takes the
+        // first free one.
+        insert_bookmark(
+            &conn,
+            &InsertableBookmark {
+                parent_guid: PINNED_SITES_FOLDER_GUID.into(),
+                position: BookmarkPosition::Append,
+                date_added: None,
+                last_modified: None,
+                guid: None,
+                url: Url::parse("https://z.example/")?,
+                title: None,
+            }
+            .into(),
+        )?;
+        assert_eq!(
+            urls(&get_top_sites(&conn, 6, 0, &[])?),
+            vec![
+                ("https://z.example/", TopSiteKind::Pinned),
+                ("https://a.example/", TopSiteKind::Frecent),
+                ("https://x.example/", TopSiteKind::Pinned),
+                ("https://b.example/", TopSiteKind::Frecent),
+                ("https://y.example/", TopSiteKind::Pinned),
+                ("https://c.example/", TopSiteKind::Frecent),
+            ]
+        );
+
+        // Pinning a site in its tile moves it to the next free one, and
+        // stores that tile.
+        pin_site(&conn, &Url::parse("https://w.example/")?, None, Some(0))?;
+        let pins = fetch_pins(&conn)?;
+        let z = pins
+            .iter()
+            .find(|pin| pin.url == "https://z.example/")
+            .unwrap();
+        assert_eq!(z.tile, Some(1));
+        assert_eq!(
+            urls(&get_pinned_sites(&conn)?),
+            vec![
+                ("https://w.example/", TopSiteKind::Pinned),
+                ("https://z.example/", TopSiteKind::Pinned),
+                ("https://x.example/", TopSiteKind::Pinned),
+                ("https://y.example/", TopSiteKind::Pinned),
+            ]
+        );
+
+        // If there aren't enough sites to fill the tiles before a pin, it
+        // moves up.
+        block_site(&conn, &Url::parse("https://b.example/")?)?;
+        block_site(&conn, &Url::parse("https://c.example/")?)?;
+        block_site(&conn, &Url::parse("https://d.example/")?)?;
+        unpin_site(&conn, &Url::parse("https://x.example/")?)?;
+        assert_eq!(
+            urls(&get_top_sites(&conn, 6, 0, &[])?),
+            vec![
+                ("https://w.example/", TopSiteKind::Pinned),
+                ("https://z.example/", TopSiteKind::Pinned),
+                ("https://a.example/", TopSiteKind::Frecent),
+                ("https://y.example/", TopSiteKind::Pinned),
+            ]
+        );
+        Ok(())
+    }
+}
@@ -6,7 +6,8 @@ use crate::error::*;
 use crate::msg_types::{DeviceType, ServiceStatus, SyncParams, SyncReason, SyncResult};
 use crate::{reset, reset_all, wipe};
 use places::{
-    bookmark_sync::engine::BookmarksEngine, history_sync::engine::HistoryEngine, PlacesApi,
+    bookmark_sync::engine::BookmarksEngine, history_metadata_sync::engine::HistoryMetadataEngine,
+    history_sync::engine::HistoryEngine, PlacesApi,
 };
 use std::collections::{HashMap, HashSet};
 use std::sync::{atomic::AtomicUsize, Arc, Weak};
@@ -284,7 +285,10 @@ impl SyncManager {
                 "Should have already checked"
             );
             if history_sync {
-                engines.push(Box::new(HistoryEngine::new(pc, &interruptee)))
+                engines.push(Box::new(HistoryEngine::new(pc, &interruptee)));
+                // History metadata is synced, reset and wiped along with
+                // history.
+                engines.push(Box::new(HistoryMetadataEngine::new(pc, &interruptee)));
             }
             if bookmarks_sync {
                 engines.push(Box::new(BookmarksEngine::new(pc, &interruptee)))
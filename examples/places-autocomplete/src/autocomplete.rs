@@ -215,7 +215,7 @@ fn import_places(
 #[cfg(not(windows))]
 mod autocomplete {
     use super::*;
-    use places::api::matcher::{search_frecent, SearchParams, SearchResult};
+    use places::api::matcher::{search_frecent, SearchMode, SearchParams, SearchResult};
     use places::ErrorKind;
     use rusqlite::{Error as RusqlError, ErrorCode};
     use sql_support::SqlInterruptHandle;
@@ -517,6 +517,7 @@ mod autocomplete {
                             autocompleter.query(SearchParams {
                                 search_string: query_str.clone(),
                                 limit: 10,
+                                mode: SearchMode::Scan,
                             })?;
                         }
                     }
@@ -599,6 +600,7 @@ mod autocomplete {
                         autocompleter.query(SearchParams {
                             search_string: query_str.clone(),
                             limit: 10,
+                            mode: SearchMode::Scan,
                         })?;
                     } else {
                         pending_change = true;
@@ -627,6 +629,7 @@ mod autocomplete {
                     autocompleter.query(SearchParams {
                         search_string: query_str.clone(),
                         limit: 10,
+                        mode: SearchMode::Scan,
                     })?;
                 }
             }
@@ -148,6 +148,45 @@ fn run_native_import(db: &PlacesDb, filename: String) -> Result<()> {
     do_import(db, root)
 }
 
+fn run_html_import(db: &PlacesDb, filename: String) -> Result<()> {
+    println!("html import from {}", filename);
+
+    let file = File::open(filename)?;
+    let result = places::import::import_html_bookmarks(db, BufReader::new(file))?;
+    println!("Import finished: {:?}", result);
+    Ok(())
+}
+
+fn run_html_export(db: &PlacesDb, filename: String) -> Result<()> {
+    println!("html export to {}", filename);
+
+    let file = File::create(filename)?;
+    let num_exported = places::import::export_html_bookmarks(db, BufWriter::new(file))?;
+    println!("Exported {} items", num_exported);
+    Ok(())
+}
+
+fn run_backup(db: &PlacesDb, dir: String, max_backups: usize) -> Result<()> {
+    match places::storage::bookmarks::backup::create_rolling_backup(db, dir, max_backups)? {
+        Some(backup) => println!("Wrote backup to {:?}", backup.path),
+        None => println!("Nothing changed since the last backup"),
+    }
+    Ok(())
+}
+
+fn run_restore(db: &PlacesDb, filename: String) -> Result<()> {
+    println!("restore from {}", filename);
+    let result = places::storage::bookmarks::backup::restore_backup_file(db, filename)?;
+    println!("Restore finished: {:?}", result);
+    Ok(())
+}
+
+fn run_stats(db: &PlacesDb) -> Result<()> {
+    let stats = places::storage::diagnostics::get_database_stats(db)?;
+    println!("{}", serde_json::to_string_pretty(&stats)?);
+    Ok(())
+}
+
 fn run_native_export(db: &PlacesDb, filename: String) -> Result<()> {
     println!("export to {}", filename);
 
@@ -364,6 +403,50 @@ enum Command {
         input_file: String,
     },
 
+    #[structopt(name = "export-html-bookmarks")]
+    /// Exports bookmarks as a bookmarks.html file, which Desktop and other
+    /// browsers can import.
+    ExportHtmlBookmarks {
+        #[structopt(name = "output-file", long, short = "o")]
+        /// The name of the output file where the html will be written.
+        output_file: String,
+    },
+
+    #[structopt(name = "import-html-bookmarks")]
+    /// Import bookmarks from a bookmarks.html file
+    ImportHtmlBookmarks {
+        #[structopt(name = "input-file", long, short = "i")]
+        /// The name of the file to read.
+        input_file: String,
+    },
+
+    #[structopt(name = "backup-bookmarks")]
+    /// Writes a Desktop-compatible .jsonlz4 bookmarks backup, unless nothing
+    /// changed since the last one.
+    BackupBookmarks {
+        #[structopt(name = "dir", long, short = "d")]
+        /// The directory holding the backups.
+        dir: String,
+
+        #[structopt(name = "max-backups", long, default_value = "15")]
+        /// The number of backups to keep.
+        max_backups: usize,
+    },
+
+    #[structopt(name = "restore-bookmarks")]
+    /// Replaces all bookmarks with a .json or .jsonlz4 backup from this
+    /// utility or from Desktop.
+    RestoreBookmarks {
+        #[structopt(name = "input-file", long, short = "i")]
+        /// The name of the file to read.
+        input_file: String,
+    },
+
+    #[structopt(name = "stats")]
+    /// Prints statistics about the database as JSON, for diagnosing large or
+    /// slow databases.
+    Stats,
+
     #[structopt(name = "import-desktop-bookmarks")]
     /// Import bookmarks from JSON file exported by desktop Firefox
     ImportDesktopBookmarks {
@@ -406,5 +489,10 @@ fn main() -> Result<()> {
         Command::ImportBookmarks { input_file } => run_native_import(&db, input_file),
         Command::ImportIosBookmarks { input_file } => run_ios_import(&api, input_file),
         Command::ImportDesktopBookmarks { input_file } => run_desktop_import(&db, input_file),
+        Command::ExportHtmlBookmarks { output_file } => run_html_export(&db, output_file),
+        Command::ImportHtmlBookmarks { input_file } => run_html_import(&db, input_file),
+        Command::BackupBookmarks { dir, max_backups } => run_backup(&db, dir, max_backups),
+        Command::RestoreBookmarks { input_file } => run_restore(&db, input_file),
+        Command::Stats => run_stats(&db),
     }
 }
@@ -3,7 +3,7 @@
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 use criterion::Criterion;
 use places::api::{
-    matcher::{match_url, search_frecent, SearchParams},
+    matcher::{match_url, search_frecent, SearchMode, SearchParams},
     places_api::ConnectionType,
 };
 use places::PlacesDb;
@@ -94,6 +94,7 @@ pub fn bench_search_frecent(c: &mut Criterion) {
             SearchParams {
                 search_string: "mozilla".into(),
                 limit: 10,
+                mode: SearchMode::Scan,
             },
         )
         .unwrap()
@@ -104,6 +105,7 @@ pub fn bench_search_frecent(c: &mut Criterion) {
             SearchParams {
                 search_string: "blog.mozilla.org".into(),
                 limit: 10,
+                mode: SearchMode::Scan,
             },
         )
         .unwrap()
@@ -114,6 +116,34 @@ pub fn bench_search_frecent(c: &mut Criterion) {
             SearchParams {
                 search_string: "https://hg.mozilla.org/mozilla-central".into(),
                 limit: 10,
+                mode: SearchMode::Scan,
+            },
+        )
+        .unwrap()
+    });
+}
+
+pub fn bench_search_frecent_full_text(c: &mut Criterion) {
+    let test_db = TestDb::new();
+    places::storage::search_index::enable_search_index(&test_db.db).unwrap();
+    db_bench!(c, "search_frecent full text string", |db: test_db| {
+        search_frecent(
+            db,
+            SearchParams {
+                search_string: "mozilla".into(),
+                limit: 10,
+                mode: SearchMode::FullText,
+            },
+        )
+        .unwrap()
+    });
+    db_bench!(c, "search_frecent full text words", |db: test_db| {
+        search_frecent(
+            db,
+            SearchParams {
+                search_string: "mozilla central".into(),
+                limit: 10,
+                mode: SearchMode::FullText,
             },
         )
         .unwrap()
@@ -0,0 +1,132 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use places::api::places_api::{ConnectionType, PlacesApi};
+use places::storage::fetch_page_info;
+use places::{types::VisitTransition, ErrorKind, Result};
+use rusqlite::{named_params, Connection, NO_PARAMS};
+use std::path::Path;
+use tempfile::tempdir;
+use types::Timestamp;
+use url::Url;
+
+fn empty_chromium_db(path: &Path) -> Result<Connection> {
+    let conn = Connection::open(path)?;
+    conn.execute_batch(include_str!("./chromium_history_schema.sql"))?;
+    Ok(conn)
+}
+
+// Converts a Unix time in milliseconds to Chromium's microseconds since 1601.
+fn chromium_time(ts: Timestamp) -> i64 {
+    (ts.as_millis() as i64 + 11_644_473_600_000) * 1000
+}
+
+fn insert_url(conn: &Connection, id: i64, url: &str, title: Option<&str>) -> Result<()> {
+    conn.execute_named(
+        "INSERT INTO urls(id, url, title, last_visit_time) VALUES(:id, :url, :title, 0)",
+        named_params! { ":id": id, ":url": url, ":title": title },
+    )?;
+    Ok(())
+}
+
+fn insert_visit(conn: &Connection, url_id: i64, date: Timestamp, transition: i64) -> Result<()> {
+    conn.execute_named(
+        "INSERT INTO visits(url, visit_time, transition) VALUES(:url, :visit_time, :transition)",
+        named_params! {
+            ":url": url_id,
+            ":visit_time": chromium_time(date),
+            ":transition": transition,
+        },
+    )?;
+    Ok(())
+}
+
+#[test]
+fn test_import_unsupported_db_version() -> Result<()> {
+    let tmpdir = tempdir().unwrap();
+    let chromium_path = tmpdir.path().join("History");
+    let chromium_db = empty_chromium_db(&chromium_path)?;
+    chromium_db.execute(
+        "UPDATE meta SET value = '19' WHERE key = 'version'",
+        NO_PARAMS,
+    )?;
+    let places_api = PlacesApi::new(tmpdir.path().join("places.sqlite"))?;
+    match places::import::import_chromium_history(&places_api, chromium_path)
+        .unwrap_err()
+        .kind()
+    {
+        ErrorKind::UnsupportedDatabaseVersion(19) => {}
+        _ => unreachable!("Should fail with UnsupportedDatabaseVersion!"),
+    }
+    Ok(())
+}
+
+#[test]
+fn test_import() -> Result<()> {
+    let tmpdir = tempdir().unwrap();
+    let chromium_path = tmpdir.path().join("History");
+    let chromium_db = empty_chromium_db(&chromium_path)?;
+
+    insert_url(&chromium_db, 1, "https://example.com/", Some("Example"))?;
+    insert_url(&chromium_db, 2, "https://example.com/redirected", None)?;
+    insert_url(&chromium_db, 3, "https://ads.example.com/frame", None)?;
+    insert_url(&chromium_db, 4, "not a url", None)?;
+
+    let start = Timestamp::from(1_565_117_389_897);
+    let later = Timestamp::from(1_565_117_399_897);
+    // Typed, with the "from address bar" and "chain start/end" qualifiers.
+    insert_visit(&chromium_db, 1, start, 0x3200_0001)?;
+    // A link, which redirected.
+    insert_visit(&chromium_db, 1, later, 0x1000_0000)?;
+    insert_visit(&chromium_db, 2, later, 0xA000_0000)?;
+    // An automatic subframe load, which we don't import.
+    insert_visit(&chromium_db, 3, later, 3)?;
+    insert_visit(&chromium_db, 4, later, 0)?;
+
+    let places_api = PlacesApi::new(tmpdir.path().join("places.sqlite"))?;
+    let metrics = places::import::import_chromium_history(&places_api, &chromium_path)?;
+    assert_eq!(metrics.num_total, 4);
+    assert_eq!(metrics.num_succeeded, 3);
+    assert_eq!(metrics.num_failed, 1);
+
+    let conn = places_api.open_connection(ConnectionType::ReadOnly)?;
+    let page = fetch_page_info(&conn, &Url::parse("https://example.com/")?)?
+        .expect("should have imported the page");
+    assert_eq!(page.page.title, "Example");
+    assert_eq!(page.page.visit_count_local, 2);
+    assert!(fetch_page_info(&conn, &Url::parse("https://ads.example.com/frame")?)?.is_none());
+
+    let mut stmt = conn.prepare(
+        "SELECT h.url, v.visit_type FROM moz_historyvisits v
+         JOIN moz_places h ON h.id = v.place_id
+         ORDER BY h.url, v.visit_date",
+    )?;
+    let visits = stmt
+        .query_map(NO_PARAMS, |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, u8>(1)?))
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    assert_eq!(
+        visits,
+        vec![
+            (
+                "https://example.com/".to_owned(),
+                VisitTransition::Typed as u8
+            ),
+            (
+                "https://example.com/".to_owned(),
+                VisitTransition::Link as u8
+            ),
+            (
+                "https://example.com/redirected".to_owned(),
+                VisitTransition::RedirectTemporary as u8
+            ),
+        ]
+    );
+
+    // Importing again doesn't duplicate visits.
+    let metrics = places::import::import_chromium_history(&places_api, &chromium_path)?;
+    assert_eq!(metrics.num_succeeded, 0);
+    Ok(())
+}
@@ -726,6 +726,92 @@ fn test_empty_db() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn test_import_descriptions() -> Result<()> {
+    use places::api::places_api::ConnectionType;
+    use places::storage::annotations::{
+        get_annotation, AnnotationTarget, AnnotationValue, DESCRIPTION_ANNO,
+    };
+
+    let tmpdir = tempdir().unwrap();
+    let fennec_path = tmpdir.path().join("browser.db");
+    let fennec_db = empty_fennec_db(&fennec_path)?;
+
+    let described = FennecBookmark {
+        _id: 10,
+        parent: 1,
+        url: Some("https://www.example.com/described".to_owned()),
+        description: Some("A page with a description".to_owned()),
+        ..Default::default()
+    };
+    let undescribed = FennecBookmark {
+        _id: 11,
+        parent: 1,
+        url: Some("https://www.example.com/undescribed".to_owned()),
+        description: Some("".to_owned()),
+        ..Default::default()
+    };
+    insert_bookmarks(&fennec_db, &get_fennec_roots())?;
+    insert_bookmarks(&fennec_db, &[described.clone(), undescribed.clone()])?;
+
+    let places_api = PlacesApi::new(tmpdir.path().join("places.sqlite"))?;
+    places::import::import_fennec_bookmarks(&places_api, fennec_path)?;
+
+    let conn = places_api.open_connection(ConnectionType::ReadOnly)?;
+    let anno = get_annotation(
+        &conn,
+        &AnnotationTarget::Item(described.guid),
+        DESCRIPTION_ANNO,
+    )?
+    .expect("should import the description");
+    assert_eq!(
+        anno.value,
+        AnnotationValue::Text("A page with a description".to_owned())
+    );
+    // Empty descriptions aren't imported.
+    assert!(get_annotation(
+        &conn,
+        &AnnotationTarget::Item(undescribed.guid),
+        DESCRIPTION_ANNO
+    )?
+    .is_none());
+    Ok(())
+}
+
+#[test]
+fn test_import_keywords() -> Result<()> {
+    use places::api::places_api::ConnectionType;
+    use places::storage::keywords::resolve_keyword_search;
+
+    let tmpdir = tempdir().unwrap();
+    let fennec_path = tmpdir.path().join("browser.db");
+    let fennec_db = empty_fennec_db(&fennec_path)?;
+
+    let search = FennecBookmark {
+        _id: 10,
+        parent: 1,
+        title: Some("Wiki search".to_owned()),
+        url: Some("https://wiki.example.com/?search=%s".to_owned()),
+        // Fennec doesn't normalize keywords, but we do.
+        keyword: Some(" Wiki ".to_owned()),
+        ..Default::default()
+    };
+    insert_bookmarks(&fennec_db, &get_fennec_roots())?;
+    insert_bookmarks(&fennec_db, &[search])?;
+
+    let places_api = PlacesApi::new(tmpdir.path().join("places.sqlite"))?;
+    places::import::import_fennec_bookmarks(&places_api, fennec_path)?;
+
+    let conn = places_api.open_connection(ConnectionType::ReadOnly)?;
+    let found = resolve_keyword_search(&conn, "wiki rust lang")?.expect("should import keyword");
+    assert_eq!(
+        found.url.as_str(),
+        "https://wiki.example.com/?search=rust%20lang"
+    );
+    assert_eq!(found.title, "Wiki search");
+    Ok(())
+}
+
 enum TimestampTestType {
     LocalNewer,
     RemoteNewer,
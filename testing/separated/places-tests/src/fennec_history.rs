@@ -368,3 +368,62 @@ fn test_invalid_utf8() -> Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn test_import_url_annotations() -> Result<()> {
+    use places::api::places_api::ConnectionType;
+    use places::storage::annotations::{get_annotations, AnnotationTarget, AnnotationValue};
+    use url::Url;
+
+    let tmpdir = tempdir().unwrap();
+    let fennec_path = tmpdir.path().join("browser.db");
+    let fennec_db = empty_fennec_db(&fennec_path)?;
+
+    let history = FennecHistory {
+        url: "https://www.example.com/".to_owned(),
+        ..Default::default()
+    };
+    let visits = [FennecVisit {
+        history: &history,
+        visit_type: VisitTransition::Link,
+        date: Timestamp::now(),
+        is_local: true,
+    }];
+    insert_history_and_visits(&fennec_db, &[history.clone()], &visits)?;
+
+    // Newer Fennec databases have a `urlannotations` table.
+    fennec_db.execute_batch(
+        "CREATE TABLE urlannotations (
+             _id INTEGER PRIMARY KEY AUTOINCREMENT,
+             url TEXT NOT NULL,
+             key TEXT NOT NULL,
+             value TEXT,
+             created INTEGER NOT NULL,
+             modified INTEGER NOT NULL,
+             sync_status TINYINT NOT NULL DEFAULT 0
+         );
+         INSERT INTO urlannotations(url, key, value, created, modified)
+         VALUES('https://www.example.com/', 'reader_view', 'true', 1, 1),
+               -- Annotations for pages we don't know about are dropped.
+               ('https://www.example.com/unknown', 'reader_view', 'true', 1, 1);",
+    )?;
+
+    let places_api = PlacesApi::new(tmpdir.path().join("places.sqlite"))?;
+    places::import::import_fennec_history(&places_api, fennec_path)?;
+
+    let conn = places_api.open_connection(ConnectionType::ReadOnly)?;
+    let annos = get_annotations(
+        &conn,
+        &AnnotationTarget::Page(Url::parse("https://www.example.com/")?),
+    )?;
+    assert_eq!(annos.len(), 1);
+    assert_eq!(annos[0].name, "fennec/reader_view");
+    assert_eq!(annos[0].value, AnnotationValue::Text("true".to_owned()));
+    assert!(get_annotations(
+        &conn,
+        &AnnotationTarget::Page(Url::parse("https://www.example.com/unknown")?),
+    )?
+    .is_empty());
+
+    Ok(())
+}
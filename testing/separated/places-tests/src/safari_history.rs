@@ -0,0 +1,183 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use places::api::places_api::{ConnectionType, PlacesApi};
+use places::storage::fetch_page_info;
+use places::{types::VisitTransition, ErrorKind, Result};
+use rusqlite::{named_params, Connection, NO_PARAMS};
+use std::path::Path;
+use tempfile::tempdir;
+use types::Timestamp;
+use url::Url;
+
+fn empty_safari_db(path: &Path) -> Result<Connection> {
+    let conn = Connection::open(path)?;
+    conn.execute_batch(include_str!("./safari_history_schema.sql"))?;
+    Ok(conn)
+}
+
+// Converts a Unix time in milliseconds to Safari's seconds since 2001.
+fn safari_time(ts: Timestamp) -> f64 {
+    ts.as_millis() as f64 / 1000.0 - 978_307_200.0
+}
+
+#[derive(Default)]
+struct SafariVisit {
+    item: i64,
+    date: u64,
+    title: Option<&'static str>,
+    redirect_source: Option<i64>,
+    origin: i64,
+    load_successful: bool,
+    synthesized: bool,
+}
+
+impl SafariVisit {
+    fn insert_into_db(&self, conn: &Connection) -> Result<i64> {
+        conn.execute_named(
+            "INSERT INTO history_visits(history_item, visit_time, title, load_successful,
+                                        synthesized, redirect_source, origin)
+             VALUES(:item, :visit_time, :title, :load_successful, :synthesized,
+                    :redirect_source, :origin)",
+            named_params! {
+                ":item": self.item,
+                ":visit_time": safari_time(Timestamp(self.date)),
+                ":title": self.title,
+                ":load_successful": self.load_successful,
+                ":synthesized": self.synthesized,
+                ":redirect_source": self.redirect_source,
+                ":origin": self.origin,
+            },
+        )?;
+        Ok(conn.last_insert_rowid())
+    }
+}
+
+#[test]
+fn test_import_not_safari() -> Result<()> {
+    let tmpdir = tempdir().unwrap();
+    let path = tmpdir.path().join("History.db");
+    Connection::open(&path)?.execute_batch("CREATE TABLE history(url TEXT)")?;
+    let places_api = PlacesApi::new(tmpdir.path().join("places.sqlite"))?;
+    match places::import::import_safari_history(&places_api, path)
+        .unwrap_err()
+        .kind()
+    {
+        ErrorKind::InvalidImportFile(_) => {}
+        _ => unreachable!("Should fail with InvalidImportFile!"),
+    }
+    Ok(())
+}
+
+#[test]
+fn test_import() -> Result<()> {
+    let tmpdir = tempdir().unwrap();
+    let safari_path = tmpdir.path().join("History.db");
+    let safari_db = empty_safari_db(&safari_path)?;
+    safari_db.execute_batch(
+        "INSERT INTO history_items(id, url, visit_count) VALUES
+            (1, 'http://example.com/', 1),
+            (2, 'https://example.com/', 2),
+            (3, 'https://example.com/missing', 1),
+            (4, 'not a url', 1);",
+    )?;
+
+    let redirect_source = SafariVisit {
+        item: 1,
+        date: 1_565_117_389_000,
+        load_successful: true,
+        ..Default::default()
+    }
+    .insert_into_db(&safari_db)?;
+    SafariVisit {
+        item: 2,
+        date: 1_565_117_389_500,
+        title: Some("Old title"),
+        redirect_source: Some(redirect_source),
+        load_successful: true,
+        ..Default::default()
+    }
+    .insert_into_db(&safari_db)?;
+    // Synced from another device.
+    SafariVisit {
+        item: 2,
+        date: 1_565_117_399_000,
+        title: Some("Example"),
+        origin: 1,
+        load_successful: true,
+        ..Default::default()
+    }
+    .insert_into_db(&safari_db)?;
+    SafariVisit {
+        item: 3,
+        date: 1_565_117_399_000,
+        load_successful: false,
+        ..Default::default()
+    }
+    .insert_into_db(&safari_db)?;
+    SafariVisit {
+        item: 2,
+        date: 1_565_000_000_000,
+        load_successful: true,
+        synthesized: true,
+        ..Default::default()
+    }
+    .insert_into_db(&safari_db)?;
+    SafariVisit {
+        item: 4,
+        date: 1_565_117_399_000,
+        load_successful: true,
+        ..Default::default()
+    }
+    .insert_into_db(&safari_db)?;
+
+    let places_api = PlacesApi::new(tmpdir.path().join("places.sqlite"))?;
+    let metrics = places::import::import_safari_history(&places_api, &safari_path)?;
+    assert_eq!(metrics.num_total, 4);
+    assert_eq!(metrics.num_succeeded, 3);
+    assert_eq!(metrics.num_failed, 1);
+
+    let conn = places_api.open_connection(ConnectionType::ReadOnly)?;
+    let page = fetch_page_info(&conn, &Url::parse("https://example.com/")?)?
+        .expect("should have imported the page");
+    assert_eq!(page.page.title, "Example");
+    assert_eq!(page.page.visit_count_local, 1);
+    assert_eq!(page.page.visit_count_remote, 1);
+
+    let mut stmt = conn.prepare(
+        "SELECT h.url, v.visit_date, v.visit_type FROM moz_historyvisits v
+         JOIN moz_places h ON h.id = v.place_id
+         ORDER BY v.visit_date",
+    )?;
+    let visits = stmt
+        .query_map(NO_PARAMS, |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, i64>(1)?,
+                row.get::<_, u8>(2)?,
+            ))
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    assert_eq!(
+        visits,
+        vec![
+            (
+                "http://example.com/".to_owned(),
+                1_565_117_389_000,
+                VisitTransition::Link as u8
+            ),
+            (
+                "https://example.com/".to_owned(),
+                1_565_117_389_500,
+                VisitTransition::RedirectTemporary as u8
+            ),
+            (
+                "https://example.com/".to_owned(),
+                1_565_117_399_000,
+                VisitTransition::Link as u8
+            ),
+        ]
+    );
+    Ok(())
+}
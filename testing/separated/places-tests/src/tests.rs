@@ -3,6 +3,8 @@
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
 mod check_coop_tx;
+mod chromium_history;
 mod fennec_bookmarks;
 mod fennec_history;
 mod ios_bookmarks;
+mod safari_history;